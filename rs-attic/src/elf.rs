@@ -0,0 +1,478 @@
+use std::collections::HashMap;
+
+use ::x64::*;
+use ::x64::encode::{Assembler, Fixup, FixupKind};
+
+// Writes an ELF64 relocatable object (a `.o`) for x86-64 Linux.
+//
+// Functions become global symbols in .text and constant pools become local
+// symbols in .rodata. Every Named label that is referred to but not defined
+// here becomes an undefined global symbol for the linker to resolve.
+pub struct ObjectWriter {
+    text: Vec<u8>,
+    rodata: Vec<u8>,
+    symbols: Vec<Symbol>,
+    // Offsets are into text.
+    relocs: Vec<Fixup>,
+}
+
+#[derive(Debug)]
+struct Symbol {
+    name: String,
+    section: Section,
+    offset: usize,
+    size: usize,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Section {
+    Text,
+    Rodata,
+}
+
+// Section header indices, in the order they are written.
+const SHN_TEXT: u16 = 1;
+const SHN_RODATA: u16 = 2;
+const SHN_SYMTAB: u16 = 4;
+const SHN_STRTAB: u16 = 5;
+const SHN_SHSTRTAB: u16 = 6;
+const SECTION_NAMES: [&str; 8] = [
+    "", ".text", ".rodata", ".rela.text", ".symtab", ".strtab", ".shstrtab", ".note.GNU-stack",
+];
+
+const EHDR_SIZE: usize = 64;
+const SHDR_SIZE: usize = 64;
+const SYM_SIZE: usize = 24;
+const RELA_SIZE: usize = 24;
+
+const ET_REL: u16 = 1;
+const EM_X86_64: u16 = 62;
+
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHT_RELA: u32 = 4;
+
+const SHF_ALLOC: u64 = 2;
+const SHF_EXECINSTR: u64 = 4;
+const SHF_INFO_LINK: u64 = 0x40;
+
+const STB_LOCAL: u8 = 0;
+const STB_GLOBAL: u8 = 1;
+const STT_NOTYPE: u8 = 0;
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+const STT_SECTION: u8 = 3;
+
+const R_X86_64_PC32: u32 = 2;
+const R_X86_64_PLT32: u32 = 4;
+
+const FUNCTION_ALIGN: usize = 16;
+
+impl Default for ObjectWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ObjectWriter {
+    pub fn new() -> Self {
+        ObjectWriter {
+            text: vec![],
+            rodata: vec![],
+            symbols: vec![],
+            relocs: vec![],
+        }
+    }
+
+    // The Local labels of f must all be bound within f.
+    pub fn add_function(&mut self, name: &str, f: &Function) {
+        let mut asm = Assembler::new();
+        asm.encode_function(f);
+        let code = asm.finish();
+
+        // Padded with int3.
+        pad_to(&mut self.text, FUNCTION_ALIGN, 0xcc);
+        let offset = self.text.len();
+        self.text.extend(&code.bytes);
+        for mut reloc in code.relocs {
+            assert!(matches!(reloc.label, Label::Named(_)),
+                    "{} is not bound in {}", reloc.label, name);
+            reloc.offset += offset;
+            self.relocs.push(reloc);
+        }
+        self.define(name, Section::Text, offset, code.bytes.len());
+    }
+
+    // Can be referred to by RIP-relative operands as Label::Named(name).
+    pub fn add_rodata(&mut self, name: &str, bytes: &[u8], align: usize) {
+        pad_to(&mut self.rodata, align, 0);
+        let offset = self.rodata.len();
+        self.rodata.extend(bytes);
+        self.define(name, Section::Rodata, offset, bytes.len());
+    }
+
+    fn define(&mut self, name: &str, section: Section, offset: usize, size: usize) {
+        assert!(self.symbols.iter().all(|s| s.name != name), "{} defined twice", name);
+        self.symbols.push(Symbol { name: name.to_owned(), section, offset, size });
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        let mut strtab = vec![0];
+        // [null, .text, .rodata, local pools..., functions..., undefined...]
+        let mut symtab = vec![0; SYM_SIZE];
+        put_sym(&mut symtab, 0, STB_LOCAL, STT_SECTION, SHN_TEXT, 0, 0);
+        put_sym(&mut symtab, 0, STB_LOCAL, STT_SECTION, SHN_RODATA, 0, 0);
+        let mut sym_ixs = HashMap::new();
+        let locals = self.symbols.iter().filter(|s| s.section == Section::Rodata);
+        let globals = self.symbols.iter().filter(|s| s.section == Section::Text);
+        let mut first_global = 0;
+        for (ix, sym) in locals.chain(globals).enumerate() {
+            let (bind, typ, shndx) = match sym.section {
+                Section::Rodata => (STB_LOCAL, STT_OBJECT, SHN_RODATA),
+                Section::Text => (STB_GLOBAL, STT_FUNC, SHN_TEXT),
+            };
+            let sym_ix = ix + 3;
+            if bind == STB_GLOBAL && first_global == 0 {
+                first_global = sym_ix;
+            }
+            let name = put_str(&mut strtab, &sym.name);
+            put_sym(&mut symtab, name, bind, typ, shndx, sym.offset as u64, sym.size as u64);
+            sym_ixs.insert(sym.name.clone(), sym_ix);
+        }
+        if first_global == 0 {
+            first_global = symtab.len() / SYM_SIZE;
+        }
+
+        let mut rela = vec![];
+        for reloc in &self.relocs {
+            let name = match reloc.label {
+                Label::Named(ref name) => name,
+                Label::Local(_) => unreachable!(),
+            };
+            let next_ix = symtab.len() / SYM_SIZE;
+            let sym_ix = *sym_ixs.entry(name.clone()).or_insert_with(|| {
+                let name = put_str(&mut strtab, name);
+                put_sym(&mut symtab, name, STB_GLOBAL, STT_NOTYPE, 0, 0, 0);
+                next_ix
+            });
+            let typ = match reloc.kind {
+                FixupKind::Branch => R_X86_64_PLT32,
+                FixupKind::RipRel => R_X86_64_PC32,
+            };
+            put_u64(&mut rela, reloc.offset as u64);
+            put_u64(&mut rela, ((sym_ix as u64) << 32) | typ as u64);
+            put_u64(&mut rela, reloc.addend as u64);
+        }
+
+        let mut shstrtab = vec![];
+        let sh_names = SECTION_NAMES.iter().map(|name| put_str(&mut shstrtab, name))
+            .collect::<Vec<_>>();
+
+        let mut out = vec![0; EHDR_SIZE];
+        // (type, flags, offset, size, link, info, align, entsize)
+        let mut headers = vec![(0, 0, 0, 0, 0, 0, 0, 0)];
+        {
+            let mut add = |out: &mut Vec<u8>, typ, flags, data: &[u8],
+                           link: u16, info: usize, align: usize, entsize: usize| {
+                pad_to(out, align, 0);
+                headers.push((typ, flags, out.len(), data.len(), link, info, align, entsize));
+                out.extend(data);
+            };
+            add(&mut out, SHT_PROGBITS, SHF_ALLOC | SHF_EXECINSTR, &self.text,
+                0, 0, FUNCTION_ALIGN, 0);
+            add(&mut out, SHT_PROGBITS, SHF_ALLOC, &self.rodata, 0, 0, 16, 0);
+            add(&mut out, SHT_RELA, SHF_INFO_LINK, &rela,
+                SHN_SYMTAB, SHN_TEXT as usize, 8, RELA_SIZE);
+            add(&mut out, SHT_SYMTAB, 0, &symtab, SHN_STRTAB, first_global, 8, SYM_SIZE);
+            add(&mut out, SHT_STRTAB, 0, &strtab, 0, 0, 1, 0);
+            add(&mut out, SHT_STRTAB, 0, &shstrtab, 0, 0, 1, 0);
+            // Asks for a non-executable stack.
+            add(&mut out, SHT_PROGBITS, 0, &[], 0, 0, 1, 0);
+        }
+
+        pad_to(&mut out, 8, 0);
+        let shoff = out.len();
+        for (ix, &(typ, flags, offset, size, link, info, align, entsize)) in
+            headers.iter().enumerate() {
+            put_u32(&mut out, sh_names[ix]);
+            put_u32(&mut out, typ);
+            put_u64(&mut out, flags);
+            // sh_addr
+            put_u64(&mut out, 0);
+            put_u64(&mut out, offset as u64);
+            put_u64(&mut out, size as u64);
+            put_u32(&mut out, link as u32);
+            put_u32(&mut out, info as u32);
+            put_u64(&mut out, align as u64);
+            put_u64(&mut out, entsize as u64);
+        }
+
+        let mut ehdr = vec![0x7f, b'E', b'L', b'F',
+                            // ELFCLASS64, ELFDATA2LSB, EV_CURRENT, ELFOSABI_NONE
+                            2, 1, 1, 0,
+                            0, 0, 0, 0, 0, 0, 0, 0];
+        put_u16(&mut ehdr, ET_REL);
+        put_u16(&mut ehdr, EM_X86_64);
+        put_u32(&mut ehdr, 1);
+        // e_entry, e_phoff
+        put_u64(&mut ehdr, 0);
+        put_u64(&mut ehdr, 0);
+        put_u64(&mut ehdr, shoff as u64);
+        // e_flags
+        put_u32(&mut ehdr, 0);
+        put_u16(&mut ehdr, EHDR_SIZE as u16);
+        // e_phentsize, e_phnum
+        put_u16(&mut ehdr, 0);
+        put_u16(&mut ehdr, 0);
+        put_u16(&mut ehdr, SHDR_SIZE as u16);
+        put_u16(&mut ehdr, headers.len() as u16);
+        put_u16(&mut ehdr, SHN_SHSTRTAB);
+        debug_assert!(ehdr.len() == EHDR_SIZE);
+        out[..EHDR_SIZE].copy_from_slice(&ehdr);
+        out
+    }
+}
+
+fn pad_to(buf: &mut Vec<u8>, align: usize, fill: u8) {
+    while !buf.len().is_multiple_of(align) {
+        buf.push(fill);
+    }
+}
+
+// Returns the offset of s.
+fn put_str(buf: &mut Vec<u8>, s: &str) -> u32 {
+    let offset = buf.len() as u32;
+    buf.extend(s.as_bytes());
+    buf.push(0);
+    offset
+}
+
+fn put_sym(buf: &mut Vec<u8>, name: u32, bind: u8, typ: u8, shndx: u16, value: u64, size: u64) {
+    put_u32(buf, name);
+    buf.push((bind << 4) | typ);
+    // st_other: STV_DEFAULT
+    buf.push(0);
+    put_u16(buf, shndx);
+    put_u64(buf, value);
+    put_u64(buf, size);
+}
+
+fn put_u16(buf: &mut Vec<u8>, v: u16) {
+    buf.extend(&v.to_le_bytes());
+}
+
+fn put_u32(buf: &mut Vec<u8>, v: u32) {
+    buf.extend(&v.to_le_bytes());
+}
+
+fn put_u64(buf: &mut Vec<u8>, v: u64) {
+    buf.extend(&v.to_le_bytes());
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Just enough of an ELF reader to check what the writer produced.
+    struct Elf<'a> {
+        bytes: &'a [u8],
+    }
+
+    #[derive(Debug)]
+    struct Shdr {
+        name: String,
+        typ: u32,
+        offset: usize,
+        size: usize,
+        link: u32,
+        info: u32,
+        entsize: usize,
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct Sym {
+        name: String,
+        bind: u8,
+        typ: u8,
+        shndx: u16,
+        value: u64,
+        size: u64,
+    }
+
+    impl<'a> Elf<'a> {
+        fn u16_at(&self, at: usize) -> u16 {
+            u16::from_le_bytes([self.bytes[at], self.bytes[at + 1]])
+        }
+
+        fn u32_at(&self, at: usize) -> u32 {
+            let mut b = [0; 4];
+            b.copy_from_slice(&self.bytes[at..at + 4]);
+            u32::from_le_bytes(b)
+        }
+
+        fn u64_at(&self, at: usize) -> u64 {
+            let mut b = [0; 8];
+            b.copy_from_slice(&self.bytes[at..at + 8]);
+            u64::from_le_bytes(b)
+        }
+
+        fn str_at(&self, at: usize) -> String {
+            let len = self.bytes[at..].iter().position(|&b| b == 0).unwrap();
+            String::from_utf8(self.bytes[at..at + len].to_vec()).unwrap()
+        }
+
+        fn shdrs(&self) -> Vec<Shdr> {
+            let shoff = self.u64_at(0x28) as usize;
+            let shnum = self.u16_at(0x3c) as usize;
+            let shstrndx = self.u16_at(0x3e) as usize;
+            let raw = (0..shnum).map(|ix| shoff + ix * SHDR_SIZE).collect::<Vec<_>>();
+            let strtab = self.u64_at(raw[shstrndx] + 0x18) as usize;
+            raw.into_iter().map(|at| Shdr {
+                name: self.str_at(strtab + self.u32_at(at) as usize),
+                typ: self.u32_at(at + 4),
+                offset: self.u64_at(at + 0x18) as usize,
+                size: self.u64_at(at + 0x20) as usize,
+                link: self.u32_at(at + 0x28),
+                info: self.u32_at(at + 0x2c),
+                entsize: self.u64_at(at + 0x38) as usize,
+            }).collect()
+        }
+
+        fn section(&self, name: &str) -> Shdr {
+            self.shdrs().into_iter().find(|s| s.name == name).unwrap()
+        }
+
+        fn data(&self, name: &str) -> &'a [u8] {
+            let s = self.section(name);
+            &self.bytes[s.offset..s.offset + s.size]
+        }
+
+        fn symbols(&self) -> Vec<Sym> {
+            let symtab = self.section(".symtab");
+            let strtab = &self.shdrs()[symtab.link as usize];
+            (0..symtab.size / symtab.entsize).map(|ix| {
+                let at = symtab.offset + ix * symtab.entsize;
+                Sym {
+                    name: self.str_at(strtab.offset + self.u32_at(at) as usize),
+                    bind: self.bytes[at + 4] >> 4,
+                    typ: self.bytes[at + 4] & 0xf,
+                    shndx: self.u16_at(at + 6),
+                    value: self.u64_at(at + 8),
+                    size: self.u64_at(at + 16),
+                }
+            }).collect()
+        }
+
+        // (offset, symbol name, type, addend)
+        fn relocs(&self) -> Vec<(u64, String, u32, i64)> {
+            let rela = self.section(".rela.text");
+            let syms = self.symbols();
+            (0..rela.size / rela.entsize).map(|ix| {
+                let at = rela.offset + ix * rela.entsize;
+                let info = self.u64_at(at + 8);
+                (self.u64_at(at),
+                 syms[(info >> 32) as usize].name.clone(),
+                 info as u32,
+                 self.u64_at(at + 16) as i64)
+            }).collect()
+        }
+    }
+
+    fn r(m: MachReg) -> Operand {
+        m.into_reg().into_op()
+    }
+
+    fn named(name: &str) -> Label {
+        Label::Named(name.to_owned())
+    }
+
+    fn sym(name: &str, bind: u8, typ: u8, shndx: u16, value: u64, size: u64) -> Sym {
+        Sym { name: name.to_owned(), bind, typ, shndx, value, size }
+    }
+
+    // f calls g and ext, and reads a constant from pool.
+    fn write_object() -> Vec<u8> {
+        let f = Function::new(vec![
+            Block::new(Label::Local(0), vec![
+                Instr::call(named("g").into_op(), 0),
+                Instr::add(r(RAX), Mem::new_rip(named("pool"), 8).into_op()),
+                Instr::jmp(Label::Local(1)),
+            ]),
+            Block::new(Label::Local(1), vec![
+                Instr::call(named("ext").into_op(), 0),
+                Instr::ret(r(RAX)),
+            ]),
+        ]);
+        let g = Function::new(vec![
+            Block::new(Label::Local(0), vec![
+                Instr::mov(r(RAX), Operand::Imm(1)),
+                Instr::ret(r(RAX)),
+            ]),
+        ]);
+        let mut w = ObjectWriter::new();
+        w.add_function("f", &f);
+        w.add_function("g", &g);
+        w.add_rodata("pool", &[1, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0], 8);
+        w.finish()
+    }
+
+    #[test]
+    fn can_write_well_formed_header_and_sections() {
+        let bytes = write_object();
+        let elf = Elf { bytes: &bytes };
+        assert_eq!(&bytes[..8], &[0x7f, b'E', b'L', b'F', 2, 1, 1, 0]);
+        assert_eq!(elf.u16_at(0x10), ET_REL);
+        assert_eq!(elf.u16_at(0x12), EM_X86_64);
+
+        let shdrs = elf.shdrs();
+        let names = shdrs.iter().map(|s| s.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, SECTION_NAMES);
+        for s in &shdrs {
+            assert!(s.offset + s.size <= bytes.len(), "{:?} is out of the file", s);
+        }
+        let rela = elf.section(".rela.text");
+        assert_eq!((rela.typ, rela.link, rela.info), (SHT_RELA, SHN_SYMTAB as u32, 1));
+        assert_eq!(elf.section(".symtab").typ, SHT_SYMTAB);
+
+        // call g; add pool+8(%rip), %rax | call ext; ret, where the jmp .L1 is
+        // left out.
+        let f_len = 5 + 7 + 5 + 1;
+        let text = elf.data(".text");
+        assert_eq!(text.len(), 32 + 8);
+        assert!(text[f_len..32].iter().all(|&b| b == 0xcc));
+        assert_eq!(&text[32..], &[0x48, 0xc7, 0xc0, 1, 0, 0, 0, 0xc3]);
+        assert_eq!(elf.data(".rodata")[8], 2);
+    }
+
+    #[test]
+    fn can_write_symbols() {
+        let bytes = write_object();
+        let elf = Elf { bytes: &bytes };
+        let syms = elf.symbols();
+        assert_eq!(syms, vec![
+            sym("", STB_LOCAL, STT_NOTYPE, 0, 0, 0),
+            sym("", STB_LOCAL, STT_SECTION, SHN_TEXT, 0, 0),
+            sym("", STB_LOCAL, STT_SECTION, SHN_RODATA, 0, 0),
+            sym("pool", STB_LOCAL, STT_OBJECT, SHN_RODATA, 0, 16),
+            sym("f", STB_GLOBAL, STT_FUNC, SHN_TEXT, 0, 18),
+            sym("g", STB_GLOBAL, STT_FUNC, SHN_TEXT, 32, 8),
+            sym("ext", STB_GLOBAL, STT_NOTYPE, 0, 0, 0),
+        ]);
+        // Locals come first.
+        assert_eq!(elf.section(".symtab").info, 4);
+    }
+
+    #[test]
+    fn can_write_relocations() {
+        let bytes = write_object();
+        let elf = Elf { bytes: &bytes };
+        assert_eq!(elf.relocs(), vec![
+            (1, "g".to_owned(), R_X86_64_PLT32, -4),
+            (8, "pool".to_owned(), R_X86_64_PC32, 8 - 4),
+            (13, "ext".to_owned(), R_X86_64_PLT32, -4),
+        ]);
+        // Left for the linker.
+        assert_eq!(&elf.data(".text")[1..5], &[0; 4]);
+    }
+}
@@ -0,0 +1,339 @@
+use ::x64::*;
+
+// The frame of a function, with %rbp as the frame pointer:
+//
+//   [rbp + 16 + 8 * ix]  incoming_arg(ix)
+//   [rbp + 8]            return address
+//   [rbp]                the caller's %rbp
+//   [rbp - 8 * (ix + 1)] saved_regs[ix]
+//                        padding
+//   [rsp + 8 * ix]       stack slots: outgoing arguments, then spill slots
+//
+// %rsp stays 16-byte aligned after the prologue, as calls require.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct FrameLayout {
+    // Callee-saved registers written by the function, except %rbp.
+    pub saved_regs: Vec<MachReg>,
+    // Subtracted from %rsp after saving the registers.
+    pub size: u32,
+}
+
+const WORD: u32 = 8;
+const STACK_ALIGN: u32 = 16;
+
+// Stack arguments that follow the ones in ARG_REGS.
+pub fn incoming_arg(ix: usize) -> Operand {
+    Mem::new(RBP.into_reg(), (2 * WORD + WORD * ix as u32) as i32).into_op()
+}
+
+pub fn outgoing_arg(ix: usize) -> Operand {
+    Mem::new(Reg::rsp(), (WORD * ix as u32) as i32).into_op()
+}
+
+// Copies the incoming arguments into params at the function entry.
+pub fn lower_params(params: &[Reg]) -> Vec<Instr> {
+    params.iter().enumerate().map(|(ix, p)| {
+        let src = match ARG_REGS.get(ix) {
+            Some(r) => r.into_reg().into_op(),
+            None => incoming_arg(ix - ARG_REGS.len()),
+        };
+        Instr::mov(p.into_op(), src)
+    }).collect()
+}
+
+// The args should be VirtualRegs or imms: they are read after some of the
+// ARG_REGS are written. The return value comes back in %rax, and a value is
+// returned by `ret`, whose operand is already fixed to %rax.
+pub fn lower_call(target: Operand, args: &[Operand], ret: Option<Reg>) -> Vec<Instr> {
+    let mut instrs = vec![];
+    for (ix, arg) in args.iter().enumerate().skip(ARG_REGS.len()) {
+        debug_assert!(!arg.is_mem(), "Can't pass {} on the stack", arg);
+        instrs.push(Instr::mov(outgoing_arg(ix - ARG_REGS.len()), arg.clone()));
+    }
+    for (arg, r) in args.iter().zip(ARG_REGS) {
+        instrs.push(Instr::mov(r.into_reg().into_op(), arg.clone()));
+    }
+    instrs.push(Instr::call(target, args.len()));
+    if let Some(ret) = ret {
+        instrs.push(Instr::mov(ret.into_op(), RAX.into_reg().into_op()));
+    }
+    instrs
+}
+
+// The number of [rsp + 8 * ix] slots used by the instructions, including
+// their ParallelMoves.
+pub fn stack_slots(instrs: &[Instr]) -> usize {
+    instrs.iter()
+        .flat_map(all_operands)
+        .map(|op| match *op {
+            Operand::Mem(ref m) if m.base_reg() == Some(Reg::rsp()) => {
+                debug_assert!(m.index.is_none() && m.disp >= 0 && (m.disp as u32).is_multiple_of(WORD),
+                              "Not a stack slot: {}", m);
+                m.disp as usize / WORD as usize + 1
+            }
+            _ => 0,
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+fn all_operands(instr: &Instr) -> Vec<&Operand> {
+    let moves = &instr.parallel_moves;
+    instr.ops.iter()
+        .chain(moves.start().iter().chain(moves.end()).flat_map(|m| vec![m.dst(), m.src()]))
+        .collect()
+}
+
+fn written_mach_regs(instr: &Instr) -> Vec<MachReg> {
+    let moves = &instr.parallel_moves;
+    let move_dsts = moves.start().iter().chain(moves.end()).filter_map(|m| match *m.dst() {
+        Operand::Reg(r) => Some(r),
+        _ => None,
+    });
+    instr.outputs().into_iter()
+        .map(|(_, r, _)| r)
+        .chain(move_dsts)
+        .filter_map(|r| match r {
+            Reg::Mach(m) => Some(m),
+            Reg::Virtual(_) => None,
+        })
+        .collect()
+}
+
+impl FrameLayout {
+    // Should be done after register allocation.
+    pub fn compute(f: &Function) -> Self {
+        let slots = f.blocks.iter().map(|b| stack_slots(&b.instrs)).max().unwrap_or(0);
+        Self::compute_with_slots(f, slots)
+    }
+
+    // With room for num_slots stack slots, as reported by
+    // lsra::allocate_function_with_slots, instead of looking for them.
+    pub fn compute_with_slots(f: &Function, slots: usize) -> Self {
+        debug_assert!(f.blocks.iter().all(|b| stack_slots(&b.instrs) <= slots),
+                      "More than {} stack slots", slots);
+        let mut saved_regs = vec![];
+        for b in &f.blocks {
+            for instr in &b.instrs {
+                for m in written_mach_regs(instr) {
+                    if m != RBP && CALLEE_SAVED.contains(&m) && !saved_regs.contains(&m) {
+                        saved_regs.push(m);
+                    }
+                }
+            }
+        }
+        saved_regs.sort();
+
+        // %rsp is aligned right after pushing %rbp.
+        let pushed = saved_regs.len() as u32 * WORD;
+        let needed = pushed + slots as u32 * WORD;
+        let size = needed.div_ceil(STACK_ALIGN) * STACK_ALIGN - pushed;
+        FrameLayout { saved_regs, size }
+    }
+
+    pub fn prologue(&self) -> Vec<Instr> {
+        let rbp = RBP.into_reg().into_op();
+        let rsp = Reg::rsp().into_op();
+        let mut instrs = vec![Instr::push(rbp.clone()), Instr::mov(rbp, rsp.clone())];
+        instrs.extend(self.saved_regs.iter().map(|r| Instr::push(r.into_reg().into_op())));
+        if self.size > 0 {
+            instrs.push(Instr::sub(rsp, Operand::Imm(self.size as Imm)));
+        }
+        instrs
+    }
+
+    // Goes right before a ret.
+    pub fn epilogue(&self) -> Vec<Instr> {
+        let mut instrs = vec![];
+        if self.size > 0 {
+            instrs.push(Instr::add(Reg::rsp().into_op(), Operand::Imm(self.size as Imm)));
+        }
+        instrs.extend(self.saved_regs.iter().rev().map(|r| Instr::pop(r.into_reg().into_op())));
+        instrs.push(Instr::pop(RBP.into_reg().into_op()));
+        instrs
+    }
+}
+
+// Wraps f with a prologue and epilogues. The ParallelMoves of a ret would be
+// done after the epilogue, so they must have been resolved.
+pub fn insert_frame(f: &mut Function) -> FrameLayout {
+    let layout = FrameLayout::compute(f);
+    insert_frame_with_layout(f, layout)
+}
+
+// Like insert_frame, with the number of stack slots from the register
+// allocator.
+pub fn insert_frame_with_slots(f: &mut Function, slots: usize) -> FrameLayout {
+    let layout = FrameLayout::compute_with_slots(f, slots);
+    insert_frame_with_layout(f, layout)
+}
+
+fn insert_frame_with_layout(f: &mut Function, layout: FrameLayout) -> FrameLayout {
+    debug_assert!(f.blocks.iter().flat_map(|b| &b.instrs)
+                      .all(|i| i.opcode != OpCode::Ret || i.parallel_moves.is_empty()),
+                  "Unresolved parallel moves on ret");
+
+    for b in &mut f.blocks {
+        let mut instrs = vec![];
+        for instr in b.instrs.drain(..) {
+            if instr.opcode == OpCode::Ret {
+                instrs.extend(layout.epilogue());
+            }
+            instrs.push(instr);
+        }
+        b.instrs = instrs;
+    }
+
+    // The prologue only runs once, so it needs its own block if the entry is
+    // a loop header.
+    if f.predecessors()[0].is_empty() {
+        let mut instrs = layout.prologue();
+        instrs.append(&mut f.blocks[0].instrs);
+        f.blocks[0].instrs = instrs;
+    } else {
+        let mut instrs = layout.prologue();
+        instrs.push(Instr::jmp(f.blocks[0].label.clone()));
+        let entry = Block::new(f.next_local_label(), instrs);
+        f.blocks.insert(0, entry);
+    }
+    layout
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ::test_utils;
+
+    fn r(m: MachReg) -> Operand {
+        m.into_reg().into_op()
+    }
+
+    fn slot(ix: i32) -> Operand {
+        Mem::new(Reg::rsp(), ix * 8).into_op()
+    }
+
+    fn label(ix: u32) -> Label {
+        Label::Local(ix)
+    }
+
+    #[test]
+    fn can_pass_args_in_sysv_order() {
+        let params = (0..7).map(Reg::new_virt).collect::<Vec<_>>();
+        let expected = vec![
+            Instr::mov(params[0].into_op(), r(RDI)),
+            Instr::mov(params[1].into_op(), r(RSI)),
+            Instr::mov(params[2].into_op(), r(RDX)),
+            Instr::mov(params[3].into_op(), r(RCX)),
+            Instr::mov(params[4].into_op(), r(R8)),
+            Instr::mov(params[5].into_op(), r(R9)),
+            Instr::mov(params[6].into_op(), Mem::new(RBP.into_reg(), 16).into_op()),
+        ];
+        test_utils::assert_eq_pretty("lower-params", &lower_params(&params), &expected);
+
+        let mut args = params.iter().map(|p| p.into_op()).collect::<Vec<_>>();
+        args.push(Operand::Imm(-1));
+        let target = Label::Named("f".to_owned()).into_op();
+        let ret = Reg::new_virt(7);
+        let expected = vec![
+            Instr::mov(slot(0), args[6].clone()),
+            Instr::mov(slot(1), Operand::Imm(-1)),
+            Instr::mov(r(RDI), args[0].clone()),
+            Instr::mov(r(RSI), args[1].clone()),
+            Instr::mov(r(RDX), args[2].clone()),
+            Instr::mov(r(RCX), args[3].clone()),
+            Instr::mov(r(R8), args[4].clone()),
+            Instr::mov(r(R9), args[5].clone()),
+            Instr::call(target.clone(), args.len()),
+            Instr::mov(ret.into_op(), r(RAX)),
+        ];
+        let instrs = lower_call(target, &args, Some(ret));
+        test_utils::assert_eq_pretty("lower-call", &instrs, &expected);
+        assert_eq!(stack_slots(&instrs), 2);
+    }
+
+    #[test]
+    fn can_lay_out_frame() {
+        let mut f = Function::new(vec![
+            Block::new(label(0), vec![
+                Instr::mov(r(RBX), Operand::Imm(1)),
+                Instr::mov(slot(2), r(RBX)),
+                Instr::cmp(r(RBX), Operand::Imm(0)),
+                Instr::jcc(Cond::E, label(1)),
+                Instr::jmp(label(2)),
+            ]),
+            Block::new(label(1), vec![
+                Instr::mov(r(R12), slot(2)),
+                Instr::mov(r(RAX), r(R12)),
+                Instr::ret(r(RAX)),
+            ]),
+            Block::new(label(2), vec![
+                Instr::mov(r(RAX), r(RBX)),
+                Instr::ret(r(RAX)),
+            ]),
+        ]);
+        let layout = insert_frame(&mut f);
+        // 2 saved regs + 3 slots, rounded up to 16 bytes.
+        assert_eq!(layout, FrameLayout { saved_regs: vec![RBX, R12], size: 32 });
+        assert_eq!(f.verify(), Ok(()));
+
+        let prologue = vec![
+            Instr::push(r(RBP)),
+            Instr::mov(r(RBP), r(RSP)),
+            Instr::push(r(RBX)),
+            Instr::push(r(R12)),
+            Instr::sub(r(RSP), Operand::Imm(32)),
+        ];
+        let epilogue = vec![
+            Instr::add(r(RSP), Operand::Imm(32)),
+            Instr::pop(r(R12)),
+            Instr::pop(r(RBX)),
+            Instr::pop(r(RBP)),
+            Instr::ret(r(RAX)),
+        ];
+        test_utils::assert_eq_pretty("frame-prologue", &f.blocks[0].instrs[..5].to_vec(), &prologue);
+        test_utils::assert_eq_pretty("frame-epilogue", &f.blocks[2].instrs[1..].to_vec(), &epilogue);
+        assert_eq!(&f.blocks[1].instrs[2..], &epilogue[..]);
+    }
+
+    #[test]
+    fn can_keep_stack_aligned() {
+        let leaf = |instrs: Vec<Instr>| Function::new(vec![Block::new(label(0), instrs)]);
+
+        let f = leaf(vec![Instr::ret(r(RAX))]);
+        assert_eq!(FrameLayout::compute(&f), FrameLayout { saved_regs: vec![], size: 0 });
+        assert_eq!(FrameLayout::compute(&f).epilogue(), vec![Instr::pop(r(RBP))]);
+
+        // Pushing %r15 leaves %rsp misaligned.
+        let f = leaf(vec![Instr::mov(r(R15), r(RDI)), Instr::ret(r(RAX))]);
+        assert_eq!(FrameLayout::compute(&f), FrameLayout { saved_regs: vec![R15], size: 8 });
+
+        let f = leaf(vec![Instr::mov(slot(0), r(RDI)), Instr::ret(r(RAX))]);
+        assert_eq!(FrameLayout::compute(&f), FrameLayout { saved_regs: vec![], size: 16 });
+
+        // Caller-saved registers and %rbp itself are not saved again.
+        let mut instr = Instr::call(r(R11), 0);
+        instr.parallel_moves.add_to_end(ParallelMove::new(slot(4), r(RAX)));
+        let f = leaf(vec![Instr::mov(r(RBP), r(RDI)), instr, Instr::ret(r(RAX))]);
+        assert_eq!(FrameLayout::compute(&f), FrameLayout { saved_regs: vec![], size: 48 });
+    }
+
+    #[test]
+    fn can_give_looping_entry_its_own_prologue() {
+        let mut f = Function::new(vec![
+            Block::new(label(0), vec![
+                Instr::cmp(r(RDI), Operand::Imm(0)),
+                Instr::jcc(Cond::Ne, label(0)),
+                Instr::jmp(label(1)),
+            ]),
+            Block::new(label(1), vec![Instr::ret(r(RAX))]),
+        ]);
+        insert_frame(&mut f);
+        assert_eq!(f.verify(), Ok(()));
+        assert_eq!(f.blocks[0].instrs, vec![
+            Instr::push(r(RBP)),
+            Instr::mov(r(RBP), r(RSP)),
+            Instr::jmp(label(0)),
+        ]);
+        assert_eq!(f.blocks[1].instrs.len(), 3);
+    }
+}
@@ -0,0 +1,458 @@
+use ::x64::*;
+
+// Turns ParallelMoves into ordinary instructions, following V8's GapResolver:
+// a move is only performed after all the other moves that read its dst, and a
+// cycle is broken by swapping the two ends of the move that closes it.
+//
+// The scratch registers, at most one per RegClass, must not appear in any of
+// the moves. A GPR one is needed for memory-to-memory moves and swaps, and for
+// storing imm64s to memory. An XMM one is needed to swap an XMM, as there's no
+// xchg for them, and lets two slots be swapped without an xchg with memory.
+struct GapResolver<'a> {
+    moves: Vec<PendingMove>,
+    scratch: &'a [Reg],
+    instrs: Vec<Instr>,
+}
+
+#[derive(Debug)]
+struct PendingMove {
+    dst: Operand,
+    src: Operand,
+    // On the DFS stack of perform_move.
+    pending: bool,
+    done: bool,
+}
+
+pub fn resolve_parallel_moves(moves: &[ParallelMove], scratch: &[Reg]) -> Vec<Instr> {
+    let mut resolver = GapResolver::new(moves, scratch);
+    resolver.run();
+    resolver.instrs
+}
+
+// Expands the ParallelMoves of every instruction in place.
+pub fn resolve_block(b: &mut Block, scratch: &[Reg]) {
+    let mut instrs = vec![];
+    for mut instr in b.instrs.drain(..) {
+        let (start, end) = instr.parallel_moves.take();
+        debug_assert!(end.is_empty() || !(instr.opcode.is_jump() || instr.opcode.is_terminator()),
+                      "Moves after {}", instr);
+        instrs.extend(resolve_parallel_moves(&start, scratch));
+        instrs.push(instr);
+        instrs.extend(resolve_parallel_moves(&end, scratch));
+    }
+    b.instrs = instrs;
+}
+
+pub fn resolve_function(f: &mut Function, scratch: &[Reg]) {
+    for b in &mut f.blocks {
+        resolve_block(b, scratch);
+    }
+}
+
+// One parallel move that does first and then then. A move of then that reads
+// what first writes reads first's src instead, and a dst written by both keeps
+// then's move.
+pub fn sequence(first: &[ParallelMove], then: &[ParallelMove]) -> Vec<ParallelMove> {
+    let mut moves = first.iter()
+        .filter(|m| then.iter().all(|t| t.dst() != m.dst()))
+        .cloned()
+        .collect::<Vec<_>>();
+    for t in then {
+        let src = first.iter().find(|m| m.dst() == t.src()).map_or(t.src(), |m| m.src());
+        if src != t.dst() {
+            moves.push(ParallelMove::new(t.dst().clone(), src.clone()));
+        }
+    }
+    moves
+}
+
+impl<'a> GapResolver<'a> {
+    fn new(moves: &[ParallelMove], scratch: &'a [Reg]) -> Self {
+        debug_assert!(moves.iter().all(|m| !m.dst().is_imm()));
+        debug_assert!(scratch.iter().all(|&s| moves.iter().all(|m| {
+            !m.dst().mentions(s) && !m.src().mentions(s)
+        })), "Scratch {:?} is used by the moves", scratch);
+        GapResolver {
+            moves: moves.iter().map(|m| PendingMove {
+                dst: m.dst().clone(),
+                src: m.src().clone(),
+                pending: false,
+                done: false,
+            }).collect(),
+            scratch,
+            instrs: vec![],
+        }
+    }
+
+    fn run(&mut self) {
+        for ix in 0..self.moves.len() {
+            if !self.moves[ix].done {
+                self.perform_move(ix);
+            }
+        }
+    }
+
+    fn perform_move(&mut self, ix: usize) {
+        let dst = self.moves[ix].dst.clone();
+        self.moves[ix].pending = true;
+        // Clear the way: everyone that reads my dst goes first.
+        for other in 0..self.moves.len() {
+            let m = &self.moves[other];
+            if !m.done && !m.pending && m.src == dst {
+                self.perform_move(other);
+            }
+        }
+        self.moves[ix].pending = false;
+        self.moves[ix].done = true;
+
+        let src = self.moves[ix].src.clone();
+        if src == dst {
+            return;
+        }
+
+        // Whoever still reads my dst is on the DFS stack: that's a cycle.
+        let in_cycle = self.moves.iter().any(|m| !m.done && m.src == dst);
+        if !in_cycle {
+            self.assemble_move(dst, src);
+            return;
+        }
+
+        self.assemble_swap(dst.clone(), src.clone());
+        // The values in src and dst are swapped, and so are their readers.
+        for m in &mut self.moves {
+            if m.done {
+                continue;
+            }
+            if m.src == src {
+                m.src = dst.clone();
+            } else if m.src == dst {
+                m.src = src.clone();
+            }
+        }
+    }
+
+    fn find_scratch(&self, class: RegClass) -> Option<Operand> {
+        self.scratch.iter().find(|s| s.class() == class).map(|s| s.into_op())
+    }
+
+    fn scratch(&self, class: RegClass) -> Operand {
+        self.find_scratch(class)
+            .unwrap_or_else(|| panic!("Need a {:?} scratch register", class))
+    }
+
+    fn assemble_move(&mut self, dst: Operand, src: Operand) {
+        match (&dst, &src) {
+            // The bits of an XMM can go through a GPR just as well.
+            (&Operand::Mem(_), &Operand::Mem(_)) => {
+                let s = self.scratch(RegClass::Gpr);
+                self.instrs.push(Instr::mov(s.clone(), src));
+                self.instrs.push(Instr::mov(dst, s));
+            }
+            (_, &Operand::Imm(i)) if !ImmWidth::Imm32.fits(i) => {
+                if dst.is_reg() {
+                    self.instrs.push(Instr::movabs(dst, i));
+                } else {
+                    let s = self.scratch(RegClass::Gpr);
+                    self.instrs.push(Instr::movabs(s.clone(), i));
+                    self.instrs.push(Instr::mov(dst, s));
+                }
+            }
+            _ => self.instrs.push(Instr::copy(dst, src)),
+        }
+    }
+
+    fn assemble_swap(&mut self, a: Operand, b: Operand) {
+        let xmm = [&a, &b].iter().any(|op| match **op {
+            Operand::Reg(r) => r.class() == RegClass::Xmm,
+            _ => false,
+        });
+        if xmm {
+            let s = self.scratch(RegClass::Xmm);
+            self.instrs.push(Instr::movsd(s.clone(), a.clone()));
+            self.instrs.push(Instr::movsd(a, b.clone()));
+            self.instrs.push(Instr::movsd(b, s));
+            return;
+        }
+        match (&a, &b, self.find_scratch(RegClass::Gpr)) {
+            (&Operand::Reg(_), &Operand::Reg(_), _) => {
+                self.instrs.push(Instr::xchg(a, b));
+            }
+            (&Operand::Mem(_), &Operand::Mem(_), _) => {
+                let s = self.scratch(RegClass::Gpr);
+                if let Some(x) = self.find_scratch(RegClass::Xmm) {
+                    self.instrs.push(Instr::mov(s.clone(), a.clone()));
+                    self.instrs.push(Instr::movsd(x.clone(), b.clone()));
+                    self.instrs.push(Instr::mov(b, s));
+                    self.instrs.push(Instr::movsd(a, x));
+                } else {
+                    self.instrs.push(Instr::mov(s.clone(), a.clone()));
+                    self.instrs.push(Instr::xchg(s.clone(), b));
+                    self.instrs.push(Instr::mov(a, s));
+                }
+            }
+            // Between a reg and a mem: an xchg with memory is implicitly
+            // locked, so go through the scratch when there's one.
+            (_, _, Some(s)) => {
+                let (r, m) = if a.is_reg() { (a, b) } else { (b, a) };
+                self.instrs.push(Instr::mov(s.clone(), m.clone()));
+                self.instrs.push(Instr::mov(m, r.clone()));
+                self.instrs.push(Instr::mov(r, s));
+            }
+            (_, _, None) => self.instrs.push(Instr::xchg(a, b)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::*;
+    use ::test_utils::XorShift;
+
+    // Just enough of x64 to run the resolved moves.
+    fn run(instrs: &[Instr], state: &mut HashMap<String, Imm>) {
+        fn key(op: &Operand) -> String {
+            format!("{}", op)
+        }
+        fn read(op: &Operand, state: &HashMap<String, Imm>) -> Imm {
+            match *op {
+                Operand::Imm(i) => i,
+                _ => state[&key(op)],
+            }
+        }
+        for instr in instrs {
+            let (a, b) = (&instr.ops[0], &instr.ops[1]);
+            assert!(!(a.is_mem() && b.is_mem()), "Not encodable: {}", instr);
+            let is_xmm = |op: &Operand| match *op {
+                Operand::Reg(r) => r.class() == RegClass::Xmm,
+                _ => false,
+            };
+            let xmm = is_xmm(a) || is_xmm(b);
+            match instr.opcode {
+                OpCode::Movsd => {
+                    assert!(!b.is_imm() && [a, b].iter().all(|op| !op.is_reg() || is_xmm(op)));
+                    let v = read(b, state);
+                    state.insert(key(a), v);
+                }
+                OpCode::Mov | OpCode::Xchg if xmm => panic!("Not encodable: {}", instr),
+                OpCode::Mov => {
+                    let v = read(b, state);
+                    assert!(!b.is_imm() || ImmWidth::Imm32.fits(v));
+                    state.insert(key(a), v);
+                }
+                OpCode::MovAbs => {
+                    assert!(a.is_reg());
+                    let v = read(b, state);
+                    state.insert(key(a), v);
+                }
+                OpCode::Xchg => {
+                    let (va, vb) = (read(a, state), read(b, state));
+                    state.insert(key(a), vb);
+                    state.insert(key(b), va);
+                }
+                _ => panic!("Unexpected {}", instr),
+            }
+        }
+    }
+
+    fn locations() -> Vec<Operand> {
+        let mut locs = (0..6).map(|ix| MachReg::new(ix).into_reg().into_op()).collect::<Vec<_>>();
+        locs.extend((0..4).map(|ix| Mem::new(Reg::rsp(), ix * 8).into_op()));
+        locs
+    }
+
+    fn xmm_locations() -> Vec<Operand> {
+        let mut locs = (0..4).map(|num| RegClass::Xmm.reg(num).into_reg().into_op())
+            .collect::<Vec<_>>();
+        locs.extend((0..4).map(|ix| Mem::new(Reg::rsp(), ix * 8).into_op()));
+        locs
+    }
+
+    fn initial_state(locs: &[Operand]) -> HashMap<String, Imm> {
+        let mut state = locs.iter().enumerate()
+            .map(|(ix, loc)| (format!("{}", loc), ix as Imm * 100))
+            .collect::<HashMap<_, _>>();
+        for s in &[R11, XMM15] {
+            state.insert(format!("{}", s), -1);
+        }
+        state
+    }
+
+    fn check_simultaneous(moves: &[ParallelMove], scratch: &[Reg]) {
+        check_simultaneous_in(&locations(), moves, scratch)
+    }
+
+    fn check_simultaneous_in(locs: &[Operand], moves: &[ParallelMove], scratch: &[Reg]) {
+        let mut state = initial_state(locs);
+        let instrs = resolve_parallel_moves(moves, scratch);
+        run(&instrs, &mut state);
+
+        let mut expected = initial_state(locs);
+        for m in moves {
+            let v = match *m.src() {
+                Operand::Imm(i) => i,
+                ref src => expected_initial(locs, src),
+            };
+            expected.insert(format!("{}", m.dst()), v);
+        }
+        for loc in locs {
+            let k = format!("{}", loc);
+            assert_eq!(state[&k], expected[&k],
+                       "{} differs after {:?}\nresolved as {:?}", k, moves, instrs);
+        }
+    }
+
+    fn expected_initial(locs: &[Operand], op: &Operand) -> Imm {
+        locs.iter().position(|l| l == op).unwrap() as Imm * 100
+    }
+
+    fn reg(ix: usize) -> Operand {
+        MachReg::new(ix).into_reg().into_op()
+    }
+
+    fn slot(ix: i32) -> Operand {
+        Mem::new(Reg::rsp(), ix * 8).into_op()
+    }
+
+    #[test]
+    fn can_order_a_chain() {
+        // r0 <- r1 <- r2: r0 has to be written first.
+        let moves = vec![
+            ParallelMove::new(reg(1), reg(2)),
+            ParallelMove::new(reg(0), reg(1)),
+        ];
+        assert_eq!(resolve_parallel_moves(&moves, &[]), vec![
+            Instr::mov(reg(0), reg(1)),
+            Instr::mov(reg(1), reg(2)),
+        ]);
+    }
+
+    #[test]
+    fn can_swap_registers_with_xchg() {
+        let moves = vec![
+            ParallelMove::new(reg(0), reg(1)),
+            ParallelMove::new(reg(1), reg(0)),
+        ];
+        assert_eq!(resolve_parallel_moves(&moves, &[]), vec![
+            Instr::xchg(reg(1), reg(0)),
+        ]);
+    }
+
+    #[test]
+    fn can_swap_memory_through_scratch() {
+        let s = R11.into_reg();
+        let moves = vec![
+            ParallelMove::new(slot(0), slot(1)),
+            ParallelMove::new(slot(1), slot(0)),
+        ];
+        assert_eq!(resolve_parallel_moves(&moves, &[s]), vec![
+            Instr::mov(s.into_op(), slot(1)),
+            Instr::xchg(s.into_op(), slot(0)),
+            Instr::mov(slot(1), s.into_op()),
+        ]);
+        check_simultaneous(&moves, &[s]);
+
+        // With both scratch registers, there's no locked xchg.
+        let x = XMM15.into_reg();
+        assert_eq!(resolve_parallel_moves(&moves, &[s, x]), vec![
+            Instr::mov(s.into_op(), slot(1)),
+            Instr::movsd(x.into_op(), slot(0)),
+            Instr::mov(slot(0), s.into_op()),
+            Instr::movsd(slot(1), x.into_op()),
+        ]);
+        check_simultaneous(&moves, &[s, x]);
+    }
+
+    #[test]
+    fn can_resolve_block() {
+        let mut instr = Instr::add(reg(0), reg(1));
+        instr.parallel_moves.add_to_start(ParallelMove::new(reg(0), slot(0)));
+        instr.parallel_moves.add_to_end(ParallelMove::new(slot(1), reg(0)));
+        let mut b = Block::new(Label::Local(0), vec![instr, Instr::ret(reg(0))]);
+        resolve_block(&mut b, &[]);
+        assert_eq!(b.instrs, vec![
+            Instr::mov(reg(0), slot(0)),
+            Instr::add(reg(0), reg(1)),
+            Instr::mov(slot(1), reg(0)),
+            Instr::ret(reg(0)),
+        ]);
+    }
+
+    #[test]
+    fn random_moves_match_simultaneous_assignment() {
+        let locs = locations();
+        let mut rng = XorShift::new(42);
+        for _ in 0..2000 {
+            let mut dsts = locs.clone();
+            let num_moves = rng.below(dsts.len() + 1);
+            let mut moves = vec![];
+            for _ in 0..num_moves {
+                let dst = dsts.swap_remove(rng.below(dsts.len()));
+                let src = if rng.chance(8) {
+                    Operand::Imm(if rng.chance(2) { 1 << 40 } else { -7 })
+                } else {
+                    rng.pick(&locs).clone()
+                };
+                moves.push(ParallelMove::new(dst, src));
+            }
+            let scratch = [R11.into_reg(), XMM15.into_reg()];
+            check_simultaneous(&moves, &scratch);
+            // An xchg with memory is implicitly locked.
+            let instrs = resolve_parallel_moves(&moves, &scratch);
+            let locked = |i: &Instr| i.opcode == OpCode::Xchg && i.ops.iter().any(|op| op.is_mem());
+            assert!(!instrs.iter().any(locked), "{:?} resolved as {:?}", moves, instrs);
+
+            // Register-only moves never need the scratch.
+            let reg_moves = moves.into_iter()
+                .filter(|m| m.dst().is_reg() && m.src().is_reg())
+                .collect::<Vec<_>>();
+            check_simultaneous(&reg_moves, &[]);
+        }
+    }
+
+    #[test]
+    fn random_xmm_moves_match_simultaneous_assignment() {
+        let locs = xmm_locations();
+        let scratch = [R11.into_reg(), XMM15.into_reg()];
+        let mut rng = XorShift::new(40);
+        for _ in 0..2000 {
+            let mut dsts = locs.clone();
+            let num_moves = rng.below(dsts.len() + 1);
+            let mut moves = vec![];
+            for _ in 0..num_moves {
+                let dst = dsts.swap_remove(rng.below(dsts.len()));
+                moves.push(ParallelMove::new(dst, rng.pick(&locs).clone()));
+            }
+            check_simultaneous_in(&locs, &moves, &scratch);
+        }
+    }
+
+    #[test]
+    fn sequenced_moves_match_running_both() {
+        let locs = locations();
+        let scratch = [R11.into_reg(), XMM15.into_reg()];
+        let mut rng = XorShift::new(43);
+        let random_moves = |rng: &mut XorShift| {
+            let mut dsts = locs.clone();
+            (0..rng.below(dsts.len() + 1)).map(|_| {
+                let dst = dsts.swap_remove(rng.below(dsts.len()));
+                ParallelMove::new(dst, rng.pick(&locs).clone())
+            }).collect::<Vec<_>>()
+        };
+        for _ in 0..2000 {
+            let (first, then) = (random_moves(&mut rng), random_moves(&mut rng));
+            let mut expected = initial_state(&locs);
+            run(&resolve_parallel_moves(&first, &scratch), &mut expected);
+            run(&resolve_parallel_moves(&then, &scratch), &mut expected);
+            let moves = sequence(&first, &then);
+            let mut state = initial_state(&locs);
+            run(&resolve_parallel_moves(&moves, &scratch), &mut state);
+            for loc in &locs {
+                let k = format!("{}", loc);
+                assert_eq!(state[&k], expected[&k],
+                           "{} differs after {:?} then {:?} as {:?}", k, first, then, moves);
+            }
+        }
+    }
+}
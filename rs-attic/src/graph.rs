@@ -5,7 +5,7 @@ pub struct Graph {
     nodes: Vec<Node>,
 }
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub struct Id(u32);
 
 #[derive(Debug, Clone)]
@@ -19,7 +19,20 @@ pub struct Node {
 #[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd)]
 pub enum Operator {
     Int64Add,
+    Int64Shl,
+    Int64Equal,
+    Int64LessThan,
+    Int64LessThanOrEqual,
     Int64Constant(i64),
+    Float64Add,
+    Float64Mul,
+    Float64LessThan,
+    Float64LessThanOrEqual,
+    ChangeInt64ToFloat64,
+    // The ix-th argument of the function.
+    Parameter(u32),
+    // Reads 8 bytes at the address.
+    Load,
     Branch,
     Return,
     Phi,
@@ -30,7 +43,18 @@ pub enum Operator {
 #[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd)]
 pub enum NodeView {
     Int64Add(Id, Id),
+    Int64Shl(Id, Id),
+    Int64Equal(Id, Id),
+    Int64LessThan(Id, Id),
+    Int64LessThanOrEqual(Id, Id),
     Int64Constant(i64),
+    Float64Add(Id, Id),
+    Float64Mul(Id, Id),
+    Float64LessThan(Id, Id),
+    Float64LessThanOrEqual(Id, Id),
+    ChangeInt64ToFloat64(Id),
+    Parameter(u32),
+    Load(Id),
     Return(Id),
     Branch(Id),
     Phi {
@@ -231,8 +255,13 @@ impl Operator {
     fn num_inputs(&self) -> Option<usize> {
         use self::Operator::*;
         let n = match self {
-            &Int64Add => 2,
-            &Int64Constant(_) => 0,
+            &Int64Add | &Int64Shl => 2,
+            &Int64Equal | &Int64LessThan | &Int64LessThanOrEqual => 2,
+            &Int64Constant(_) | &Parameter(_) => 0,
+            &Float64Add | &Float64Mul => 2,
+            &Float64LessThan | &Float64LessThanOrEqual => 2,
+            &ChangeInt64ToFloat64 => 1,
+            &Load => 1,
             &Branch => 1,
             &Return => 1,
             &Phi => return None,
@@ -249,7 +278,18 @@ impl Operator {
         let i = &n.inputs;
         match self {
             &Int64Add => NodeView::Int64Add(i[0], i[1]),
+            &Int64Shl => NodeView::Int64Shl(i[0], i[1]),
+            &Int64Equal => NodeView::Int64Equal(i[0], i[1]),
+            &Int64LessThan => NodeView::Int64LessThan(i[0], i[1]),
+            &Int64LessThanOrEqual => NodeView::Int64LessThanOrEqual(i[0], i[1]),
             &Int64Constant(i) => NodeView::Int64Constant(i),
+            &Float64Add => NodeView::Float64Add(i[0], i[1]),
+            &Float64Mul => NodeView::Float64Mul(i[0], i[1]),
+            &Float64LessThan => NodeView::Float64LessThan(i[0], i[1]),
+            &Float64LessThanOrEqual => NodeView::Float64LessThanOrEqual(i[0], i[1]),
+            &ChangeInt64ToFloat64 => NodeView::ChangeInt64ToFloat64(i[0]),
+            &Parameter(ix) => NodeView::Parameter(ix),
+            &Load => NodeView::Load(i[0]),
             &Branch => NodeView::Branch(i[0]),
             &Return => NodeView::Return(i[0]),
             &Phi => NodeView::Phi {
@@ -0,0 +1,1186 @@
+use std::collections::{HashMap, HashSet};
+
+use ::frame;
+use ::graph::{Graph, Id, NodeView};
+use ::legalize;
+use ::lsra::Hints;
+use ::schedule::Schedule;
+use ::x64::*;
+
+// Lowers a scheduled Graph into x64 code over VirtualRegs by covering the
+// nodes with the tree patterns of RULES. Every node that isn't folded into
+// its user gets its own VirtualReg, and a Phi is written by the ParallelMoves
+// of the jumps into its block. Schedule block ix becomes the Block labeled
+// Local(ix).
+struct InstrSelector<'a> {
+    g: &'a Graph,
+    s: &'a Schedule,
+    block_of: HashMap<Id, usize>,
+    vregs: HashMap<Id, Reg>,
+    best: HashMap<Id, Option<Match>>,
+    // The chosen Matches of the nodes that are computed on their own.
+    chosen: HashMap<Id, Match>,
+    // Folded into the Match of their user.
+    covered: HashSet<Id>,
+    // Constants that are used from a register rather than as imms.
+    materialized: HashSet<Id>,
+    // Only carry the Phi moves of a critical edge.
+    edge_blocks: Vec<Block>,
+}
+
+// A tree pattern over the nodes. Only the root and the leaves can have other
+// users: the interior nodes are folded into the instructions of the root.
+#[derive(Debug)]
+enum Pat {
+    // Any node, in a register.
+    Reg,
+    // An Int64Constant that fits.
+    Imm(ImmWidth),
+    // An Int64Constant that is a valid Scale::log2.
+    Scale,
+    Node(Op, &'static [Pat]),
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Op {
+    Add,
+    Shl,
+    Load,
+    Equal,
+    LessThan,
+    LessThanOrEqual,
+    Float64Add,
+    Float64Mul,
+    Float64LessThan,
+    Float64LessThanOrEqual,
+    ToFloat64,
+    Branch,
+}
+
+// Each leaf of the pattern is passed as an Operand: a VirtualReg for Pat::Reg
+// and an Imm for the others.
+#[derive(Copy, Clone)]
+enum Emit {
+    // Writes the dst.
+    Value(fn(Operand, &[Operand]) -> Vec<Instr>),
+    // Sets the flags for a jcc.
+    Flags(fn(&[Operand]) -> (Instr, Cond)),
+    // Sets the flags like Flags, and writes the dst with whether the Cond
+    // holds, as a 0 or 1.
+    Setcc(fn(&[Operand]) -> (Instr, Cond)),
+}
+
+struct Rule {
+    pat: Pat,
+    // Roughly in instructions, with a copy for a two-address form at half
+    // an instruction: the allocator can often coalesce it.
+    cost: u32,
+    emit: Emit,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Leaf {
+    Reg(Id),
+    Imm(i64),
+}
+
+#[derive(Clone)]
+struct Match {
+    rule: &'static Rule,
+    leaves: Vec<Leaf>,
+    // The interior nodes.
+    covered: Vec<Id>,
+    // Including the leaves that are only computed for this match.
+    cost: u32,
+}
+
+const R: Pat = Pat::Reg;
+const I32: Pat = Pat::Imm(ImmWidth::Imm32);
+const I8: Pat = Pat::Imm(ImmWidth::Imm8);
+const S: Pat = Pat::Scale;
+
+// Where several rules match, the cheapest one wins, then the first one.
+static RULES: &[Rule] = &[
+    Rule { pat: Pat::Node(Op::Add, &[R, I32]), cost: 3, emit: Emit::Value(add_imm) },
+    Rule { pat: Pat::Node(Op::Add, &[I32, R]), cost: 3, emit: Emit::Value(add_imm_rev) },
+    Rule { pat: Pat::Node(Op::Add, &[R, R]), cost: 3, emit: Emit::Value(add) },
+    Rule {
+        pat: Pat::Node(Op::Add, &[Pat::Node(Op::Add, &[R, R]), I32]),
+        cost: 2,
+        emit: Emit::Value(lea_base_index_disp),
+    },
+    Rule {
+        pat: Pat::Node(Op::Add, &[Pat::Node(Op::Add, &[R, R]), R]),
+        cost: 4,
+        emit: Emit::Value(lea_base_index_then_add),
+    },
+    Rule {
+        pat: Pat::Node(Op::Add, &[R, Pat::Node(Op::Shl, &[R, S])]),
+        cost: 2,
+        emit: Emit::Value(lea_scaled),
+    },
+    Rule { pat: Pat::Node(Op::Shl, &[R, I8]), cost: 3, emit: Emit::Value(shl) },
+    Rule { pat: Pat::Node(Op::Shl, &[R, R]), cost: 3, emit: Emit::Value(shl) },
+    Rule { pat: Pat::Node(Op::Load, &[R]), cost: 2, emit: Emit::Value(load) },
+    Rule {
+        pat: Pat::Node(Op::Load, &[Pat::Node(Op::Add, &[R, I32])]),
+        cost: 2,
+        emit: Emit::Value(load_disp),
+    },
+    Rule {
+        pat: Pat::Node(Op::Load, &[Pat::Node(Op::Add, &[R, R])]),
+        cost: 2,
+        emit: Emit::Value(load_indexed),
+    },
+    Rule {
+        pat: Pat::Node(Op::Load, &[Pat::Node(Op::Add, &[R, Pat::Node(Op::Shl, &[R, S])])]),
+        cost: 2,
+        emit: Emit::Value(load_scaled),
+    },
+    Rule { pat: Pat::Node(Op::Branch, &[R]), cost: 2, emit: Emit::Flags(test_nonzero) },
+    Rule {
+        pat: Pat::Node(Op::Branch, &[Pat::Node(Op::Equal, &[R, I32])]),
+        cost: 2,
+        emit: Emit::Flags(cmp_e),
+    },
+    Rule {
+        pat: Pat::Node(Op::Branch, &[Pat::Node(Op::Equal, &[I32, R])]),
+        cost: 2,
+        emit: Emit::Flags(cmp_e_rev),
+    },
+    Rule {
+        pat: Pat::Node(Op::Branch, &[Pat::Node(Op::Equal, &[R, R])]),
+        cost: 2,
+        emit: Emit::Flags(cmp_e),
+    },
+    Rule {
+        pat: Pat::Node(Op::Branch, &[Pat::Node(Op::LessThan, &[R, I32])]),
+        cost: 2,
+        emit: Emit::Flags(cmp_l),
+    },
+    Rule {
+        pat: Pat::Node(Op::Branch, &[Pat::Node(Op::LessThan, &[I32, R])]),
+        cost: 2,
+        emit: Emit::Flags(cmp_l_rev),
+    },
+    Rule {
+        pat: Pat::Node(Op::Branch, &[Pat::Node(Op::LessThan, &[R, R])]),
+        cost: 2,
+        emit: Emit::Flags(cmp_l),
+    },
+    Rule {
+        pat: Pat::Node(Op::Branch, &[Pat::Node(Op::LessThanOrEqual, &[R, I32])]),
+        cost: 2,
+        emit: Emit::Flags(cmp_le),
+    },
+    Rule {
+        pat: Pat::Node(Op::Branch, &[Pat::Node(Op::LessThanOrEqual, &[I32, R])]),
+        cost: 2,
+        emit: Emit::Flags(cmp_le_rev),
+    },
+    Rule {
+        pat: Pat::Node(Op::Branch, &[Pat::Node(Op::LessThanOrEqual, &[R, R])]),
+        cost: 2,
+        emit: Emit::Flags(cmp_le),
+    },
+    // The compares that aren't folded into a Branch, e.g. because it's in
+    // another block or the value is also used elsewhere.
+    Rule { pat: Pat::Node(Op::Equal, &[R, I32]), cost: 3, emit: Emit::Setcc(cmp_e) },
+    Rule { pat: Pat::Node(Op::Equal, &[I32, R]), cost: 3, emit: Emit::Setcc(cmp_e_rev) },
+    Rule { pat: Pat::Node(Op::Equal, &[R, R]), cost: 3, emit: Emit::Setcc(cmp_e) },
+    Rule { pat: Pat::Node(Op::LessThan, &[R, I32]), cost: 3, emit: Emit::Setcc(cmp_l) },
+    Rule { pat: Pat::Node(Op::LessThan, &[I32, R]), cost: 3, emit: Emit::Setcc(cmp_l_rev) },
+    Rule { pat: Pat::Node(Op::LessThan, &[R, R]), cost: 3, emit: Emit::Setcc(cmp_l) },
+    Rule { pat: Pat::Node(Op::LessThanOrEqual, &[R, I32]), cost: 3, emit: Emit::Setcc(cmp_le) },
+    Rule {
+        pat: Pat::Node(Op::LessThanOrEqual, &[I32, R]),
+        cost: 3,
+        emit: Emit::Setcc(cmp_le_rev),
+    },
+    Rule { pat: Pat::Node(Op::LessThanOrEqual, &[R, R]), cost: 3, emit: Emit::Setcc(cmp_le) },
+    Rule { pat: Pat::Node(Op::Float64Add, &[R, R]), cost: 3, emit: Emit::Value(addsd) },
+    Rule { pat: Pat::Node(Op::Float64Mul, &[R, R]), cost: 3, emit: Emit::Value(mulsd) },
+    Rule { pat: Pat::Node(Op::ToFloat64, &[R]), cost: 2, emit: Emit::Value(cvtsi2sd) },
+    Rule {
+        pat: Pat::Node(Op::Branch, &[Pat::Node(Op::Float64LessThan, &[R, R])]),
+        cost: 2,
+        emit: Emit::Flags(ucomisd_lt),
+    },
+    Rule {
+        pat: Pat::Node(Op::Branch, &[Pat::Node(Op::Float64LessThanOrEqual, &[R, R])]),
+        cost: 2,
+        emit: Emit::Flags(ucomisd_le),
+    },
+    Rule {
+        pat: Pat::Node(Op::Float64LessThan, &[R, R]),
+        cost: 3,
+        emit: Emit::Setcc(ucomisd_lt),
+    },
+    Rule {
+        pat: Pat::Node(Op::Float64LessThanOrEqual, &[R, R]),
+        cost: 3,
+        emit: Emit::Setcc(ucomisd_le),
+    },
+];
+
+// The cost of materializing a constant that is used from a register.
+const MATERIALIZE_COST: u32 = 2;
+
+fn add(dst: Operand, ops: &[Operand]) -> Vec<Instr> {
+    vec![Instr::new3(OpCode::Add, dst, ops[0].clone(), ops[1].clone())]
+}
+
+fn add_imm(dst: Operand, ops: &[Operand]) -> Vec<Instr> {
+    add(dst, ops)
+}
+
+fn add_imm_rev(dst: Operand, ops: &[Operand]) -> Vec<Instr> {
+    add(dst, &[ops[1].clone(), ops[0].clone()])
+}
+
+fn lea_base_index_disp(dst: Operand, ops: &[Operand]) -> Vec<Instr> {
+    vec![Instr::lea(dst, mem(&ops[0], Some((&ops[1], 0)), imm(&ops[2])))]
+}
+
+fn lea_base_index_then_add(dst: Operand, ops: &[Operand]) -> Vec<Instr> {
+    vec![Instr::lea(dst.clone(), mem(&ops[0], Some((&ops[1], 0)), 0)),
+         Instr::add(dst, ops[2].clone())]
+}
+
+fn lea_scaled(dst: Operand, ops: &[Operand]) -> Vec<Instr> {
+    vec![Instr::lea(dst, mem(&ops[0], Some((&ops[1], imm(&ops[2]))), 0))]
+}
+
+fn shl(dst: Operand, ops: &[Operand]) -> Vec<Instr> {
+    vec![Instr::new3(OpCode::Shl, dst, ops[0].clone(), ops[1].clone())]
+}
+
+fn load(dst: Operand, ops: &[Operand]) -> Vec<Instr> {
+    vec![Instr::mov(dst, mem(&ops[0], None, 0).into_op())]
+}
+
+fn load_disp(dst: Operand, ops: &[Operand]) -> Vec<Instr> {
+    vec![Instr::mov(dst, mem(&ops[0], None, imm(&ops[1])).into_op())]
+}
+
+fn load_indexed(dst: Operand, ops: &[Operand]) -> Vec<Instr> {
+    vec![Instr::mov(dst, mem(&ops[0], Some((&ops[1], 0)), 0).into_op())]
+}
+
+fn load_scaled(dst: Operand, ops: &[Operand]) -> Vec<Instr> {
+    vec![Instr::mov(dst, mem(&ops[0], Some((&ops[1], imm(&ops[2]))), 0).into_op())]
+}
+
+fn test_nonzero(ops: &[Operand]) -> (Instr, Cond) {
+    (Instr::cmp(ops[0].clone(), Operand::Imm(0)), Cond::Ne)
+}
+
+fn cmp_e(ops: &[Operand]) -> (Instr, Cond) {
+    (Instr::cmp(ops[0].clone(), ops[1].clone()), Cond::E)
+}
+
+fn cmp_e_rev(ops: &[Operand]) -> (Instr, Cond) {
+    (Instr::cmp(ops[1].clone(), ops[0].clone()), Cond::E)
+}
+
+fn cmp_l(ops: &[Operand]) -> (Instr, Cond) {
+    (Instr::cmp(ops[0].clone(), ops[1].clone()), Cond::L)
+}
+
+// imm < r is r > imm.
+fn cmp_l_rev(ops: &[Operand]) -> (Instr, Cond) {
+    (Instr::cmp(ops[1].clone(), ops[0].clone()), Cond::G)
+}
+
+fn cmp_le(ops: &[Operand]) -> (Instr, Cond) {
+    (Instr::cmp(ops[0].clone(), ops[1].clone()), Cond::Le)
+}
+
+fn cmp_le_rev(ops: &[Operand]) -> (Instr, Cond) {
+    (Instr::cmp(ops[1].clone(), ops[0].clone()), Cond::Ge)
+}
+
+fn addsd(dst: Operand, ops: &[Operand]) -> Vec<Instr> {
+    vec![Instr::new3(OpCode::Addsd, dst, ops[0].clone(), ops[1].clone())]
+}
+
+fn mulsd(dst: Operand, ops: &[Operand]) -> Vec<Instr> {
+    vec![Instr::new3(OpCode::Mulsd, dst, ops[0].clone(), ops[1].clone())]
+}
+
+fn cvtsi2sd(dst: Operand, ops: &[Operand]) -> Vec<Instr> {
+    vec![Instr::cvtsi2sd(dst, ops[0].clone())]
+}
+
+// a < b is b > a, where the unsigned conds are false for a NaN: ucomisd sets
+// both ZF and CF when the operands are unordered.
+fn ucomisd_lt(ops: &[Operand]) -> (Instr, Cond) {
+    (Instr::ucomisd(ops[1].clone(), ops[0].clone()), Cond::A)
+}
+
+fn ucomisd_le(ops: &[Operand]) -> (Instr, Cond) {
+    (Instr::ucomisd(ops[1].clone(), ops[0].clone()), Cond::Ae)
+}
+
+fn imm(op: &Operand) -> i32 {
+    match *op {
+        Operand::Imm(i) => i as i32,
+        _ => panic!("Not an imm: {}", op),
+    }
+}
+
+fn reg(op: &Operand) -> Reg {
+    match *op {
+        Operand::Reg(r) => r,
+        _ => panic!("Not a reg: {}", op),
+    }
+}
+
+// [base + index << log2 + disp]
+fn mem(base: &Operand, index: Option<(&Operand, i32)>, disp: i32) -> Mem {
+    match index {
+        None => Mem::new(reg(base), disp),
+        Some((index, log2)) => {
+            let scale = [Scale::S1, Scale::S2, Scale::S4, Scale::S8][log2 as usize];
+            Mem::new_indexed(Some(reg(base)), reg(index), scale, disp)
+        }
+    }
+}
+
+fn op_of(view: &NodeView) -> Option<(Op, Vec<Id>)> {
+    let res = match *view {
+        NodeView::Int64Add(a, b) => (Op::Add, vec![a, b]),
+        NodeView::Int64Shl(a, b) => (Op::Shl, vec![a, b]),
+        NodeView::Load(a) => (Op::Load, vec![a]),
+        NodeView::Int64Equal(a, b) => (Op::Equal, vec![a, b]),
+        NodeView::Int64LessThan(a, b) => (Op::LessThan, vec![a, b]),
+        NodeView::Int64LessThanOrEqual(a, b) => (Op::LessThanOrEqual, vec![a, b]),
+        NodeView::Float64Add(a, b) => (Op::Float64Add, vec![a, b]),
+        NodeView::Float64Mul(a, b) => (Op::Float64Mul, vec![a, b]),
+        NodeView::Float64LessThan(a, b) => (Op::Float64LessThan, vec![a, b]),
+        NodeView::Float64LessThanOrEqual(a, b) => (Op::Float64LessThanOrEqual, vec![a, b]),
+        NodeView::ChangeInt64ToFloat64(a) => (Op::ToFloat64, vec![a]),
+        NodeView::Branch(a) => (Op::Branch, vec![a]),
+        _ => return None,
+    };
+    Some(res)
+}
+
+// The result is in two-address form with legal immediates, and is ready for
+// the register allocator, together with the Hints that tie the copies it
+// needs. Only Int64s can be returned, in RAX.
+pub fn select(g: &Graph, s: &Schedule) -> Result<(Function, Hints), String> {
+    debug_assert_eq!(s.verify(g), Ok(()));
+    let mut isel = InstrSelector {
+        g,
+        s,
+        block_of: HashMap::new(),
+        vregs: HashMap::new(),
+        best: HashMap::new(),
+        chosen: HashMap::new(),
+        covered: HashSet::new(),
+        materialized: HashSet::new(),
+        edge_blocks: vec![],
+    };
+    for (ix, b) in s.blocks.iter().enumerate() {
+        for &n in &b.nodes {
+            isel.block_of.insert(n, ix);
+            if let NodeView::Return(v) = g.view_node(n) {
+                if isel.class_of(v, &mut HashSet::new()) != RegClass::Gpr {
+                    return Err(format!("{:?} returns a Float64", n));
+                }
+            }
+        }
+    }
+    for ix in 0..s.blocks.len() {
+        isel.cover_block(ix);
+    }
+    let mut blocks = (0..s.blocks.len()).map(|ix| isel.select_block(ix)).collect::<Vec<_>>();
+    blocks.append(&mut isel.edge_blocks);
+    let mut f = Function::new(blocks);
+    let hints = legalize::legalize_two_address(&mut f);
+    // A materialized constant can be any Int64.
+    legalize::legalize_imms(&mut f);
+    Ok((f, hints))
+}
+
+impl<'a> InstrSelector<'a> {
+    fn vreg(&mut self, n: Id) -> Operand {
+        if let Some(r) = self.vregs.get(&n) {
+            return r.into_op();
+        }
+        let class = self.class_of(n, &mut HashSet::new());
+        let r = Reg::new_virt_in(class, self.vregs.len() as u32);
+        self.vregs.insert(n, r);
+        r.into_op()
+    }
+
+    // A Phi takes the class of its inputs. Those that only go through other
+    // Phis are left as Gprs.
+    fn class_of(&self, n: Id, visiting: &mut HashSet<Id>) -> RegClass {
+        match self.g.view_node(n) {
+            NodeView::Float64Add(..) | NodeView::Float64Mul(..) |
+            NodeView::ChangeInt64ToFloat64(_) => RegClass::Xmm,
+            NodeView::Phi { value_inputs, .. } => {
+                if !visiting.insert(n) {
+                    return RegClass::Gpr;
+                }
+                value_inputs.iter()
+                    .map(|&i| self.class_of(i, visiting))
+                    .find(|&c| c == RegClass::Xmm)
+                    .unwrap_or(RegClass::Gpr)
+            }
+            _ => RegClass::Gpr,
+        }
+    }
+
+    // Chooses the Matches of a block from its last node backwards, so that a
+    // node is only computed on its own if its users didn't fold it.
+    fn cover_block(&mut self, ix: usize) {
+        let (g, s) = (self.g, self.s);
+        for &n in s.blocks[ix].nodes.iter().rev() {
+            let view = g.view_node(n);
+            match view {
+                NodeView::Phi { ref value_inputs, .. } => {
+                    self.materialized.extend(value_inputs.iter().cloned());
+                }
+                NodeView::Return(v) => {
+                    self.materialized.insert(v);
+                }
+                _ => (),
+            }
+            if self.covered.contains(&n) || op_of(&view).is_none() {
+                continue;
+            }
+            let m = self.best_match(n)
+                .unwrap_or_else(|| panic!("No rule covers {:?}", g.view_node(n)));
+            self.covered.extend(m.covered.iter().cloned());
+            for leaf in &m.leaves {
+                if let Leaf::Reg(l) = *leaf {
+                    self.materialized.insert(l);
+                }
+            }
+            self.chosen.insert(n, m);
+        }
+    }
+
+    fn best_match(&mut self, n: Id) -> Option<Match> {
+        if let Some(m) = self.best.get(&n) {
+            return m.clone();
+        }
+        let mut best: Option<Match> = None;
+        for rule in RULES {
+            let mut m = Match { rule, leaves: vec![], covered: vec![], cost: rule.cost };
+            if !self.match_pat(&rule.pat, n, n, &mut m) {
+                continue;
+            }
+            let leaf_costs = m.leaves.clone().into_iter()
+                .map(|leaf| match leaf {
+                    Leaf::Reg(l) => self.leaf_cost(n, l),
+                    Leaf::Imm(_) => Some(0),
+                })
+                .sum::<Option<u32>>();
+            match leaf_costs {
+                Some(c) => m.cost += c,
+                // A leaf that no rule can compute.
+                None => continue,
+            }
+            if best.as_ref().is_none_or(|b| m.cost < b.cost) {
+                best = Some(m);
+            }
+        }
+        self.best.insert(n, best.clone());
+        best
+    }
+
+    // Of a leaf in a register that is only computed for the match at root, or
+    // None if no rule can compute it.
+    fn leaf_cost(&mut self, root: Id, l: Id) -> Option<u32> {
+        let view = self.g.view_node(l);
+        match view {
+            NodeView::Int64Constant(_) => Some(MATERIALIZE_COST),
+            _ if op_of(&view).is_none() => Some(0),
+            _ if self.is_foldable(root, l) => self.best_match(l).map(|m| m.cost),
+            _ => self.best_match(l).map(|_| 0),
+        }
+    }
+
+    // Only has the one user, right in the block of root.
+    fn is_foldable(&self, root: Id, n: Id) -> bool {
+        self.g.num_uses(n) == 1 && self.block_of.get(&n) == self.block_of.get(&root)
+    }
+
+    fn match_pat(&self, pat: &Pat, n: Id, root: Id, m: &mut Match) -> bool {
+        let view = self.g.view_node(n);
+        match *pat {
+            Pat::Reg => {
+                m.leaves.push(Leaf::Reg(n));
+                true
+            }
+            Pat::Imm(width) => match view {
+                NodeView::Int64Constant(i) if width.fits(i) => {
+                    m.leaves.push(Leaf::Imm(i));
+                    true
+                }
+                _ => false,
+            },
+            Pat::Scale => match view {
+                NodeView::Int64Constant(i) if (0..=3).contains(&i) => {
+                    m.leaves.push(Leaf::Imm(i));
+                    true
+                }
+                _ => false,
+            },
+            Pat::Node(op, pats) => {
+                let inputs = match op_of(&view) {
+                    Some((node_op, inputs)) if node_op == op => inputs,
+                    _ => return false,
+                };
+                if n != root {
+                    if !self.is_foldable(root, n) {
+                        return false;
+                    }
+                    m.covered.push(n);
+                }
+                pats.iter().zip(inputs).all(|(p, i)| self.match_pat(p, i, root, m))
+            }
+        }
+    }
+
+    fn select_block(&mut self, ix: usize) -> Block {
+        let (g, s) = (self.g, self.s);
+        let b = &s.blocks[ix];
+        let mut instrs = vec![];
+        let mut falls_through = true;
+        if ix == 0 {
+            instrs.extend(self.lower_params());
+        }
+        for &n in &b.nodes {
+            if self.covered.contains(&n) {
+                continue;
+            }
+            match g.view_node(n) {
+                NodeView::Int64Constant(i) => {
+                    if self.materialized.contains(&n) {
+                        instrs.push(Instr::mov(self.vreg(n), Operand::Imm(i)));
+                    }
+                }
+                NodeView::Phi { .. } => {
+                    self.vreg(n);
+                }
+                NodeView::Parameter(_) => (),
+                NodeView::Return(v) => {
+                    let rax = RAX.into_reg().into_op();
+                    let v = self.vreg(v);
+                    instrs.push(Instr::mov(rax.clone(), v));
+                    instrs.push(Instr::ret(rax));
+                    falls_through = false;
+                }
+                _ => {
+                    let m = self.chosen[&n].clone();
+                    let ops = m.leaves.iter().map(|&leaf| match leaf {
+                        Leaf::Reg(l) => self.vreg(l),
+                        Leaf::Imm(i) => Operand::Imm(i),
+                    }).collect::<Vec<_>>();
+                    match m.rule.emit {
+                        Emit::Value(emit) => {
+                            let dst = self.vreg(n);
+                            instrs.extend(emit(dst, &ops));
+                        }
+                        Emit::Flags(emit) => {
+                            let (cmp, cond) = emit(&ops);
+                            instrs.push(cmp);
+                            let if_true = self.edge_target(ix, b.succs[0]);
+                            instrs.push(Instr::jcc(cond, if_true));
+                            instrs.push(self.jump(ix, b.succs[1]));
+                            falls_through = false;
+                        }
+                        Emit::Setcc(emit) => {
+                            // Zeroed before the flags are set, so it can't
+                            // share a register with the operands.
+                            let dst = self.vreg(n);
+                            let (cmp, cond) = emit(&ops);
+                            instrs.push(Instr::mov(dst.clone(), Operand::Imm(0)));
+                            instrs.push(cmp);
+                            instrs.push(Instr::setcc(cond, dst));
+                        }
+                    }
+                }
+            }
+        }
+        if falls_through {
+            instrs.push(self.jump(ix, b.succs[0]));
+        }
+        Block::new(Label::Local(ix as u32), instrs)
+    }
+
+    // Copies the arguments into the Parameters at the entry, before anything
+    // can overwrite the ARG_REGS.
+    fn lower_params(&mut self) -> Vec<Instr> {
+        let (g, s) = (self.g, self.s);
+        let mut instrs = vec![];
+        for &n in &s.blocks[0].nodes {
+            if let NodeView::Parameter(ix) = g.view_node(n) {
+                let ix = ix as usize;
+                let src = match ARG_REGS.get(ix) {
+                    Some(r) => r.into_reg().into_op(),
+                    None => frame::incoming_arg(ix - ARG_REGS.len()),
+                };
+                instrs.push(Instr::mov(self.vreg(n), src));
+            }
+        }
+        instrs
+    }
+
+    // A jmp that also writes the Phis of `to`.
+    fn jump(&mut self, from: usize, to: usize) -> Instr {
+        let mut jmp = Instr::jmp(Label::Local(to as u32));
+        for m in self.phi_moves(from, to) {
+            jmp.parallel_moves.add_to_start(m);
+        }
+        jmp
+    }
+
+    // The moves of a jcc would also be done when it's not taken, so they get
+    // their own block.
+    fn edge_target(&mut self, from: usize, to: usize) -> Label {
+        if self.phi_moves(from, to).is_empty() {
+            return Label::Local(to as u32);
+        }
+        let label = Label::Local((self.s.blocks.len() + self.edge_blocks.len()) as u32);
+        let jmp = self.jump(from, to);
+        self.edge_blocks.push(Block::new(label.clone(), vec![jmp]));
+        label
+    }
+
+    fn phi_moves(&mut self, from: usize, to: usize) -> Vec<ParallelMove> {
+        let (g, s) = (self.g, self.s);
+        let pred_ix = s.blocks[to].preds.iter().position(|&p| p == from).unwrap();
+        let mut moves = vec![];
+        for &n in &s.blocks[to].nodes {
+            if let NodeView::Phi { value_inputs, .. } = g.view_node(n) {
+                moves.push(ParallelMove::new(self.vreg(n), self.vreg(value_inputs[pred_ix])));
+            }
+        }
+        moves
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ::graph::Operator;
+    use ::frame;
+    use ::gap_resolver;
+    use ::lsra;
+    use ::test_utils;
+    use ::x64::emu::{self, Emulator};
+    use ::x64::encode::Assembler;
+
+    fn node(g: &mut Graph, op: Operator, inputs: &[Id]) -> Id {
+        let n = g.add_node(op);
+        for &i in inputs {
+            g.add_input(n, i);
+        }
+        n
+    }
+
+    fn constant(g: &mut Graph, i: i64) -> Id {
+        node(g, Operator::Int64Constant(i), &[])
+    }
+
+    fn v(ix: u32) -> Operand {
+        Operand::new_virt_reg(ix)
+    }
+
+    fn r(m: MachReg) -> Operand {
+        m.into_reg().into_op()
+    }
+
+    fn emulate(f: &Function, args: &[i64]) -> Result<i64, String> {
+        Emulator::new().call(f, args)
+    }
+
+    // A single block that returns the last node.
+    fn leaf(g: &mut Graph, mut nodes: Vec<Id>) -> Schedule {
+        let ret = node(g, Operator::Return, &[*nodes.last().unwrap()]);
+        nodes.push(ret);
+        let mut s = Schedule::new();
+        s.add_block(nodes);
+        s
+    }
+
+    fn ret(v: Operand) -> Vec<Instr> {
+        vec![Instr::mov(r(RAX), v), Instr::ret(r(RAX))]
+    }
+
+    // (40 + 2) + 100
+    fn straight_line() -> (Graph, Schedule) {
+        let mut g = Graph::new();
+        let c40 = constant(&mut g, 40);
+        let c2 = constant(&mut g, 2);
+        let c100 = constant(&mut g, 100);
+        let add1 = node(&mut g, Operator::Int64Add, &[c40, c2]);
+        let add2 = node(&mut g, Operator::Int64Add, &[add1, c100]);
+        let s = leaf(&mut g, vec![c40, c2, add1, c100, add2]);
+        (g, s)
+    }
+
+    #[test]
+    fn can_select_straight_line_code() {
+        let (g, s) = straight_line();
+        let (f, _) = select(&g, &s).unwrap();
+        let mut expected = vec![
+            Instr::mov(v(0), Operand::Imm(40)),
+            Instr::mov(v(1), Operand::Imm(2)),
+            Instr::lea(v(2), Mem::new_indexed(Some(Reg::new_virt(0)), Reg::new_virt(1),
+                                              Scale::S1, 100)),
+        ];
+        expected.extend(ret(v(2)));
+        test_utils::assert_eq_pretty("isel-straight-line", &f.blocks[0].instrs, &expected);
+        assert_eq!(emulate(&f, &[]), Ok(142));
+    }
+
+    #[test]
+    fn can_feed_lsra() {
+        let (g, s) = straight_line();
+        let (mut f, hints) = select(&g, &s).unwrap();
+        let block = f.blocks.pop().unwrap();
+        let allocated = lsra::allocate_block(block, 4, &hints).unwrap();
+        let allocated = Function::new(vec![allocated]);
+        assert!(allocated.blocks[0].instrs.iter()
+                    .all(|i| i.reg_operands().iter().all(|&(_, r, _)| r.is_mach())),
+                "{}", allocated);
+        assert_eq!(emulate(&allocated, &[]), Ok(142));
+    }
+
+    #[test]
+    fn can_fold_imms() {
+        // (p0 + 5) << 3
+        let mut g = Graph::new();
+        let p0 = node(&mut g, Operator::Parameter(0), &[]);
+        let c5 = constant(&mut g, 5);
+        let c3 = constant(&mut g, 3);
+        let add = node(&mut g, Operator::Int64Add, &[p0, c5]);
+        let shl = node(&mut g, Operator::Int64Shl, &[add, c3]);
+        let s = leaf(&mut g, vec![p0, c5, add, c3, shl]);
+        let (f, hints) = select(&g, &s).unwrap();
+        let mut expected = vec![
+            Instr::mov(v(0), r(RDI)),
+            Instr::mov(v(1), v(0)),
+            Instr::add(v(1), Operand::Imm(5)),
+            Instr::mov(v(2), v(1)),
+            Instr::shl(v(2), Operand::Imm(3)),
+        ];
+        expected.extend(ret(v(2)));
+        test_utils::assert_eq_pretty("isel-fold-imms", &f.blocks[0].instrs, &expected);
+        assert_eq!(hints.get(&Reg::new_virt(1)), Some(&Reg::new_virt(0)));
+        assert_eq!(hints.get(&Reg::new_virt(2)), Some(&Reg::new_virt(1)));
+        assert_eq!(emulate(&f, &[2]), Ok(56));
+    }
+
+    #[test]
+    fn can_select_lea_for_nested_adds() {
+        // (p0 + p1) + p2
+        let mut g = Graph::new();
+        let ps = (0..3).map(|ix| node(&mut g, Operator::Parameter(ix), &[])).collect::<Vec<_>>();
+        let add1 = node(&mut g, Operator::Int64Add, &[ps[0], ps[1]]);
+        let add2 = node(&mut g, Operator::Int64Add, &[add1, ps[2]]);
+        let s = leaf(&mut g, vec![ps[0], ps[1], ps[2], add1, add2]);
+        let (f, _) = select(&g, &s).unwrap();
+        let mut expected = vec![
+            Instr::mov(v(0), r(RDI)),
+            Instr::mov(v(1), r(RSI)),
+            Instr::mov(v(2), r(RDX)),
+            Instr::lea(v(3), Mem::new_indexed(Some(Reg::new_virt(0)), Reg::new_virt(1),
+                                              Scale::S1, 0)),
+            Instr::add(v(3), v(2)),
+        ];
+        expected.extend(ret(v(3)));
+        test_utils::assert_eq_pretty("isel-lea", &f.blocks[0].instrs, &expected);
+        assert_eq!(emulate(&f, &[1, 20, 300]), Ok(321));
+    }
+
+    #[test]
+    fn can_keep_shared_subtrees_in_regs() {
+        // a = p0 + p1; (a + 1) + a
+        let mut g = Graph::new();
+        let p0 = node(&mut g, Operator::Parameter(0), &[]);
+        let p1 = node(&mut g, Operator::Parameter(1), &[]);
+        let c1 = constant(&mut g, 1);
+        let a = node(&mut g, Operator::Int64Add, &[p0, p1]);
+        let b = node(&mut g, Operator::Int64Add, &[a, c1]);
+        let c = node(&mut g, Operator::Int64Add, &[b, a]);
+        let s = leaf(&mut g, vec![p0, p1, a, c1, b, c]);
+        let (f, _) = select(&g, &s).unwrap();
+        let mut expected = vec![
+            Instr::mov(v(0), r(RDI)),
+            Instr::mov(v(1), r(RSI)),
+            Instr::mov(v(2), v(0)),
+            Instr::add(v(2), v(1)),
+            Instr::mov(v(3), v(2)),
+            Instr::add(v(3), Operand::Imm(1)),
+            Instr::mov(v(4), v(3)),
+            Instr::add(v(4), v(2)),
+        ];
+        expected.extend(ret(v(4)));
+        test_utils::assert_eq_pretty("isel-shared", &f.blocks[0].instrs, &expected);
+        assert_eq!(emulate(&f, &[3, 4]), Ok(15));
+    }
+
+    // sum = 0; for (i = 0; i < n; i += 1) sum += xs[i]
+    fn array_sum(g: &mut Graph) -> Schedule {
+        let xs = node(g, Operator::Parameter(0), &[]);
+        let n = node(g, Operator::Parameter(1), &[]);
+        let c0 = constant(g, 0);
+        let c1 = constant(g, 1);
+        let c3 = constant(g, 3);
+        let merge = node(g, Operator::Merge, &[]);
+        let i = node(g, Operator::Phi, &[merge, c0]);
+        let sum = node(g, Operator::Phi, &[merge, c0]);
+        let cmp = node(g, Operator::Int64LessThan, &[i, n]);
+        let branch = node(g, Operator::Branch, &[cmp]);
+        let offset = node(g, Operator::Int64Shl, &[i, c3]);
+        let addr = node(g, Operator::Int64Add, &[xs, offset]);
+        let x = node(g, Operator::Load, &[addr]);
+        let sum2 = node(g, Operator::Int64Add, &[sum, x]);
+        let i2 = node(g, Operator::Int64Add, &[i, c1]);
+        g.add_input(i, i2);
+        g.add_input(sum, sum2);
+        let ret = node(g, Operator::Return, &[sum]);
+
+        let mut s = Schedule::new();
+        let entry = s.add_block(vec![xs, n, c0, c1, c3]);
+        let header = s.add_block(vec![i, sum, cmp, branch]);
+        let body = s.add_block(vec![offset, addr, x, sum2, i2]);
+        let exit = s.add_block(vec![ret]);
+        s.add_edge(entry, header);
+        s.add_edge(header, body);
+        s.add_edge(header, exit);
+        s.add_edge(body, header);
+        s
+    }
+
+    #[test]
+    fn can_fuse_compares_and_scaled_loads() {
+        let mut g = Graph::new();
+        let s = array_sum(&mut g);
+        let (f, _) = select(&g, &s).unwrap();
+        assert_eq!(f.verify(), Ok(()));
+        // v0 = xs, v1 = n, v2 = 0, v3 = i, v4 = sum
+        let cmp_jcc = vec![
+            Instr::cmp(v(3), v(1)),
+            Instr::jcc(Cond::L, Label::Local(2)),
+        ];
+        test_utils::assert_eq_pretty("isel-fused-cmp", &f.blocks[1].instrs[..2].to_vec(),
+                                     &cmp_jcc);
+        let load = Mem::new_indexed(Some(Reg::new_virt(0)), Reg::new_virt(3), Scale::S8, 0);
+        assert_eq!(f.blocks[2].instrs[0], Instr::mov(v(5), load.into_op()));
+
+        let mut emu = Emulator::new();
+        let xs = emu::STACK_BASE as i64;
+        for ix in 0..10 {
+            emu.store(xs + 8 * ix, ix * ix).unwrap();
+        }
+        assert_eq!(emu.call(&f, &[xs, 10]), Ok(285));
+        assert_eq!(emulate(&f, &[xs, 0]), Ok(0));
+    }
+
+    // sum = 1 << 40; for (i = 0; i < n; i += 1) sum += xs[i]
+    #[test]
+    fn can_compile_loops_with_wide_constants() {
+        let mut g = Graph::new();
+        let xs = node(&mut g, Operator::Parameter(0), &[]);
+        let n = node(&mut g, Operator::Parameter(1), &[]);
+        let c0 = constant(&mut g, 0);
+        let c1 = constant(&mut g, 1);
+        let c3 = constant(&mut g, 3);
+        let big = constant(&mut g, 1 << 40);
+        let merge = node(&mut g, Operator::Merge, &[]);
+        let i = node(&mut g, Operator::Phi, &[merge, c0]);
+        let sum = node(&mut g, Operator::Phi, &[merge, big]);
+        let cmp = node(&mut g, Operator::Int64LessThan, &[i, n]);
+        let branch = node(&mut g, Operator::Branch, &[cmp]);
+        let offset = node(&mut g, Operator::Int64Shl, &[i, c3]);
+        let addr = node(&mut g, Operator::Int64Add, &[xs, offset]);
+        let x = node(&mut g, Operator::Load, &[addr]);
+        let sum2 = node(&mut g, Operator::Int64Add, &[sum, x]);
+        let i2 = node(&mut g, Operator::Int64Add, &[i, c1]);
+        g.add_input(i, i2);
+        g.add_input(sum, sum2);
+        let ret = node(&mut g, Operator::Return, &[sum]);
+
+        let mut s = Schedule::new();
+        let entry = s.add_block(vec![xs, n, c0, c1, c3, big]);
+        let header = s.add_block(vec![i, sum, cmp, branch]);
+        let body = s.add_block(vec![offset, addr, x, sum2, i2]);
+        let exit = s.add_block(vec![ret]);
+        s.add_edge(entry, header);
+        s.add_edge(header, body);
+        s.add_edge(header, exit);
+        s.add_edge(body, header);
+
+        let (f, hints) = select(&g, &s).unwrap();
+        assert!(f.blocks[0].instrs.iter().any(|i| i.opcode == OpCode::MovAbs), "{}", f);
+        let all_gprs = RegClass::Gpr.allocatable().len();
+        for &num_regs in &[3, 4, all_gprs] {
+            let mut allocated = lsra::allocate_function(f.clone(), num_regs, &hints)
+                .unwrap();
+            frame::insert_frame(&mut allocated);
+            gap_resolver::resolve_function(&mut allocated, &[R11.into_reg()]);
+            assert_eq!(allocated.verify(), Ok(()));
+            Assembler::new().encode_function(&allocated);
+
+            let mut emu = Emulator::new();
+            let xs = emu::STACK_BASE as i64;
+            for ix in 0..10 {
+                emu.store(xs + 8 * ix, ix * ix).unwrap();
+            }
+            assert_eq!(emu.call(&allocated, &[xs, 10]), Ok((1 << 40) + 285), "{}", allocated);
+            assert_eq!(emulate(&allocated, &[xs, 0]), Ok(1 << 40), "{}", allocated);
+        }
+    }
+
+    // x = float(p0); for (acc = x, i = 0; acc <= float(p1); i += 1) acc = acc * x + acc
+    #[test]
+    fn can_select_float64_ops() {
+        let mut g = Graph::new();
+        let p0 = node(&mut g, Operator::Parameter(0), &[]);
+        let p1 = node(&mut g, Operator::Parameter(1), &[]);
+        let c0 = constant(&mut g, 0);
+        let c1 = constant(&mut g, 1);
+        let x = node(&mut g, Operator::ChangeInt64ToFloat64, &[p0]);
+        let limit = node(&mut g, Operator::ChangeInt64ToFloat64, &[p1]);
+        let merge = node(&mut g, Operator::Merge, &[]);
+        let acc = node(&mut g, Operator::Phi, &[merge, x]);
+        let i = node(&mut g, Operator::Phi, &[merge, c0]);
+        let cmp = node(&mut g, Operator::Float64LessThanOrEqual, &[acc, limit]);
+        let branch = node(&mut g, Operator::Branch, &[cmp]);
+        let mul = node(&mut g, Operator::Float64Mul, &[acc, x]);
+        let acc2 = node(&mut g, Operator::Float64Add, &[mul, acc]);
+        let i2 = node(&mut g, Operator::Int64Add, &[i, c1]);
+        g.add_input(acc, acc2);
+        g.add_input(i, i2);
+        let ret = node(&mut g, Operator::Return, &[i]);
+
+        let mut s = Schedule::new();
+        let entry = s.add_block(vec![p0, p1, c0, c1, x, limit]);
+        let header = s.add_block(vec![acc, i, cmp, branch]);
+        let body = s.add_block(vec![mul, acc2, i2]);
+        let exit = s.add_block(vec![ret]);
+        s.add_edge(entry, header);
+        s.add_edge(header, body);
+        s.add_edge(header, exit);
+        s.add_edge(body, header);
+
+        let (f, _) = select(&g, &s).unwrap();
+        assert_eq!(f.verify(), Ok(()));
+        // The Phi of acc takes the class of x.
+        let ucomisd = &f.blocks[header].instrs[0];
+        assert_eq!(ucomisd.opcode, OpCode::Ucomisd, "{}", ucomisd);
+        assert!(ucomisd.reg_operands().iter().all(|&(_, r, _)| r.class() == RegClass::Xmm),
+                "{}", ucomisd);
+        assert_eq!(f.blocks[header].instrs[1], Instr::jcc(Cond::Ae, Label::Local(body as u32)));
+        // 2, 6, 18, 54 and then 162.
+        assert_eq!(emulate(&f, &[2, 100]), Ok(4));
+        assert_eq!(emulate(&f, &[3, 2]), Ok(0));
+        assert_eq!(emulate(&f, &[-1, -2]), Ok(0));
+    }
+
+    #[test]
+    fn can_reject_float64_returns() {
+        let mut g = Graph::new();
+        let p0 = node(&mut g, Operator::Parameter(0), &[]);
+        let x = node(&mut g, Operator::ChangeInt64ToFloat64, &[p0]);
+        let s = leaf(&mut g, vec![p0, x]);
+        let ret = s.blocks[0].nodes[2];
+        assert_eq!(select(&g, &s).unwrap_err(), format!("{:?} returns a Float64", ret));
+    }
+
+    #[test]
+    fn can_swap_compares_with_imm_lhs() {
+        // 10 < p0 ? 1 : 2
+        for &(p0, expected) in &[(11, 1), (10, 2)] {
+            let mut g = Graph::new();
+            let p = node(&mut g, Operator::Parameter(0), &[]);
+            let c10 = constant(&mut g, 10);
+            let cmp = node(&mut g, Operator::Int64LessThan, &[c10, p]);
+            let branch = node(&mut g, Operator::Branch, &[cmp]);
+            let c1 = constant(&mut g, 1);
+            let c2 = constant(&mut g, 2);
+            let ret1 = node(&mut g, Operator::Return, &[c1]);
+            let ret2 = node(&mut g, Operator::Return, &[c2]);
+            let mut s = Schedule::new();
+            let entry = s.add_block(vec![p, c10, cmp, branch]);
+            let if_true = s.add_block(vec![c1, ret1]);
+            let if_false = s.add_block(vec![c2, ret2]);
+            s.add_edge(entry, if_true);
+            s.add_edge(entry, if_false);
+
+            let (f, _) = select(&g, &s).unwrap();
+            assert_eq!(f.blocks[0].instrs[1], Instr::cmp(v(0), Operand::Imm(10)));
+            assert_eq!(f.blocks[0].instrs[2], Instr::jcc(Cond::G, Label::Local(1)));
+            assert_eq!(emulate(&f, &[p0]), Ok(expected));
+        }
+    }
+
+    // Allocated and framed, so the whole pipeline runs.
+    fn compile(f: &Function, hints: &Hints) -> Function {
+        let mut allocated = lsra::allocate_function(f.clone(), 4, hints).unwrap();
+        frame::insert_frame(&mut allocated);
+        gap_resolver::resolve_function(&mut allocated, &[R11.into_reg()]);
+        Assembler::new().encode_function(&allocated);
+        allocated
+    }
+
+    #[test]
+    fn can_branch_on_compares_from_other_blocks() {
+        // c = p0 < p1; (then in the next block) c ? 1 : 2
+        let mut g = Graph::new();
+        let p0 = node(&mut g, Operator::Parameter(0), &[]);
+        let p1 = node(&mut g, Operator::Parameter(1), &[]);
+        let cmp = node(&mut g, Operator::Int64LessThan, &[p0, p1]);
+        let branch = node(&mut g, Operator::Branch, &[cmp]);
+        let c1 = constant(&mut g, 1);
+        let c2 = constant(&mut g, 2);
+        let ret1 = node(&mut g, Operator::Return, &[c1]);
+        let ret2 = node(&mut g, Operator::Return, &[c2]);
+        let mut s = Schedule::new();
+        let entry = s.add_block(vec![p0, p1, cmp]);
+        let test = s.add_block(vec![branch]);
+        let if_true = s.add_block(vec![c1, ret1]);
+        let if_false = s.add_block(vec![c2, ret2]);
+        s.add_edge(entry, test);
+        s.add_edge(test, if_true);
+        s.add_edge(test, if_false);
+
+        let (f, hints) = select(&g, &s).unwrap();
+        assert_eq!(f.verify(), Ok(()));
+        let expected = vec![
+            Instr::mov(v(0), r(RDI)),
+            Instr::mov(v(1), r(RSI)),
+            Instr::mov(v(2), Operand::Imm(0)),
+            Instr::cmp(v(0), v(1)),
+            Instr::setcc(Cond::L, v(2)),
+            Instr::jmp(Label::Local(test as u32)),
+        ];
+        test_utils::assert_eq_pretty("isel-setcc", &f.blocks[entry].instrs, &expected);
+        assert_eq!(f.blocks[test].instrs[0], Instr::cmp(v(2), Operand::Imm(0)));
+        let compiled = compile(&f, &hints);
+        for &(a, b, expected) in &[(1, 2, 1), (2, 2, 2), (-5, -6, 2)] {
+            assert_eq!(emulate(&f, &[a, b]), Ok(expected));
+            assert_eq!(emulate(&compiled, &[a, b]), Ok(expected), "{}", compiled);
+        }
+    }
+
+    #[test]
+    fn can_return_compares() {
+        // p0 <= 5
+        let mut g = Graph::new();
+        let p0 = node(&mut g, Operator::Parameter(0), &[]);
+        let c5 = constant(&mut g, 5);
+        let cmp = node(&mut g, Operator::Int64LessThanOrEqual, &[p0, c5]);
+        let s = leaf(&mut g, vec![p0, c5, cmp]);
+        let (f, hints) = select(&g, &s).unwrap();
+        assert_eq!(f.blocks[0].instrs[2], Instr::cmp(v(0), Operand::Imm(5)));
+        assert_eq!(f.blocks[0].instrs[3], Instr::setcc(Cond::Le, v(1)));
+        let compiled = compile(&f, &hints);
+        for &(p0, expected) in &[(5, 1), (6, 0), (i64::MIN, 1)] {
+            assert_eq!(emulate(&f, &[p0]), Ok(expected));
+            assert_eq!(emulate(&compiled, &[p0]), Ok(expected), "{}", compiled);
+        }
+
+        // float(p0) < float(p1)
+        let mut g = Graph::new();
+        let p0 = node(&mut g, Operator::Parameter(0), &[]);
+        let p1 = node(&mut g, Operator::Parameter(1), &[]);
+        let x = node(&mut g, Operator::ChangeInt64ToFloat64, &[p0]);
+        let y = node(&mut g, Operator::ChangeInt64ToFloat64, &[p1]);
+        let cmp = node(&mut g, Operator::Float64LessThan, &[x, y]);
+        let s = leaf(&mut g, vec![p0, p1, x, y, cmp]);
+        let (f, hints) = select(&g, &s).unwrap();
+        assert!(f.blocks[0].instrs.iter().any(|i| i.opcode == OpCode::Setcc(Cond::A)), "{}", f);
+        let compiled = compile(&f, &hints);
+        for &(a, b, expected) in &[(1, 2, 1), (2, 2, 0), (3, -3, 0)] {
+            assert_eq!(emulate(&f, &[a, b]), Ok(expected));
+            assert_eq!(emulate(&compiled, &[a, b]), Ok(expected), "{}", compiled);
+        }
+    }
+
+    #[test]
+    fn can_select_loops_with_phis() {
+        // sum = 0; for (i = 10; i != 0; i += -1) sum += i
+        let mut g = Graph::new();
+        let c0 = constant(&mut g, 0);
+        let c10 = constant(&mut g, 10);
+        let c_minus1 = constant(&mut g, -1);
+        let merge = node(&mut g, Operator::Merge, &[]);
+        let i = node(&mut g, Operator::Phi, &[merge, c10]);
+        let sum = node(&mut g, Operator::Phi, &[merge, c0]);
+        let branch = node(&mut g, Operator::Branch, &[i]);
+        let sum2 = node(&mut g, Operator::Int64Add, &[sum, i]);
+        let i2 = node(&mut g, Operator::Int64Add, &[i, c_minus1]);
+        g.add_input(i, i2);
+        g.add_input(sum, sum2);
+        let ret = node(&mut g, Operator::Return, &[sum]);
+
+        let mut s = Schedule::new();
+        let entry = s.add_block(vec![c0, c10, c_minus1]);
+        let header = s.add_block(vec![i, sum, branch]);
+        let body = s.add_block(vec![sum2, i2]);
+        let exit = s.add_block(vec![ret]);
+        s.add_edge(entry, header);
+        s.add_edge(header, body);
+        s.add_edge(header, exit);
+        s.add_edge(body, header);
+
+        let (f, _) = select(&g, &s).unwrap();
+        assert_eq!(f.verify(), Ok(()));
+        // No critical edges here, so the Phi moves are on the back edge and the
+        // loop entry.
+        assert_eq!(f.blocks.len(), 4);
+        let back_edge = f.blocks[body].instrs.last().unwrap();
+        assert_eq!(back_edge.parallel_moves.start().len(), 2, "{}", back_edge);
+        assert_eq!(emulate(&f, &[]), Ok(55));
+    }
+
+    #[test]
+    fn can_split_critical_edges_for_phis() {
+        // x = c ? 5 : 7
+        for &(c, expected) in &[(1, 5), (0, 7)] {
+            let mut g = Graph::new();
+            let cond = constant(&mut g, c);
+            let c5 = constant(&mut g, 5);
+            let c7 = constant(&mut g, 7);
+            let branch = node(&mut g, Operator::Branch, &[cond]);
+            let merge = node(&mut g, Operator::Merge, &[]);
+            let x = node(&mut g, Operator::Phi, &[merge, c5, c7]);
+            let ret = node(&mut g, Operator::Return, &[x]);
+
+            let mut s = Schedule::new();
+            let entry = s.add_block(vec![cond, c5, branch]);
+            let join = s.add_block(vec![x, ret]);
+            let if_false = s.add_block(vec![c7]);
+            s.add_edge(entry, join);
+            s.add_edge(entry, if_false);
+            s.add_edge(if_false, join);
+
+            let (f, _) = select(&g, &s).unwrap();
+            assert_eq!(f.verify(), Ok(()));
+            assert_eq!(f.blocks.len(), 4);
+            assert_eq!(f.blocks[0].instrs[3], Instr::jcc(Cond::Ne, Label::Local(3)));
+            assert_eq!(emulate(&f, &[]), Ok(expected));
+        }
+    }
+
+    #[test]
+    fn can_reject_bad_schedules() {
+        let (g, mut s) = straight_line();
+        s.blocks[0].nodes.swap(4, 5);
+        assert!(s.verify(&g).unwrap_err().contains("is not at the end of B0"));
+        let (g, mut s) = straight_line();
+        let b = s.add_block(vec![]);
+        s.add_edge(0, b);
+        assert_eq!(s.verify(&g), Err("B0 should have 0 succs, not 1".to_owned()));
+    }
+}
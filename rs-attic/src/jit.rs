@@ -0,0 +1,224 @@
+use std::collections::HashMap;
+use std::{io, mem, ptr};
+
+use ::x64::*;
+use ::x64::encode::{Assembler, Code};
+use ::x64::decode;
+
+// Encoded code mapped into this process. The mapping is writable only while
+// the code is being copied in, and executable only after that (W^X).
+pub struct JitCode {
+    ptr: *mut u8,
+    // Of the mapping, which can be longer than the code.
+    len: usize,
+    code_len: usize,
+    labels: HashMap<Label, usize>,
+}
+
+pub type JitFn0 = extern "sysv64" fn() -> i64;
+pub type JitFn1 = extern "sysv64" fn(i64) -> i64;
+pub type JitFn2 = extern "sysv64" fn(i64, i64) -> i64;
+
+mod sys {
+    pub const PROT_READ: i32 = 1;
+    pub const PROT_WRITE: i32 = 2;
+    pub const PROT_EXEC: i32 = 4;
+    pub const MAP_PRIVATE: i32 = 2;
+    pub const MAP_ANONYMOUS: i32 = 0x20;
+
+    extern "C" {
+        pub fn mmap(addr: *mut u8, len: usize, prot: i32, flags: i32,
+                    fd: i32, offset: i64) -> *mut u8;
+        pub fn mprotect(addr: *mut u8, len: usize, prot: i32) -> i32;
+        pub fn munmap(addr: *mut u8, len: usize) -> i32;
+    }
+}
+
+impl JitCode {
+    // The entry of f is at offset 0.
+    pub fn compile(f: &Function) -> Result<Self, String> {
+        let mut asm = Assembler::new();
+        asm.encode_function(f);
+        Self::new(asm.finish())
+    }
+
+    // All the labels must be bound: there's no linker to resolve the relocs.
+    pub fn new(code: Code) -> Result<Self, String> {
+        if let Some(reloc) = code.relocs.first() {
+            return Err(format!("Unresolved label {}", reloc.label));
+        }
+        // mmap doesn't take an empty length.
+        let len = code.bytes.len().max(1);
+        unsafe {
+            let ptr = sys::mmap(ptr::null_mut(), len, sys::PROT_READ | sys::PROT_WRITE,
+                                sys::MAP_PRIVATE | sys::MAP_ANONYMOUS, -1, 0);
+            // MAP_FAILED
+            if ptr as isize == -1 {
+                return Err(format!("mmap: {}", io::Error::last_os_error()));
+            }
+            // From now on the mapping is released by drop.
+            let jit = JitCode { ptr, len, code_len: code.bytes.len(), labels: code.labels };
+            ptr::copy_nonoverlapping(code.bytes.as_ptr(), ptr, code.bytes.len());
+            if sys::mprotect(ptr, len, sys::PROT_READ | sys::PROT_EXEC) != 0 {
+                return Err(format!("mprotect: {}", io::Error::last_os_error()));
+            }
+            Ok(jit)
+        }
+    }
+
+    pub fn entry_addr(&self) -> *const u8 {
+        self.ptr
+    }
+
+    pub fn label_addr(&self, label: &Label) -> Option<*const u8> {
+        self.labels.get(label).map(|&offset| unsafe { self.ptr.add(offset) as *const u8 })
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        unsafe { ::std::slice::from_raw_parts(self.ptr, self.code_len) }
+    }
+
+    pub fn disassemble(&self) -> Result<String, String> {
+        decode::disassemble(self.bytes(), &self.labels, &[])
+    }
+
+    /// # Safety
+    /// F must be an `extern "sysv64" fn` type matching the code, and must not
+    /// be called after self is dropped.
+    pub unsafe fn entry<F: Copy>(&self) -> F {
+        debug_assert!(mem::size_of::<F>() == mem::size_of::<*const u8>());
+        mem::transmute_copy(&self.ptr)
+    }
+}
+
+impl Drop for JitCode {
+    fn drop(&mut self) {
+        let res = unsafe { sys::munmap(self.ptr, self.len) };
+        debug_assert!(res == 0, "munmap: {}", io::Error::last_os_error());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use super::*;
+    use ::frame;
+    use ::legalize;
+
+    fn r(m: MachReg) -> Operand {
+        m.into_reg().into_op()
+    }
+
+    fn label(ix: u32) -> Label {
+        Label::Local(ix)
+    }
+
+    // Legalizes f and gives it a frame, like a real compilation would.
+    fn compile(mut f: Function) -> JitCode {
+        legalize::legalize_imms(&mut f);
+        frame::insert_frame(&mut f);
+        assert_eq!(f.verify(), Ok(()));
+        JitCode::compile(&f).unwrap()
+    }
+
+    // The permissions of the mapping that contains addr, e.g. "r-xp".
+    fn mapping_perms(addr: *const u8) -> String {
+        let addr = addr as usize;
+        let maps = fs::read_to_string("/proc/self/maps").unwrap();
+        for line in maps.lines() {
+            let mut words = line.split_whitespace();
+            let range = words.next().unwrap();
+            let perms = words.next().unwrap();
+            let mut bounds = range.split('-')
+                .map(|x| usize::from_str_radix(x, 16).unwrap());
+            let (start, end) = (bounds.next().unwrap(), bounds.next().unwrap());
+            if start <= addr && addr < end {
+                return perms.to_owned();
+            }
+        }
+        panic!("{:x} is not mapped", addr)
+    }
+
+    #[test]
+    fn can_run_leaf_function() {
+        let jit = compile(Function::new(vec![
+            Block::new(label(0), vec![
+                Instr::mov(r(RAX), r(RDI)),
+                Instr::imul(r(RAX), r(RSI)),
+                Instr::mov(r(RCX), Operand::Imm(1 << 40)),
+                Instr::add(r(RAX), r(RCX)),
+                Instr::ret(r(RAX)),
+            ]),
+        ]));
+        assert_eq!(mapping_perms(jit.entry_addr()), "r-xp");
+        let f: JitFn2 = unsafe { jit.entry() };
+        assert_eq!(f(6, 7), 42 + (1 << 40));
+        assert_eq!(f(-3, 5), -15 + (1 << 40));
+    }
+
+    #[test]
+    fn can_run_loops_with_callee_saved_regs_and_stack_slots() {
+        // sum = 0; for (i = n; i > 0; i--) sum += i
+        let slot = Mem::new(Reg::rsp(), 8).into_op();
+        let jit = compile(Function::new(vec![
+            Block::new(label(0), vec![
+                Instr::mov(r(RBX), r(RDI)),
+                Instr::mov(slot.clone(), Operand::Imm(0)),
+                Instr::jmp(label(1)),
+            ]),
+            Block::new(label(1), vec![
+                Instr::cmp(r(RBX), Operand::Imm(0)),
+                Instr::jcc(Cond::Le, label(2)),
+                Instr::jmp(label(3)),
+            ]),
+            Block::new(label(3), vec![
+                Instr::mov(r(R12), slot.clone()),
+                Instr::add(r(R12), r(RBX)),
+                Instr::mov(slot.clone(), r(R12)),
+                Instr::sub(r(RBX), Operand::Imm(1)),
+                Instr::jmp(label(1)),
+            ]),
+            Block::new(label(2), vec![
+                Instr::mov(r(RAX), slot.clone()),
+                Instr::ret(r(RAX)),
+            ]),
+        ]));
+        let f: JitFn1 = unsafe { jit.entry() };
+        assert_eq!(f(0), 0);
+        assert_eq!(f(100), 5050);
+        assert!(jit.label_addr(&label(3)).is_some());
+        let listing = jit.disassemble().unwrap();
+        assert!(listing.starts_with(".L0:\n     0:  55 "), "{}", listing);
+        assert!(listing.contains("jle .L2\n"), "{}", listing);
+    }
+
+    extern "sysv64" fn weighted_sum(a: i64, b: i64, c: i64, d: i64,
+                                    e: i64, f: i64, g: i64, h: i64) -> i64 {
+        a + 2 * b + 3 * c + 4 * d + 5 * e + 6 * f + 7 * g + 8 * h
+    }
+
+    #[test]
+    fn can_call_back_into_rust() {
+        let callee = weighted_sum as extern "sysv64" fn(i64, i64, i64, i64,
+                                                        i64, i64, i64, i64) -> i64;
+        let mut instrs = vec![Instr::movabs(r(R11), callee as usize as Imm)];
+        let args = (1..9).map(Operand::Imm).collect::<Vec<_>>();
+        instrs.extend(frame::lower_call(r(R11), &args, None));
+        instrs.push(Instr::ret(r(RAX)));
+        let jit = compile(Function::new(vec![Block::new(label(0), instrs)]));
+        let f: JitFn0 = unsafe { jit.entry() };
+        assert_eq!(f(), weighted_sum(1, 2, 3, 4, 5, 6, 7, 8));
+    }
+
+    #[test]
+    fn can_reject_unresolved_labels() {
+        let f = Function::new(vec![
+            Block::new(label(0), vec![
+                Instr::call(Label::Named("ext".to_owned()).into_op(), 0),
+                Instr::ret(r(RAX)),
+            ]),
+        ]);
+        assert_eq!(JitCode::compile(&f).err(), Some("Unresolved label ext".to_owned()));
+    }
+}
@@ -0,0 +1,333 @@
+use std::mem;
+
+use ::x64::*;
+use ::lsra::Hints;
+
+// Makes every immediate fit in the form of its instruction, according to
+// OperandDesc::imm:
+// - Sign-extended imm32s (or imm8s for shifts) stay inline.
+// - `mov r, imm64` becomes `movabs r, imm64`.
+// - Anything else is first materialized into a fresh VirtualReg, which is
+//   not used anywhere else in f.
+pub fn legalize_imms(f: &mut Function) {
+    let mut next_virt_ix = f.next_virt_ix();
+    for b in &mut f.blocks {
+        legalize_block_imms(b, &mut next_virt_ix);
+    }
+}
+
+fn legalize_block_imms(b: &mut Block, next_virt_ix: &mut u32) {
+    let mut instrs = vec![];
+    for mut instr in b.instrs.drain(..) {
+        let desc = instr.desc();
+        for (ix, op_desc) in desc.operands.iter().enumerate() {
+            let i = match instr.ops[ix] {
+                Operand::Imm(i) => i,
+                _ => continue,
+            };
+            if op_desc.accepts_imm(i) {
+                continue;
+            }
+            if instr.opcode == OpCode::Mov && instr.ops[0].is_reg() {
+                // The same operands, and the ParallelMoves stay.
+                instr.opcode = OpCode::MovAbs;
+                continue;
+            }
+            let tmp = Reg::new_virt(*next_virt_ix).into_op();
+            *next_virt_ix += 1;
+            instrs.push(materialize(tmp.clone(), i));
+            instr.ops[ix] = tmp;
+        }
+        instrs.push(instr);
+    }
+    b.instrs = instrs;
+}
+
+fn materialize(dst: Operand, i: Imm) -> Instr {
+    if ImmWidth::Imm32.fits(i) {
+        Instr::mov(dst, Operand::Imm(i))
+    } else {
+        Instr::movabs(dst, i)
+    }
+}
+
+// Rewrites every three-address `op d, a, b` (see Instr::new3) into the
+// two-address form of x64: `mov d, a; op d, b`. Returns hints that tie d to a,
+// so that the allocator can give them the same register and turn the mov into
+// a self-move. Fresh VirtualRegs are not used anywhere else in f.
+pub fn legalize_two_address(f: &mut Function) -> Hints {
+    let mut hints = Hints::new();
+    let mut next_virt_ix = next_virt_ix_of(f);
+    for b in &mut f.blocks {
+        legalize_block_two_address(b, &mut hints, &mut next_virt_ix);
+    }
+    hints
+}
+
+fn legalize_block_two_address(b: &mut Block, hints: &mut Hints, next_virt_ix: &mut u32) {
+    let old = mem::take(&mut b.instrs);
+    for (ix, instr) in old.iter().enumerate() {
+        if !instr.is_three_address() {
+            b.instrs.push(instr.clone());
+            continue;
+        }
+        let opcode = instr.opcode;
+        let (d, mut x, mut y) = (instr.ops[0].clone(), instr.ops[1].clone(), instr.ops[2].clone());
+        let dr = match d {
+            Operand::Reg(r) => r,
+            _ => panic!("Not a register dst: {:?}", instr),
+        };
+        // Either there's no copy at all, or the copy can be coalesced.
+        let swap = x != d &&
+            (y == d || (!dies_after(&old, ix, &x) && dies_after(&old, ix, &y)));
+        if opcode.is_commutative() && swap {
+            mem::swap(&mut x, &mut y);
+        }
+        if x == d {
+            b.instrs.push(Instr::new2(opcode, d, y));
+        } else if y.mentions(dr) {
+            // The copy would clobber y, so compute into a fresh reg instead.
+            let tmp = Reg::new_virt_in(dr.class(), *next_virt_ix);
+            *next_virt_ix += 1;
+            b.instrs.push(Instr::copy(tmp.into_op(), x.clone()));
+            b.instrs.push(Instr::new2(opcode, tmp.into_op(), y));
+            b.instrs.push(Instr::copy(d, tmp.into_op()));
+            tie(hints, tmp, &x);
+            hints.insert(dr, tmp);
+        } else {
+            b.instrs.push(Instr::copy(d, x.clone()));
+            b.instrs.push(Instr::new2(opcode, Operand::Reg(dr), y));
+            tie(hints, dr, &x);
+        }
+    }
+}
+
+fn tie(hints: &mut Hints, r: Reg, to: &Operand) {
+    if let Operand::Reg(to) = *to {
+        hints.insert(r, to);
+    }
+}
+
+// Whether op is a reg that isn't mentioned after instrs[ix]. Only looks at the
+// block: a reg that lives out of it gets a worse hint, but that's all.
+fn dies_after(instrs: &[Instr], ix: usize, op: &Operand) -> bool {
+    match *op {
+        Operand::Reg(r) => !instrs[ix + 1..].iter().any(|i| i.ops.iter().any(|op| op.mentions(r))),
+        _ => false,
+    }
+}
+
+// Like Function::next_virt_ix, which can't deal with the three-address form.
+fn next_virt_ix_of(f: &Function) -> u32 {
+    let mut next = 0;
+    let instrs = f.blocks.iter().flat_map(|b| &b.instrs);
+    let moves = instrs.clone().flat_map(|i| i.parallel_moves.start().iter().chain(i.parallel_moves.end()));
+    let move_ops = moves.flat_map(|m| vec![m.dst(), m.src()]);
+    for op in instrs.flat_map(|i| &i.ops).chain(move_ops) {
+        let regs = match *op {
+            Operand::Reg(r) => vec![r],
+            Operand::Mem(ref m) => m.regs().into_iter().map(|(_, r)| r).collect(),
+            _ => vec![],
+        };
+        for r in regs {
+            if let Reg::Virtual(v) = r {
+                next = next.max(v.0 + 1);
+            }
+        }
+    }
+    next
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ::test_utils;
+
+    fn op_vreg(ix: u32) -> Operand {
+        Operand::new_virt_reg(ix)
+    }
+
+    const BIG: Imm = 1 << 40;
+
+    #[test]
+    fn can_keep_imms_that_fit() {
+        let v0 = op_vreg(0);
+        let instrs = vec![
+            Instr::mov(v0.clone(), Operand::Imm(-1)),
+            Instr::add(v0.clone(), Operand::Imm(i32::MIN as Imm)),
+            Instr::cmp(v0.clone(), Operand::Imm(i32::MAX as Imm)),
+            Instr::shl(v0.clone(), Operand::Imm(63)),
+        ];
+        let mut f = Function::new(vec![Block::new(Label::Local(0), instrs.clone())]);
+        legalize_imms(&mut f);
+        test_utils::assert_eq_pretty("legalize-imms-fit", &f.blocks[0].instrs, &instrs);
+    }
+
+    #[test]
+    fn can_materialize_wide_imms() {
+        let v0 = op_vreg(0);
+        let v1 = op_vreg(1);
+        let v2 = op_vreg(2);
+        let v3 = op_vreg(3);
+        let slot = Mem::new(Reg::rsp(), 8).into_op();
+        let mut f = Function::new(vec![Block::new(Label::Local(0), vec![
+            Instr::mov(v0.clone(), Operand::Imm(BIG)),
+            Instr::add(v0.clone(), Operand::Imm(-BIG)),
+            Instr::mov(slot.clone(), Operand::Imm(BIG)),
+            Instr::shl(v0.clone(), Operand::Imm(200)),
+        ])]);
+        legalize_imms(&mut f);
+
+        let expected = vec![
+            Instr::movabs(v0.clone(), BIG),
+            Instr::movabs(v1.clone(), -BIG),
+            Instr::add(v0.clone(), v1.clone()),
+            Instr::movabs(v2.clone(), BIG),
+            Instr::mov(slot.clone(), v2.clone()),
+            Instr::mov(v3.clone(), Operand::Imm(200)),
+            Instr::shl(v0.clone(), v3.clone()),
+        ];
+        test_utils::assert_eq_pretty("legalize-imms-wide", &f.blocks[0].instrs, &expected);
+    }
+
+    #[test]
+    fn can_materialize_into_vregs_unused_by_other_blocks() {
+        let v0 = op_vreg(0);
+        let v1 = op_vreg(1);
+        let v2 = op_vreg(2);
+        let v3 = op_vreg(3);
+        let mut f = Function::new(vec![
+            Block::new(Label::Local(0), vec![
+                Instr::add(v0.clone(), Operand::Imm(BIG)),
+                Instr::jmp(Label::Local(1)),
+            ]),
+            Block::new(Label::Local(1), vec![
+                Instr::add(v0.clone(), v1.clone()),
+                Instr::ret(v0.clone()),
+            ]),
+        ]);
+        let mut phi = Instr::add(v1.clone(), Operand::Imm(BIG));
+        phi.parallel_moves.add_to_start(ParallelMove::new(v2.clone(), v0.clone()));
+        f.blocks[1].instrs.insert(0, phi);
+        legalize_imms(&mut f);
+
+        // v1 and v2 are only used in the second block.
+        assert_eq!(f.blocks[0].instrs[0], Instr::movabs(v3.clone(), BIG));
+        assert_eq!(f.blocks[1].instrs[0], Instr::movabs(op_vreg(4), BIG));
+    }
+
+    #[test]
+    fn can_keep_parallel_moves_of_movabs() {
+        let v0 = op_vreg(0);
+        let v1 = op_vreg(1);
+        let mut instr = Instr::mov(v0.clone(), Operand::Imm(BIG));
+        instr.parallel_moves.add_to_start(ParallelMove::new(v1.clone(), v0.clone()));
+        instr.parallel_moves.add_to_end(ParallelMove::new(v0.clone(), v1.clone()));
+        let mut f = Function::new(vec![Block::new(Label::Local(0), vec![
+            instr.clone(),
+            Instr::ret(v0.clone()),
+        ])]);
+        legalize_imms(&mut f);
+
+        let mut expected = Instr::movabs(v0.clone(), BIG);
+        expected.parallel_moves = instr.parallel_moves;
+        assert_eq!(f.blocks[0].instrs[0], expected);
+    }
+
+    #[test]
+    fn can_legalize_two_address() {
+        let v = op_vreg;
+        let vr = Reg::new_virt;
+        let mut f = Function::new(vec![Block::new(Label::Local(0), vec![
+            // v1 dies but v0 doesn't, so the copy is from v1.
+            Instr::new3(OpCode::Add, v(2), v(0), v(1)),
+            Instr::new3(OpCode::Sub, v(3), v(2), v(0)),
+            Instr::new3(OpCode::Add, v(3), v(3), Operand::Imm(1)),
+            Instr::new3(OpCode::IMul, v(3), v(0), v(3)),
+            // Would clobber v3 with a copy.
+            Instr::new3(OpCode::Sub, v(3), v(0), v(3)),
+            Instr::new3(OpCode::Add, v(4), Operand::Imm(5), v(3)),
+            Instr::ret(v(4)),
+        ])]);
+        let hints = legalize_two_address(&mut f);
+
+        let expected = vec![
+            Instr::mov(v(2), v(1)),
+            Instr::add(v(2), v(0)),
+            Instr::mov(v(3), v(2)),
+            Instr::sub(v(3), v(0)),
+            Instr::add(v(3), Operand::Imm(1)),
+            Instr::imul(v(3), v(0)),
+            Instr::mov(v(5), v(0)),
+            Instr::sub(v(5), v(3)),
+            Instr::mov(v(3), v(5)),
+            Instr::mov(v(4), v(3)),
+            Instr::add(v(4), Operand::Imm(5)),
+            Instr::ret(v(4)),
+        ];
+        test_utils::assert_eq_pretty("legalize-two-address", &f.blocks[0].instrs, &expected);
+        let expected_hints = [(2, 1), (3, 5), (5, 0), (4, 3)].iter()
+            .map(|&(d, a)| (vr(d), vr(a)))
+            .collect::<Hints>();
+        assert_eq!(hints, expected_hints);
+        assert_eq!(f.verify(), Ok(()));
+    }
+
+    #[test]
+    fn can_take_fresh_vregs_unused_by_other_blocks() {
+        let v = op_vreg;
+        let mut f = Function::new(vec![
+            Block::new(Label::Local(0), vec![
+                Instr::mov(v(0), Operand::Imm(1)),
+                Instr::mov(v(1), Operand::Imm(2)),
+                // Would clobber v1 with a copy.
+                Instr::new3(OpCode::Sub, v(1), v(0), v(1)),
+                Instr::jmp(Label::Local(1)),
+            ]),
+            Block::new(Label::Local(1), vec![
+                Instr::new3(OpCode::Add, v(2), v(1), v(1)),
+                Instr::ret(v(2)),
+            ]),
+        ]);
+        legalize_two_address(&mut f);
+
+        let expected = vec![
+            Instr::mov(v(0), Operand::Imm(1)),
+            Instr::mov(v(1), Operand::Imm(2)),
+            Instr::mov(v(3), v(0)),
+            Instr::sub(v(3), v(1)),
+            Instr::mov(v(1), v(3)),
+            Instr::jmp(Label::Local(1)),
+        ];
+        test_utils::assert_eq_pretty("legalize-two-address-fresh", &f.blocks[0].instrs, &expected);
+        assert_eq!(f.verify(), Ok(()));
+    }
+
+    #[test]
+    fn can_copy_xmms_with_movsd() {
+        let x = |ix| Reg::new_virt_in(RegClass::Xmm, ix).into_op();
+        let mut f = Function::new(vec![Block::new(Label::Local(0), vec![
+            Instr::new3(OpCode::Addsd, x(2), x(0), x(1)),
+            Instr::new3(OpCode::Mulsd, x(2), x(2), x(1)),
+        ])]);
+        legalize_two_address(&mut f);
+
+        let expected = vec![
+            Instr::movsd(x(2), x(0)),
+            Instr::addsd(x(2), x(1)),
+            Instr::mulsd(x(2), x(1)),
+        ];
+        test_utils::assert_eq_pretty("legalize-two-address-xmm", &f.blocks[0].instrs, &expected);
+    }
+
+    #[test]
+    fn can_report_imm_widths() {
+        let src = |op: OpCode| op.desc().operands[1].imm;
+        assert_eq!(src(OpCode::Add), Some(ImmWidth::Imm32));
+        assert_eq!(src(OpCode::Shl), Some(ImmWidth::Imm8));
+        assert_eq!(src(OpCode::MovAbs), Some(ImmWidth::Imm64));
+        assert_eq!(OpCode::IDiv.desc().operands[0].imm, None);
+        assert_eq!(ImmWidth::of(-129), ImmWidth::Imm32);
+        assert_eq!(ImmWidth::of(1 << 31), ImmWidth::Imm64);
+    }
+}
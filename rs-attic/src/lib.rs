@@ -1,8 +1,18 @@
 #![feature(conservative_impl_trait)]
 
 pub mod graph;
+pub mod schedule;
+pub mod isel;
 pub mod x64;
 pub mod lsra;
+pub mod linearize;
+pub mod legalize;
+pub mod gap_resolver;
+pub mod frame;
+pub mod peephole;
+pub mod elf;
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+pub mod jit;
 mod utils;
 
 #[cfg(test)]
@@ -0,0 +1,377 @@
+use std::collections::{HashMap, HashSet};
+
+use ::x64::*;
+
+// The natural loops of a Function, found from the back edges of a DFS from
+// the entry. Loops that share a header are one loop.
+#[derive(Debug, Clone)]
+pub struct LoopInfo {
+    // Of each block, the number of loops around it. A header is in its own
+    // loop.
+    pub depth: Vec<u32>,
+    // The header of the innermost loop around each block.
+    pub innermost: Vec<Option<usize>>,
+    bodies: HashMap<usize, HashSet<usize>>,
+}
+
+impl LoopInfo {
+    pub fn compute(f: &Function) -> Self {
+        let succs = f.successors();
+        let preds = f.predecessors();
+        let mut latches: HashMap<usize, Vec<usize>> = HashMap::new();
+        let mut reachable = vec![false; f.blocks.len()];
+        let mut on_stack = vec![false; f.blocks.len()];
+        // (block, next successor to visit)
+        let mut stack = vec![(0, 0)];
+        reachable[0] = true;
+        on_stack[0] = true;
+        while let Some(&mut (ix, ref mut next)) = stack.last_mut() {
+            if let Some(&succ) = succs[ix].get(*next) {
+                *next += 1;
+                if on_stack[succ] {
+                    latches.entry(succ).or_default().push(ix);
+                } else if !reachable[succ] {
+                    reachable[succ] = true;
+                    on_stack[succ] = true;
+                    stack.push((succ, 0));
+                }
+            } else {
+                on_stack[ix] = false;
+                stack.pop();
+            }
+        }
+
+        // Everything that reaches a latch without going through the header.
+        let mut bodies = HashMap::new();
+        for (header, latches) in latches {
+            let mut body = HashSet::new();
+            body.insert(header);
+            let mut work = latches;
+            while let Some(ix) = work.pop() {
+                if reachable[ix] && body.insert(ix) {
+                    work.extend(preds[ix].iter().cloned());
+                }
+            }
+            bodies.insert(header, body);
+        }
+
+        let mut depth = vec![0; f.blocks.len()];
+        let mut innermost: Vec<Option<usize>> = vec![None; f.blocks.len()];
+        for (&header, body) in &bodies {
+            for &ix in body {
+                depth[ix] += 1;
+                let is_inner = innermost[ix].is_none_or(|h| bodies[&h].len() > body.len());
+                if is_inner {
+                    innermost[ix] = Some(header);
+                }
+            }
+        }
+        LoopInfo { depth, innermost, bodies }
+    }
+
+    pub fn is_header(&self, ix: usize) -> bool {
+        self.bodies.contains_key(&ix)
+    }
+
+    pub fn contains(&self, header: usize, ix: usize) -> bool {
+        self.bodies[&header].contains(&ix)
+    }
+}
+
+// The dominator tree of a Function, by Cooper, Harvey and Kennedy's "A
+// Simple, Fast Dominance Algorithm".
+#[derive(Debug, Clone)]
+pub struct DomTree {
+    // The immediate dominator of each block. None for the entry and the
+    // unreachable blocks.
+    pub idom: Vec<Option<usize>>,
+}
+
+impl DomTree {
+    pub fn compute(f: &Function) -> Self {
+        let preds = f.predecessors();
+        let rpo = f.compute_rpo();
+        let mut rpo_ixs = vec![usize::MAX; f.blocks.len()];
+        for (i, &ix) in rpo.iter().enumerate() {
+            rpo_ixs[ix] = i;
+        }
+        // The entry is its own idom until the end, to stop the walks up.
+        let mut idom = vec![None; f.blocks.len()];
+        idom[0] = Some(0);
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &ix in &rpo[1..] {
+                let mut new_idom = None;
+                for &p in preds[ix].iter().filter(|&&p| idom[p].is_some()) {
+                    new_idom = Some(match new_idom {
+                        None => p,
+                        Some(mut q) => {
+                            let mut p = p;
+                            while p != q {
+                                while rpo_ixs[p] > rpo_ixs[q] {
+                                    p = idom[p].unwrap();
+                                }
+                                while rpo_ixs[q] > rpo_ixs[p] {
+                                    q = idom[q].unwrap();
+                                }
+                            }
+                            p
+                        }
+                    });
+                }
+                if idom[ix] != new_idom {
+                    idom[ix] = new_idom;
+                    changed = true;
+                }
+            }
+        }
+        idom[0] = None;
+        DomTree { idom }
+    }
+
+    // A block dominates itself.
+    pub fn dominates(&self, a: usize, mut b: usize) -> bool {
+        loop {
+            if a == b {
+                return true;
+            }
+            match self.idom[b] {
+                Some(up) => b = up,
+                None => return false,
+            }
+        }
+    }
+
+    // The closest block that dominates both, for reachable a and b.
+    pub fn common_dominator(&self, mut a: usize, b: usize) -> usize {
+        while !self.dominates(a, b) {
+            a = self.idom[a].expect("Unreachable block");
+        }
+        a
+    }
+}
+
+// The order to lay the blocks of f out in, and to number their instructions
+// by for the register allocator. This is a reverse postorder where every
+// loop is placed as a whole, so that a value live across a loop doesn't also
+// cover unrelated blocks, and then the cold blocks are moved to the end. The
+// entry stays first, and the unreachable blocks come last.
+pub fn linear_order(f: &Function) -> Vec<usize> {
+    let loops = LoopInfo::compute(f);
+    let rpo = f.compute_rpo();
+    let mut placed = vec![false; f.blocks.len()];
+    let mut order = vec![];
+    place_region(None, &rpo, &loops, &mut placed, &mut order);
+    let cold = find_cold_blocks(f, &rpo);
+    let (mut hot, cold): (Vec<_>, Vec<_>) = order.into_iter().partition(|&ix| !cold[ix]);
+    hot.extend(cold);
+    hot.extend((0..f.blocks.len()).filter(|&ix| !placed[ix]));
+    hot
+}
+
+// The blocks of the loop of header (or of the whole function) in RPO, with
+// each loop inside placed in full where its header is.
+fn place_region(header: Option<usize>, rpo: &[usize], loops: &LoopInfo,
+                placed: &mut Vec<bool>, order: &mut Vec<usize>) {
+    for &ix in rpo {
+        if placed[ix] || header.is_some_and(|h| !loops.contains(h, ix)) {
+            continue;
+        }
+        if Some(ix) != header && loops.is_header(ix) {
+            place_region(Some(ix), rpo, loops, placed, order);
+        } else {
+            placed[ix] = true;
+            order.push(ix);
+        }
+    }
+}
+
+// Block::is_cold, and the blocks that can only be reached through them.
+fn find_cold_blocks(f: &Function, rpo: &[usize]) -> Vec<bool> {
+    let preds = f.predecessors();
+    // Starts from all cold, so that a loop that is only entered from a cold
+    // block is cold as a whole.
+    let mut cold = vec![true; f.blocks.len()];
+    cold[0] = false;
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for &ix in &rpo[1..] {
+            let is_cold = f.blocks[ix].is_cold || preds[ix].iter().all(|&p| cold[p]);
+            if cold[ix] != is_cold {
+                cold[ix] = is_cold;
+                changed = true;
+            }
+        }
+    }
+    cold
+}
+
+const LOOP_TRIP_COUNT: u32 = 10;
+
+// How often each block runs for every time the function is called, guessed
+// as LOOP_TRIP_COUNT for every loop around it. The cold blocks are taken to
+// never run.
+pub fn estimate_frequencies(f: &Function) -> Vec<u32> {
+    let loops = LoopInfo::compute(f);
+    let cold = find_cold_blocks(f, &f.compute_rpo());
+    (0..f.blocks.len())
+        .map(|ix| if cold[ix] { 0 } else { LOOP_TRIP_COUNT.saturating_pow(loops.depth[ix]) })
+        .collect()
+}
+
+// Gives every block that falls through a jmp to where it used to fall into,
+// so that the blocks can be moved around. A jmp to the next block is only left
+// out when the code is emitted.
+pub fn make_jumps_explicit(f: &mut Function) {
+    for ix in 0..f.blocks.len() {
+        if f.blocks[ix].falls_through() {
+            let next = f.blocks[ix + 1].label.clone();
+            f.blocks[ix].instrs.push(Instr::jmp(next));
+        }
+    }
+}
+
+// Reorders the blocks of f by linear_order, after make_jumps_explicit.
+pub fn linearize(f: &mut Function) {
+    let order = linear_order(f);
+    debug_assert_eq!(order[0], 0, "The entry moved");
+    make_jumps_explicit(f);
+    let mut blocks = f.blocks.drain(..).map(Some).collect::<Vec<_>>();
+    f.blocks = order.into_iter().map(|ix| blocks[ix].take().unwrap()).collect();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ::x64::emu::Emulator;
+
+    fn r(m: MachReg) -> Operand {
+        m.into_reg().into_op()
+    }
+
+    fn label(ix: u32) -> Label {
+        Label::Local(ix)
+    }
+
+    fn ret() -> Vec<Instr> {
+        vec![Instr::mov(r(RAX), r(RDI)), Instr::ret(r(RAX))]
+    }
+
+    // Block ix counts rdi up and branches to the first target when it
+    // reaches limit.
+    fn branch(ix: u32, limit: i64, if_true: u32, if_false: u32) -> Block {
+        Block::new(label(ix), vec![
+            Instr::add(r(RDI), Operand::Imm(1)),
+            Instr::cmp(r(RDI), Operand::Imm(limit)),
+            Instr::jcc(Cond::Ge, label(if_true)),
+            Instr::jmp(label(if_false)),
+        ])
+    }
+
+    fn jump(ix: u32, to: u32) -> Block {
+        Block::new(label(ix), vec![Instr::add(r(RDI), Operand::Imm(1)), Instr::jmp(label(to))])
+    }
+
+    fn labels(f: &Function) -> Vec<Label> {
+        f.blocks.iter().map(|b| b.label.clone()).collect()
+    }
+
+    // The DFS goes to the back edge before the break, so a plain RPO puts the
+    // break in the middle of the loop.
+    fn loop_with_break() -> Function {
+        Function::new(vec![
+            jump(0, 1),
+            branch(1, 100, 5, 2),
+            branch(2, 50, 4, 3),
+            Block::new(label(3), ret()),
+            jump(4, 1),
+            Block::new(label(5), ret()),
+        ])
+    }
+
+    #[test]
+    fn can_find_loops() {
+        let f = Function::new(vec![
+            jump(0, 1),
+            // An outer loop of 1, 2, 3, 4 with an inner loop of 2, 3.
+            branch(1, 100, 5, 2),
+            jump(2, 3),
+            branch(3, 10, 4, 2),
+            jump(4, 1),
+            Block::new(label(5), ret()),
+        ]);
+        let loops = LoopInfo::compute(&f);
+        assert_eq!(loops.depth, vec![0, 1, 2, 2, 1, 0]);
+        assert_eq!(loops.innermost, vec![None, Some(1), Some(2), Some(2), Some(1), None]);
+        assert!(loops.is_header(1) && loops.is_header(2) && !loops.is_header(3));
+    }
+
+    #[test]
+    fn can_find_dominators() {
+        let mut f = loop_with_break();
+        // Unreachable.
+        f.blocks.push(jump(6, 3));
+        let doms = DomTree::compute(&f);
+        assert_eq!(doms.idom, vec![None, Some(0), Some(1), Some(2), Some(2), Some(1), None]);
+        assert!(doms.dominates(1, 4) && doms.dominates(4, 4) && !doms.dominates(4, 1));
+        assert!(!doms.dominates(0, 6));
+        assert_eq!(doms.common_dominator(3, 5), 1);
+        assert_eq!(doms.common_dominator(4, 3), 2);
+        assert_eq!(doms.common_dominator(0, 3), 0);
+    }
+
+    #[test]
+    fn can_estimate_frequencies() {
+        let mut f = loop_with_break();
+        assert_eq!(estimate_frequencies(&f), vec![1, 10, 10, 1, 10, 1]);
+        f.blocks[2].is_cold = true;
+        // The back edge only comes from the cold block.
+        assert_eq!(estimate_frequencies(&f), vec![1, 10, 0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn can_keep_loops_contiguous() {
+        let f = loop_with_break();
+        assert_eq!(f.compute_rpo(), vec![0, 1, 2, 3, 4, 5]);
+        assert_eq!(linear_order(&f), vec![0, 1, 2, 4, 3, 5]);
+    }
+
+    #[test]
+    fn can_move_cold_blocks_out_of_line() {
+        let mut f = loop_with_break();
+        // The break, then whatever it jumps to.
+        f.blocks[3] = jump(3, 6);
+        f.blocks[3].is_cold = true;
+        f.blocks.push(Block::new(label(6), ret()));
+        assert_eq!(linear_order(&f), vec![0, 1, 2, 4, 5, 3, 6]);
+
+        // Also a loop that is only entered from a cold block.
+        f.blocks[6] = branch(6, 60, 7, 6);
+        f.blocks.push(Block::new(label(7), ret()));
+        f.blocks[0] = branch(0, 0, 3, 1);
+        assert_eq!(linear_order(&f), vec![0, 1, 2, 4, 5, 3, 6, 7]);
+        // But not one that is also entered from a hot block.
+        f.blocks[0] = branch(0, 0, 6, 1);
+        assert_eq!(linear_order(&f), vec![0, 1, 2, 4, 5, 6, 7, 3]);
+    }
+
+    #[test]
+    fn can_linearize_with_the_same_behavior() {
+        let mut f = loop_with_break();
+        // Unreachable, and falls through into the next block.
+        f.blocks.insert(4, Block::new(label(6), vec![Instr::add(r(RDI), Operand::Imm(7))]));
+        let mut linear = f.clone();
+        linearize(&mut linear);
+        assert_eq!(linear.verify(), Ok(()));
+        assert_eq!(labels(&linear), vec![label(0), label(1), label(2), label(4), label(3),
+                                         label(5), label(6)]);
+        assert_eq!(linear.blocks[6].instrs.last(), Some(&Instr::jmp(label(4))));
+        for &n in &[0, 40, 60, 200] {
+            let expected = Emulator::new().call(&f, &[n]);
+            assert!(expected.is_ok(), "{:?}", expected);
+            assert_eq!(Emulator::new().call(&linear, &[n]), expected);
+        }
+    }
+}
@@ -1,12 +1,15 @@
 #![allow(dead_code)]
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::cmp::Ordering;
+use std::mem;
 
 use ::x64::*;
+use ::frame;
+use ::gap_resolver;
+use ::linearize;
 use ::utils;
 
-// Hints are attached here.
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
 struct LifetimePosition {
     ix: u32,
@@ -20,6 +23,8 @@ struct UsePosition {
     // | In v8 this is a pointer to the operand for this pos. In Rust however
     // we need to use indices rather than raw pointers.
     ctx: RegContext,
+    // The other side of a copy, whose register this use would like to share.
+    hint: Option<Reg>,
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
@@ -37,33 +42,72 @@ struct LiveRange {
     is_splinter: bool,
     spill_at: Option<LifetimePosition>,
     reload_at: Option<LifetimePosition>,
+    // Would like the register of this reg, e.g. to turn a mov into a self-move.
+    hint: Option<Reg>,
+    // The only def of the reg, when it can be done again instead of a reload
+    // (see find_remat_defs). Such a range is never spilled.
+    remat: Option<Instr>,
+    // The value is stored to the slot only once, where
+    // CommitSpillingPhase::place_spills put the spill_at of one of the
+    // pieces, so the others and the edges into the slot don't store it again.
+    stored_once: bool,
 }
 
+// Maps a reg to the reg whose register it would like to share.
+pub type Hints = HashMap<Reg, Reg>;
+
+// The RegClasses are allocated independently: the active and the inactive
+// ranges are kept per class, indexed by RegClass::ix.
 struct LinearScan {
     data: RegAllocData,
     unhandled_ranges: IxVec,
-    active_ranges: IxVec,
-    inactive_ranges: IxVec,
+    active_ranges: Vec<IxVec>,
+    inactive_ranges: Vec<IxVec>,
 }
 
 struct RegAllocData {
-    block: Block,
+    f: Function,
+    numbering: InstrNumbering,
     liveness: LiveRangeVec,
+    // Of each RegClass, taken from the start of RegClass::allocatable.
     num_regs_available: usize,
+    // Of each block, see linearize::estimate_frequencies.
+    frequencies: Vec<u32>,
+    // Of each reg, the hull of its live range and how much of that it is
+    // live for, from before anything was split.
+    lifetimes: HashMap<Reg, (UseInterval, usize)>,
 }
 
 struct CommitRegAssignmentPhase {
     data: RegAllocData,
 }
 
+// Gives the spilled regs whose lifetimes don't overlap the same slot.
 struct SpillSlotAllocator {
-    next_slot: usize,
-    spill_slots: HashMap<u32, usize>,
+    // Of each slot, where it becomes free again.
+    free_from: Vec<LifetimePosition>,
+    spill_slots: HashMap<Reg, usize>,
 }
 
+// Of each (block_ix, instr_ix), the defs that are done again there: the first
+// ones before its start moves, and the others after them.
+type Remats = HashMap<(usize, usize), (Vec<Instr>, Vec<Instr>)>;
+
 struct CommitSpillingPhase {
     alloc: SpillSlotAllocator,
     data: RegAllocData,
+    remats: Remats,
+}
+
+// Moves the values live across each control flow edge from where they are at
+// the end of the predecessor to where they are at the start of the successor,
+// as a split range can be in a different location on the two sides.
+struct ResolveControlFlowPhase {
+    alloc: SpillSlotAllocator,
+    data: RegAllocData,
+    remats: Remats,
+    // Of each block, as computed before the regs were assigned.
+    live_in: Vec<RegSet>,
 }
 
 type IxVec = Vec<usize>;
@@ -165,18 +209,6 @@ impl UseInterval {
         self.start <= pos && pos < self.end
     }
 
-    fn contains_pos_inclusive(&self, pos: LifetimePosition) -> bool {
-        self.start <= pos && pos <= self.end
-    }
-
-    fn split_at(&mut self, before: LifetimePosition, after: LifetimePosition) -> Self {
-        debug_assert!(self.contains_pos(before));
-        debug_assert!(self.contains_pos_inclusive(after));
-        let r = Self::new(after, self.end);
-        self.end = before;
-        r
-    }
-
     fn first_intersection(&self, it: &UseInterval) -> Option<LifetimePosition> {
         if it.start < self.start {
             it.first_intersection(self)
@@ -190,7 +222,11 @@ impl UseInterval {
 
 impl UsePosition {
     fn new(pos: LifetimePosition, ctx: RegContext) -> Self {
-        Self { pos, ctx }
+        Self { pos, ctx, hint: None }
+    }
+
+    fn with_hint(self, hint: Option<Reg>) -> Self {
+        Self { hint, ..self }
     }
 
     fn reg(&self) -> Reg {
@@ -204,6 +240,44 @@ impl UsePosition {
     fn is_output(&self) -> bool {
         !self.is_input()
     }
+
+    fn is_move(&self) -> bool {
+        matches!(self.ctx.operand_ix, RegLocInInstr::StartMove(..))
+    }
+
+    // Where to spill the value once this is its last use with the register,
+    // and until when the register has to be kept for that. A src is spilled
+    // along with the start moves, a move dst right after them, and the output
+    // of an instruction in its end moves.
+    fn spill_after(&self) -> (LifetimePosition, LifetimePosition) {
+        let ix = self.pos.instr_ix();
+        if self.is_input() {
+            let reg_end = if self.is_move() {
+                LifetimePosition::new_gap_end(ix)
+            } else {
+                LifetimePosition::new_instr_start(ix)
+            };
+            (LifetimePosition::new_gap_start(ix), reg_end)
+        } else if self.is_move() {
+            (LifetimePosition::new_gap_end(ix), LifetimePosition::new_instr_start(ix))
+        } else {
+            (LifetimePosition::new_instr_end(ix), LifetimePosition::new_gap_start(ix + 1))
+        }
+    }
+
+    // Where to reload the value for this use, after it was spilled. For a src
+    // of the start moves that's right before them, and otherwise along with
+    // them.
+    fn reload_before(&self) -> Option<LifetimePosition> {
+        let ix = self.pos.instr_ix();
+        if self.is_output() {
+            None
+        } else if self.is_move() {
+            Some(LifetimePosition::new_gap_start(ix))
+        } else {
+            Some(LifetimePosition::new_gap_end(ix))
+        }
+    }
 }
 
 
@@ -225,6 +299,9 @@ impl LiveRange {
             is_splinter: false,
             spill_at: None,
             reload_at: None,
+            hint: None,
+            remat: None,
+            stored_once: false,
         }
     }
 
@@ -241,6 +318,11 @@ impl LiveRange {
         self.assigned.unwrap()
     }
 
+    // The number of positions it covers.
+    fn live_length(&self) -> usize {
+        self.intervals.iter().map(|it| it.end.computed_ix() - it.start.computed_ix()).sum()
+    }
+
     fn contains_pos(&self, pos: LifetimePosition) -> bool {
         self.intervals.iter().find(|it| it.contains_pos(pos)).is_some()
     }
@@ -254,76 +336,72 @@ impl LiveRange {
         self.poses.push(pos);
     }
 
-    fn split_poses_at(&mut self, splinter_start: LifetimePosition) -> Vec<UsePosition> {
-        let next_ix = self.poses.iter()
-            .position(|u| {
-                if u.is_input() {
-                    // pos is an output position so it can never have the same position
-                    // as another input position.
-                    debug_assert!(u.pos != splinter_start);
-                }
-                u.pos >= splinter_start
-            })
-            .unwrap();
-        debug_assert!(self.poses[next_ix - 1].pos < splinter_start);
-        self.poses.split_off(next_ix)
-    }
-
-    // before must be included in self.intervals after the split,
-    // after must be included in splinters after the split.
-    fn split_intervals(&mut self,
-                       before: LifetimePosition, after: LifetimePosition,
-                       needs_spill_reload: bool) -> Vec<UseInterval> {
-        let before_ix = self.intervals.iter()
-            .position(|it| it.contains_pos_inclusive(before))
-            .unwrap();
-        let next_ix = before_ix + 1;
-        if needs_spill_reload {
-            // `after` must be an input. `before` and `after` must be in the same interval.
-            debug_assert!(self.intervals[before_ix].end != before);
-            debug_assert!(self.intervals[before_ix].contains_pos_inclusive(after));
-            let splinter = self.intervals[before_ix].split_at(before, after);
-            let mut splinters = self.intervals.split_off(next_ix);
-            splinters.insert(0, splinter);
-            splinters
-        } else {
-            // `after` must be an output. `before` and `after` must be in two
-            // consecutive intervals.
-            debug_assert!(self.intervals[before_ix].end == before);
-            debug_assert!(self.intervals[next_ix].start == after);
-            self.intervals.split_off(next_ix)
-        }
-    }
-
-    fn split_at(&mut self, splinter_start: LifetimePosition, fresh_range_ix: usize) -> Self {
+    // Splits off the uses from pos on into a splinter, which has no register
+    // yet, or None if there are no such uses. The uses that would keep the
+    // register past pos go too. The range keeps the register until pos, or
+    // until right after its last use if that's later, and is then spilled if
+    // the value is still live. The splinter reloads it right before its first
+    // use, unless that's a def. In between, the value is in its spill slot.
+    fn split_at(&mut self, pos: LifetimePosition, fresh_range_ix: usize) -> Option<Self> {
+        debug_assert!(!self.is_fixed(), "Splitting a fixed range {:?}", self);
         self.debug_check_interior_sorted();
-        // | Necessarily true?
-        // debug_assert!(pos.is_instr_start());
-        // Find the pos before and after ix.
-
-        let splinter_poses = self.split_poses_at(splinter_start);
-        // Eager spill, right after the last use before pos.
-        let before = self.last_pos().pos;
-        // Lazy reload, just before the first use after pos.
-        let after = splinter_poses[0].pos;
-
-        // For * - split - def, we don't need to do spill and reload.
-        let needs_spill_reload = !splinter_poses[0].is_output();
-        let splinter_intervals = self.split_intervals(before, after,
-                                                      needs_spill_reload);
-        self.split_to = Some(fresh_range_ix);
-        self.spill_at = utils::some_if(needs_spill_reload, || before.gap_after());
+        let next_ix = self.split_use_ix(pos);
+        assert!(next_ix > 0, "Nothing to keep when splitting {:?} at {:?}", self, pos);
+        let splinter_poses = self.poses.split_off(next_ix);
+        let (mut spill_at, mut reg_end) = self.last_pos().spill_after();
+        if pos > reg_end {
+            // Only ever at a gap, and then nothing happens at the end of the
+            // instr before.
+            debug_assert!(pos.is_gap_start(), "Splitting {:?} at {:?}", self, pos);
+            spill_at = LifetimePosition::new_instr_end(pos.instr_ix() - 1);
+            reg_end = pos;
+        }
+        let is_live = self.intervals.iter().any(|it| it.start < reg_end && reg_end < it.end);
+        let is_clean = self.reload_at.is_some() && self.poses.iter().all(|u| u.is_input());
+        let needs_spill = is_live && !is_clean &&
+            splinter_poses.first().is_none_or(|u| u.is_input());
+        // What was spilled at the end of the range now goes to the splinter.
+        let old_spill_at = mem::replace(&mut self.spill_at,
+                                        utils::some_if(needs_spill, || spill_at));
+
+        let intervals = mem::take(&mut self.intervals);
+        self.intervals = intervals.iter()
+            .filter(|it| it.start < reg_end)
+            .map(|it| UseInterval::new(it.start, it.end.min(reg_end)))
+            .collect();
+        let first = splinter_poses.first()?;
+        let reload_at = first.reload_before();
+        let start = reload_at.unwrap_or(first.pos);
+        // Likewise a splinter that starts with a reload and only reads.
+        let spill_at = if reload_at.is_some() && splinter_poses.iter().all(|u| u.is_input()) {
+            None
+        } else {
+            old_spill_at
+        };
         let res = Self {
-            intervals: splinter_intervals,
+            intervals: intervals.iter()
+                .filter(|it| it.end > start)
+                .map(|it| UseInterval::new(it.start.max(start), it.end))
+                .collect(),
             poses: splinter_poses,
             assigned: None,
             is_splinter: true,
-            split_to: None,
-            spill_at: None,
-            reload_at: utils::some_if(needs_spill_reload, || after.gap_before()),
+            split_to: self.split_to.replace(fresh_range_ix),
+            spill_at,
+            reload_at,
+            hint: None,
+            remat: self.remat.clone(),
+            stored_once: self.stored_once,
         };
-        println!("split({:#?}) -> {:#?} + {:#?}", splinter_start, self, res);
-        res
+        Some(res)
+    }
+
+    // The first of the uses that split_at(pos) gives to the splinter. An
+    // input right at pos has already had the register.
+    fn split_use_ix(&self, pos: LifetimePosition) -> usize {
+        self.poses.iter()
+            .position(|u| u.spill_after().1 > pos)
+            .unwrap_or(self.poses.len())
     }
 
     fn debug_check_interior_sorted(&self) {
@@ -341,6 +419,18 @@ impl LiveRange {
         self.poses.sort();
     }
 
+    // After sorting. The intervals of neighbouring blocks touch.
+    fn merge_adjacent_intervals(&mut self) {
+        let mut merged: Vec<UseInterval> = vec![];
+        for it in self.intervals.drain(..) {
+            match merged.last_mut() {
+                Some(last) if it.start <= last.end => last.end = last.end.max(it.end),
+                _ => merged.push(it),
+            }
+        }
+        self.intervals = merged;
+    }
+
     fn cmp_by_first_start(&self, other: &LiveRange) -> Ordering {
         let r = self.first_interval().start.cmp(&other.first_interval().start);
         if r == Ordering::Equal {
@@ -365,6 +455,12 @@ impl LiveRange {
             .find(|p| &before < p)
     }
 
+    fn first_use_from(&self, start: LifetimePosition) -> Option<LifetimePosition> {
+        self.poses.iter()
+            .map(|p| p.pos)
+            .find(|p| &start <= p)
+    }
+
     fn last_interval(&self) -> &UseInterval {
         self.intervals.last().unwrap()
     }
@@ -381,6 +477,12 @@ impl LiveRange {
             .next()
     }
 
+    // Of a MachReg that appears in the code. It keeps its register, and is
+    // never split or spilled.
+    fn is_fixed(&self) -> bool {
+        self.reg().is_mach()
+    }
+
     fn is_for(&self, r: Reg) -> bool {
         self.reg() == r
     }
@@ -391,94 +493,517 @@ impl LiveRange {
 }
 
 
-fn find_or_create_live_range(ranges: &mut LiveRangeVec, r: Reg) -> &mut LiveRange {
-    let ix;
-    if let Some(ix_) = ranges.iter().position(|rg| rg.is_for(r)) {
-        ix = ix_;
-    } else {
-        ix = ranges.len();
-        ranges.push(LiveRange::new());
-    }
-    &mut ranges[ix]
-}
-
-fn add_interval_and_poses_to_live_range(ranges: &mut LiveRangeVec, reg: Reg,
-                                        interval: UseInterval, poses: Vec<UsePosition>) {
-    // Maybe also add hint.
-    let range = find_or_create_live_range(ranges, reg);
-    range.add_interval(interval);
-    for pos in poses {
-        range.add_pos(pos);
-    }
-}
-
-fn analyze_block_liveness(b: &Block) -> LiveRangeVec {
-    let mut live: HashMap<Reg, Vec<UsePosition>> = HashMap::new();
-    let mut ranges = vec![];
-    // FIXME: use actual value.
-    let block_id = 0;
-
-    // XXX: Hack to make sure that %rax is live at the end of the block.
-    // let last_ix = LifetimePosition::new_instr_end(b.instrs().len() - 1);
-    // live.insert(Reg::new_mach(0), vec![last_ix]);
-
-    for (instr_ix, instr) in b.instrs().iter().enumerate().rev() {
-        // An instruction defines its dst at the end of the ix.
-        let pos_end = LifetimePosition::new_instr_end(instr_ix);
-        for (operand_ix, output) in instr.outputs() {
-            let octx = RegContext::new_output(output, block_id, instr_ix, operand_ix);
-            if let Some(pos_uses) = live.remove(&output) {
-                let interval = UseInterval::new(
-                    pos_end,
-                    pos_uses.iter().last().unwrap().pos);
-                let mut poses = vec![UsePosition::new(pos_end, octx)];
-                poses.extend(pos_uses.into_iter());
-                add_interval_and_poses_to_live_range(&mut ranges, output, interval, poses);
-            } else {
-                // A def that is not used.
-                println!("[WARN] Unused output: {:?} @ {}", output, instr_ix);
+// Numbers the instructions of a Function one after another, in the order of
+// its blocks. This is the LifetimePosition::ix of an instruction.
+#[derive(Debug, Clone)]
+struct InstrNumbering {
+    // The number of the first instruction of each block, and then the number
+    // of instructions.
+    block_starts: Vec<usize>,
+}
+
+impl InstrNumbering {
+    fn new(f: &Function) -> Self {
+        let mut block_starts = vec![0];
+        for b in &f.blocks {
+            let next = block_starts.last().unwrap() + b.instrs.len();
+            block_starts.push(next);
+        }
+        Self { block_starts }
+    }
+
+    fn ix(&self, block_ix: usize, instr_ix: usize) -> usize {
+        self.block_starts[block_ix] + instr_ix
+    }
+
+    // (block_ix, instr_ix) of the numbered instruction.
+    fn locate(&self, ix: usize) -> (usize, usize) {
+        let block_ix = match self.block_starts.binary_search(&ix) {
+            // Skips the empty blocks.
+            Ok(block_ix) => block_ix + self.block_starts[block_ix..].iter()
+                .take_while(|&&start| start == ix).count() - 1,
+            Err(next) => next - 1,
+        };
+        debug_assert!(block_ix + 1 < self.block_starts.len(), "{} is out of range", ix);
+        (block_ix, ix - self.block_starts[block_ix])
+    }
+
+    fn block_start(&self, block_ix: usize) -> LifetimePosition {
+        LifetimePosition::new_gap_start(self.block_starts[block_ix])
+    }
+
+    // Also the start of the next block.
+    fn block_end(&self, block_ix: usize) -> LifetimePosition {
+        LifetimePosition::new_gap_start(self.block_starts[block_ix + 1])
+    }
+
+    fn is_block_start(&self, pos: LifetimePosition) -> bool {
+        pos.is_gap_start() && self.block_starts.binary_search(&pos.instr_ix()).is_ok()
+    }
+}
+
+type RegSet = HashSet<Reg>;
+
+// The regs live at the start of b, given those live at its end. The start
+// moves of an instruction read their srcs before it does anything.
+fn live_in_of(b: &Block, live_out: &RegSet) -> RegSet {
+    let mut live = live_out.clone();
+    for instr in b.instrs.iter().rev() {
+        for (_, r, _) in instr.outputs() {
+            live.remove(&r);
+        }
+        for (_, r, _) in instr.inputs() {
+            live.insert(r);
+        }
+        let (dsts, srcs): (Vec<_>, Vec<_>) = instr.start_move_regs().into_iter()
+            .partition(|(loc, _)| loc.is_move_dst());
+        for (_, r) in dsts {
+            live.remove(&r);
+        }
+        live.extend(srcs.into_iter().map(|(_, r)| r));
+    }
+    live
+}
+
+// Iterates to a fixpoint over the successors, so a reg used in a loop is live
+// around all of it. Returns (live_in, live_out) of each block.
+fn compute_live_sets(f: &Function) -> (Vec<RegSet>, Vec<RegSet>) {
+    let succs = f.successors();
+    let mut live_in = vec![RegSet::new(); f.blocks.len()];
+    let mut live_out = vec![RegSet::new(); f.blocks.len()];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        // Backwards, so that a straight line only takes one round.
+        for block_ix in (0..f.blocks.len()).rev() {
+            let out = succs[block_ix].iter()
+                .flat_map(|&succ| live_in[succ].iter().cloned())
+                .collect::<RegSet>();
+            let in_ = live_in_of(&f.blocks[block_ix], &out);
+            if in_ != live_in[block_ix] || out != live_out[block_ix] {
+                live_in[block_ix] = in_;
+                live_out[block_ix] = out;
+                changed = true;
+            }
+        }
+    }
+    (live_in, live_out)
+}
+
+// Collects the UseIntervals and the UsePositions of each reg, and makes them
+// into LiveRanges at the end.
+#[derive(Default)]
+struct LiveRangeBuilder {
+    ranges: HashMap<Reg, LiveRange>,
+}
+
+impl LiveRangeBuilder {
+    fn add(&mut self, reg: Reg, interval: UseInterval, poses: Vec<UsePosition>) {
+        let range = self.ranges.entry(reg).or_insert_with(LiveRange::new);
+        range.add_interval(interval);
+        for pos in poses {
+            range.add_pos(pos);
+        }
+    }
+
+    fn build(self) -> LiveRangeVec {
+        let mut ranges = self.ranges.into_values()
+            .map(|mut range| {
+                range.sort_interior_by_start();
+                range.merge_adjacent_intervals();
+                range
+            })
+            .collect::<Vec<_>>();
+        // Just for better testability.
+        ranges.sort_by(|x, y| x.cmp_by_first_start(y));
+        ranges
+    }
+}
+
+// The LiveRanges of all the regs of f, over the InstrNumbering of f. A reg
+// that is live across a block boundary gets an interval that covers the
+// whole block it is live through, and the intervals that touch are merged.
+fn analyze_liveness(f: &Function) -> LiveRangeVec {
+    let numbering = InstrNumbering::new(f);
+    let (live_ins, live_outs) = compute_live_sets(f);
+    let mut builder = LiveRangeBuilder::default();
+
+    for (block_ix, b) in f.blocks.iter().enumerate().rev() {
+        // From a reg to the end of its interval in this block, and its uses
+        // in there.
+        let mut live: HashMap<Reg, (LifetimePosition, Vec<UsePosition>)> = live_outs[block_ix]
+            .iter()
+            .map(|&r| (r, (numbering.block_end(block_ix), vec![])))
+            .collect();
+
+        for (instr_ix, instr) in b.instrs().iter().enumerate().rev() {
+            let ix = numbering.ix(block_ix, instr_ix);
+            // An instruction defines its dst at the end of the ix.
+            let pos_end = LifetimePosition::new_instr_end(ix);
+            for (operand_ix, output, desc) in instr.outputs() {
+                let pos_def = def_position(desc.role, ix);
+                let hint = copy_hint(instr, &operand_ix);
+                let octx = RegContext::new_output(output, block_ix, instr_ix, operand_ix)
+                    .with_desc(&desc);
+                let def = UsePosition::new(pos_def, octx).with_hint(hint);
+                if let Some((end, pos_uses)) = live.remove(&output) {
+                    let mut poses = vec![def];
+                    poses.extend(pos_uses);
+                    builder.add(output, UseInterval::new(pos_def, end), poses);
+                } else {
+                    // A def that is not used still needs a register to write
+                    // to, and an implicit def or a clobber still destroys what
+                    // its register holds: e.g. whatever lives across a call.
+                    builder.add(output, UseInterval::new(pos_def, pos_end.gap_after()),
+                                vec![def]);
+                }
+            }
+            // An instruction uses its srcs at the start of the ix.
+            for (operand_ix, input, desc) in instr.inputs() {
+                let hint = copy_hint(instr, &operand_ix);
+                let ictx = RegContext::new_input(input, block_ix, instr_ix, operand_ix)
+                    .with_desc(&desc);
+                let pos = LifetimePosition::new_instr_start(ix);
+                let u = UsePosition::new(pos, ictx).with_hint(hint);
+                add_use(&mut live, input, pos, u);
+            }
+            // The start moves read all their srcs at the start of the gap and
+            // then write their dsts. A src is kept until then, so that a reload
+            // right before the moves can't go to its register.
+            let move_regs = instr.start_move_regs();
+            let pos_move_def = LifetimePosition::new_gap_end(ix);
+            for &(ref loc, r) in &move_regs {
+                if !loc.is_move_dst() {
+                    continue;
+                }
+                let octx = move_context(r, UseKind::Output, block_ix, instr_ix, loc);
+                let def = UsePosition::new(pos_move_def, octx).with_hint(copy_hint(instr, loc));
+                let (end, pos_uses) = live.remove(&r)
+                    .unwrap_or((LifetimePosition::new_instr_start(ix), vec![]));
+                let mut poses = vec![def];
+                poses.extend(pos_uses);
+                builder.add(r, UseInterval::new(pos_move_def, end), poses);
+            }
+            for &(ref loc, r) in &move_regs {
+                if !loc.is_move_dst() {
+                    let ictx = move_context(r, UseKind::Input, block_ix, instr_ix, loc);
+                    let u = UsePosition::new(LifetimePosition::new_gap_start(ix), ictx)
+                        .with_hint(copy_hint(instr, loc));
+                    add_use(&mut live, r, pos_move_def, u);
+                }
             }
         }
-        // An instruction uses its srcs at the start of the ix.
-        for (operand_ix, input) in instr.inputs() {
-            let ictx = RegContext::new_input(input, block_id, instr_ix, operand_ix);
-            let mut pos_start = vec![
-                UsePosition::new(LifetimePosition::new_instr_start(instr_ix), ictx)
-            ];
-            if let Some(pos_next_uses) = live.remove(&input) {
-                // Multiple uses before a def: add them all.
-                pos_start.extend(pos_next_uses);
+
+        debug_assert_eq!(live.keys().cloned().collect::<RegSet>(), live_ins[block_ix],
+                         "Live in of B{}", block_ix);
+        for (r, (end, poses)) in live {
+            builder.add(r, UseInterval::new(numbering.block_start(block_ix), end), poses);
+        }
+    }
+
+    builder.build()
+}
+
+// Where an output of the instruction at ix is written. An early clobber is
+// written before the srcs are read, so it has to overlap with all of them.
+fn def_position(role: OperandRole, ix: usize) -> LifetimePosition {
+    if role == OperandRole::EarlyClobber {
+        LifetimePosition::new_gap_end(ix)
+    } else {
+        LifetimePosition::new_instr_end(ix)
+    }
+}
+
+// Multiple uses before a def: they all go to the same interval, which ends
+// at the end of the last one unless the reg lives on past the block.
+fn add_use(live: &mut HashMap<Reg, (LifetimePosition, Vec<UsePosition>)>,
+           r: Reg, end: LifetimePosition, u: UsePosition) {
+    let &mut (_, ref mut poses) = live.entry(r).or_insert_with(|| (end, vec![]));
+    poses.insert(0, u);
+}
+
+// The reg on the other side of a copy from the operand at loc: of a mov
+// between regs, which is also how legalize_two_address ties an operand and
+// isolate_fixed_operands gets values in and out of their fixed registers, or
+// of a start move, which is how a Phi gets its inputs.
+fn copy_hint(instr: &Instr, loc: &RegLocInInstr) -> Option<Reg> {
+    let other = match *loc {
+        RegLocInInstr::Explicit(ix, RegLocInOp::Reg) if instr.is_copy() => {
+            &instr.ops[1 - ix as usize]
+        }
+        RegLocInInstr::StartMove(ix, side) => {
+            let mov = &instr.parallel_moves.start()[ix as usize];
+            if side == MoveSide::Dst { mov.src() } else { mov.dst() }
+        }
+        _ => return None,
+    };
+    match *other {
+        Operand::Reg(r) => Some(r),
+        _ => None,
+    }
+}
+
+fn move_context(r: Reg, kind: UseKind, block_ix: usize, instr_ix: usize,
+                loc: &RegLocInInstr) -> RegContext {
+    let ctx = RegContext::new(r, kind, block_ix, instr_ix, loc.clone());
+    RegContext { class: r.class(), ..ctx }
+}
+
+// Moves every operand with a fixed register constraint into its MachReg right
+// around the instruction, so that the allocator only needs to deal with
+// MachRegs at the fixed positions.
+fn isolate_fixed_operands(b: &mut Block) {
+    let mut instrs = vec![];
+    for mut instr in b.instrs.drain(..) {
+        let mut after = vec![];
+        for (loc, r, desc) in instr.reg_operands() {
+            let mreg = match desc.fixed {
+                Some(mreg) if mreg.into_reg() != r => mreg.into_reg(),
+                _ => continue,
+            };
+            if desc.role.is_read() {
+                instrs.push(Instr::copy(mreg.into_op(), r.into_op()));
+            }
+            if desc.role.is_written() {
+                after.push(Instr::copy(r.into_op(), mreg.into_op()));
             }
-            live.insert(input, pos_start);
+            instr.set_reg_at(&loc, mreg);
         }
+        instrs.push(instr);
+        instrs.extend(after);
     }
+    b.instrs = instrs;
+}
 
-    for ref mut range in &mut ranges {
-        range.sort_interior_by_start();
+// Allocates the VirtualRegs of a single block to the first
+// num_regs_available MachRegs of each RegClass::allocatable. Fails if an
+// instruction needs more registers at once than that.
+pub fn allocate_block(block: Block, num_regs_available: usize,
+                      hints: &Hints) -> Result<Block, String> {
+    let f = allocate_function(Function::new(vec![block]), num_regs_available, hints)?;
+    Ok(f.blocks.into_iter().next().unwrap())
+}
+
+// The critical edges are split, and the blocks are put in their
+// linear_order, which is kept in the result.
+pub fn allocate_function(f: Function, num_regs_available: usize,
+                         hints: &Hints) -> Result<Function, String> {
+    Ok(allocate_function_with_slots(f, num_regs_available, hints)?.0)
+}
+
+// Also gives the number of stack slots that the result needs, for
+// frame::insert_frame_with_slots. The spill slots are shared by the regs that
+// are never in them at the same time.
+pub fn allocate_function_with_slots(mut f: Function, num_regs_available: usize,
+                                    hints: &Hints) -> Result<(Function, usize), String> {
+    linearize::make_jumps_explicit(&mut f);
+    f.split_critical_edges();
+    linearize::linearize(&mut f);
+    for b in &mut f.blocks {
+        isolate_fixed_operands(b);
+    }
+    let (live_in, _) = compute_live_sets(&f);
+    let mut liveness = analyze_liveness(&f);
+    let remat_defs = find_remat_defs(&f);
+    for range in &mut liveness {
+        range.hint = hints.get(&range.reg()).cloned();
+        range.remat = remat_defs.get(&range.reg()).cloned();
+    }
+    let mut lsra = LinearScan::new(RegAllocData::new(f, liveness, num_regs_available));
+    lsra.run()?;
+    let mut assignment = CommitRegAssignmentPhase::new(lsra.data);
+    assignment.run();
+    let mut spilling = CommitSpillingPhase::new(assignment.data);
+    spilling.run();
+    let mut resolution = ResolveControlFlowPhase::new(spilling, live_in);
+    resolution.run();
+    let mut f = resolution.data.f;
+    insert_remats(&mut f, resolution.remats);
+    for b in &mut f.blocks {
+        remove_self_moves(b);
+    }
+    Ok((f, resolution.alloc.num_slots()))
+}
+
+// The regs with a single def that only depends on constants, so that it can
+// be done again anywhere: a mov of an imm, or a lea of a frame address, as
+// %rsp and %rbp are kept for the frame (see ::frame). The defs are kept
+// without their ParallelMoves.
+fn find_remat_defs(f: &Function) -> HashMap<Reg, Instr> {
+    let mut defs: HashMap<Reg, Option<Instr>> = HashMap::new();
+    for instr in f.blocks.iter().flat_map(|b| &b.instrs) {
+        let def = utils::some_if(is_remat_def(instr), || {
+            let mut def = instr.clone();
+            def.parallel_moves.take();
+            def
+        });
+        let outputs = instr.outputs().into_iter().map(|(_, r, _)| (r, def.clone()));
+        let move_dsts = instr.start_move_regs().into_iter()
+            .filter(|(loc, _)| loc.is_move_dst())
+            .map(|(_, r)| (r, None));
+        for (r, def) in outputs.chain(move_dsts).filter(|&(r, _)| !r.is_mach()) {
+            // Not when there is more than one.
+            defs.entry(r).and_modify(|d| *d = None).or_insert(def);
+        }
     }
+    defs.into_iter().filter_map(|(r, def)| def.map(|d| (r, d))).collect()
+}
+
+fn is_remat_def(instr: &Instr) -> bool {
+    if instr.ops.len() != 2 || !instr.ops[0].is_reg() {
+        return false;
+    }
+    match (instr.opcode, &instr.ops[1]) {
+        (OpCode::Mov, Operand::Imm(_)) | (OpCode::MovAbs, Operand::Imm(_)) => true,
+        (OpCode::Lea, Operand::Mem(m)) => {
+            m.index.is_none() &&
+                m.base_reg().is_some_and(|r| r == Reg::rsp() || r == RBP.into_reg())
+        }
+        _ => false,
+    }
+}
+
+// The def of a rematerialized range, writing to dst instead.
+fn rematerialize(def: &Instr, dst: Operand) -> Instr {
+    let mut instr = def.clone();
+    instr.ops[0] = dst;
+    instr
+}
 
-    // Just for better testability.
-    ranges.sort_by(|x, y| x.cmp_by_first_start(y));
+// Goes last, as it changes the InstrNumbering.
+fn insert_remats(f: &mut Function, mut remats: Remats) {
+    for (block_ix, b) in f.blocks.iter_mut().enumerate() {
+        let mut instrs = vec![];
+        for (instr_ix, mut instr) in b.instrs.drain(..).enumerate() {
+            if let Some((before, mut after)) = remats.remove(&(block_ix, instr_ix)) {
+                instrs.extend(before);
+                if !after.is_empty() {
+                    let (start, end) = instr.parallel_moves.take();
+                    set_parallel_moves(&mut after[0], start, vec![]);
+                    set_parallel_moves(&mut instr, vec![], end);
+                    instrs.extend(after);
+                }
+            }
+            instrs.push(instr);
+        }
+        b.instrs = instrs;
+    }
+}
 
-    ranges
+// Drops the copies whose src and dst got the same register, and such moves in
+// the gaps. The moves around a dropped copy go to the start of the next
+// instruction, which there is as a copy is no jump, unless that's a ret,
+// which can't have any.
+fn remove_self_moves(b: &mut Block) {
+    let is_before_ret = (0..b.instrs.len())
+        .map(|ix| b.instrs.get(ix + 1).is_some_and(|next| next.opcode == OpCode::Ret))
+        .collect::<Vec<_>>();
+    let mut instrs = vec![];
+    let mut pending = vec![];
+    for (ix, mut instr) in b.instrs.drain(..).enumerate() {
+        let (start, end) = instr.parallel_moves.take();
+        let start = gap_resolver::sequence(&pending, &start);
+        if instr.is_copy() && instr.ops[0] == instr.ops[1] {
+            let moves = gap_resolver::sequence(&start, &end);
+            if moves.is_empty() || !is_before_ret[ix] {
+                pending = moves;
+                continue;
+            }
+        }
+        let end = end.into_iter().filter(|m| m.dst() != m.src()).collect();
+        set_parallel_moves(&mut instr, start, end);
+        pending = vec![];
+        instrs.push(instr);
+    }
+    debug_assert!(pending.is_empty(), "Moves after the end of {}", b.label);
+    b.instrs = instrs;
 }
 
 impl RegAllocData {
-    fn new(block: Block, liveness: LiveRangeVec, num_regs_available: usize) -> Self {
+    fn new(f: Function, liveness: LiveRangeVec, num_regs_available: usize) -> Self {
+        let mut lifetimes: HashMap<Reg, (UseInterval, usize)> = HashMap::new();
+        for range in &liveness {
+            let hull = UseInterval::new(range.first_interval().start,
+                                        range.last_interval().end);
+            let length = range.live_length();
+            lifetimes.entry(range.reg())
+                .and_modify(|&mut (ref mut it, ref mut len)| {
+                    *it = UseInterval::new(it.start.min(hull.start), it.end.max(hull.end));
+                    *len += length;
+                })
+                .or_insert((hull, length));
+        }
         Self {
-            block,
+            numbering: InstrNumbering::new(&f),
+            frequencies: linearize::estimate_frequencies(&f),
+            f,
             liveness,
             num_regs_available,
+            lifetimes,
+        }
+    }
+
+    // The registers of the class that the ranges can get.
+    fn allocatable(&self, class: RegClass) -> &'static [MachReg] {
+        let regs = class.allocatable();
+        &regs[..self.num_regs_available.min(regs.len())]
+    }
+
+    // The index of mreg in allocatable, if it's there.
+    fn reg_ix(&self, mreg: MachReg) -> Option<usize> {
+        self.allocatable(mreg.class()).iter().position(|&r| r == mreg)
+    }
+
+    fn instr(&self, ix: usize) -> &Instr {
+        let (block_ix, instr_ix) = self.numbering.locate(ix);
+        &self.f.blocks[block_ix].instrs[instr_ix]
+    }
+
+    fn instr_at(&mut self, ix: usize) -> &mut Instr {
+        let (block_ix, instr_ix) = self.numbering.locate(ix);
+        &mut self.f.blocks[block_ix].instrs[instr_ix]
+    }
+
+    // The range of each reg and what it was split into.
+    fn pieces(&self) -> HashMap<Reg, IxVec> {
+        let mut pieces: HashMap<Reg, IxVec> = HashMap::new();
+        for (ix, range) in self.liveness.iter().enumerate() {
+            pieces.entry(range.reg()).or_default().push(ix);
         }
+        pieces
+    }
+
+    // The piece of a range that covers pos. A piece that is reloaded right at
+    // pos only covers it after the edges into pos.
+    fn piece_at(&self, pieces: &[usize], pos: LifetimePosition) -> Option<usize> {
+        let liveness = &self.liveness;
+        pieces.iter().cloned()
+            .find(|&ix| liveness[ix].contains_pos(pos) && liveness[ix].reload_at != Some(pos))
     }
 }
 
 impl LinearScan {
-    fn new(data: RegAllocData) -> Self {
+    // The fixed ranges already have their registers, and start out inactive
+    // so that they block them from the start. Those of the registers that are
+    // not available can't get in anyone's way.
+    fn new(mut data: RegAllocData) -> Self {
+        let mut unhandled_ranges = vec![];
+        let mut inactive_ranges = vec![vec![]; RegClass::ALL.len()];
+        for ix in 0..data.liveness.len() {
+            match data.liveness[ix].reg() {
+                Reg::Mach(mreg) => {
+                    data.liveness[ix].set_assigned_reg(mreg);
+                    if data.reg_ix(mreg).is_some() {
+                        inactive_ranges[mreg.class().ix()].push(ix);
+                    }
+                }
+                _ => unhandled_ranges.push(ix),
+            }
+        }
         Self {
-            unhandled_ranges: (0..data.liveness.len()).collect(),
-            active_ranges: vec![],
-            inactive_ranges: vec![],
+            unhandled_ranges,
+            active_ranges: vec![vec![]; RegClass::ALL.len()],
+            inactive_ranges,
             data,
         }
     }
@@ -495,118 +1020,246 @@ impl LinearScan {
         sort_unhandled(&self.data.liveness, &mut self.unhandled_ranges);
     }
 
-    fn run(&mut self) {
+    fn run(&mut self) -> Result<(), String> {
         self.sort_unhandled();
 
         while let Some(current_range_ix) = self.unhandled_ranges.pop() {
             self.prepare_current_ix(current_range_ix);
-            self.process_current_ix(current_range_ix);
+            self.process_current_ix(current_range_ix)?;
         }
+        Ok(())
     }
 
     fn prepare_current_ix(&mut self, current_ix: usize) {
         let ref current = self.data.liveness[current_ix];
         let start = current.first_interval().start;
 
+        let class = current.reg().class().ix();
         shuffle_active_inactive(start, true,
-                                &mut self.active_ranges,
-                                &mut self.inactive_ranges,
+                                &mut self.active_ranges[class],
+                                &mut self.inactive_ranges[class],
                                 &self.data.liveness);
 
         shuffle_active_inactive(start, false,
-                                &mut self.inactive_ranges,
-                                &mut self.active_ranges,
+                                &mut self.inactive_ranges[class],
+                                &mut self.active_ranges[class],
                                 &self.data.liveness);
 
         debug_assert!(!current.has_reg_assigned());
     }
 
-    fn process_current_ix(&mut self, current_ix: usize) {
-        // XXX: Missing fixed reg handling.
-        if let Some((mreg, pos)) = self.farthest_free_until_reg(current_ix) {
-            if !self.try_allocate_free_reg(current_ix, mreg, pos) {
-                // Must be partially allocatable.
-                // XXX: also call pos.gap_before()?
+    fn process_current_ix(&mut self, current_ix: usize) -> Result<(), String> {
+        let free_until = self.find_free_until_regs(current_ix);
+        if self.try_allocate_free_reg(current_ix, &free_until).is_some() {
+            return Ok(());
+        }
+        // Splitting before free_until only helps if current still gets the
+        // reg for its first use.
+        let first_reg_end = self.data.liveness[current_ix].first_pos().spill_after().1;
+        match self.farthest_free_until_reg(current_ix, &free_until) {
+            Some((mreg, pos)) if first_reg_end <= pos => {
                 self.allocate_partially_free_reg(current_ix, mreg, pos);
-                println!("alloc_partially_free_reg({:?}, {:#?})", mreg, self.data.liveness[current_ix]);
-            } else {
-                println!("alloc_free_reg({:?}, {:#?})", mreg, self.data.liveness[current_ix]);
             }
-        } else {
-            // All blocked. Spill from an active range.
-            self.allocate_blocked_reg(current_ix);
-            println!("alloc_blocked_reg({:#?})", self.data.liveness[current_ix]);
+            _ => {
+                // All blocked. Spill from an active range.
+                self.allocate_blocked_reg(current_ix)?;
+            }
         }
+        Ok(())
     }
 
-    fn try_allocate_free_reg(&mut self,
-                             current_ix: usize, mreg: MachReg,
-                             free_until: LifetimePosition) -> bool {
-        let ref mut current = self.data.liveness[current_ix];
-        if current.last_interval().end <= free_until {
-            // Allocatable for the whole range.
-            current.set_assigned_reg(mreg);
-            self.active_ranges.push(current_ix);
-            true
-        } else {
-            let partially = current.first_interval().start < free_until;
-            debug_assert!(partially,
-                          "free_until_reg {:?} @ pos {:?} <= current {:?}.",
-                          mreg, free_until, current);
-            false
+    // Allocatable for the whole range: the hinted reg if it is, and otherwise
+    // the one that stays free the longest.
+    fn try_allocate_free_reg(&mut self, current_ix: usize,
+                             free_until: &[Option<LifetimePosition>]) -> Option<MachReg> {
+        let end = self.data.liveness[current_ix].last_interval().end;
+        let is_free = |mreg: MachReg| {
+            self.data.reg_ix(mreg).is_some_and(|ix| free_until[ix].is_some_and(|pos| end <= pos))
+        };
+        let mreg = self.hinted_reg(current_ix)
+            .filter(|&mreg| mreg.class() == self.class_of(current_ix) && is_free(mreg))
+            .or_else(|| self.farthest_free_until_reg(current_ix, free_until)
+                     .map(|(mreg, _)| mreg)
+                     .filter(|&mreg| is_free(mreg)))?;
+        self.data.liveness[current_ix].set_assigned_reg(mreg);
+        self.activate(current_ix);
+        Some(mreg)
+    }
+
+    // The register of the hinted reg, as it was right before the first use
+    // that has a hint, or before current starts for LiveRange::hint.
+    fn hinted_reg(&self, current_ix: usize) -> Option<MachReg> {
+        let current = &self.data.liveness[current_ix];
+        let (hint, pos) = match current.hint {
+            Some(hint) => (hint, current.first_interval().start),
+            None => current.poses.iter().find_map(|u| u.hint.map(|hint| (hint, u.pos)))?,
+        };
+        match hint {
+            Reg::Mach(mreg) => Some(mreg),
+            hint => self.data.liveness.iter()
+                .filter(|range| range.is_for(hint) && range.has_reg_assigned())
+                .filter(|range| range.first_interval().start < pos)
+                .max_by_key(|range| range.first_interval().start)
+                .map(|range| range.assigned_reg()),
         }
     }
 
     fn allocate_partially_free_reg(&mut self,
-                                      current_ix: usize, mreg: MachReg,
-                                      free_until: LifetimePosition) {
-        self.split_and_assign_reg(current_ix, free_until, mreg, None);
-        self.active_ranges.push(current_ix);
-    }
-
-    fn split_and_assign_reg(&mut self,
-                            range_ix_to_split: usize,
-                            splinter_start: LifetimePosition,
-                            reg: MachReg,
-                            assign_to: Option<usize>) {
-        let splinter = {
-            let splinter_ix = self.data.liveness.len();
-            let assign_to = assign_to.unwrap_or(splinter_ix);
-            let r = self.data.liveness[range_ix_to_split]
-                .split_at(splinter_start, splinter_ix);
-            self.data.liveness[assign_to].set_assigned_reg(reg);
-            r
-        };
-        self.add_unhandled_and_sort(splinter);
+                                   current_ix: usize, mreg: MachReg,
+                                   free_until: LifetimePosition) {
+        self.data.liveness[current_ix].set_assigned_reg(mreg);
+        self.split_and_requeue(current_ix, free_until);
+        self.activate(current_ix);
+    }
+
+    // The rest of the range from about pos on gets allocated later. At a
+    // block start, ResolveControlFlowPhase spills it on the edges instead.
+    fn split_and_requeue(&mut self, range_ix: usize, pos: LifetimePosition) {
+        let pos = self.find_optimal_split_pos(range_ix, pos);
+        let splinter_ix = self.data.liveness.len();
+        let range = &mut self.data.liveness[range_ix];
+        let splinter = range.split_at(pos, splinter_ix);
+        if self.data.numbering.is_block_start(pos) {
+            range.spill_at = None;
+        }
+        if let Some(splinter) = splinter {
+            self.add_unhandled_and_sort(splinter);
+        }
     }
 
+    // Wimmer's optimal split position, from right after the last use that is
+    // kept up to pos: in the block that runs the least often, and otherwise
+    // as late as possible. Apart from right after that use, only the starts
+    // of the blocks and the gaps are considered, as that's where the moves go.
+    fn find_optimal_split_pos(&self, range_ix: usize, pos: LifetimePosition) -> LifetimePosition {
+        let range = &self.data.liveness[range_ix];
+        let next_ix = range.split_use_ix(pos);
+        if next_ix == 0 {
+            return pos;
+        }
+        let last_kept = range.poses[next_ix - 1].pos;
+        let earliest = range.poses[next_ix - 1].spill_after().1;
+        let frequency = |pos: LifetimePosition| {
+            self.data.frequencies[self.data.numbering.locate(pos.instr_ix()).0]
+        };
+        let mut best = LifetimePosition::new_gap_start(pos.instr_ix());
+        // The spill would go at the end of a jcc.
+        while !self.data.numbering.is_block_start(best) &&
+            self.data.instr(best.instr_ix() - 1).opcode.is_jump() {
+            best = LifetimePosition::new_gap_start(best.instr_ix() - 1);
+        }
+        if best <= earliest {
+            return earliest;
+        }
+        let mut best_frequency = frequency(best);
+        let mut block_ix = self.data.numbering.locate(best.instr_ix()).0;
+        loop {
+            let block_start = self.data.numbering.block_start(block_ix);
+            if block_start <= earliest {
+                break;
+            }
+            if frequency(block_start) < best_frequency {
+                best = block_start;
+                best_frequency = frequency(block_start);
+            }
+            block_ix -= 1;
+        }
+        if frequency(last_kept) < best_frequency {
+            best = earliest;
+        }
+        debug_assert_eq!(range.split_use_ix(best), next_ix);
+        best
+    }
+
+    // Wimmer's allocateBlockedReg: the reg whose other ranges are used the
+    // farthest away goes to current, and they are split and spilled in the
+    // meantime. If even those uses come before current needs a register,
+    // current is spilled instead until it does. That fails when more values
+    // need a register at the first use of current than there are registers,
+    // e.g. when there are fewer than the operands of an instruction.
+    fn allocate_blocked_reg(&mut self, current_ix: usize) -> Result<(), String> {
+        let class = self.class_of(current_ix);
+        let (use_pos, block_pos) = self.find_next_reg_uses(current_ix);
+        let reg_ix = use_pos.iter().enumerate()
+            // | Find farthest use, preferring smaller reg_ix
+            .max_by_key(|&(reg_ix, p)| (p, -(reg_ix as isize)))
+            .map(|(reg_ix, _)| reg_ix)
+            .unwrap();
+        let mreg = self.data.allocatable(class)[reg_ix];
+        let (start, first_pos) = {
+            let current = &self.data.liveness[current_ix];
+            (current.first_interval().start, current.first_pos().clone())
+        };
 
-    fn allocate_blocked_reg(&mut self, current_ix: usize) {
-        let (mreg, (range_ix, pos)) = self.farthest_next_reg_use(current_ix);
-        let (first_pos, last_pos) = {
-            let ref current = self.data.liveness[current_ix];
-            (current.first_pos().pos, current.last_pos().pos)
+        let too_many_uses = || {
+            let instr = self.data.instr(first_pos.pos.instr_ix());
+            format!("Too many register uses at {:?}: {}", first_pos.pos, instr)
         };
-        if pos < first_pos {
-            panic!("No impl: need to spill pos.");
-        } else if pos < last_pos {
-            // Partially free, split the other range.
-            self.split_and_assign_reg(range_ix, first_pos,
-                                      mreg, Some(current_ix));
-            self.active_ranges.push(current_ix);
-        } else if pos == first_pos {
-            panic!("Too many register uses in one position: {:?}", pos);
-        } else {
-            // Reg is entirely free.
-            self.data.liveness[current_ix].set_assigned_reg(mreg);
-            self.active_ranges.push(current_ix);
+        if use_pos[reg_ix] < first_pos.pos {
+            let reload_at = first_pos.reload_before()
+                .filter(|&reload_at| start < reload_at)
+                .ok_or_else(too_many_uses)?;
+            // Only ever true for a splinter that starts before its first use, so
+            // the value is already in the spill slot.
+            debug_assert!(self.data.liveness[current_ix].reload_at.is_some());
+            let current = &mut self.data.liveness[current_ix];
+            current.intervals = current.intervals.iter()
+                .filter(|it| it.end > reload_at)
+                .map(|it| UseInterval::new(it.start.max(reload_at), it.end))
+                .collect();
+            current.reload_at = Some(reload_at);
+            self.unhandled_ranges.push(current_ix);
+            self.sort_unhandled();
+            return Ok(());
+        }
+        if use_pos[reg_ix] == first_pos.pos {
+            return Err(too_many_uses());
+        }
+
+        self.data.liveness[current_ix].set_assigned_reg(mreg);
+        if block_pos[reg_ix] < self.data.liveness[current_ix].last_interval().end {
+            // Until a fixed range needs the reg.
+            self.split_and_requeue(current_ix, block_pos[reg_ix]);
+        }
+        let holders = self.active_ranges(class).chain(self.inactive_ranges(class))
+            .filter(|&(ix, range)| {
+                ix != current_ix && !range.is_fixed() && range.assigned_reg() == mreg
+            })
+            .map(|(ix, _)| ix)
+            .collect::<Vec<_>>();
+        for ix in holders {
+            let range = &self.data.liveness[ix];
+            if range.first_pos().pos >= start {
+                // It hasn't used the reg yet, so it can go back as a whole.
+                self.data.liveness[ix].assigned = None;
+                self.active_ranges[class.ix()].retain(|&active| active != ix);
+                self.inactive_ranges[class.ix()].retain(|&inactive| inactive != ix);
+                self.unhandled_ranges.push(ix);
+                self.sort_unhandled();
+            } else if range.first_intersection(&self.data.liveness[current_ix]).is_some() ||
+                self.active_ranges[class.ix()].contains(&ix) {
+                self.split_and_requeue(ix, start);
+            }
         }
+        self.activate(current_ix);
+        Ok(())
+    }
+
+    fn activate(&mut self, range_ix: usize) {
+        let class = self.class_of(range_ix);
+        self.active_ranges[class.ix()].push(range_ix);
+    }
+
+    fn class_of(&self, range_ix: usize) -> RegClass {
+        self.data.liveness[range_ix].reg().class()
     }
 
-    fn farthest_free_until_reg(&self,
-                               current_ix: usize) -> Option<(MachReg, LifetimePosition)> {
+    fn farthest_free_until_reg(&self, current_ix: usize,
+                               free_until: &[Option<LifetimePosition>])
+                               -> Option<(MachReg, LifetimePosition)> {
+        let regs = self.data.allocatable(self.class_of(current_ix));
         // NOTE: None is smaller than any Some(_).
-        self.find_free_until_regs(current_ix)
+        free_until
             .iter()
             // | Add reg_ix
             .enumerate()
@@ -614,33 +1267,24 @@ impl LinearScan {
             .max_by_key(|&(reg_ix, p)| (p, -(reg_ix as isize)))
             .iter()
             // | Join nested options
-            .flat_map(|&(ix, mb_p)| mb_p.map(|p| (MachReg::new(ix), p)))
+            .flat_map(|&(ix, mb_p)| mb_p.map(|p| (regs[ix], p)))
             .next()
     }
 
-    fn farthest_next_reg_use(&self,
-                             current_ix: usize) -> (MachReg, (usize, LifetimePosition)) {
-        self.find_next_reg_uses(current_ix)
-            .into_iter().enumerate()
-            // | Find farthest range, preferring smaller reg_ix
-            .max_by_key(|&(reg_ix, (_, p))| (p, -(reg_ix as isize)))
-            .map(|(reg_ix, p)| (MachReg::new(reg_ix), p))
-            .unwrap()
-    }
-
     fn find_free_until_regs(&self, current_ix: usize) -> Vec<Option<LifetimePosition>> {
         let ref current = self.data.liveness[current_ix];
-        let mut free_until = vec![Some(LifetimePosition::max()); self.data.num_regs_available];
+        let class = current.reg().class();
+        let mut free_until = vec![Some(LifetimePosition::max()); self.data.allocatable(class).len()];
 
-        for (_, active_range) in self.active_ranges() {
+        for (_, active_range) in self.active_ranges(class) {
             // All the regs occupied by active ranges are not available.
-            free_until[active_range.assigned_reg().ix()] = None;
+            free_until[self.reg_ix_of(active_range)] = None;
         }
 
-        for (_, inactive_range) in self.inactive_ranges() {
+        for (_, inactive_range) in self.inactive_ranges(class) {
             // Some of the inactive ranges might leave lifetime holes.
             if let Some(sect) = inactive_range.first_intersection(current) {
-                let reg_ix = inactive_range.assigned_reg().ix();
+                let reg_ix = self.reg_ix_of(inactive_range);
                 utils::inplace_min(&mut free_until[reg_ix], Some(sect));
             }
         }
@@ -648,39 +1292,58 @@ impl LinearScan {
         free_until
     }
 
-    fn find_next_reg_uses(&self, current_ix: usize) -> Vec<(usize, LifetimePosition)> {
-        let mut next_use = vec![(usize::max_value(), LifetimePosition::max());
-                                self.data.num_regs_available];
-        let ref current = self.data.liveness[current_ix];
+    // Of each reg, the first use of it by another range from the start of
+    // current on, and where a fixed range blocks it.
+    fn find_next_reg_uses(&self, current_ix: usize) -> (Vec<LifetimePosition>, Vec<LifetimePosition>) {
+        let current = &self.data.liveness[current_ix];
+        let class = current.reg().class();
+        let num_regs = self.data.allocatable(class).len();
+        let mut use_pos = vec![LifetimePosition::max(); num_regs];
+        let mut block_pos = vec![LifetimePosition::max(); num_regs];
+        let start = current.first_interval().start;
 
-        for (range_ix, active_range) in self.active_ranges() {
-            // TODO: Respect fixed / non-spillable ranges.
-            let reg_ix = active_range.assigned_reg().ix();
-            // V8 might spill an active range earlier if its next reg use is not beneficial.
-            if let Some(u) = active_range.first_use_after(current.first_pos().pos) {
-                // FIXME: There might not exist a use after current in active_range,
-                // if the later is a split parent... Or not?
-                utils::inplace_min_by(&mut next_use[reg_ix], (range_ix, u), |x, y| x.1 < y.1);
+        for (_, active_range) in self.active_ranges(class) {
+            let reg_ix = self.reg_ix_of(active_range);
+            if active_range.is_fixed() {
+                // Not even a spill frees it.
+                use_pos[reg_ix] = start;
+                block_pos[reg_ix] = start;
+            } else if let Some(u) = active_range.first_use_from(start) {
+                utils::inplace_min(&mut use_pos[reg_ix], u);
             }
         }
 
-        for (range_ix, inactive_range) in self.inactive_ranges() {
-            // TODO: Respect fixed ranges.
-            let reg_ix = inactive_range.assigned_reg().ix();
-            if let Some(sect) = inactive_range.first_intersection(current) {
-                utils::inplace_min_by(&mut next_use[reg_ix], (range_ix, sect), |x, y| x.1 < y.1);
+        for (_, inactive_range) in self.inactive_ranges(class) {
+            let reg_ix = self.reg_ix_of(inactive_range);
+            let sect = match inactive_range.first_intersection(current) {
+                Some(sect) => sect,
+                None => continue,
+            };
+            if inactive_range.is_fixed() {
+                utils::inplace_min(&mut block_pos[reg_ix], sect);
+                utils::inplace_min(&mut use_pos[reg_ix], sect);
+            } else if let Some(u) = inactive_range.first_use_from(start) {
+                utils::inplace_min(&mut use_pos[reg_ix], u);
             }
         }
 
-        next_use
+        (use_pos, block_pos)
     }
 
-    fn active_ranges<'a>(&'a self) -> impl Iterator<Item=(usize, &LiveRange)> + 'a {
-        self.active_ranges.iter().cloned().map(move |ix| (ix, &self.data.liveness[ix]))
+    // Only the allocatable registers are ever active or inactive.
+    fn reg_ix_of(&self, range: &LiveRange) -> usize {
+        self.data.reg_ix(range.assigned_reg()).unwrap()
     }
 
-    fn inactive_ranges<'a>(&'a self) -> impl Iterator<Item=(usize, &LiveRange)> + 'a {
-        self.inactive_ranges.iter().cloned().map(move |ix| (ix, &self.data.liveness[ix]))
+    fn active_ranges<'a>(&'a self,
+                         class: RegClass) -> impl Iterator<Item=(usize, &'a LiveRange)> + 'a {
+        self.active_ranges[class.ix()].iter().cloned().map(move |ix| (ix, &self.data.liveness[ix]))
+    }
+
+    fn inactive_ranges<'a>(&'a self,
+                           class: RegClass) -> impl Iterator<Item=(usize, &'a LiveRange)> + 'a {
+        self.inactive_ranges[class.ix()].iter().cloned()
+            .map(move |ix| (ix, &self.data.liveness[ix]))
     }
 }
 
@@ -722,7 +1385,8 @@ impl CommitRegAssignmentPhase {
             let mreg = range.assigned.unwrap();
             for pos in &range.poses {
                 let ref ctx = pos.ctx;
-                let instr = &mut self.data.block.instrs[ctx.instr_ix as usize];
+                let b = &mut self.data.f.blocks[ctx.block_id as usize];
+                let instr = &mut b.instrs[ctx.instr_ix as usize];
                 instr.set_reg_at(&ctx.operand_ix, Reg::Mach(mreg));
             }
         }
@@ -730,66 +1394,344 @@ impl CommitRegAssignmentPhase {
 }
 
 impl SpillSlotAllocator {
-    fn new() -> Self {
-        Self {
+    // Spill slots go above the first_slot stack slots that are already in use,
+    // e.g. by outgoing call arguments. The lifetimes are taken in the order
+    // of where they start, and each gets the lowest slot that is free by
+    // then, like a linear scan.
+    fn new(first_slot: usize, mut lifetimes: Vec<(Reg, UseInterval)>) -> Self {
+        lifetimes.sort_by_key(|&(r, ref it)| (it.start, r));
+        let mut alloc = Self {
+            free_from: vec![LifetimePosition::max(); first_slot],
             spill_slots: HashMap::new(),
-            next_slot: 1,
+        };
+        for (r, lifetime) in lifetimes {
+            alloc.assign(r, &lifetime);
         }
+        alloc
     }
 
-    fn slot_for(&mut self, reg: &Reg) -> Operand {
-        let next_slot = &mut self.next_slot;
-        let ix = self.spill_slots.entry(reg.virt_ix()).or_insert_with(|| {
-            let ix = *next_slot;
-            *next_slot += 1;
-            ix
-        });
-        Mem {
-            base: Reg::rsp(),
-            index: None,
-            disp: (*ix << 3) as u32,
-        }.into_op()
+    // A slot is 8 bytes, and a wider spill_slot_size takes aligned ones.
+    fn assign(&mut self, reg: Reg, lifetime: &UseInterval) {
+        let size = reg.class().spill_slot_size() / 8;
+        let mut ix = 0;
+        loop {
+            if self.free_from.len() < ix + size {
+                self.free_from.resize(ix + size, LifetimePosition::new_gap_start(0));
+            }
+            if self.free_from[ix..ix + size].iter().all(|&pos| pos <= lifetime.start) {
+                break;
+            }
+            ix += size;
+        }
+        for pos in &mut self.free_from[ix..ix + size] {
+            *pos = lifetime.end;
+        }
+        self.spill_slots.insert(reg, ix);
+    }
+
+    fn slot_for(&self, reg: &Reg) -> Operand {
+        let ix = self.spill_slots.get(reg)
+            .unwrap_or_else(|| panic!("No spill slot for {:?}", reg));
+        Mem::new(Reg::rsp(), (*ix << 3) as i32).into_op()
+    }
+
+    // Including the ones below the spill slots.
+    fn num_slots(&self) -> usize {
+        self.free_from.len()
     }
 }
 
 impl CommitSpillingPhase {
-    fn new(data: RegAllocData) -> Self {
+    fn new(mut data: RegAllocData) -> Self {
+        Self::place_spills(&mut data);
+        let first_slot = data.f.blocks.iter()
+            .map(|b| frame::stack_slots(&b.instrs))
+            .max()
+            .unwrap_or(0);
         Self {
-            alloc: SpillSlotAllocator::new(),
+            alloc: SpillSlotAllocator::new(first_slot, Self::spilled_lifetimes(&data)),
             data,
+            remats: Remats::new(),
+        }
+    }
+
+    // A reg with a single def, which is the same value wherever it's live, is
+    // stored once for all of its pieces (like SpillAtDefinition in V8):
+    // right after the def, or at the start of a block that runs less often,
+    // where it's in a register and which still dominates every read of the
+    // slot. Those are the reloads, and the edges into a register from where
+    // the range is in the slot. Without any, it isn't stored at all.
+    fn place_spills(data: &mut RegAllocData) {
+        let doms = linearize::DomTree::compute(&data.f);
+        let succs = data.f.successors();
+        let mut pieces = data.pieces().into_iter()
+            .filter(|&(r, ref ixs)| !r.is_mach() && data.liveness[ixs[0]].remat.is_none())
+            .collect::<Vec<_>>();
+        pieces.sort();
+        for (_, ixs) in pieces {
+            let defs = ixs.iter()
+                .flat_map(|&ix| data.liveness[ix].poses.iter().map(move |u| (ix, u)))
+                .filter(|&(_, u)| u.is_output())
+                .collect::<Vec<_>>();
+            if defs.len() != 1 {
+                continue;
+            }
+            let (def_ix, def) = defs[0];
+            let def_block = data.numbering.locate(def.pos.instr_ix()).0;
+
+            let mut reads = ixs.iter()
+                .filter_map(|&ix| data.liveness[ix].reload_at)
+                .map(|pos| data.numbering.locate(pos.instr_ix()).0)
+                .collect::<Vec<_>>();
+            for (pred, pred_succs) in succs.iter().enumerate() {
+                let pred_end = LifetimePosition::new_instr_end(
+                    data.numbering.block_end(pred).instr_ix() - 1);
+                if data.piece_at(&ixs, pred_end).is_none() && pred_succs.iter()
+                    .any(|&succ| data.piece_at(&ixs, data.numbering.block_start(succ)).is_some())
+                {
+                    reads.push(pred);
+                }
+            }
+            let lca = reads.iter().cloned().reduce(|a, b| doms.common_dominator(a, b));
+            if lca.is_some_and(|lca| !doms.dominates(def_block, lca)) {
+                // Read before the def.
+                continue;
+            }
+
+            let spill_at = lca.map(|lca| {
+                let mut best = (data.frequencies[def_block], def_ix, def.spill_after().0);
+                let mut path = vec![];
+                let mut ix = lca;
+                while ix != def_block {
+                    path.push(ix);
+                    ix = doms.idom[ix].unwrap();
+                }
+                for &ix in path.iter().rev() {
+                    let start = data.numbering.block_start(ix);
+                    if let Some(piece) = data.piece_at(&ixs, start) {
+                        if data.frequencies[ix] < best.0 {
+                            best = (data.frequencies[ix], piece, start);
+                        }
+                    }
+                }
+                (best.1, best.2)
+            });
+            for &ix in &ixs {
+                data.liveness[ix].spill_at = None;
+                data.liveness[ix].stored_once = true;
+            }
+            if let Some((piece, pos)) = spill_at {
+                data.liveness[piece].spill_at = Some(pos);
+            }
         }
     }
 
+    // Of the regs that are in a slot anywhere: where a piece is spilled or
+    // reloaded, or where no piece covers the range any more. The
+    // rematerialized ones never are.
+    fn spilled_lifetimes(data: &RegAllocData) -> Vec<(Reg, UseInterval)> {
+        let mut covered: HashMap<Reg, usize> = HashMap::new();
+        let mut spilled = RegSet::new();
+        for range in &data.liveness {
+            if !range.reg().is_mach() && range.remat.is_none() {
+                *covered.entry(range.reg()).or_default() += range.live_length();
+                if range.spill_at.is_some() || range.reload_at.is_some() {
+                    spilled.insert(range.reg());
+                }
+            }
+        }
+        covered.into_iter()
+            .filter(|&(r, length)| spilled.contains(&r) || length < data.lifetimes[&r].1)
+            .map(|(r, _)| (r, data.lifetimes[&r].0.clone()))
+            .collect()
+    }
+
 
+    // See UsePosition::spill_after and reload_before for where they go. Those
+    // that go right before or after the start moves are merged into them
+    // once they are all there. A rematerialized range does its def again
+    // instead of a reload, in the same order with the start moves, as the
+    // def reads nothing that they could write.
     fn run(&mut self) {
-        let instrs = &mut self.data.block.instrs;
-        for range in &self.data.liveness {
-            let vr = range.reg();
-            let mr = range.assigned_reg().into_reg();
-            if let Some(pos) = range.reload_at {
-                debug_assert!(pos.is_gap_end());
+        let mut before_start: HashMap<usize, Vec<ParallelMove>> = HashMap::new();
+        let mut after_start: HashMap<usize, Vec<ParallelMove>> = HashMap::new();
+        for range_ix in 0..self.data.liveness.len() {
+            let (vr, mr, reload_at, spill_at) = {
+                let range = &self.data.liveness[range_ix];
+                (range.reg(), range.assigned_reg().into_reg(), range.reload_at, range.spill_at)
+            };
+            if let Some(ref def) = self.data.liveness[range_ix].remat {
+                if let Some(pos) = reload_at {
+                    let instr = rematerialize(def, mr.into_op());
+                    let entry = self.remats.entry(self.data.numbering.locate(pos.instr_ix()))
+                        .or_default();
+                    if pos.is_gap_end() {
+                        entry.1.push(instr);
+                    } else {
+                        debug_assert!(pos.is_gap_start());
+                        entry.0.push(instr);
+                    }
+                }
+                continue;
+            }
+            if let Some(pos) = reload_at {
                 let slot = self.alloc.slot_for(&vr);
                 let reload = ParallelMove::new(mr.into_op(), slot);
-                instrs[pos.instr_ix()]
-                    .parallel_moves
-                    .add_to_start(reload);
+                let ix = pos.instr_ix();
+                if pos.is_gap_end() {
+                    self.data.instr_at(ix).parallel_moves.add_to_start(reload);
+                } else {
+                    debug_assert!(pos.is_gap_start());
+                    before_start.entry(ix).or_default().push(reload);
+                }
             }
-            if let Some(pos) = range.spill_at {
-                debug_assert!(pos.is_gap_start());
+            if let Some(pos) = spill_at {
                 let slot = self.alloc.slot_for(&vr);
                 let spill = ParallelMove::new(slot, mr.into_op());
-                instrs[pos.instr_ix() - 1]
-                    .parallel_moves
-                    .add_to_end(spill);
+                let ix = pos.instr_ix();
+                match pos.local_offset {
+                    GAP_START => self.data.instr_at(ix).parallel_moves.add_to_start(spill),
+                    GAP_END => after_start.entry(ix).or_default().push(spill),
+                    _ => {
+                        debug_assert_eq!(pos.local_offset, INSTR_END);
+                        self.data.instr_at(ix).parallel_moves.add_to_end(spill);
+                    }
+                }
+            }
+        }
+        let ixs = before_start.keys().chain(after_start.keys()).cloned().collect::<HashSet<_>>();
+        for ix in ixs {
+            let before = before_start.remove(&ix).unwrap_or_default();
+            let after = after_start.remove(&ix).unwrap_or_default();
+            let instr = self.data.instr_at(ix);
+            let (start, end) = instr.parallel_moves.take();
+            let start = gap_resolver::sequence(&before, &gap_resolver::sequence(&start, &after));
+            set_parallel_moves(instr, start, end);
+        }
+    }
+}
+
+impl ResolveControlFlowPhase {
+    fn new(spilling: CommitSpillingPhase, live_in: Vec<RegSet>) -> Self {
+        Self {
+            alloc: spilling.alloc,
+            data: spilling.data,
+            remats: spilling.remats,
+            live_in,
+        }
+    }
+
+    // The moves of an edge go at the start of the successor when it has no
+    // other predecessor, and otherwise at the end of the predecessor, which
+    // then has no other successor as there are no critical edges. Either way
+    // they are merged into the moves already there. The defs that are done
+    // again on an edge go right after its moves, which is before the other
+    // start moves of the successor, as those may read them.
+    fn run(&mut self) {
+        let pieces = self.data.pieces();
+        let succs = self.data.f.successors();
+        let preds = self.data.f.predecessors();
+        for (pred, pred_succs) in succs.iter().enumerate() {
+            let pred_end = LifetimePosition::new_instr_end(
+                self.data.numbering.block_end(pred).instr_ix() - 1);
+            for &succ in pred_succs {
+                let succ_start = self.data.numbering.block_start(succ);
+                let mut live = self.live_in[succ].iter()
+                    .filter(|r| !r.is_mach())
+                    .cloned()
+                    .collect::<Vec<_>>();
+                live.sort();
+                let mut moves = vec![];
+                let mut remats = vec![];
+                for r in live {
+                    let from_ix = self.data.piece_at(&pieces[&r], pred_end);
+                    let to_ix = self.data.piece_at(&pieces[&r], succ_start);
+                    let from = self.location_of(&pieces[&r], from_ix);
+                    let to = self.location_of(&pieces[&r], to_ix);
+                    let is_stored = to_ix.is_none()
+                        && self.data.liveness[pieces[&r][0]].stored_once;
+                    match (to, from.clone()) {
+                        (Some(_), Some(_)) if is_stored => {}
+                        (Some(to), Some(from)) => if from != to {
+                            moves.push(ParallelMove::new(to, from));
+                        },
+                        (Some(to), None) => {
+                            let def = self.data.liveness[pieces[&r][0]].remat.as_ref().unwrap();
+                            remats.push(rematerialize(def, to));
+                        }
+                        // Done again where it's used next.
+                        (None, _) => {}
+                    }
+                    // A reloaded piece only reads the value that is in the
+                    // slot, which is not so when it comes from another piece.
+                    let is_reloaded = to_ix.is_some_and(|ix| {
+                        let range = &self.data.liveness[ix];
+                        range.reload_at.is_some() && range.remat.is_none() && !range.stored_once
+                    });
+                    if is_reloaded && from_ix.is_some() && from_ix != to_ix {
+                        moves.push(ParallelMove::new(self.alloc.slot_for(&r), from.unwrap()));
+                    }
+                }
+                if preds[succ].len() == 1 && !remats.is_empty() {
+                    set_parallel_moves(&mut remats[0], moves, vec![]);
+                    let before = &mut self.remats.entry((succ, 0)).or_default().0;
+                    before.splice(0..0, remats);
+                    continue;
+                }
+                if !remats.is_empty() {
+                    let last = self.data.f.blocks[pred].instrs.len() - 1;
+                    self.remats.entry((pred, last)).or_default().1.extend(remats);
+                }
+                if moves.is_empty() {
+                    continue;
+                }
+                if preds[succ].len() == 1 {
+                    let instr = &mut self.data.f.blocks[succ].instrs[0];
+                    let (start, end) = instr.parallel_moves.take();
+                    set_parallel_moves(instr, gap_resolver::sequence(&moves, &start), end);
+                } else {
+                    debug_assert_eq!(pred_succs.len(), 1, "Critical edge {} -> {}",
+                                     self.data.f.blocks[pred].label,
+                                     self.data.f.blocks[succ].label);
+                    let instr = self.data.f.blocks[pred].instrs.last_mut().unwrap();
+                    let (start, end) = instr.parallel_moves.take();
+                    set_parallel_moves(instr, gap_resolver::sequence(&start, &moves), end);
+                }
             }
         }
     }
+
+    // In the register of the piece, or else in the spill slot, as that's
+    // where a range is between a split and the reload. None for a
+    // rematerialized range, which has no slot.
+    fn location_of(&self, pieces: &[usize], piece: Option<usize>) -> Option<Operand> {
+        let liveness = &self.data.liveness;
+        match piece {
+            Some(ix) => Some(liveness[ix].assigned_reg().into_reg().into_op()),
+            None if liveness[pieces[0]].remat.is_some() => None,
+            None => Some(self.alloc.slot_for(&liveness[pieces[0]].reg())),
+        }
+    }
+}
+
+fn set_parallel_moves(instr: &mut Instr, start: Vec<ParallelMove>, end: Vec<ParallelMove>) {
+    for m in start {
+        instr.parallel_moves.add_to_start(m);
+    }
+    for m in end {
+        instr.parallel_moves.add_to_end(m);
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use ::gap_resolver;
+    use ::legalize;
+    use ::peephole;
     use ::test_utils;
+    use ::test_utils::XorShift;
+    use ::x64::emu::Emulator;
 
     fn vreg(ix: u32) -> Reg {
         Reg::new_virt(ix)
@@ -815,12 +1757,16 @@ mod test {
         LifetimePosition::new_instr_end(ix).computed_ix()
     }
 
+    fn op_reg(ix: u8) -> RegLocInInstr {
+        RegLocInInstr::Explicit(ix, RegLocInOp::Reg)
+    }
+
     fn dst_reg() -> RegLocInInstr {
-        RegLocInInstr::Dst(RegLocInOp::Reg)
+        op_reg(0)
     }
 
     fn src_reg() -> RegLocInInstr {
-        RegLocInInstr::Src(RegLocInOp::Reg)
+        op_reg(1)
     }
 
     fn live_range(r: Reg, intervals: &[usize],
@@ -855,7 +1801,7 @@ mod test {
             Instr::mov(m0.clone(), v1.clone()),
             Instr::ret(m0.clone()),
         ];
-        Block::new(instrs)
+        Block::new(Label::Local(0), instrs)
     }
 
     fn simple_block_spill() -> Block {
@@ -878,13 +1824,13 @@ mod test {
             // Instr::mov(m0.clone(), v0.clone()),
             Instr::ret(v0.clone()),
         ];
-        Block::new(instrs)
+        Block::new(Label::Local(0), instrs)
     }
 
     #[test]
     fn can_analyze_live_ranges_for_single_block() {
         let b = simple_block_nospill();
-        let ls = analyze_block_liveness(&b);
+        let ls = analyze_liveness(&Function::new(vec![b]));
         let rg0 = live_range(vreg(0), &[
             pos_end(0), pos_start(3),
         ], &[
@@ -892,7 +1838,7 @@ mod test {
             (pos_start(2), src_reg(), UseKind::Input),
             (pos_start(3), src_reg(), UseKind::Input),
         ]);
-        let rg1 = live_range(vreg(1), &[
+        let mut rg1 = live_range(vreg(1), &[
             pos_end(1), pos_start(2),
             pos_end(2), pos_start(3),
             pos_end(3), pos_start(4),
@@ -904,22 +1850,275 @@ mod test {
             (pos_end(3), dst_reg(), UseKind::Output),
             (pos_start(4), src_reg(), UseKind::Input),
         ]);
-        let rg2 = live_range(mreg(0), &[
+        let mut rg2 = live_range(mreg(0), &[
             pos_end(4), pos_start(5)
         ], &[
             (pos_end(4), dst_reg(), UseKind::Output),
-            (pos_start(5), src_reg(), UseKind::Input),
+            (pos_start(5), op_reg(0), UseKind::Input),
         ]);
+        // ret reads its operand from %rax.
+        rg2.poses[1].ctx.fixed = Some(RAX);
+        // And the two sides of the mov into it would like to share it.
+        rg1.poses[5].hint = Some(mreg(0));
+        rg2.poses[0].hint = Some(vreg(1));
         let expected = vec![rg0, rg1, rg2];
         test_utils::assert_eq_pretty("analyze-liveness-1block", &ls, &expected);
     }
 
+    // sum = 0; for (i = 0; i < n; i += 1) sum += i, with the Phis as the
+    // start moves of the jumps into the loop header.
+    fn loop_function() -> Function {
+        let v = op_vreg;
+        let label = Label::Local;
+        let mut enter = Instr::jmp(label(1));
+        enter.parallel_moves.add_to_start(ParallelMove::new(v(1), v(2)));
+        enter.parallel_moves.add_to_start(ParallelMove::new(v(3), v(2)));
+        let mut back_edge = Instr::jmp(label(1));
+        back_edge.parallel_moves.add_to_start(ParallelMove::new(v(1), v(5)));
+        back_edge.parallel_moves.add_to_start(ParallelMove::new(v(3), v(4)));
+        Function::new(vec![
+            // 0..3
+            Block::new(label(0), vec![
+                Instr::mov(v(0), op_mreg(7)),
+                Instr::mov(v(2), Operand::Imm(0)),
+                enter,
+            ]),
+            // 3..6
+            Block::new(label(1), vec![
+                Instr::cmp(v(1), v(0)),
+                Instr::jcc(Cond::Ge, label(3)),
+                Instr::jmp(label(2)),
+            ]),
+            // 6..11
+            Block::new(label(2), vec![
+                Instr::mov(v(4), v(3)),
+                Instr::add(v(4), v(1)),
+                Instr::mov(v(5), v(1)),
+                Instr::add(v(5), Operand::Imm(1)),
+                back_edge,
+            ]),
+            // 11..13
+            Block::new(label(3), vec![
+                Instr::mov(op_mreg(0), v(3)),
+                Instr::ret(op_mreg(0)),
+            ]),
+        ])
+    }
+
+    fn intervals_of(ls: &LiveRangeVec, r: Reg) -> Vec<(usize, usize)> {
+        let range = ls.iter().find(|range| range.is_for(r)).unwrap();
+        range.intervals.iter().map(|it| (it.start.computed_ix(), it.end.computed_ix())).collect()
+    }
+
+    fn gap_start(ix: usize) -> usize {
+        LifetimePosition::new_gap_start(ix).computed_ix()
+    }
+
+    fn gap_end(ix: usize) -> usize {
+        LifetimePosition::new_gap_end(ix).computed_ix()
+    }
+
+    #[test]
+    fn can_analyze_live_ranges_across_blocks() {
+        let f = loop_function();
+        let (live_in, live_out) = compute_live_sets(&f);
+        let set = |ixs: &[u32]| ixs.iter().map(|&ix| vreg(ix)).collect::<RegSet>();
+        assert_eq!(live_in[1], set(&[0, 1, 3]));
+        assert_eq!(live_out[2], set(&[0, 1, 3]));
+        assert_eq!(live_in[3], set(&[3]));
+
+        let ls = analyze_liveness(&f);
+        // n is used in the header, so it lives through the whole loop body.
+        assert_eq!(intervals_of(&ls, vreg(0)), vec![(pos_end(0), gap_start(11))]);
+        // The Phis are written by the start moves of both jumps into the
+        // header, and are dead in the body between their last read and the
+        // back edge.
+        assert_eq!(intervals_of(&ls, vreg(1)),
+                   vec![(gap_end(2), pos_start(8)), (gap_end(10), gap_start(11))]);
+        assert_eq!(intervals_of(&ls, vreg(3)),
+                   vec![(gap_end(2), pos_start(6)), (gap_end(10), pos_start(11))]);
+        // The last use is by the start moves, which hold on to it for the
+        // whole gap.
+        assert_eq!(intervals_of(&ls, vreg(5)),
+                   vec![(pos_end(8), pos_start(9)), (pos_end(9), gap_end(10))]);
+
+        let v1 = ls.iter().find(|range| range.is_for(vreg(1))).unwrap();
+        let v1_defs = v1.poses.iter()
+            .filter(|u| u.is_output())
+            .map(|u| (u.ctx.block_id, u.ctx.instr_ix, u.ctx.operand_ix.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(v1_defs, vec![
+            (0, 2, RegLocInInstr::StartMove(0, MoveSide::Dst)),
+            (2, 4, RegLocInInstr::StartMove(0, MoveSide::Dst)),
+        ]);
+    }
+
+    #[test]
+    fn can_allocate_loops_without_splitting() {
+        let f = loop_function();
+        let allocated = allocate_function(f.clone(), 4, &Hints::new()).unwrap();
+        for b in &allocated.blocks {
+            for instr in &b.instrs {
+                assert!(instr.reg_operands().iter().all(|&(_, r, _)| r.is_mach()) &&
+                        instr.start_move_regs().iter().all(|&(_, r)| r.is_mach()),
+                        "{}", allocated);
+            }
+        }
+        let mut resolved = allocated.clone();
+        for b in &mut resolved.blocks {
+            gap_resolver::resolve_block(b, &[]);
+        }
+        for n in &[0, 1, 10] {
+            let expected = Emulator::new().call(&f, &[*n]);
+            assert_eq!(expected, Ok(n * (n - 1) / 2));
+            assert_eq!(Emulator::new().call(&allocated, &[*n]), expected, "{}", allocated);
+            assert_eq!(Emulator::new().call(&resolved, &[*n]), expected, "{}", resolved);
+        }
+    }
+
+    // Each of the Phis gets the register of its input from the back edge,
+    // which is also where the copies in the body put their results.
+    #[test]
+    fn can_coalesce_phi_moves() {
+        let f = loop_function();
+        let mut allocated = allocate_function(f, 4, &Hints::new()).unwrap();
+        let body = allocated.blocks.iter().find(|b| b.label == Label::Local(2)).unwrap();
+        assert_eq!(body.instrs.len(), 3, "{}", allocated);
+        assert!(body.instrs.iter().all(|instr| instr.parallel_moves.is_empty()), "{}", allocated);
+        frame::insert_frame(&mut allocated);
+        gap_resolver::resolve_function(&mut allocated, &[]);
+        for n in &[0, 1, 10] {
+            assert_eq!(Emulator::new().call(&allocated, &[*n]), Ok(n * (n - 1) / 2),
+                       "{}", allocated);
+        }
+    }
+
+    #[test]
+    fn can_resolve_split_ranges_across_edges() {
+        let f = loop_function();
+        let mut liveness = analyze_liveness(&f);
+        // v1 goes to the stack after the cmp, and comes back in another
+        // register for the add, so the back edge has to move it over.
+        let v1 = liveness.iter().position(|range| range.is_for(vreg(1))).unwrap();
+        let fresh_ix = liveness.len();
+        let mut child = liveness[v1].split_at(LifetimePosition::new_gap_start(4), fresh_ix).unwrap();
+        liveness[v1].hint = Some(RCX.into_reg());
+        child.hint = Some(RBX.into_reg());
+        liveness.push(child);
+
+        let mut lsra = LinearScan::new(RegAllocData::new(f.clone(), liveness, 4));
+        lsra.run().unwrap();
+        assert_eq!(lsra.data.liveness[v1].assigned, Some(RCX));
+        assert_eq!(lsra.data.liveness[fresh_ix].assigned, Some(RBX));
+        let mut assignment = CommitRegAssignmentPhase::new(lsra.data);
+        assignment.run();
+        let mut spilling = CommitSpillingPhase::new(assignment.data);
+        spilling.run();
+        let mut resolution = ResolveControlFlowPhase::new(spilling, compute_live_sets(&f).0);
+        resolution.run();
+        let mut resolved = resolution.data.f;
+        // Straight from v5, as the move is merged into the phi moves.
+        let back_edge = resolved.blocks[2].instrs.last().unwrap();
+        assert!(back_edge.parallel_moves.start().iter()
+                .any(|m| *m.dst() == RCX.into_reg().into_op()), "{}", resolved);
+
+        frame::insert_frame(&mut resolved);
+        gap_resolver::resolve_function(&mut resolved, &[]);
+        for n in &[0, 1, 10] {
+            assert_eq!(Emulator::new().call(&resolved, &[*n]), Ok(n * (n - 1) / 2),
+                       "{}", resolved);
+        }
+    }
+
+    #[test]
+    fn can_analyze_implicit_operands() {
+        let v0 = op_vreg(0);
+        let rax = RAX.into_reg().into_op();
+        let instrs = vec![
+            Instr::mov(v0.clone(), Operand::Imm(7)),
+            Instr::mov(rax.clone(), Operand::Imm(42)),
+            Instr::cqo(),
+            Instr::idiv(v0.clone()),
+            Instr::ret(rax.clone()),
+        ];
+        let ls = analyze_liveness(&Function::new(vec![Block::new(Label::Local(0), instrs)]));
+        let mut rax_use = live_range(mreg(0), &[
+            pos_end(1), pos_start(3),
+            pos_end(3), pos_start(4),
+        ], &[
+            (pos_end(1), dst_reg(), UseKind::Output),
+            (pos_start(2), RegLocInInstr::Implicit(0), UseKind::Input),
+            (pos_start(3), RegLocInInstr::Implicit(0), UseKind::Input),
+            (pos_end(3), RegLocInInstr::Implicit(0), UseKind::Output),
+            (pos_start(4), op_reg(0), UseKind::Input),
+        ]);
+        for pos in &mut rax_use.poses[1..] {
+            pos.ctx.fixed = Some(RAX);
+        }
+        let v0_use = live_range(vreg(0), &[
+            pos_end(0), pos_start(3),
+        ], &[
+            (pos_end(0), dst_reg(), UseKind::Output),
+            (pos_start(3), op_reg(0), UseKind::Input),
+        ]);
+        // The unused rdx output of idiv still gets a range over the idiv.
+        let mut rdx_use = live_range(mreg(2), &[
+            pos_end(2), pos_start(3),
+            pos_end(3), gap_start(4),
+        ], &[
+            (pos_end(2), RegLocInInstr::Implicit(1), UseKind::Output),
+            (pos_start(3), RegLocInInstr::Implicit(1), UseKind::Input),
+            (pos_end(3), RegLocInInstr::Implicit(1), UseKind::Output),
+        ]);
+        for pos in &mut rdx_use.poses {
+            pos.ctx.fixed = Some(RDX);
+        }
+        let expected = vec![v0_use, rax_use, rdx_use];
+        test_utils::assert_eq_pretty("analyze-liveness-implicit", &ls, &expected);
+    }
+
+    // The srcs of an instruction are live up to its start, where a plain def
+    // can reuse their registers but an early clobber can't.
+    #[test]
+    fn can_make_early_clobbers_interfere_with_inputs() {
+        let ix = 3;
+        let src = UseInterval::new(LifetimePosition::new_gap_start(1),
+                                   LifetimePosition::new_instr_start(ix));
+        let dst = |role| UseInterval::new(def_position(role, ix),
+                                          LifetimePosition::new_gap_start(ix + 1));
+        assert_eq!(src.first_intersection(&dst(OperandRole::Def)), None);
+        assert_eq!(src.first_intersection(&dst(OperandRole::EarlyClobber)),
+                   Some(LifetimePosition::new_gap_end(ix)));
+    }
+
+    #[test]
+    fn can_isolate_fixed_operands() {
+        let v0 = op_vreg(0);
+        let v1 = op_vreg(1);
+        let rcx = RCX.into_reg().into_op();
+        let rax = RAX.into_reg().into_op();
+        let mut b = Block::new(Label::Local(0), vec![
+            Instr::shl(v0.clone(), v1.clone()),
+            Instr::ret(v0.clone()),
+        ]);
+        isolate_fixed_operands(&mut b);
+
+        let expected_instrs = vec![
+            Instr::mov(rcx.clone(), v1.clone()),
+            Instr::shl(v0.clone(), rcx.clone()),
+            Instr::mov(rax.clone(), v0.clone()),
+            Instr::ret(rax.clone()),
+        ];
+        test_utils::assert_eq_pretty("isolate-fixed-operands", &b.instrs, &expected_instrs);
+    }
+
     #[test]
     fn can_lsra_for_single_block_nospill() {
         let block = simple_block_nospill();
-        let liveness = analyze_block_liveness(&block);
-        let mut lsra = LinearScan::new(RegAllocData::new(block, liveness, 4));
-        lsra.run();
+        let f = Function::new(vec![block]);
+        let liveness = analyze_liveness(&f);
+        let mut lsra = LinearScan::new(RegAllocData::new(f, liveness, 4));
+        lsra.run().unwrap();
         let mut assignment = CommitRegAssignmentPhase::new(lsra.data);
         assignment.run();
 
@@ -935,15 +2134,16 @@ mod test {
         ];
 
         test_utils::assert_eq_pretty("lsra-instr-1block-nospill",
-                                     &assignment.data.block.instrs, &expected_instrs);
+                                     &assignment.data.f.blocks[0].instrs, &expected_instrs);
     }
 
     #[test]
     fn can_lsra_for_single_block_spill() {
         let block = simple_block_spill();
-        let liveness = analyze_block_liveness(&block);
-        let mut lsra = LinearScan::new(RegAllocData::new(block, liveness, 2));
-        lsra.run();
+        let f = Function::new(vec![block]);
+        let liveness = analyze_liveness(&f);
+        let mut lsra = LinearScan::new(RegAllocData::new(f, liveness, 2));
+        lsra.run().unwrap();
         let mut rass = CommitRegAssignmentPhase::new(lsra.data);
         rass.run();
         let mut spill = CommitSpillingPhase::new(rass.data);
@@ -951,17 +2151,718 @@ mod test {
 
         let m0 = op_mreg(0);
         let m1 = op_mreg(1);
+        let slot = |ix| Mem::new(Reg::rsp(), ix * 8).into_op();
+        let spilled = |mut instr: Instr, src: &Operand, ix| {
+            instr.parallel_moves.add_to_end(ParallelMove::new(slot(ix), src.clone()));
+            instr
+        };
+        let reloaded = |mut instr: Instr, dst: &Operand, ix| {
+            instr.parallel_moves.add_to_start(ParallelMove::new(dst.clone(), slot(ix)));
+            instr
+        };
+        // Each new value takes %rax from the one before, which is spilled as
+        // late as possible and reloaded right before its only use.
         let expected_instrs = vec![
-            Instr::mov(m0.clone(), Operand::Imm(42)),
-            Instr::mov(m1.clone(), Operand::Imm(0)),
-            Instr::add(m1.clone(), m0.clone()),
-            Instr::add(m1.clone(), m0.clone()),
-            Instr::mov(m0.clone(), m1.clone()),
+            Instr::mov(m0.clone(), Operand::Imm(0)),
+            spilled(Instr::mov(m1.clone(), Operand::Imm(1)), &m0, 0),
+            spilled(Instr::mov(m0.clone(), Operand::Imm(2)), &m0, 1),
+            spilled(Instr::mov(m0.clone(), Operand::Imm(3)), &m0, 2),
+            reloaded(Instr::add(m0.clone(), m1.clone()), &m0, 0),
+            reloaded(Instr::add(m0.clone(), m1.clone()), &m1, 1),
+            reloaded(Instr::add(m0.clone(), m1.clone()), &m1, 2),
             Instr::ret(m0.clone()),
         ];
 
         test_utils::assert_eq_pretty("lsra-instr-1block-spill",
-                                     &spill.data.block.instrs, &expected_instrs);
+                                     &spill.data.f.blocks[0].instrs, &expected_instrs);
+    }
+
+    // Three sums like simple_block_spill, one after another, and added up in
+    // v0. Each spills its own values, which are dead by the next one.
+    #[test]
+    fn can_share_spill_slots() {
+        let v = op_vreg;
+        let mut instrs = vec![];
+        for k in 0..3 {
+            for i in 0..4 {
+                instrs.push(Instr::mov(v(4 * k + i), Operand::Imm((4 * k + i) as Imm)));
+            }
+            for i in 1..4 {
+                instrs.push(Instr::add(v(4 * k), v(4 * k + i)));
+            }
+            if k > 0 {
+                instrs.push(Instr::add(v(0), v(4 * k)));
+            }
+        }
+        instrs.push(Instr::mov(op_mreg(0), v(0)));
+        instrs.push(Instr::ret(op_mreg(0)));
+        let f = Function::new(vec![Block::new(Label::Local(0), instrs)]);
+
+        let (mut allocated, num_slots) = allocate_function_with_slots(f, 2, &Hints::new()).unwrap();
+        let spilled = allocated.blocks[0].instrs.iter()
+            .flat_map(|instr| instr.parallel_moves.end())
+            .filter(|m| m.dst().is_mem())
+            .count();
+        assert!(num_slots < spilled, "{} slots for {} spills: {}", num_slots, spilled, allocated);
+        assert_eq!(frame::stack_slots(&allocated.blocks[0].instrs), num_slots);
+
+        let layout = frame::insert_frame_with_slots(&mut allocated, num_slots);
+        assert_eq!(layout.size as usize, (num_slots * 8).div_ceil(16) * 16);
+        assert_eq!(Emulator::new().call(&allocated, &[]), Ok(66), "{}", allocated);
+    }
+
+    // v0 lives across the call, whose clobbers aren't read afterwards, and
+    // so must keep it out of all of them.
+    #[test]
+    fn can_keep_values_live_across_calls() {
+        let v = op_vreg;
+        let rax = op_mreg(0);
+        let f = Function::new(vec![Block::new(Label::Local(0), vec![
+            Instr::mov(v(0), RDI.into_reg().into_op()),
+            Instr::call(Label::Named("f".to_owned()).into_op(), 0),
+            Instr::mov(v(1), rax.clone()),
+            Instr::add(v(1), v(0)),
+            Instr::mov(rax.clone(), v(1)),
+            Instr::ret(rax.clone()),
+        ])]);
+        for &num_regs in &[4, RegClass::Gpr.allocatable().len()] {
+            let mut allocated = allocate_function(f.clone(), num_regs, &Hints::new()).unwrap();
+            frame::insert_frame(&mut allocated);
+            gap_resolver::resolve_function(&mut allocated, &[R11.into_reg()]);
+            let mut emu = Emulator::new();
+            emu.define_extern("f", 0, |_| 40);
+            assert_eq!(emu.call(&allocated, &[2]), Ok(42), "{}", allocated);
+        }
+    }
+
+    // x1 to x5 are all live at once, so some of them are spilled, each into
+    // an 8-byte slot of its own like a GPR.
+    #[test]
+    fn can_spill_xmms_into_single_slots() {
+        let v = op_vreg;
+        let x = |ix| Reg::new_virt_in(RegClass::Xmm, ix).into_op();
+        let xmm0 = XMM0.into_reg();
+        let mut instrs = vec![
+            Instr::mov(v(0), Operand::Imm(3)),
+            Instr::cvtsi2sd(x(0), v(0)),
+        ];
+        for ix in 1..6 {
+            instrs.push(Instr::movsd(x(ix), x(ix - 1)));
+            instrs.push(Instr::addsd(x(ix), x(0)));
+        }
+        for ix in 1..6 {
+            instrs.push(Instr::addsd(x(0), x(ix)));
+        }
+        instrs.push(Instr::movsd(xmm0.into_op(), x(0)));
+        instrs.push(Instr::mov(op_mreg(0), v(0)));
+        instrs.push(Instr::ret(op_mreg(0)));
+        let f = Function::new(vec![Block::new(Label::Local(0), instrs)]);
+
+        let (mut allocated, num_slots) = allocate_function_with_slots(f, 2, &Hints::new()).unwrap();
+        let slots = allocated.blocks[0].instrs.iter()
+            .flat_map(|instr| instr.parallel_moves.start().iter().chain(instr.parallel_moves.end()))
+            .flat_map(|m| vec![m.dst(), m.src()])
+            .filter_map(|op| match *op {
+                Operand::Mem(ref m) => Some(m.disp),
+                _ => None,
+            })
+            .collect::<HashSet<_>>();
+        assert!(!slots.is_empty(), "{}", allocated);
+        assert_eq!(slots.len(), num_slots, "{}", allocated);
+
+        frame::insert_frame_with_slots(&mut allocated, num_slots);
+        let mut emu = Emulator::new();
+        assert_eq!(emu.call(&allocated, &[]), Ok(3), "{}", allocated);
+        // 3 + 6 + 9 + 12 + 15 + 18
+        assert_eq!(emu.reg(xmm0), Ok(63f64.to_bits() as i64));
+    }
+
+    // v0 is live across the cold block without being used there, and is the
+    // one to make room for its temporaries.
+    #[test]
+    fn can_split_on_the_cold_path() {
+        let v = op_vreg;
+        let label = Label::Local;
+        let mut cold = Block::new(label(2), vec![
+            Instr::mov(v(2), Operand::Imm(1)),
+            Instr::mov(v(3), Operand::Imm(2)),
+            Instr::add(v(2), v(3)),
+            Instr::add(v(1), v(2)),
+            Instr::jmp(label(1)),
+        ]);
+        cold.is_cold = true;
+        let f = Function::new(vec![
+            Block::new(label(0), vec![
+                Instr::mov(v(0), Operand::Imm(7)),
+                Instr::mov(v(1), op_mreg(7)),
+                Instr::cmp(v(1), Operand::Imm(0)),
+                Instr::jcc(Cond::Ge, label(1)),
+                Instr::jmp(label(2)),
+            ]),
+            cold,
+            Block::new(label(1), vec![
+                Instr::mov(v(4), v(0)),
+                Instr::add(v(4), v(1)),
+                Instr::mov(op_mreg(0), v(4)),
+                Instr::ret(op_mreg(0)),
+            ]),
+        ]);
+        let mut allocated = allocate_function(f, 3, &Hints::new()).unwrap();
+        for b in allocated.blocks.iter().filter(|b| !b.is_cold) {
+            for instr in &b.instrs {
+                let (start, end) = (instr.parallel_moves.start(), instr.parallel_moves.end());
+                assert!(start.iter().chain(end).all(|m| !m.dst().is_mem()), "{}", allocated);
+            }
+        }
+        frame::insert_frame(&mut allocated);
+        gap_resolver::resolve_function(&mut allocated, &[R11.into_reg()]);
+        for &(n, expected) in &[(5, 12), (-3, 7)] {
+            assert_eq!(Emulator::new().call(&allocated, &[n]), Ok(expected), "{}", allocated);
+        }
+    }
+
+    // v0 is spilled in L5 and reloaded in L3, and the piece from the reload
+    // on also covers the cold L4, which v0 enters in a register from L2. It
+    // still has to be in the slot by the back edge from L4.
+    #[test]
+    fn can_store_values_entering_reloaded_ranges() {
+        let v = op_vreg;
+        let label = Label::Local;
+        let mut cold = Block::new(label(4), vec![
+            Instr::add(v(11), v(0)),
+            Instr::mov(v(40), Operand::Imm(33)),
+            Instr::add(v(12), v(40)),
+            Instr::jmp(label(6)),
+        ]);
+        cold.is_cold = true;
+        let f = Function::new(vec![
+            Block::new(label(0), vec![
+                Instr::mov(v(0), Operand::Imm(856)),
+                Instr::mov(v(10), Operand::Imm(4)),
+                Instr::mov(v(11), Operand::Imm(4)),
+                Instr::mov(v(12), Operand::Imm(1)),
+                Instr::mov(v(19), op_mreg(7)),
+                Instr::jmp(label(1)),
+            ]),
+            Block::new(label(1), vec![
+                Instr::cmp(v(19), Operand::Imm(0)),
+                Instr::jcc(Cond::Le, label(3)),
+                Instr::jmp(label(2)),
+            ]),
+            Block::new(label(2), vec![
+                Instr::add(v(11), v(0)),
+                Instr::add(v(10), v(0)),
+                Instr::cmp(v(10), Operand::Imm(38)),
+                Instr::jcc(Cond::Ge, label(4)),
+                Instr::jmp(label(5)),
+            ]),
+            Block::new(label(3), vec![
+                Instr::mov(v(99), Operand::Imm(0)),
+                Instr::add(v(99), v(0)),
+                Instr::add(v(99), v(10)),
+                Instr::add(v(99), v(11)),
+                Instr::add(v(99), v(12)),
+                Instr::mov(op_mreg(0), v(99)),
+                Instr::ret(op_mreg(0)),
+            ]),
+            cold,
+            Block::new(label(5), vec![
+                Instr::add(v(10), v(0)),
+                Instr::mov(v(41), Operand::Imm(29)),
+                Instr::add(v(12), v(41)),
+                Instr::jmp(label(6)),
+            ]),
+            Block::new(label(6), vec![
+                Instr::mov(v(42), Operand::Imm(44)),
+                Instr::add(v(11), v(42)),
+                Instr::sub(v(19), Operand::Imm(1)),
+                Instr::jmp(label(1)),
+            ]),
+        ]);
+        let mut allocated = allocate_function(f.clone(), 3, &Hints::new()).unwrap();
+        frame::insert_frame(&mut allocated);
+        gap_resolver::resolve_function(&mut allocated, &[R11.into_reg()]);
+        for n in 0..4 {
+            let expected = Emulator::new().call(&f, &[n]);
+            assert!(expected.is_ok(), "{:?}", expected);
+            assert_eq!(Emulator::new().call(&allocated, &[n]), expected, "{}", allocated);
+        }
+    }
+
+    // v0 and v5 make room for the temporaries of L2, and are done again in
+    // L1 instead of going through a slot. The difference of the two frame
+    // addresses doesn't depend on %rsp.
+    #[test]
+    fn can_rematerialize_instead_of_spilling() {
+        let v = op_vreg;
+        let label = Label::Local;
+        let frame_addr = |disp| Mem::new(Reg::rsp(), disp);
+        let f = Function::new(vec![
+            Block::new(label(0), vec![
+                Instr::mov(v(0), Operand::Imm(7)),
+                Instr::lea(v(5), frame_addr(24)),
+                Instr::mov(v(1), op_mreg(7)),
+                Instr::cmp(v(1), Operand::Imm(0)),
+                Instr::jcc(Cond::Ge, label(1)),
+                Instr::jmp(label(2)),
+            ]),
+            Block::new(label(2), vec![
+                Instr::mov(v(2), Operand::Imm(1)),
+                Instr::mov(v(3), Operand::Imm(2)),
+                Instr::add(v(2), v(3)),
+                Instr::add(v(1), v(2)),
+                Instr::jmp(label(1)),
+            ]),
+            Block::new(label(1), vec![
+                Instr::lea(v(6), frame_addr(8)),
+                Instr::mov(v(7), v(5)),
+                Instr::sub(v(7), v(6)),
+                Instr::mov(v(4), v(0)),
+                Instr::add(v(4), v(1)),
+                Instr::add(v(4), v(7)),
+                Instr::mov(op_mreg(0), v(4)),
+                Instr::ret(op_mreg(0)),
+            ]),
+        ]);
+        let mut allocated = allocate_function(f, 3, &Hints::new()).unwrap();
+        for instr in allocated.blocks.iter().flat_map(|b| &b.instrs) {
+            let (start, end) = (instr.parallel_moves.start(), instr.parallel_moves.end());
+            assert!(start.iter().chain(end).all(|m| !m.dst().is_mem()), "{}", allocated);
+        }
+        let count = |opcode: OpCode, src: &Operand| allocated.blocks.iter()
+            .flat_map(|b| &b.instrs)
+            .filter(|instr| instr.opcode == opcode && instr.ops[1] == *src)
+            .count();
+        assert!(count(OpCode::Mov, &Operand::Imm(7)) > 1, "{}", allocated);
+        assert!(count(OpCode::Lea, &frame_addr(24).into_op()) > 1, "{}", allocated);
+
+        frame::insert_frame(&mut allocated);
+        gap_resolver::resolve_function(&mut allocated, &[R11.into_reg()]);
+        for &(n, expected) in &[(5, 28), (-3, 23)] {
+            assert_eq!(Emulator::new().call(&allocated, &[n]), Ok(expected), "{}", allocated);
+        }
+    }
+
+    fn parallel_moves_of(b: &Block) -> Vec<ParallelMove> {
+        b.instrs.iter()
+            .flat_map(|instr| instr.parallel_moves.start().iter().chain(instr.parallel_moves.end()))
+            .cloned()
+            .collect()
+    }
+
+    // v5 has to make room on both sides of the diamond, but is the same value
+    // on both, so it's stored once where it's defined.
+    #[test]
+    fn can_spill_at_definition() {
+        let v = op_vreg;
+        let label = Label::Local;
+        let arm = |ix, k| Block::new(label(ix), vec![
+            Instr::mov(v(2), v(1)),
+            Instr::add(v(2), Operand::Imm(k)),
+            Instr::mov(v(3), v(2)),
+            Instr::add(v(3), v(1)),
+            Instr::mov(v(4), v(3)),
+            Instr::add(v(4), v(2)),
+            Instr::jmp(label(3)),
+        ]);
+        let f = Function::new(vec![
+            Block::new(label(0), vec![
+                Instr::mov(v(1), op_mreg(7)),
+                Instr::mov(v(0), v(1)),
+                Instr::add(v(0), v(0)),
+                Instr::mov(v(5), v(0)),
+                Instr::cmp(v(1), Operand::Imm(0)),
+                Instr::jcc(Cond::Ge, label(1)),
+                Instr::jmp(label(2)),
+            ]),
+            arm(1, 1),
+            arm(2, 2),
+            Block::new(label(3), vec![
+                Instr::add(v(4), v(5)),
+                Instr::mov(op_mreg(0), v(4)),
+                Instr::ret(op_mreg(0)),
+            ]),
+        ]);
+        let mut allocated = allocate_function(f, 2, &Hints::new()).unwrap();
+        let l3 = allocated.blocks.iter().find(|b| b.label == label(3)).unwrap();
+        let slot = parallel_moves_of(l3).into_iter()
+            .find(|m| m.src().is_mem())
+            .expect("v5 is reloaded in L3")
+            .src()
+            .clone();
+        let stores = allocated.blocks.iter()
+            .map(|b| parallel_moves_of(b).iter().filter(|m| *m.dst() == slot).count())
+            .collect::<Vec<_>>();
+        assert_eq!(stores, vec![1, 0, 0, 0], "{}", allocated);
+
+        frame::insert_frame(&mut allocated);
+        gap_resolver::resolve_function(&mut allocated, &[R11.into_reg()]);
+        for &(n, expected) in &[(5, 27), (-3, -11)] {
+            assert_eq!(Emulator::new().call(&allocated, &[n]), Ok(expected), "{}", allocated);
+        }
+    }
+
+    // v5 is defined in the loop, but only has to make room on the cold paths
+    // below L4, so it's stored once at the start of L4 rather than on every
+    // iteration.
+    #[test]
+    fn can_spill_at_a_colder_dominator() {
+        let v = op_vreg;
+        let label = Label::Local;
+        let arm = |ix, k| Block::new(label(ix), vec![
+            Instr::mov(v(2), Operand::Imm(k)),
+            Instr::mov(v(3), v(1)),
+            Instr::add(v(3), v(2)),
+            Instr::add(v(3), v(9)),
+            Instr::add(v(2), v(3)),
+            Instr::add(v(9), v(2)),
+            Instr::add(v(9), v(5)),
+            Instr::jmp(label(5)),
+        ]);
+        let mut f = Function::new(vec![
+            Block::new(label(0), vec![
+                Instr::mov(v(1), op_mreg(7)),
+                Instr::mov(v(9), Operand::Imm(0)),
+                Instr::jmp(label(1)),
+            ]),
+            Block::new(label(1), vec![
+                Instr::cmp(v(1), Operand::Imm(0)),
+                Instr::jcc(Cond::Le, label(3)),
+                Instr::jmp(label(2)),
+            ]),
+            Block::new(label(2), vec![
+                Instr::mov(v(5), v(1)),
+                Instr::sub(v(1), Operand::Imm(1)),
+                Instr::add(v(9), v(5)),
+                Instr::cmp(v(5), Operand::Imm(2)),
+                Instr::jcc(Cond::Le, label(4)),
+                Instr::jmp(label(5)),
+            ]),
+            Block::new(label(3), vec![
+                Instr::mov(op_mreg(0), v(9)),
+                Instr::ret(op_mreg(0)),
+            ]),
+            Block::new(label(4), vec![
+                Instr::cmp(v(5), Operand::Imm(1)),
+                Instr::jcc(Cond::Le, label(6)),
+                Instr::jmp(label(7)),
+            ]),
+            Block::new(label(5), vec![Instr::jmp(label(1))]),
+            arm(6, 10),
+            arm(7, 20),
+        ]);
+        f.blocks[4].is_cold = true;
+        let mut allocated = allocate_function(f, 3, &Hints::new()).unwrap();
+        let l4 = allocated.blocks.iter().find(|b| b.label == label(4)).unwrap();
+        let slot = parallel_moves_of(l4).into_iter()
+            .find(|m| m.dst().is_mem())
+            .expect("v5 is stored at the start of L4")
+            .dst()
+            .clone();
+        let stores = allocated.blocks.iter()
+            .flat_map(parallel_moves_of)
+            .filter(|m| *m.dst() == slot)
+            .count();
+        assert_eq!(stores, 1, "{}", allocated);
+
+        frame::insert_frame(&mut allocated);
+        gap_resolver::resolve_function(&mut allocated, &[R11.into_reg()]);
+        for &(n, expected) in &[(0, 0), (1, 23), (2, 117), (3, 129)] {
+            assert_eq!(Emulator::new().call(&allocated, &[n]), Ok(expected), "{}", allocated);
+        }
+    }
+
+    // n is last used by the loop header, but has to make room after the
+    // loop, which is where it should be spilled.
+    #[test]
+    fn can_split_outside_loops() {
+        let v = op_vreg;
+        let mut f = loop_function();
+        f.blocks[3] = Block::new(Label::Local(3), vec![
+            Instr::mov(v(6), Operand::Imm(1)),
+            Instr::mov(v(7), Operand::Imm(2)),
+            Instr::add(v(6), v(7)),
+            Instr::add(v(3), v(6)),
+            Instr::add(v(3), v(0)),
+            Instr::mov(op_mreg(0), v(3)),
+            Instr::ret(op_mreg(0)),
+        ]);
+        let mut allocated = allocate_function(f, 3, &Hints::new()).unwrap();
+        let in_loop = [Label::Local(1), Label::Local(2)];
+        for b in allocated.blocks.iter().filter(|b| in_loop.contains(&b.label)) {
+            for instr in &b.instrs {
+                let (start, end) = (instr.parallel_moves.start(), instr.parallel_moves.end());
+                assert!(start.iter().chain(end).all(|m| !m.dst().is_mem()), "{}", allocated);
+            }
+        }
+        frame::insert_frame(&mut allocated);
+        gap_resolver::resolve_function(&mut allocated, &[R11.into_reg()]);
+        for n in &[0, 1, 10] {
+            assert_eq!(Emulator::new().call(&allocated, &[*n]), Ok(n * (n - 1) / 2 + 3 + n),
+                       "{}", allocated);
+        }
+    }
+
+    // Runs b as a leaf function with a frame, so that spill slots have room.
+    fn emulate(b: &Block) -> Result<i64, String> {
+        let mut f = Function::new(vec![b.clone()]);
+        frame::insert_frame(&mut f);
+        Emulator::new().call(&f, &[])
+    }
+
+    fn assert_same_behavior(before: &Block, after: &Block) {
+        let expected = emulate(before);
+        assert!(expected.is_ok(), "{:?}", expected);
+        assert_eq!(emulate(after), expected, "{}", after);
+        // Also with the ParallelMoves done by real instructions.
+        let mut resolved = after.clone();
+        gap_resolver::resolve_block(&mut resolved, &[]);
+        assert_eq!(emulate(&resolved), expected, "{}", resolved);
+        // And after cleaning up.
+        peephole::optimize_block(&mut resolved);
+        assert_eq!(emulate(&resolved), expected, "{}", resolved);
+    }
+
+    fn allocate(block: Block, num_regs_available: usize) -> Block {
+        let f = Function::new(vec![block]);
+        let liveness = analyze_liveness(&f);
+        let mut lsra = LinearScan::new(RegAllocData::new(f, liveness, num_regs_available));
+        lsra.run().unwrap();
+        let mut rass = CommitRegAssignmentPhase::new(lsra.data);
+        rass.run();
+        let mut spill = CommitSpillingPhase::new(rass.data);
+        spill.run();
+        spill.data.f.blocks.pop().unwrap()
+    }
+
+    #[test]
+    fn can_preserve_behavior_for_single_block_nospill() {
+        let block = simple_block_nospill();
+        assert_same_behavior(&block, &allocate(block.clone(), 4));
+    }
+
+    #[test]
+    fn can_coalesce_tied_copies() {
+        let v = op_vreg;
+        let block = Block::new(Label::Local(0), vec![
+            Instr::mov(v(5), Operand::Imm(1)),
+            Instr::mov(v(1), Operand::Imm(11)),
+            Instr::mov(v(0), Operand::Imm(6)),
+            // Frees the lowest register, which would be taken by default.
+            Instr::add(v(0), v(5)),
+            Instr::new3(OpCode::Add, v(2), v(0), v(1)),
+            Instr::new3(OpCode::Sub, v(3), v(2), v(0)),
+            Instr::mov(op_mreg(0), v(3)),
+            Instr::ret(op_mreg(0)),
+        ]);
+        let mut f = Function::new(vec![block]);
+        let hints = legalize::legalize_two_address(&mut f);
+        let block = f.blocks.pop().unwrap();
+        let allocated = allocate_block(block.clone(), 4, &hints).unwrap();
+        assert_same_behavior(&block, &allocated);
+
+        // The copies of legalize_two_address were self-moves, and are gone.
+        for &ix in &[4, 6] {
+            assert_eq!(block.instrs[ix].opcode, OpCode::Mov);
+        }
+        assert_eq!(allocated.instrs.len(), block.instrs.len() - 2, "{}", allocated);
+    }
+
+    #[test]
+    fn can_allocate_register_classes_independently() {
+        let v = op_vreg;
+        let xv = |ix| Reg::new_virt_in(RegClass::Xmm, ix).into_op();
+        let block = Block::new(Label::Local(0), vec![
+            Instr::mov(v(0), Operand::Imm(3)),
+            Instr::cvtsi2sd(xv(0), v(0)),
+            Instr::movsd(xv(1), xv(0)),
+            Instr::mulsd(xv(1), xv(0)),
+            Instr::addsd(xv(0), xv(1)),
+            Instr::ucomisd(xv(0), xv(1)),
+            Instr::mov(v(1), v(0)),
+            Instr::add(v(1), v(0)),
+            Instr::mov(op_mreg(0), v(1)),
+            Instr::ret(op_mreg(0)),
+        ]);
+        let allocated = allocate(block.clone(), 2);
+        assert_same_behavior(&block, &allocated);
+        for instr in &allocated.instrs {
+            for &(_, r, _) in &instr.reg_operands() {
+                assert!(r.is_mach() && r.mach_ix() % 16 < 2, "{}", allocated);
+            }
+        }
+        // Both classes start from their first register.
+        assert_eq!(allocated.instrs[0].ops[0], op_mreg(0));
+        assert_eq!(allocated.instrs[1].ops[0], XMM0.into_reg().into_op());
+    }
+
+    #[test]
+    fn can_allocate_around_fixed_registers() {
+        let v = op_vreg;
+        let (rax, rcx) = (op_mreg(0), op_mreg(1));
+        let block = Block::new(Label::Local(0), vec![
+            Instr::mov(v(0), Operand::Imm(100)),
+            Instr::mov(v(1), Operand::Imm(7)),
+            Instr::mov(v(2), Operand::Imm(3)),
+            Instr::mov(rax.clone(), v(0)),
+            // Both of v1 and v2 live across the use of %rax and %rdx.
+            Instr::cqo(),
+            Instr::idiv(v(1)),
+            Instr::mov(v(3), rax.clone()),
+            // The count goes through %rcx while v1 is still live.
+            Instr::shl(v(3), v(2)),
+            Instr::add(v(3), v(1)),
+            Instr::mov(rax.clone(), v(3)),
+            Instr::ret(rax.clone()),
+        ]);
+        let allocated = allocate_block(block.clone(), 4, &Hints::new()).unwrap();
+        assert_same_behavior(&block, &allocated);
+        assert_eq!(emulate(&allocated), Ok(((100 / 7) << 3) + 7));
+        let shl = allocated.instrs.iter().find(|instr| instr.opcode == OpCode::Shl).unwrap();
+        assert_eq!(shl.ops[1], rcx);
+    }
+
+    // isolate_fixed_operands gets every fixed operand its own register, but
+    // an instruction can still need more registers than there are.
+    #[test]
+    fn can_fail_with_too_few_registers() {
+        let v = op_vreg;
+        let rax = op_mreg(0);
+        let add = Block::new(Label::Local(0), vec![
+            Instr::mov(v(0), Operand::Imm(1)),
+            Instr::mov(v(1), Operand::Imm(2)),
+            Instr::add(v(0), v(1)),
+            Instr::mov(rax.clone(), v(0)),
+            Instr::ret(rax.clone()),
+        ]);
+        let err = allocate_block(add.clone(), 1, &Hints::new()).unwrap_err();
+        assert!(err.starts_with("Too many register uses"), "{}", err);
+        assert_same_behavior(&add, &allocate_block(add.clone(), 2, &Hints::new()).unwrap());
+
+        // The divisor can't go in %rax.
+        let idiv = Block::new(Label::Local(0), vec![
+            Instr::mov(v(0), Operand::Imm(7)),
+            Instr::mov(rax.clone(), Operand::Imm(42)),
+            Instr::cqo(),
+            Instr::idiv(v(0)),
+            Instr::ret(rax.clone()),
+        ]);
+        let err = allocate_block(idiv.clone(), 1, &Hints::new()).unwrap_err();
+        assert!(err.contains("idivq"), "{}", err);
+        assert_same_behavior(&idiv, &allocate_block(idiv.clone(), 2, &Hints::new()).unwrap());
+    }
+
+    #[test]
+    fn can_preserve_behavior_for_single_block_spill() {
+        let block = simple_block_spill();
+        assert_same_behavior(&block, &allocate(block.clone(), 2));
+    }
+
+    // Values that are all live at once, and then added up.
+    fn random_block(rng: &mut XorShift) -> Block {
+        let v = op_vreg;
+        let (rax, rcx) = (op_mreg(0), op_mreg(1));
+        let num_values = 4 + rng.below(6) as u32;
+        let mut instrs = vec![];
+        for ix in 0..num_values {
+            if ix < 2 || rng.chance(3) {
+                instrs.push(Instr::mov(v(ix), Operand::Imm(rng.below(100) as i64)));
+                continue;
+            }
+            let (a, b) = (v(rng.below(ix as usize) as u32), v(rng.below(ix as usize) as u32));
+            instrs.push(Instr::mov(v(ix), a));
+            match rng.below(4) {
+                0 => instrs.push(Instr::add(v(ix), b)),
+                1 => instrs.push(Instr::sub(v(ix), b)),
+                2 => {
+                    // A shift count goes through %rcx.
+                    instrs.push(Instr::mov(rcx.clone(), Operand::Imm(rng.below(4) as i64)));
+                    instrs.push(Instr::shl(v(ix), rcx.clone()));
+                }
+                _ => {
+                    // And a division through %rax and %rdx.
+                    instrs.push(Instr::mov(rax.clone(), v(ix)));
+                    instrs.push(Instr::mov(v(ix), Operand::Imm(1 + rng.below(9) as i64)));
+                    instrs.push(Instr::cqo());
+                    instrs.push(Instr::idiv(v(ix)));
+                    instrs.push(Instr::mov(v(ix), rax.clone()));
+                }
+            }
+        }
+        let sum = v(num_values);
+        instrs.push(Instr::mov(sum.clone(), Operand::Imm(0)));
+        for ix in 0..num_values {
+            instrs.push(Instr::add(sum.clone(), v(ix)));
+        }
+        instrs.push(Instr::mov(rax.clone(), sum));
+        instrs.push(Instr::ret(rax));
+        Block::new(Label::Local(0), instrs)
     }
-}
 
+    // The MachRegs that the code and its ParallelMoves put values in, leaving
+    // out those that only form an address.
+    fn value_regs(instrs: &[Instr]) -> Vec<MachReg> {
+        let moves = instrs.iter().flat_map(|instr| {
+            let moves = &instr.parallel_moves;
+            moves.start().iter().chain(moves.end()).flat_map(|m| vec![m.dst(), m.src()])
+        });
+        instrs.iter()
+            .flat_map(|instr| &instr.ops)
+            .chain(moves)
+            .filter_map(|op| match *op {
+                Operand::Reg(Reg::Mach(m)) => Some(m),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn can_allocate_the_full_register_file() {
+        let reserved = [RSP, RBP, R11];
+        let num_gprs = RegClass::Gpr.allocatable().len();
+        assert_eq!(num_gprs, 16 - reserved.len());
+        let mut rng = XorShift::new(46);
+        for _ in 0..300 {
+            let block = random_block(&mut rng);
+            for &num_regs in &[5, 8, num_gprs] {
+                let allocated = allocate_block(block.clone(), num_regs, &Hints::new()).unwrap();
+                assert!(value_regs(&allocated.instrs).iter().all(|r| !reserved.contains(r)),
+                        "{}", allocated);
+                assert_same_behavior(&block, &allocated);
+            }
+        }
+
+        let scratch = SCRATCH_REGS.iter().map(|r| r.into_reg()).collect::<Vec<_>>();
+        let mut allocated = allocate_function(loop_function(), num_gprs, &Hints::new()).unwrap();
+        frame::insert_frame(&mut allocated);
+        gap_resolver::resolve_function(&mut allocated, &scratch);
+        for n in &[0, 1, 10] {
+            assert_eq!(Emulator::new().call(&allocated, &[*n]), Ok(n * (n - 1) / 2),
+                       "{}", allocated);
+        }
+    }
+
+    #[test]
+    fn can_spill_under_high_register_pressure() {
+        let mut rng = XorShift::new(45);
+        for _ in 0..300 {
+            let block = random_block(&mut rng);
+            for &num_regs in &[3, 4] {
+                let allocated = allocate_block(block.clone(), num_regs, &Hints::new()).unwrap();
+                assert_same_behavior(&block, &allocated);
+            }
+        }
+
+        // Also across the edges of a loop.
+        let f = loop_function();
+        for &num_regs in &[2, 3] {
+            let mut allocated = allocate_function(f.clone(), num_regs, &Hints::new()).unwrap();
+            frame::insert_frame(&mut allocated);
+            gap_resolver::resolve_function(&mut allocated, &[R11.into_reg()]);
+            for n in &[0, 1, 10] {
+                assert_eq!(Emulator::new().call(&allocated, &[*n]), Ok(n * (n - 1) / 2),
+                           "{}", allocated);
+            }
+        }
+    }
+}
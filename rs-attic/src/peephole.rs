@@ -0,0 +1,471 @@
+use ::x64::*;
+
+// Cleans up allocated code. Each rule looks at the instructions of a block
+// from ix on, and can replace the first `len` of them. Runs after the
+// ParallelMoves are resolved.
+struct Rewrite {
+    len: usize,
+    instrs: Vec<Instr>,
+}
+
+type Rule = fn(&[Instr], usize) -> Option<Rewrite>;
+
+static RULES: &[Rule] = &[
+    remove_self_move,
+    remove_nop_arith,
+    zero_with_xor,
+    forward_spilled_value,
+    remove_store_of_reload,
+    fold_reload_into_use,
+];
+
+pub fn optimize_block(b: &mut Block) {
+    debug_assert!(b.instrs.iter().all(|i| i.parallel_moves.is_empty()),
+                  "Unresolved parallel moves in {}", b.label);
+    let mut ix = 0;
+    while ix < b.instrs.len() {
+        match RULES.iter().filter_map(|rule| rule(&b.instrs, ix)).next() {
+            Some(rw) => {
+                b.instrs.splice(ix..ix + rw.len, rw.instrs);
+                // The rewrite can complete a pattern that starts right before it.
+                ix = ix.saturating_sub(1);
+            }
+            None => ix += 1,
+        }
+    }
+}
+
+// Also lets blocks fall through into the next one, which makes the layout
+// final: reordering the blocks afterwards would break the fallthroughs.
+pub fn optimize_function(f: &mut Function) {
+    for b in &mut f.blocks {
+        optimize_block(b);
+    }
+    for ix in 1..f.blocks.len() {
+        let next = f.blocks[ix].label.clone();
+        let instrs = &mut f.blocks[ix - 1].instrs;
+        if instrs.len() < 2 {
+            continue;
+        }
+        if instrs.last().unwrap().jump_target() == Some(&next) {
+            instrs.pop();
+            continue;
+        }
+        // jcc next; jmp l -> j!cc l
+        let (jcc, jmp) = (&instrs[instrs.len() - 2], &instrs[instrs.len() - 1]);
+        if let (OpCode::Jcc(cond), OpCode::Jmp) = (jcc.opcode, jmp.opcode) {
+            if jcc.jump_target() == Some(&next) {
+                let target = jmp.jump_target().unwrap().clone();
+                instrs.pop();
+                *instrs.last_mut().unwrap() = Instr::jcc(cond.inverse(), target);
+            }
+        }
+    }
+}
+
+// Whether r can be read after instrs[from..]. Values can live into the
+// successors, but not past a ret.
+fn is_live_after(instrs: &[Instr], from: usize, r: Reg) -> bool {
+    for instr in &instrs[from..] {
+        if instr.inputs().iter().any(|&(_, i, _)| i == r) {
+            return true;
+        }
+        if instr.outputs().iter().any(|&(_, o, _)| o == r) {
+            return false;
+        }
+    }
+    instrs.last().is_none_or(|i| i.opcode != OpCode::Ret)
+}
+
+// The flags never live across blocks in the code we generate: every jcc
+// follows its cmp in the same block.
+fn are_flags_live_after(instrs: &[Instr], from: usize) -> bool {
+    for instr in &instrs[from..] {
+        if instr.opcode.reads_flags() {
+            return true;
+        }
+        if instr.opcode.writes_flags() {
+            return false;
+        }
+    }
+    false
+}
+
+fn delete(len: usize) -> Option<Rewrite> {
+    Some(Rewrite { len, instrs: vec![] })
+}
+
+// mov r, r
+fn remove_self_move(instrs: &[Instr], ix: usize) -> Option<Rewrite> {
+    let i = &instrs[ix];
+    if matches!(i.opcode, OpCode::Mov | OpCode::Movsd) && i.ops[0].is_reg() &&
+        i.ops[0] == i.ops[1] {
+        return delete(1);
+    }
+    None
+}
+
+// add r, 0 and sub r, 0, unless the flags they set are read.
+fn remove_nop_arith(instrs: &[Instr], ix: usize) -> Option<Rewrite> {
+    let i = &instrs[ix];
+    if matches!(i.opcode, OpCode::Add | OpCode::Sub) && i.ops[1] == Operand::Imm(0) &&
+        !are_flags_live_after(instrs, ix + 1) {
+        return delete(1);
+    }
+    None
+}
+
+// mov r, 0 -> xor r, r, which is shorter but clobbers the flags.
+fn zero_with_xor(instrs: &[Instr], ix: usize) -> Option<Rewrite> {
+    let i = &instrs[ix];
+    if i.opcode == OpCode::Mov && i.ops[0].is_reg() && i.ops[1] == Operand::Imm(0) &&
+        !are_flags_live_after(instrs, ix + 1) {
+        let r = i.ops[0].clone();
+        return Some(Rewrite { len: 1, instrs: vec![Instr::xor(r.clone(), r)] });
+    }
+    None
+}
+
+// The (mem, reg) of `mov mem, reg` or the (reg, mem) of `mov reg, mem`.
+fn as_mov(instr: Option<&Instr>) -> Option<(&Operand, &Operand)> {
+    match instr {
+        Some(i) if i.opcode == OpCode::Mov && (i.ops[0].is_mem() || i.ops[1].is_mem()) &&
+            (i.ops[0].is_reg() || i.ops[1].is_reg()) => Some((&i.ops[0], &i.ops[1])),
+        _ => None,
+    }
+}
+
+// mov m, r; mov r2, m -> mov m, r; mov r2, r
+fn forward_spilled_value(instrs: &[Instr], ix: usize) -> Option<Rewrite> {
+    let (m, r) = as_mov(instrs.get(ix))?;
+    let (r2, m2) = as_mov(instrs.get(ix + 1))?;
+    if !m.is_mem() || m2 != m {
+        return None;
+    }
+    let mut rewritten = vec![instrs[ix].clone()];
+    if r2 != r {
+        rewritten.push(Instr::mov(r2.clone(), r.clone()));
+    }
+    Some(Rewrite { len: 2, instrs: rewritten })
+}
+
+// mov r, m; mov m, r -> mov r, m
+fn remove_store_of_reload(instrs: &[Instr], ix: usize) -> Option<Rewrite> {
+    let (r, m) = as_mov(instrs.get(ix))?;
+    let (m2, r2) = as_mov(instrs.get(ix + 1))?;
+    match *r {
+        // Otherwise the store goes to a different address.
+        Operand::Reg(reg) if m2 == m && r2 == r && !m.mentions(reg) => {
+            Some(Rewrite { len: 2, instrs: vec![instrs[ix].clone()] })
+        }
+        _ => None,
+    }
+}
+
+// mov r, m; op d, r -> op d, m, if r is dead after that.
+fn fold_reload_into_use(instrs: &[Instr], ix: usize) -> Option<Rewrite> {
+    let (r, m) = as_mov(instrs.get(ix))?;
+    let r = match *r {
+        Operand::Reg(r) if m.is_mem() && !m.mentions(r) => r,
+        _ => return None,
+    };
+    let user = instrs.get(ix + 1)?;
+    let folds = matches!(user.opcode, OpCode::Add | OpCode::Sub | OpCode::Xor |
+                                      OpCode::IMul | OpCode::Cmp | OpCode::Mov);
+    if !folds || user.ops[1] != r.into_op() || !user.ops[0].is_reg() ||
+        user.ops[0].mentions(r) || is_live_after(instrs, ix + 2, r) {
+        return None;
+    }
+    let folded = Instr::new2(user.opcode, user.ops[0].clone(), m.clone());
+    Some(Rewrite { len: 2, instrs: vec![folded] })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ::frame;
+    use ::test_utils::{self, XorShift};
+    use ::x64::emu::Emulator;
+
+    fn r(m: MachReg) -> Operand {
+        m.into_reg().into_op()
+    }
+
+    fn slot(ix: i32) -> Operand {
+        Mem::new(Reg::rsp(), 8 * ix).into_op()
+    }
+
+    fn label(ix: u32) -> Label {
+        Label::Local(ix)
+    }
+
+    fn imm(i: i64) -> Operand {
+        Operand::Imm(i)
+    }
+
+    fn emulate(f: &Function, args: &[i64]) -> Result<i64, String> {
+        let mut f = f.clone();
+        frame::insert_frame(&mut f);
+        Emulator::new().call(&f, args)
+    }
+
+    // Optimizes a single block, and checks it against the emulator.
+    fn assert_optimizes_to(tag: &str, before: Vec<Instr>, expected: Vec<Instr>) {
+        let before = Function::new(vec![Block::new(label(0), before)]);
+        let mut after = before.clone();
+        optimize_function(&mut after);
+        test_utils::assert_eq_pretty(tag, &after.blocks[0].instrs, &expected);
+        for args in &[[0, 0], [3, -7]] {
+            let res = emulate(&before, args);
+            assert!(res.is_ok(), "{:?}", res);
+            assert_eq!(emulate(&after, args), res);
+        }
+    }
+
+    #[test]
+    fn can_remove_self_moves_and_nop_arith() {
+        assert_optimizes_to("peephole-nops", vec![
+            Instr::mov(r(RAX), r(RDI)),
+            Instr::mov(r(RAX), r(RAX)),
+            Instr::add(r(RAX), imm(0)),
+            Instr::sub(r(RAX), imm(0)),
+            Instr::add(r(RAX), r(RSI)),
+            Instr::ret(r(RAX)),
+        ], vec![
+            Instr::mov(r(RAX), r(RDI)),
+            Instr::add(r(RAX), r(RSI)),
+            Instr::ret(r(RAX)),
+        ]);
+    }
+
+    #[test]
+    fn can_zero_with_xor() {
+        assert_optimizes_to("peephole-xor", vec![
+            Instr::mov(r(RAX), imm(0)),
+            Instr::add(r(RAX), r(RDI)),
+            Instr::ret(r(RAX)),
+        ], vec![
+            Instr::xor(r(RAX), r(RAX)),
+            Instr::add(r(RAX), r(RDI)),
+            Instr::ret(r(RAX)),
+        ]);
+    }
+
+    #[test]
+    fn can_keep_live_flags() {
+        // The mov can't touch the flags of the cmp.
+        let f = Function::new(vec![
+            Block::new(label(0), vec![
+                Instr::mov(r(RAX), r(RDI)),
+                Instr::cmp(r(RDI), r(RSI)),
+                Instr::mov(r(RAX), imm(0)),
+                Instr::jcc(Cond::L, label(2)),
+                Instr::jmp(label(1)),
+            ]),
+            Block::new(label(1), vec![Instr::ret(r(RAX))]),
+            Block::new(label(2), vec![Instr::mov(r(RAX), r(RDI)), Instr::ret(r(RAX))]),
+        ]);
+        let mut after = f.clone();
+        optimize_function(&mut after);
+        assert_eq!(after.blocks[0].instrs[..4], f.blocks[0].instrs[..4]);
+        // The jmp to the next block is gone instead.
+        assert_eq!(after.blocks[0].instrs.len(), 4);
+        assert_eq!(after.verify(), Ok(()));
+        assert_eq!(emulate(&after, &[1, 2]), Ok(1));
+        assert_eq!(emulate(&after, &[2, 1]), Ok(0));
+
+        // Nor can the add, as its flags are read.
+        let mut b = Block::new(label(0), vec![
+            Instr::add(r(RDI), imm(0)),
+            Instr::jcc(Cond::E, label(1)),
+        ]);
+        let before = b.clone();
+        optimize_block(&mut b);
+        assert_eq!(b, before);
+    }
+
+    #[test]
+    fn can_forward_spilled_values() {
+        assert_optimizes_to("peephole-spill-reload", vec![
+            Instr::mov(r(RCX), r(RDI)),
+            Instr::mov(slot(0), r(RCX)),
+            Instr::mov(r(RCX), slot(0)),
+            Instr::mov(slot(1), r(RSI)),
+            Instr::mov(r(RAX), slot(1)),
+            Instr::add(r(RAX), r(RCX)),
+            Instr::mov(r(RDX), slot(0)),
+            Instr::mov(slot(0), r(RDX)),
+            Instr::add(r(RAX), r(RDX)),
+            Instr::ret(r(RAX)),
+        ], vec![
+            Instr::mov(r(RCX), r(RDI)),
+            Instr::mov(slot(0), r(RCX)),
+            Instr::mov(slot(1), r(RSI)),
+            Instr::mov(r(RAX), r(RSI)),
+            Instr::add(r(RAX), r(RCX)),
+            Instr::add(r(RAX), slot(0)),
+            Instr::ret(r(RAX)),
+        ]);
+    }
+
+    #[test]
+    fn can_fold_reloads_into_uses() {
+        assert_optimizes_to("peephole-fold-reload", vec![
+            Instr::mov(slot(0), r(RDI)),
+            Instr::mov(slot(1), r(RSI)),
+            Instr::mov(r(RAX), imm(10)),
+            Instr::mov(r(RCX), slot(0)),
+            Instr::imul(r(RAX), r(RCX)),
+            // Still read by the add.
+            Instr::mov(r(RDX), slot(1)),
+            Instr::sub(r(RAX), r(RDX)),
+            Instr::add(r(RAX), r(RDX)),
+            Instr::ret(r(RAX)),
+        ], vec![
+            Instr::mov(slot(0), r(RDI)),
+            Instr::mov(slot(1), r(RSI)),
+            Instr::mov(r(RAX), imm(10)),
+            Instr::imul(r(RAX), slot(0)),
+            Instr::mov(r(RDX), slot(1)),
+            Instr::sub(r(RAX), r(RDX)),
+            Instr::add(r(RAX), r(RDX)),
+            Instr::ret(r(RAX)),
+        ]);
+    }
+
+    #[test]
+    fn can_remove_jumps_to_next_block() {
+        // return p0 < p1 ? 1 : 2
+        let f = Function::new(vec![
+            Block::new(label(0), vec![
+                Instr::cmp(r(RDI), r(RSI)),
+                Instr::jcc(Cond::L, label(1)),
+                Instr::jmp(label(2)),
+            ]),
+            Block::new(label(1), vec![Instr::mov(r(RAX), imm(1)), Instr::jmp(label(3))]),
+            Block::new(label(2), vec![Instr::mov(r(RAX), imm(2)), Instr::jmp(label(3))]),
+            Block::new(label(3), vec![Instr::ret(r(RAX))]),
+        ]);
+        let mut after = f.clone();
+        optimize_function(&mut after);
+        assert_eq!(after.verify(), Ok(()));
+        assert_eq!(after.blocks[0].instrs[1..], [Instr::jcc(Cond::Ge, label(2))]);
+        assert_eq!(after.blocks[1].instrs.last(), Some(&Instr::jmp(label(3))));
+        assert_eq!(after.blocks[2].instrs.last(), Some(&Instr::mov(r(RAX), imm(2))));
+        assert_eq!(after.successors(), vec![vec![2, 1], vec![3], vec![3], vec![]]);
+        for args in &[[1, 2], [2, 1]] {
+            assert_eq!(emulate(&after, args), emulate(&f, args));
+        }
+    }
+
+    const REGS: &[MachReg] = &[RAX, RCX, RDX, RSI, RDI, R8, R9];
+    const NUM_SLOTS: i32 = 4;
+
+    fn random_reg(rng: &mut XorShift) -> Operand {
+        r(*rng.pick(REGS))
+    }
+
+    fn random_src(rng: &mut XorShift) -> Operand {
+        match rng.below(4) {
+            0 => imm(0),
+            1 => imm(rng.below(100) as i64 - 50),
+            _ => random_reg(rng),
+        }
+    }
+
+    // Looks like allocated code, with spills and reloads around.
+    fn random_body(rng: &mut XorShift, len: usize) -> Vec<Instr> {
+        let mut instrs = vec![];
+        for _ in 0..len {
+            let s = slot(rng.below(NUM_SLOTS as usize) as i32);
+            let (a, b) = (random_reg(rng), random_reg(rng));
+            match rng.below(10) {
+                0 => instrs.push(Instr::mov(a, random_src(rng))),
+                1 => instrs.push(Instr::mov(a.clone(), a)),
+                2 => {
+                    instrs.push(Instr::mov(s.clone(), a));
+                    instrs.push(Instr::mov(b, s));
+                }
+                3 => {
+                    instrs.push(Instr::mov(a.clone(), s.clone()));
+                    instrs.push(Instr::mov(s, a));
+                }
+                4 => {
+                    instrs.push(Instr::mov(a.clone(), s));
+                    let opcode = *rng.pick(&[OpCode::Add, OpCode::Sub, OpCode::IMul]);
+                    instrs.push(Instr::new2(opcode, b, a));
+                }
+                5 => instrs.push(Instr::add(a, random_src(rng))),
+                6 => instrs.push(Instr::sub(a, random_src(rng))),
+                7 => instrs.push(Instr::imul(a, b)),
+                8 => instrs.push(Instr::xor(a, b)),
+                _ => instrs.push(Instr::mov(s, a)),
+            }
+        }
+        instrs
+    }
+
+    // Mixes all the regs and slots into the result.
+    fn checksum() -> Vec<Instr> {
+        let mut instrs = vec![];
+        for &reg in REGS.iter().filter(|&&reg| reg != RAX) {
+            instrs.push(Instr::imul(r(RAX), imm(31)));
+            instrs.push(Instr::add(r(RAX), r(reg)));
+        }
+        for ix in 0..NUM_SLOTS {
+            instrs.push(Instr::imul(r(RAX), imm(31)));
+            instrs.push(Instr::add(r(RAX), slot(ix)));
+        }
+        instrs.push(Instr::ret(r(RAX)));
+        instrs
+    }
+
+    fn random_function(rng: &mut XorShift) -> Function {
+        let mut entry = vec![];
+        for &reg in REGS {
+            entry.push(Instr::mov(r(reg), imm(rng.below(3) as i64)));
+        }
+        for ix in 0..NUM_SLOTS {
+            entry.push(Instr::mov(slot(ix), random_reg(rng)));
+        }
+        entry.extend(random_body(rng, 20));
+        entry.push(Instr::cmp(random_reg(rng), random_reg(rng)));
+        // Can't be touched while the flags are live.
+        for _ in 0..rng.below(3) {
+            let (a, b) = (random_reg(rng), random_reg(rng));
+            entry.push(if rng.chance(2) { Instr::mov(a, imm(0)) } else { Instr::mov(a, b) });
+        }
+        let cond = *rng.pick(&[Cond::L, Cond::Le, Cond::G, Cond::Ge, Cond::E, Cond::Ne]);
+        entry.push(Instr::jcc(cond, label(1)));
+        entry.push(Instr::jmp(label(2)));
+
+        let mut if_true = random_body(rng, 10);
+        if_true.push(Instr::jmp(label(3)));
+        let mut if_false = random_body(rng, 10);
+        if_false.push(Instr::jmp(label(3)));
+        Function::new(vec![
+            Block::new(label(0), entry),
+            Block::new(label(1), if_true),
+            Block::new(label(2), if_false),
+            Block::new(label(3), checksum()),
+        ])
+    }
+
+    #[test]
+    fn random_functions_keep_their_behavior() {
+        let mut rng = XorShift::new(38);
+        let (mut before_len, mut after_len) = (0, 0);
+        for _ in 0..200 {
+            let f = random_function(&mut rng);
+            let mut after = f.clone();
+            optimize_function(&mut after);
+            assert_eq!(after.verify(), Ok(()));
+            let expected = emulate(&f, &[]);
+            assert!(expected.is_ok(), "{:?}\n{}", expected, f);
+            assert_eq!(emulate(&after, &[]), expected, "{}\n=>\n{}", f, after);
+            before_len += f.blocks.iter().map(|b| b.instrs.len()).sum::<usize>();
+            after_len += after.blocks.iter().map(|b| b.instrs.len()).sum::<usize>();
+        }
+        assert!(after_len * 10 < before_len * 9, "{} -> {}", before_len, after_len);
+    }
+}
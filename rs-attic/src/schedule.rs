@@ -0,0 +1,87 @@
+use ::graph::{Graph, Id, NodeView};
+
+// The result of global code motion: the nodes of a Graph placed into basic
+// blocks. blocks[0] is the entry.
+#[derive(Debug, Clone, Default)]
+pub struct Schedule {
+    pub blocks: Vec<ScheduledBlock>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ScheduledBlock {
+    // In execution order. Phis come first, and a Branch or a Return can only
+    // be the last.
+    pub nodes: Vec<Id>,
+    // A Branch goes to [if_true, if_false]. A block that doesn't end with a
+    // Branch or a Return jumps to its only successor.
+    pub succs: Vec<usize>,
+    // In the order of the value_inputs of the Phis.
+    pub preds: Vec<usize>,
+}
+
+impl Schedule {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_block(&mut self, nodes: Vec<Id>) -> usize {
+        self.blocks.push(ScheduledBlock { nodes, ..ScheduledBlock::default() });
+        self.blocks.len() - 1
+    }
+
+    pub fn add_edge(&mut self, from: usize, to: usize) {
+        self.blocks[from].succs.push(to);
+        self.blocks[to].preds.push(from);
+    }
+
+    pub fn verify(&self, g: &Graph) -> Result<(), String> {
+        let mut scheduled = vec![];
+        for (ix, b) in self.blocks.iter().enumerate() {
+            let mut num_succs = 1;
+            let mut seen_non_phi = false;
+            for (node_ix, &n) in b.nodes.iter().enumerate() {
+                if scheduled.contains(&n) {
+                    return Err(format!("{:?} is scheduled twice", n));
+                }
+                scheduled.push(n);
+                let is_last = node_ix + 1 == b.nodes.len();
+                match g.view_node(n) {
+                    NodeView::Phi { value_inputs, .. } => {
+                        if seen_non_phi {
+                            return Err(format!("Phi {:?} is not at the start of B{}", n, ix));
+                        }
+                        if value_inputs.len() != b.preds.len() {
+                            return Err(format!("Phi {:?} has {} inputs but B{} has {} preds",
+                                               n, value_inputs.len(), ix, b.preds.len()));
+                        }
+                    }
+                    NodeView::Branch(_) | NodeView::Return(_) if !is_last => {
+                        return Err(format!("{:?} is not at the end of B{}", n, ix));
+                    }
+                    NodeView::Branch(_) => num_succs = 2,
+                    NodeView::Return(_) => num_succs = 0,
+                    NodeView::Parameter(_) if ix != 0 => {
+                        return Err(format!("Parameter {:?} is not in the entry", n));
+                    }
+                    NodeView::Merge(_) | NodeView::Dead => {
+                        return Err(format!("{:?} can't be scheduled", n));
+                    }
+                    _ => (),
+                }
+                if !matches!(g.view_node(n), NodeView::Phi { .. }) {
+                    seen_non_phi = true;
+                }
+            }
+            if b.succs.len() != num_succs {
+                return Err(format!("B{} should have {} succs, not {}",
+                                   ix, num_succs, b.succs.len()));
+            }
+            for (succ_ix, succ) in b.succs.iter().enumerate() {
+                if b.succs[..succ_ix].contains(succ) {
+                    return Err(format!("B{} has two edges to B{}", ix, succ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
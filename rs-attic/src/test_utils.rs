@@ -17,3 +17,34 @@ pub fn assert_eq_pretty<A: fmt::Debug + Eq>(tag: &str, lhs: &A, rhs: &A) {
         panic!("[{}] lhs != rhs", tag);
     }
 }
+
+// A tiny deterministic PRNG for the randomized tests, so that they don't need
+// an external crate and failures are reproducible from the seed.
+pub struct XorShift(u64);
+
+impl XorShift {
+    pub fn new(seed: u64) -> Self {
+        XorShift(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.0 = x;
+        x
+    }
+
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+
+    pub fn chance(&mut self, one_in: usize) -> bool {
+        self.below(one_in) == 0
+    }
+
+    pub fn pick<'a, A>(&mut self, xs: &'a [A]) -> &'a A {
+        &xs[self.below(xs.len())]
+    }
+}
@@ -1,16 +1,35 @@
-use std::fmt;
+use std::{fmt, mem};
+use std::collections::HashMap;
+
+pub mod encode;
+pub mod decode;
+pub mod emu;
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub enum Operand {
     Reg(Reg),
     Mem(Mem),
     Imm(Imm),
+    // Target of a jump or a direct call.
+    Label(Label),
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd)]
 pub enum RegLocInInstr {
-    Dst(RegLocInOp),
-    Src(RegLocInOp),
+    // ops[ix], as described by OpCodeDesc::operands[ix].
+    Explicit(u8, RegLocInOp),
+    // OpCodeDesc::implicit[ix]. Always a fixed MachReg.
+    Implicit(u8),
+    // OpCodeDesc::clobbers[ix].
+    Clobber(u8),
+    // A register operand of parallel_moves.start()[ix].
+    StartMove(u8, MoveSide),
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub enum MoveSide {
+    Dst,
+    Src,
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd)]
@@ -20,13 +39,37 @@ pub enum RegLocInOp {
     MemIndex,
 }
 
-pub type Imm = u32;
+pub type Imm = i64;
 
+// [base + index * scale + disp]
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct Mem {
-    pub base: Reg,
+    pub base: Option<MemBase>,
     pub index: Option<Reg>,
-    pub disp: u32,
+    pub scale: Scale,
+    pub disp: i32,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum MemBase {
+    Reg(Reg),
+    // [rip + label + disp]. Can't be used together with an index.
+    Rip(Label),
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub enum Scale {
+    S1,
+    S2,
+    S4,
+    S8,
+}
+
+#[derive(Debug, Hash, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub enum Label {
+    Local(u32),
+    // A symbol, possibly defined outside of the code being assembled.
+    Named(String),
 }
 
 #[derive(Debug, Hash, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
@@ -36,7 +79,8 @@ pub enum Reg {
 }
 
 #[derive(Hash, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
-pub struct VirtualReg(pub u32);
+pub struct VirtualReg(pub u32, pub RegClass);
+// 0..16 are the GPRs and 16..32 are the XMMs, see RegClass::reg.
 #[derive(Hash, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
 pub struct MachReg(pub u32);
 
@@ -62,13 +106,116 @@ pub struct ParallelMove {
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
 pub enum OpCode {
     Add,
+    Sub,
+    Xor,
+    IMul,
     Mov,
+    MovAbs,
+    Lea,
+    Cmp,
+    Cqo,
+    IDiv,
+    Shl,
+    Sar,
+    // With the number of arguments passed in ARG_REGS.
+    Call(u8),
     Ret,
+    Xchg,
+    Push,
+    Pop,
+    Jmp,
+    Jcc(Cond),
+    // Sets the low byte of its operand to whether the Cond holds, and keeps
+    // the rest: zero the operand first for a 0 or 1.
+    Setcc(Cond),
+    // Scalar doubles in the low half of an XMM.
+    Movsd,
+    Addsd,
+    Mulsd,
+    Cvtsi2sd,
+    // Sets the flags like an unsigned cmp, and all of zf, pf and cf if
+    // either is a NaN.
+    Ucomisd,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub enum Cond {
+    L,
+    Le,
+    G,
+    Ge,
+    E,
+    Ne,
+    // Unsigned: below, below or equal, above, above or equal.
+    B,
+    Be,
+    A,
+    Ae,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub enum OperandRole {
+    // Read at the start of the instruction.
+    Use,
+    // Written at the end of the instruction.
+    Def,
+    // Read, then written in place (the two-address form).
+    UseDef,
+    // Written before all the inputs are consumed, so it must not share a
+    // register with any of them.
+    EarlyClobber,
+}
+
+#[derive(Debug, Hash, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub enum RegClass {
+    Gpr,
+    Xmm,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct OperandDesc {
+    pub role: OperandRole,
+    pub class: RegClass,
+    // The operand must live in this register at the instruction.
+    pub fixed: Option<MachReg>,
+    // The widest immediate that can be used in place of the register.
+    pub imm: Option<ImmWidth>,
+}
+
+// Immediates are always sign-extended to 64 bits.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub enum ImmWidth {
+    Imm8,
+    Imm32,
+    Imm64,
 }
 
 #[derive(Debug)]
+pub struct OpCodeDesc {
+    // One for each of Instr::ops.
+    pub operands: &'static [OperandDesc],
+    // Registers accessed without being spelled out, e.g. rdx:rax for idiv.
+    // These must all be fixed.
+    pub implicit: &'static [OperandDesc],
+    // Registers whose values are destroyed, e.g. caller-saved ones for call.
+    pub clobbers: &'static [MachReg],
+}
+
+// A block always ends with a jmp or a ret, optionally preceded by jccs, so
+// there's no fallthrough between blocks.
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub struct Block {
+    pub label: Label,
     pub instrs: Vec<Instr>,
+    // Rarely executed, e.g. an error path. Laid out after all the others.
+    pub is_cold: bool,
+}
+
+// blocks[0] is the entry. Block ids used elsewhere (e.g. by RegContext) are
+// indices into blocks.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Function {
+    pub blocks: Vec<Block>,
 }
 
 // `Zipper` to blocks[id].instrs[id].use_kind[ix]
@@ -79,6 +226,9 @@ pub struct RegContext {
     pub block_id: u32,
     pub instr_ix: u32,
     pub operand_ix: RegLocInInstr,
+    // Constraints from the OperandDesc.
+    pub class: RegClass,
+    pub fixed: Option<MachReg>,
 }
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd)]
@@ -87,11 +237,240 @@ pub enum UseKind {
     Output,
 }
 
+pub const RAX: MachReg = MachReg(0);
+pub const RCX: MachReg = MachReg(1);
+pub const RDX: MachReg = MachReg(2);
+pub const RBX: MachReg = MachReg(3);
+pub const RSP: MachReg = MachReg(4);
+pub const RBP: MachReg = MachReg(5);
+pub const RSI: MachReg = MachReg(6);
+pub const RDI: MachReg = MachReg(7);
+pub const R8: MachReg = MachReg(8);
+pub const R9: MachReg = MachReg(9);
+pub const R10: MachReg = MachReg(10);
+pub const R11: MachReg = MachReg(11);
+pub const R12: MachReg = MachReg(12);
+pub const R13: MachReg = MachReg(13);
+pub const R14: MachReg = MachReg(14);
+pub const R15: MachReg = MachReg(15);
+pub const XMM0: MachReg = MachReg(16);
+pub const XMM1: MachReg = MachReg(17);
+pub const XMM2: MachReg = MachReg(18);
+pub const XMM3: MachReg = MachReg(19);
+pub const XMM4: MachReg = MachReg(20);
+pub const XMM5: MachReg = MachReg(21);
+pub const XMM6: MachReg = MachReg(22);
+pub const XMM7: MachReg = MachReg(23);
+pub const XMM8: MachReg = MachReg(24);
+pub const XMM9: MachReg = MachReg(25);
+pub const XMM10: MachReg = MachReg(26);
+pub const XMM11: MachReg = MachReg(27);
+pub const XMM12: MachReg = MachReg(28);
+pub const XMM13: MachReg = MachReg(29);
+pub const XMM14: MachReg = MachReg(30);
+pub const XMM15: MachReg = MachReg(31);
+
+const REG_NAMES: [&str; 32] = [
+    "rax", "rcx", "rdx", "rbx", "rsp", "rbp", "rsi", "rdi",
+    "r8", "r9", "r10", "r11", "r12", "r13", "r14", "r15",
+    "xmm0", "xmm1", "xmm2", "xmm3", "xmm4", "xmm5", "xmm6", "xmm7",
+    "xmm8", "xmm9", "xmm10", "xmm11", "xmm12", "xmm13", "xmm14", "xmm15",
+];
+
+// The low bytes of the GPRs, as written by setcc.
+const BYTE_REG_NAMES: [&str; 16] = [
+    "al", "cl", "dl", "bl", "spl", "bpl", "sil", "dil",
+    "r8b", "r9b", "r10b", "r11b", "r12b", "r13b", "r14b", "r15b",
+];
+
+// System V AMD64. %rsp and %rbp are reserved for the frame (see ::frame).
+// All the XMMs are caller-saved.
+pub const CALLER_SAVED: &[MachReg] = &[
+    RAX, RCX, RDX, RSI, RDI, R8, R9, R10, R11,
+    XMM0, XMM1, XMM2, XMM3, XMM4, XMM5, XMM6, XMM7,
+    XMM8, XMM9, XMM10, XMM11, XMM12, XMM13, XMM14, XMM15,
+];
+pub const CALLEE_SAVED: &[MachReg] = &[RBX, RBP, R12, R13, R14, R15];
+// One of each RegClass, for gap_resolver::resolve_function to break cycles
+// with after register allocation.
+pub const SCRATCH_REGS: &[MachReg] = &[R11, XMM15];
+// What the register allocator hands out, in this order: all but %rsp, %rbp
+// and the SCRATCH_REGS.
+const ALLOCATABLE_GPRS: &[MachReg] = &[
+    RAX, RCX, RDX, RBX, RSI, RDI, R8, R9, R10, R12, R13, R14, R15,
+];
+const ALLOCATABLE_XMMS: &[MachReg] = &[
+    XMM0, XMM1, XMM2, XMM3, XMM4, XMM5, XMM6, XMM7,
+    XMM8, XMM9, XMM10, XMM11, XMM12, XMM13, XMM14,
+];
+// The first integer arguments, in order. The rest are passed on the stack.
+pub const ARG_REGS: &[MachReg] = &[RDI, RSI, RDX, RCX, R8, R9];
+
+// Descriptor table
+
+const fn gpr(role: OperandRole) -> OperandDesc {
+    OperandDesc { role, class: RegClass::Gpr, fixed: None, imm: None }
+}
+
+const fn xmm(role: OperandRole) -> OperandDesc {
+    OperandDesc { role, class: RegClass::Xmm, fixed: None, imm: None }
+}
+
+const fn fixed(role: OperandRole, r: MachReg) -> OperandDesc {
+    OperandDesc { role, class: r.class(), fixed: Some(r), imm: None }
+}
+
+const fn or_imm(desc: OperandDesc, width: ImmWidth) -> OperandDesc {
+    OperandDesc { imm: Some(width), ..desc }
+}
+
+static BINARY_INPLACE: OpCodeDesc = OpCodeDesc {
+    operands: &[gpr(OperandRole::UseDef),
+                or_imm(gpr(OperandRole::Use), ImmWidth::Imm32)],
+    implicit: &[],
+    clobbers: &[],
+};
+
+static SHIFT: OpCodeDesc = OpCodeDesc {
+    operands: &[gpr(OperandRole::UseDef),
+                or_imm(fixed(OperandRole::Use, RCX), ImmWidth::Imm8)],
+    implicit: &[],
+    clobbers: &[],
+};
+
+static MOV: OpCodeDesc = OpCodeDesc {
+    operands: &[gpr(OperandRole::Def),
+                or_imm(gpr(OperandRole::Use), ImmWidth::Imm32)],
+    implicit: &[],
+    clobbers: &[],
+};
+
+// Only the register dst form.
+static MOVABS: OpCodeDesc = OpCodeDesc {
+    operands: &[gpr(OperandRole::Def),
+                or_imm(gpr(OperandRole::Use), ImmWidth::Imm64)],
+    implicit: &[],
+    clobbers: &[],
+};
+
+// The src is a Mem, whose address is computed but not read.
+static LEA: OpCodeDesc = OpCodeDesc {
+    operands: &[gpr(OperandRole::Def), gpr(OperandRole::Use)],
+    implicit: &[],
+    clobbers: &[],
+};
+
+static CMP: OpCodeDesc = OpCodeDesc {
+    operands: &[gpr(OperandRole::Use),
+                or_imm(gpr(OperandRole::Use), ImmWidth::Imm32)],
+    implicit: &[],
+    clobbers: &[],
+};
+
+static CQO: OpCodeDesc = OpCodeDesc {
+    operands: &[],
+    implicit: &[fixed(OperandRole::Use, RAX), fixed(OperandRole::Def, RDX)],
+    clobbers: &[],
+};
+
+static IDIV: OpCodeDesc = OpCodeDesc {
+    operands: &[gpr(OperandRole::Use)],
+    implicit: &[fixed(OperandRole::UseDef, RAX), fixed(OperandRole::UseDef, RDX)],
+    clobbers: &[],
+};
+
+const ARG_USES: &[OperandDesc] = &[
+    fixed(OperandRole::Use, RDI), fixed(OperandRole::Use, RSI),
+    fixed(OperandRole::Use, RDX), fixed(OperandRole::Use, RCX),
+    fixed(OperandRole::Use, R8), fixed(OperandRole::Use, R9),
+];
+
+const CALL_TARGET: &[OperandDesc] = &[gpr(OperandRole::Use)];
+
+// Only reads the ARG_REGS that hold an argument, so that the others are free
+// until the call.
+const fn call(num_args: usize) -> OpCodeDesc {
+    OpCodeDesc {
+        operands: CALL_TARGET,
+        implicit: ARG_USES.split_at(num_args).0,
+        clobbers: CALLER_SAVED,
+    }
+}
+
+static CALLS: [OpCodeDesc; 7] = [call(0), call(1), call(2), call(3), call(4), call(5), call(6)];
+
+static RET: OpCodeDesc = OpCodeDesc {
+    operands: &[fixed(OperandRole::Use, RAX)],
+    implicit: &[],
+    clobbers: &[],
+};
+
+static XCHG: OpCodeDesc = OpCodeDesc {
+    operands: &[gpr(OperandRole::UseDef), gpr(OperandRole::UseDef)],
+    implicit: &[],
+    clobbers: &[],
+};
+
+static PUSH: OpCodeDesc = OpCodeDesc {
+    operands: &[gpr(OperandRole::Use)],
+    implicit: &[],
+    clobbers: &[],
+};
+
+static POP: OpCodeDesc = OpCodeDesc {
+    operands: &[gpr(OperandRole::Def)],
+    implicit: &[],
+    clobbers: &[],
+};
+
+// The operand is a Label.
+static JMP: OpCodeDesc = OpCodeDesc {
+    operands: &[gpr(OperandRole::Use)],
+    implicit: &[],
+    clobbers: &[],
+};
+
+// Either side can be a m64.
+static MOVSD: OpCodeDesc = OpCodeDesc {
+    operands: &[xmm(OperandRole::Def), xmm(OperandRole::Use)],
+    implicit: &[],
+    clobbers: &[],
+};
+
+// The src can be a m64.
+static BINARY_SSE: OpCodeDesc = OpCodeDesc {
+    operands: &[xmm(OperandRole::UseDef), xmm(OperandRole::Use)],
+    implicit: &[],
+    clobbers: &[],
+};
+
+static CVTSI2SD: OpCodeDesc = OpCodeDesc {
+    operands: &[xmm(OperandRole::Def), gpr(OperandRole::Use)],
+    implicit: &[],
+    clobbers: &[],
+};
+
+static UCOMISD: OpCodeDesc = OpCodeDesc {
+    operands: &[xmm(OperandRole::Use), xmm(OperandRole::Use)],
+    implicit: &[],
+    clobbers: &[],
+};
+
+// Only writes the low byte, so the rest is read too.
+static SETCC: OpCodeDesc = OpCodeDesc {
+    operands: &[gpr(OperandRole::UseDef)],
+    implicit: &[],
+    clobbers: &[],
+};
+
 // Impls
 
 impl fmt::Debug for VirtualReg {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
-        write!(fmt, "%v{}", self.0)
+        match self.1 {
+            RegClass::Gpr => write!(fmt, "%v{}", self.0),
+            RegClass::Xmm => write!(fmt, "%xv{}", self.0),
+        }
     }
 }
 
@@ -101,31 +480,460 @@ impl fmt::Debug for MachReg {
     }
 }
 
+// AT&T syntax, which is what the Kotlin side emits as well.
+
+impl fmt::Display for MachReg {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "%{}", self.name())
+    }
+}
+
+impl fmt::Display for Reg {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Reg::Virtual(v) => write!(fmt, "{:?}", v),
+            Reg::Mach(m) => write!(fmt, "{}", m),
+        }
+    }
+}
+
+impl fmt::Display for Label {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Label::Local(ix) => write!(fmt, ".L{}", ix),
+            Label::Named(ref name) => write!(fmt, "{}", name),
+        }
+    }
+}
+
+impl fmt::Display for Mem {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(label) = self.rip_label() {
+            write!(fmt, "{}", label)?;
+            if self.disp != 0 {
+                write!(fmt, "{:+}", self.disp)?;
+            }
+            return write!(fmt, "(%rip)");
+        }
+        if self.disp != 0 || (self.base.is_none() && self.index.is_none()) {
+            write!(fmt, "{}", self.disp)?;
+        }
+        write!(fmt, "(")?;
+        if let Some(base) = self.base_reg() {
+            write!(fmt, "{}", base)?;
+        }
+        if let Some(index) = self.index {
+            write!(fmt, ", {}, {}", index, self.scale.factor())?;
+        }
+        write!(fmt, ")")
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Operand::Reg(r) => write!(fmt, "{}", r),
+            Operand::Mem(ref m) => write!(fmt, "{}", m),
+            Operand::Imm(i) => write!(fmt, "${}", i),
+            Operand::Label(ref l) => write!(fmt, "{}", l),
+        }
+    }
+}
+
+impl fmt::Display for Instr {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{}", self.opcode.mnemonic())?;
+        let ops = match self.opcode {
+            // The operand of ret is only there to keep %rax alive.
+            OpCode::Ret => return Ok(()),
+            OpCode::Call(_) if !self.ops[0].is_label() => {
+                return write!(fmt, " *{}", self.ops[0]);
+            }
+            _ => &self.ops,
+        };
+        // AT&T puts the dst last.
+        for (ix, op) in ops.iter().enumerate().rev() {
+            let sep = if ix + 1 == ops.len() { " " } else { ", " };
+            match (self.opcode, ix, op) {
+                (OpCode::Shl, 1, &Operand::Reg(Reg::Mach(RCX))) |
+                (OpCode::Sar, 1, &Operand::Reg(Reg::Mach(RCX))) => {
+                    write!(fmt, "{}%cl", sep)?
+                }
+                (OpCode::Setcc(_), 0, &Operand::Reg(Reg::Mach(m))) => {
+                    write!(fmt, "{}%{}", sep, BYTE_REG_NAMES[m.num()])?
+                }
+                _ => write!(fmt, "{}{}", sep, op)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Block {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(fmt, "{}:", self.label)?;
+        for instr in &self.instrs {
+            writeln!(fmt, "\t{}", instr)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        for b in &self.blocks {
+            write!(fmt, "{}", b)?;
+        }
+        Ok(())
+    }
+}
+
 impl Block {
-    pub fn new(instrs: Vec<Instr>) -> Self {
-        Block { instrs }
+    pub fn new(label: Label, instrs: Vec<Instr>) -> Self {
+        Block { label, instrs, is_cold: false }
+    }
+
+    // Targets of the trailing jumps, in order.
+    pub fn successors(&self) -> Vec<&Label> {
+        let jumps = self.instrs.iter().rev()
+            .take_while(|instr| instr.opcode.is_jump())
+            .collect::<Vec<_>>();
+        jumps.into_iter().rev().map(|instr| instr.jump_target().unwrap()).collect()
+    }
+
+    // Into the next block of the Function, which only happens once the jumps
+    // to the next block are removed.
+    pub fn falls_through(&self) -> bool {
+        self.instrs.last().is_none_or(|instr| !instr.opcode.is_terminator())
+    }
+
+    pub fn verify(&self) -> Result<(), String> {
+        if self.instrs.is_empty() {
+            return Err(format!("{}: empty block", self.label));
+        }
+        // The jumps are all at the end, and only the last instruction can be a
+        // terminator.
+        let body_len = self.instrs.len() - self.successors().len();
+        let last_ix = self.instrs.len() - 1;
+        if let Some((_, instr)) = self.instrs.iter().enumerate().find(|&(ix, instr)| {
+            (ix < body_len && instr.opcode.is_jump()) ||
+                (ix < last_ix && instr.opcode.is_terminator())
+        }) {
+            return Err(format!("{}: jump in the middle: {}", self.label, instr));
+        }
+        Ok(())
     }
 
     pub fn instrs(&self) -> &[Instr] {
         &self.instrs
     }
+
+    // The first VirtualReg index that is not used in this block, including
+    // its start moves.
+    pub fn next_virt_ix(&self) -> u32 {
+        self.instrs.iter()
+            .flat_map(|instr| {
+                let regs = instr.reg_operands().into_iter().map(|(_, r, _)| r);
+                regs.chain(instr.start_move_regs().into_iter().map(|(_, r)| r))
+            })
+            .filter_map(|r| match r {
+                Reg::Virtual(v) => Some(v.0 + 1),
+                Reg::Mach(_) => None,
+            })
+            .max()
+            .unwrap_or(0)
+    }
 }
 
 impl OpCode {
-    pub fn has_dst(self) -> bool {
+    pub fn mnemonic(self) -> &'static str {
+        match self {
+            OpCode::Add => "addq",
+            OpCode::Sub => "subq",
+            OpCode::Xor => "xorq",
+            OpCode::IMul => "imulq",
+            OpCode::Mov => "movq",
+            OpCode::MovAbs => "movabsq",
+            OpCode::Lea => "leaq",
+            OpCode::Cmp => "cmpq",
+            OpCode::Cqo => "cqto",
+            OpCode::IDiv => "idivq",
+            OpCode::Shl => "shlq",
+            OpCode::Sar => "sarq",
+            OpCode::Call(_) => "call",
+            OpCode::Ret => "ret",
+            OpCode::Xchg => "xchgq",
+            OpCode::Push => "pushq",
+            OpCode::Pop => "popq",
+            OpCode::Jmp => "jmp",
+            OpCode::Jcc(Cond::L) => "jl",
+            OpCode::Jcc(Cond::Le) => "jle",
+            OpCode::Jcc(Cond::G) => "jg",
+            OpCode::Jcc(Cond::Ge) => "jge",
+            OpCode::Jcc(Cond::E) => "je",
+            OpCode::Jcc(Cond::Ne) => "jne",
+            OpCode::Jcc(Cond::B) => "jb",
+            OpCode::Jcc(Cond::Be) => "jbe",
+            OpCode::Jcc(Cond::A) => "ja",
+            OpCode::Jcc(Cond::Ae) => "jae",
+            OpCode::Setcc(Cond::L) => "setl",
+            OpCode::Setcc(Cond::Le) => "setle",
+            OpCode::Setcc(Cond::G) => "setg",
+            OpCode::Setcc(Cond::Ge) => "setge",
+            OpCode::Setcc(Cond::E) => "sete",
+            OpCode::Setcc(Cond::Ne) => "setne",
+            OpCode::Setcc(Cond::B) => "setb",
+            OpCode::Setcc(Cond::Be) => "setbe",
+            OpCode::Setcc(Cond::A) => "seta",
+            OpCode::Setcc(Cond::Ae) => "setae",
+            OpCode::Movsd => "movsd",
+            OpCode::Addsd => "addsd",
+            OpCode::Mulsd => "mulsd",
+            OpCode::Cvtsi2sd => "cvtsi2sdq",
+            OpCode::Ucomisd => "ucomisd",
+        }
+    }
+
+    pub fn desc(self) -> &'static OpCodeDesc {
         match self {
-            OpCode::Add | OpCode::Mov => true,
-            OpCode::Ret => false,
+            OpCode::Add | OpCode::Sub | OpCode::Xor | OpCode::IMul => &BINARY_INPLACE,
+            OpCode::Shl | OpCode::Sar => &SHIFT,
+            OpCode::Mov => &MOV,
+            OpCode::MovAbs => &MOVABS,
+            OpCode::Lea => &LEA,
+            OpCode::Cmp => &CMP,
+            OpCode::Cqo => &CQO,
+            OpCode::IDiv => &IDIV,
+            OpCode::Call(num_args) => &CALLS[num_args as usize],
+            OpCode::Ret => &RET,
+            OpCode::Xchg => &XCHG,
+            OpCode::Push => &PUSH,
+            OpCode::Pop => &POP,
+            OpCode::Jmp | OpCode::Jcc(_) => &JMP,
+            OpCode::Setcc(_) => &SETCC,
+            OpCode::Movsd => &MOVSD,
+            OpCode::Addsd | OpCode::Mulsd => &BINARY_SSE,
+            OpCode::Cvtsi2sd => &CVTSI2SD,
+            OpCode::Ucomisd => &UCOMISD,
         }
     }
 
-    pub fn reads_dst(self) -> bool {
+    pub fn is_jump(self) -> bool {
+        matches!(self, OpCode::Jmp | OpCode::Jcc(_))
+    }
+
+    // Ends a block.
+    pub fn is_terminator(self) -> bool {
+        matches!(self, OpCode::Jmp | OpCode::Ret)
+    }
+
+    // `op a, b` and `op b, a` compute the same value.
+    pub fn is_commutative(self) -> bool {
+        matches!(self, OpCode::Add | OpCode::Xor | OpCode::IMul | OpCode::Addsd | OpCode::Mulsd)
+    }
+
+    pub fn reads_flags(self) -> bool {
+        matches!(self, OpCode::Jcc(_) | OpCode::Setcc(_))
+    }
+
+    // Including leaving them undefined.
+    pub fn writes_flags(self) -> bool {
+        matches!(self, OpCode::Add | OpCode::Sub | OpCode::Xor | OpCode::Cmp | OpCode::IMul |
+                       OpCode::IDiv | OpCode::Shl | OpCode::Sar | OpCode::Call(_) |
+                       OpCode::Ucomisd)
+    }
+}
+
+impl Cond {
+    pub fn inverse(self) -> Self {
         match self {
-            OpCode::Add => true,
-            OpCode::Mov => false,
-            OpCode::Ret => false,
+            Cond::L => Cond::Ge,
+            Cond::Le => Cond::G,
+            Cond::G => Cond::Le,
+            Cond::Ge => Cond::L,
+            Cond::E => Cond::Ne,
+            Cond::Ne => Cond::E,
+            Cond::B => Cond::Ae,
+            Cond::Be => Cond::A,
+            Cond::A => Cond::Be,
+            Cond::Ae => Cond::B,
+        }
+    }
+}
+
+impl Function {
+    pub fn new(blocks: Vec<Block>) -> Self {
+        Function { blocks }
+    }
+
+    pub fn entry(&self) -> &Block {
+        &self.blocks[0]
+    }
+
+    pub fn verify(&self) -> Result<(), String> {
+        let ixs = self.block_ixs();
+        if ixs.len() != self.blocks.len() {
+            return Err("Duplicated block labels".to_owned());
+        }
+        if let Some(last) = self.blocks.last().filter(|b| b.falls_through()) {
+            return Err(format!("{}: not terminated: {}", last.label,
+                               last.instrs.last().unwrap()));
+        }
+        for b in &self.blocks {
+            b.verify()?;
+            if let Some(l) = b.successors().into_iter().find(|l| !ixs.contains_key(*l)) {
+                return Err(format!("{}: jumps to unknown {}", b.label, l));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn block_ixs(&self) -> HashMap<&Label, usize> {
+        self.blocks.iter().enumerate().map(|(ix, b)| (&b.label, ix)).collect()
+    }
+
+    // Duplicated edges (e.g. `jcc L; jmp L`) are kept. A fallthrough comes
+    // last.
+    pub fn successors(&self) -> Vec<Vec<usize>> {
+        let ixs = self.block_ixs();
+        self.blocks.iter().enumerate()
+            .map(|(ix, b)| {
+                let mut succs = b.successors().into_iter().map(|l| ixs[l]).collect::<Vec<_>>();
+                if b.falls_through() {
+                    succs.push(ix + 1);
+                }
+                succs
+            })
+            .collect()
+    }
+
+    pub fn predecessors(&self) -> Vec<Vec<usize>> {
+        let mut preds = vec![vec![]; self.blocks.len()];
+        for (ix, succs) in self.successors().into_iter().enumerate() {
+            for succ in succs {
+                preds[succ].push(ix);
+            }
+        }
+        preds
+    }
+
+    // Only the blocks reachable from the entry are included.
+    pub fn compute_po(&self) -> Vec<usize> {
+        let succs = self.successors();
+        let mut visited = vec![false; self.blocks.len()];
+        let mut po = vec![];
+        // (block, next successor to visit)
+        let mut stack = vec![(0, 0)];
+        visited[0] = true;
+        while let Some(&mut (ix, ref mut next)) = stack.last_mut() {
+            if let Some(&succ) = succs[ix].get(*next) {
+                *next += 1;
+                if !visited[succ] {
+                    visited[succ] = true;
+                    stack.push((succ, 0));
+                }
+            } else {
+                po.push(ix);
+                stack.pop();
+            }
+        }
+        po
+    }
+
+    pub fn compute_rpo(&self) -> Vec<usize> {
+        let mut rpo = self.compute_po();
+        rpo.reverse();
+        rpo
+    }
+
+    // The first VirtualReg index that is not used in any of the blocks.
+    pub fn next_virt_ix(&self) -> u32 {
+        self.blocks.iter().map(|b| b.next_virt_ix()).max().unwrap_or(0)
+    }
+
+    pub fn next_local_label(&self) -> Label {
+        let next = self.blocks.iter()
+            .filter_map(|b| match b.label {
+                Label::Local(ix) => Some(ix + 1),
+                Label::Named(_) => None,
+            })
+            .max()
+            .unwrap_or(0);
+        Label::Local(next)
+    }
+
+    // An edge is critical if it leaves a block with several successors and
+    // enters a block with several predecessors. Such an edge gets a fresh block
+    // that only jumps to the original target, which gives later passes a place
+    // to put the moves that belong to that edge.
+    pub fn split_critical_edges(&mut self) {
+        let preds = self.predecessors();
+        let ixs = self.blocks.iter().map(|b| b.label.clone()).enumerate()
+            .map(|(ix, l)| (l, ix))
+            .collect::<HashMap<_, _>>();
+        let mut new_blocks = vec![];
+        let mut next_label = match self.next_local_label() {
+            Label::Local(ix) => ix,
+            Label::Named(_) => unreachable!(),
+        };
+        for b in &mut self.blocks {
+            debug_assert!(!b.falls_through(), "{} falls through", b.label);
+            let num_succs = b.successors().len();
+            if num_succs < 2 {
+                continue;
+            }
+            let first_jump = b.instrs.len() - num_succs;
+            for instr in &mut b.instrs[first_jump..] {
+                let target = instr.jump_target().unwrap().clone();
+                if preds[ixs[&target]].len() < 2 {
+                    continue;
+                }
+                let label = Label::Local(next_label);
+                next_label += 1;
+                instr.ops[0] = label.clone().into_op();
+                new_blocks.push(Block::new(label, vec![Instr::jmp(target)]));
+            }
+        }
+        self.blocks.extend(new_blocks);
+    }
+}
+
+impl OperandRole {
+    pub fn is_read(self) -> bool {
+        matches!(self, OperandRole::Use | OperandRole::UseDef)
+    }
+
+    pub fn is_written(self) -> bool {
+        self != OperandRole::Use
+    }
+}
+
+impl OperandDesc {
+    // For the registers that form a memory address.
+    pub fn address() -> Self {
+        gpr(OperandRole::Use)
+    }
+
+    pub fn accepts_imm(&self, i: Imm) -> bool {
+        self.imm.is_some_and(|w| w.fits(i))
+    }
+}
+
+impl ImmWidth {
+    // The narrowest width that can hold i.
+    pub fn of(i: Imm) -> Self {
+        if i as i8 as Imm == i {
+            ImmWidth::Imm8
+        } else if i as i32 as Imm == i {
+            ImmWidth::Imm32
+        } else {
+            ImmWidth::Imm64
         }
     }
+
+    pub fn fits(self, i: Imm) -> bool {
+        ImmWidth::of(i) <= self
+    }
 }
 
 impl Operand {
@@ -136,6 +944,68 @@ impl Operand {
     pub fn new_virt_reg(ix: u32) -> Self {
         Operand::Reg(Reg::new_virt(ix))
     }
+
+    pub fn is_reg(&self) -> bool {
+        matches!(*self, Operand::Reg(_))
+    }
+
+    pub fn is_label(&self) -> bool {
+        matches!(*self, Operand::Label(_))
+    }
+
+    pub fn is_mem(&self) -> bool {
+        matches!(*self, Operand::Mem(_))
+    }
+
+    pub fn is_imm(&self) -> bool {
+        matches!(*self, Operand::Imm(_))
+    }
+
+    // Whether r is the operand itself or is part of its address.
+    pub fn mentions(&self, r: Reg) -> bool {
+        match *self {
+            Operand::Reg(r2) => r == r2,
+            Operand::Mem(ref m) => m.regs().iter().any(|&(_, r2)| r == r2),
+            Operand::Imm(_) | Operand::Label(_) => false,
+        }
+    }
+}
+
+impl RegClass {
+    pub const ALL: [RegClass; 2] = [RegClass::Gpr, RegClass::Xmm];
+
+    pub fn ix(self) -> usize {
+        self as usize
+    }
+
+    // The register whose number in the encoding is num.
+    pub fn reg(self, num: usize) -> MachReg {
+        debug_assert!(num < 16);
+        MachReg::new(self.ix() * 16 + num)
+    }
+
+    pub fn allocatable(self) -> &'static [MachReg] {
+        match self {
+            RegClass::Gpr => ALLOCATABLE_GPRS,
+            RegClass::Xmm => ALLOCATABLE_XMMS,
+        }
+    }
+
+    // An XMM only ever holds a scalar double, which is what the movsd of a
+    // spill or a reload moves.
+    pub fn spill_slot_size(self) -> usize {
+        match self {
+            RegClass::Gpr => 8,
+            RegClass::Xmm => 8,
+        }
+    }
+
+    pub fn mov_opcode(self) -> OpCode {
+        match self {
+            RegClass::Gpr => OpCode::Mov,
+            RegClass::Xmm => OpCode::Movsd,
+        }
+    }
 }
 
 impl MachReg {
@@ -143,6 +1013,15 @@ impl MachReg {
         MachReg(ix as u32)
     }
 
+    pub const fn class(self) -> RegClass {
+        if self.0 < 16 { RegClass::Gpr } else { RegClass::Xmm }
+    }
+
+    // The number in the encoding, within the class.
+    pub fn num(self) -> usize {
+        self.ix() & 15
+    }
+
     pub fn into_reg(self) -> Reg {
         Reg::Mach(self)
     }
@@ -150,6 +1029,10 @@ impl MachReg {
     pub fn ix(self) -> usize {
         self.0 as usize
     }
+
+    pub fn name(self) -> &'static str {
+        REG_NAMES[self.ix()]
+    }
 }
 
 impl Reg {
@@ -158,10 +1041,7 @@ impl Reg {
     }
 
     pub fn is_mach(&self) -> bool {
-        match self {
-            &Reg::Mach(_) => true,
-            _ => false,
-        }
+        matches!(self, Reg::Mach(_))
     }
 
     pub fn mach_ix(&self) -> usize {
@@ -179,12 +1059,22 @@ impl Reg {
     }
 
     pub fn rsp() -> Self {
-        // FIXME
-        Reg::new_mach(100)
+        RSP.into_reg()
     }
 
     pub fn new_virt(ix: u32) -> Self {
-        Reg::Virtual(VirtualReg(ix))
+        Reg::new_virt_in(RegClass::Gpr, ix)
+    }
+
+    pub fn new_virt_in(class: RegClass, ix: u32) -> Self {
+        Reg::Virtual(VirtualReg(ix, class))
+    }
+
+    pub fn class(&self) -> RegClass {
+        match *self {
+            Reg::Virtual(v) => v.1,
+            Reg::Mach(m) => m.class(),
+        }
     }
 
     pub fn into_op(self) -> Operand {
@@ -201,6 +1091,17 @@ impl Instr {
         Self::new(opcode, vec![dst, src])
     }
 
+    // `dst = a op b`, for an opcode whose dst is UseDef. This is not an x64
+    // instruction: see legalize::legalize_two_address.
+    pub fn new3(opcode: OpCode, dst: Operand, a: Operand, b: Operand) -> Self {
+        debug_assert_eq!(opcode.desc().operands[0].role, OperandRole::UseDef);
+        Self::new(opcode, vec![dst, a, b])
+    }
+
+    pub fn is_three_address(&self) -> bool {
+        self.ops.len() == self.desc().operands.len() + 1
+    }
+
     pub fn new1(opcode: OpCode, op: Operand) -> Self {
         Self::new(opcode, vec![op])
     }
@@ -213,83 +1114,220 @@ impl Instr {
         Self::new2(OpCode::Mov, dst, src)
     }
 
+    pub fn movabs(dst: Operand, imm: Imm) -> Self {
+        Self::new2(OpCode::MovAbs, dst, Operand::Imm(imm))
+    }
+
+    pub fn lea(dst: Operand, addr: Mem) -> Self {
+        Self::new2(OpCode::Lea, dst, addr.into_op())
+    }
+
     pub fn ret(op: Operand) -> Self {
         Self::new1(OpCode::Ret, op)
     }
 
-    fn dst(&self) -> Option<&Operand> {
-        if self.opcode.has_dst() {
-            Some(&self.ops[0])
-        } else {
-            None
-        }
+    pub fn xchg(a: Operand, b: Operand) -> Self {
+        Self::new2(OpCode::Xchg, a, b)
     }
 
-    fn dst_mut(&mut self) -> Option<&mut Operand> {
-        if self.opcode.has_dst() {
-            Some(&mut self.ops[0])
-        } else {
-            None
-        }
+    // A mov or a movsd, by the class of the registers involved.
+    pub fn copy(dst: Operand, src: Operand) -> Self {
+        let class = [&dst, &src].iter()
+            .filter_map(|op| match **op {
+                Operand::Reg(r) => Some(r.class()),
+                _ => None,
+            })
+            .next()
+            .unwrap_or(RegClass::Gpr);
+        Self::new2(class.mov_opcode(), dst, src)
     }
 
-    fn src(&self) -> &Operand {
-        let ix = if self.opcode.has_dst() {
-            1
-        } else {
-            0
-        };
+    // A mov or a movsd from a reg to a reg.
+    pub fn is_copy(&self) -> bool {
+        matches!(self.opcode, OpCode::Mov | OpCode::Movsd) &&
+            self.ops[0].is_reg() && self.ops[1].is_reg()
+    }
 
-        &self.ops[ix]
+    pub fn movsd(dst: Operand, src: Operand) -> Self {
+        Self::new2(OpCode::Movsd, dst, src)
     }
 
-    fn src_mut(&mut self) -> &mut Operand {
-        let ix = if self.opcode.has_dst() {
-            1
-        } else {
-            0
-        };
+    pub fn addsd(dst: Operand, src: Operand) -> Self {
+        Self::new2(OpCode::Addsd, dst, src)
+    }
 
-        &mut self.ops[ix]
+    pub fn mulsd(dst: Operand, src: Operand) -> Self {
+        Self::new2(OpCode::Mulsd, dst, src)
     }
 
-    pub fn set_reg_at(&mut self, ix: &RegLocInInstr, r: Reg) {
-        match ix {
-            &RegLocInInstr::Dst(ref dst) => self.dst_mut().unwrap().set_reg_at(dst, r),
-            &RegLocInInstr::Src(ref src) => self.src_mut().set_reg_at(src, r),
-        }
+    pub fn cvtsi2sd(dst: Operand, src: Operand) -> Self {
+        Self::new2(OpCode::Cvtsi2sd, dst, src)
     }
 
-    pub fn outputs(&self) -> Option<(RegLocInInstr, Reg)> {
-        self.dst().and_then(|op| match op {
-            &Operand::Reg(r) => Some((RegLocInInstr::Dst(RegLocInOp::Reg), r)),
-            _ => None,
-        })
+    pub fn ucomisd(lhs: Operand, rhs: Operand) -> Self {
+        Self::new2(OpCode::Ucomisd, lhs, rhs)
+    }
+
+    pub fn xor(dst: Operand, src: Operand) -> Self {
+        Self::new2(OpCode::Xor, dst, src)
+    }
+
+    pub fn sub(dst: Operand, src: Operand) -> Self {
+        Self::new2(OpCode::Sub, dst, src)
+    }
+
+    pub fn imul(dst: Operand, src: Operand) -> Self {
+        Self::new2(OpCode::IMul, dst, src)
+    }
+
+    pub fn cmp(lhs: Operand, rhs: Operand) -> Self {
+        Self::new2(OpCode::Cmp, lhs, rhs)
+    }
+
+    pub fn cqo() -> Self {
+        Self::new(OpCode::Cqo, vec![])
+    }
+
+    pub fn idiv(divisor: Operand) -> Self {
+        Self::new1(OpCode::IDiv, divisor)
+    }
+
+    pub fn shl(dst: Operand, count: Operand) -> Self {
+        Self::new2(OpCode::Shl, dst, count)
+    }
+
+    pub fn sar(dst: Operand, count: Operand) -> Self {
+        Self::new2(OpCode::Sar, dst, count)
+    }
+
+    pub fn push(src: Operand) -> Self {
+        Self::new1(OpCode::Push, src)
+    }
+
+    pub fn pop(dst: Operand) -> Self {
+        Self::new1(OpCode::Pop, dst)
+    }
+
+    // Of a function that takes num_args arguments, the first of which are
+    // passed in ARG_REGS.
+    pub fn call(target: Operand, num_args: usize) -> Self {
+        Self::new1(OpCode::Call(num_args.min(ARG_REGS.len()) as u8), target)
+    }
+
+    pub fn jmp(target: Label) -> Self {
+        Self::new1(OpCode::Jmp, target.into_op())
+    }
+
+    pub fn jcc(cond: Cond, target: Label) -> Self {
+        Self::new1(OpCode::Jcc(cond), target.into_op())
+    }
+
+    pub fn setcc(cond: Cond, dst: Operand) -> Self {
+        Self::new1(OpCode::Setcc(cond), dst)
     }
 
-    pub fn inputs(&self) -> Vec<(RegLocInInstr, Reg)> {
-        let mut srcs = vec![];
-        match self.src() {
-            &Operand::Reg(r) => { srcs.push((RegLocInOp::Reg, r)); }
-            &Operand::Mem(ref m) => { srcs.extend(m.regs()); }
-            _ => (),
+    pub fn jump_target(&self) -> Option<&Label> {
+        match self.ops.first() {
+            Some(Operand::Label(l)) if self.opcode.is_jump() => Some(l),
+            _ => None,
         }
-        let mut dsts = vec![];
-        match self.dst() {
-            Some(&Operand::Reg(r)) => {
-                if self.opcode.reads_dst() {
-                    dsts.push((RegLocInOp::Reg, r));
+    }
+
+    pub fn desc(&self) -> &'static OpCodeDesc {
+        self.opcode.desc()
+    }
+
+    pub fn set_reg_at(&mut self, ix: &RegLocInInstr, r: Reg) {
+        match *ix {
+            RegLocInInstr::Explicit(op_ix, ref loc) => {
+                self.ops[op_ix as usize].set_reg_at(loc, r)
+            }
+            RegLocInInstr::StartMove(ix, side) => {
+                let mov = &mut self.parallel_moves.start[ix as usize];
+                match side {
+                    MoveSide::Dst => mov.dst = r.into_op(),
+                    MoveSide::Src => mov.src = r.into_op(),
                 }
             }
-            Some(&Operand::Mem(ref m)) => {
-                dsts.extend(m.regs())
+            _ => panic!("Can't reassign {:?} of {:?}", ix, self),
+        }
+    }
+
+    // All the registers mentioned by this instruction together with the
+    // descriptor of their operands. Registers in a memory operand are always
+    // read, no matter what the role of the operand is.
+    pub fn reg_operands(&self) -> Vec<(RegLocInInstr, Reg, OperandDesc)> {
+        let desc = self.desc();
+        debug_assert!(desc.operands.len() == self.ops.len(),
+                      "Wrong number of operands: {:?}", self);
+        let mut res = vec![];
+        for (op_ix, (op, op_desc)) in self.ops.iter().zip(desc.operands).enumerate() {
+            let op_ix = op_ix as u8;
+            match *op {
+                Operand::Reg(r) => {
+                    res.push((RegLocInInstr::Explicit(op_ix, RegLocInOp::Reg), r, *op_desc));
+                }
+                Operand::Mem(ref m) => {
+                    res.extend(m.regs().into_iter().map(|(loc, r)| {
+                        (RegLocInInstr::Explicit(op_ix, loc), r, OperandDesc::address())
+                    }));
+                }
+                Operand::Imm(_) | Operand::Label(_) => (),
             }
-            _ => (),
         }
-        srcs.into_iter().map(|(loc, r)| (RegLocInInstr::Src(loc), r))
-            .chain(dsts.into_iter().map(|(loc, r)| (RegLocInInstr::Dst(loc), r)))
+        for (ix, op_desc) in desc.implicit.iter().enumerate() {
+            let r = op_desc.fixed.expect("Implicit operands must be fixed");
+            res.push((RegLocInInstr::Implicit(ix as u8), r.into_reg(), *op_desc));
+        }
+        for (ix, r) in desc.clobbers.iter().enumerate() {
+            let op_desc = fixed(OperandRole::Def, *r);
+            res.push((RegLocInInstr::Clobber(ix as u8), r.into_reg(), op_desc));
+        }
+        res
+    }
+
+    pub fn outputs(&self) -> Vec<(RegLocInInstr, Reg, OperandDesc)> {
+        self.reg_operands().into_iter()
+            .filter(|(_, _, desc)| desc.role.is_written())
+            .collect()
+    }
+
+    pub fn inputs(&self) -> Vec<(RegLocInInstr, Reg, OperandDesc)> {
+        self.reg_operands().into_iter()
+            .filter(|(_, _, desc)| desc.role.is_read())
             .collect()
     }
+
+    // The registers that the start moves copy between. Those in a memory
+    // operand (i.e. of a spill slot) are left out.
+    pub fn start_move_regs(&self) -> Vec<(RegLocInInstr, Reg)> {
+        let mut res = vec![];
+        for (ix, mov) in self.parallel_moves.start.iter().enumerate() {
+            for &(side, op) in &[(MoveSide::Dst, &mov.dst), (MoveSide::Src, &mov.src)] {
+                if let Operand::Reg(r) = *op {
+                    res.push((RegLocInInstr::StartMove(ix as u8, side), r));
+                }
+            }
+        }
+        res
+    }
+}
+
+impl RegLocInInstr {
+    pub fn op_loc(&self) -> Option<&RegLocInOp> {
+        match *self {
+            RegLocInInstr::Explicit(_, ref loc) => Some(loc),
+            _ => None,
+        }
+    }
+
+    pub fn is_explicit(&self) -> bool {
+        self.op_loc().is_some()
+    }
+
+    pub fn is_move_dst(&self) -> bool {
+        matches!(*self, RegLocInInstr::StartMove(_, MoveSide::Dst))
+    }
 }
 
 impl ParallelMoves {
@@ -300,6 +1338,10 @@ impl ParallelMoves {
         }
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.start.is_empty() && self.end.is_empty()
+    }
+
     pub fn add_to_start(&mut self, mov: ParallelMove) {
         self.start.push(mov);
     }
@@ -307,19 +1349,45 @@ impl ParallelMoves {
     pub fn add_to_end(&mut self, mov: ParallelMove) {
         self.end.push(mov);
     }
+
+    pub fn start(&self) -> &[ParallelMove] {
+        &self.start
+    }
+
+    pub fn end(&self) -> &[ParallelMove] {
+        &self.end
+    }
+
+    // Leaves self empty.
+    pub fn take(&mut self) -> (Vec<ParallelMove>, Vec<ParallelMove>) {
+        (mem::take(&mut self.start), mem::take(&mut self.end))
+    }
 }
 
 impl ParallelMove {
     pub fn new(dst: Operand, src: Operand) -> Self {
         Self { dst, src }
     }
+
+    pub fn dst(&self) -> &Operand {
+        &self.dst
+    }
+
+    pub fn src(&self) -> &Operand {
+        &self.src
+    }
 }
 
 impl Operand {
     fn set_reg_at(&mut self, ix: &RegLocInOp, to_r: Reg) {
         match (self, ix) {
             (&mut Operand::Reg(ref mut r), &RegLocInOp::Reg) => *r = to_r,
-            (&mut Operand::Mem(ref mut m), &RegLocInOp::MemBase) => m.base = to_r,
+            (&mut Operand::Mem(ref mut m), &RegLocInOp::MemBase) => {
+                match m.base {
+                    Some(MemBase::Reg(ref mut r)) => *r = to_r,
+                    ref base => panic!("No base reg in {:?}", base),
+                }
+            }
             (&mut Operand::Mem(ref mut m), &RegLocInOp::MemIndex) => {
                 *(m.index.as_mut().unwrap()) = to_r;
             }
@@ -329,8 +1397,53 @@ impl Operand {
 }
 
 impl Mem {
+    // [base + disp]
+    pub fn new(base: Reg, disp: i32) -> Self {
+        Mem {
+            base: Some(MemBase::Reg(base)),
+            index: None,
+            scale: Scale::S1,
+            disp,
+        }
+    }
+
+    // [base + index * scale + disp], where base is optional.
+    pub fn new_indexed(base: Option<Reg>, index: Reg, scale: Scale, disp: i32) -> Self {
+        Mem {
+            base: base.map(MemBase::Reg),
+            index: Some(index),
+            scale,
+            disp,
+        }
+    }
+
+    // [rip + label + disp]
+    pub fn new_rip(label: Label, disp: i32) -> Self {
+        Mem {
+            base: Some(MemBase::Rip(label)),
+            index: None,
+            scale: Scale::S1,
+            disp,
+        }
+    }
+
+    pub fn base_reg(&self) -> Option<Reg> {
+        match self.base {
+            Some(MemBase::Reg(r)) => Some(r),
+            _ => None,
+        }
+    }
+
+    pub fn rip_label(&self) -> Option<&Label> {
+        match self.base {
+            Some(MemBase::Rip(ref l)) => Some(l),
+            _ => None,
+        }
+    }
+
     pub fn regs(&self) -> Vec<(RegLocInOp, Reg)> {
-        let mut rs = vec![(RegLocInOp::MemBase, self.base)];
+        let mut rs = vec![];
+        rs.extend(self.base_reg().map(|r| (RegLocInOp::MemBase, r)));
         rs.extend(self.index.iter().map(|r| (RegLocInOp::MemIndex, *r)));
         rs
     }
@@ -340,6 +1453,27 @@ impl Mem {
     }
 }
 
+impl Scale {
+    pub fn factor(self) -> u32 {
+        1 << self.log2()
+    }
+
+    pub fn log2(self) -> u8 {
+        match self {
+            Scale::S1 => 0,
+            Scale::S2 => 1,
+            Scale::S4 => 2,
+            Scale::S8 => 3,
+        }
+    }
+}
+
+impl Label {
+    pub fn into_op(self) -> Operand {
+        Operand::Label(self)
+    }
+}
+
 impl RegContext {
     pub fn new(reg: Reg, kind: UseKind,
                block_id: usize, instr_ix: usize, operand_ix: RegLocInInstr) -> Self {
@@ -349,9 +1483,15 @@ impl RegContext {
             block_id: block_id as u32,
             instr_ix: instr_ix as u32,
             operand_ix,
+            class: RegClass::Gpr,
+            fixed: None,
         }
     }
 
+    pub fn with_desc(self, desc: &OperandDesc) -> Self {
+        RegContext { class: desc.class, fixed: desc.fixed, ..self }
+    }
+
     pub fn new_input(reg: Reg, block_id: usize,
                      instr_ix: usize, operand_ix: RegLocInInstr) -> Self {
         RegContext::new(reg, UseKind::Input, block_id, instr_ix, operand_ix)
@@ -366,3 +1506,92 @@ impl RegContext {
         RegContext { kind: UseKind::Input, ..self.clone() }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn label(ix: u32) -> Label {
+        Label::Local(ix)
+    }
+
+    fn branch(l: u32, taken: u32, not_taken: u32) -> Block {
+        Block::new(label(l), vec![
+            Instr::cmp(Operand::new_virt_reg(0), Operand::Imm(0)),
+            Instr::jcc(Cond::E, label(taken)),
+            Instr::jmp(label(not_taken)),
+        ])
+    }
+
+    // 0 -> {1, 2}, 2 -> {3, 1}, 1 -> 3
+    fn loopy_function() -> Function {
+        Function::new(vec![
+            branch(0, 1, 2),
+            Block::new(label(1), vec![Instr::jmp(label(3))]),
+            branch(2, 3, 1),
+            Block::new(label(3), vec![Instr::ret(Operand::new_virt_reg(0))]),
+        ])
+    }
+
+    #[test]
+    fn can_compute_cfg_edges() {
+        let f = loopy_function();
+        assert_eq!(f.verify(), Ok(()));
+        assert_eq!(f.successors(), vec![vec![1, 2], vec![3], vec![3, 1], vec![]]);
+        assert_eq!(f.predecessors(), vec![vec![], vec![0, 2], vec![0], vec![1, 2]]);
+        assert_eq!(f.compute_po(), vec![3, 1, 2, 0]);
+        assert_eq!(f.compute_rpo(), vec![0, 2, 1, 3]);
+    }
+
+    #[test]
+    fn can_reject_malformed_blocks() {
+        let f = Function::new(vec![
+            Block::new(label(0), vec![Instr::jmp(label(1)), Instr::jmp(label(0))]),
+        ]);
+        assert!(f.verify().is_err());
+        let f = Function::new(vec![
+            Block::new(label(0), vec![Instr::cqo()]),
+        ]);
+        assert!(f.verify().is_err());
+    }
+
+    #[test]
+    fn can_tell_what_roles_read_and_write() {
+        let roles = [OperandRole::Use, OperandRole::Def, OperandRole::UseDef,
+                     OperandRole::EarlyClobber];
+        let read = roles.iter().map(|r| r.is_read()).collect::<Vec<_>>();
+        let written = roles.iter().map(|r| r.is_written()).collect::<Vec<_>>();
+        assert_eq!(read, vec![true, false, true, false]);
+        assert_eq!(written, vec![false, true, true, true]);
+    }
+
+    #[test]
+    fn can_read_only_the_arg_regs_of_a_call() {
+        let target = Label::Named("f".to_owned()).into_op();
+        let fixed_inputs = |num_args| Instr::call(target.clone(), num_args).inputs().iter()
+            .filter_map(|&(_, r, desc)| desc.fixed.map(|_| r))
+            .collect::<Vec<_>>();
+        assert_eq!(fixed_inputs(0), vec![]);
+        assert_eq!(fixed_inputs(2), vec![RDI.into_reg(), RSI.into_reg()]);
+        // The rest go on the stack.
+        assert_eq!(fixed_inputs(8).len(), ARG_REGS.len());
+        assert_eq!(Instr::call(target, 2).outputs().len(), CALLER_SAVED.len());
+    }
+
+    #[test]
+    fn can_split_critical_edges() {
+        let mut f = loopy_function();
+        f.split_critical_edges();
+        assert_eq!(f.verify(), Ok(()));
+        assert_eq!(f.blocks.len(), 7);
+        assert_eq!(f.blocks[0].successors(), vec![&label(4), &label(2)]);
+        assert_eq!(f.blocks[2].successors(), vec![&label(5), &label(6)]);
+        assert_eq!(f.blocks[6].instrs, vec![Instr::jmp(label(1))]);
+        for (ix, preds) in f.predecessors().into_iter().enumerate() {
+            for pred in preds {
+                let num_succs = f.blocks[pred].successors().len();
+                assert!(num_succs < 2 || f.predecessors()[ix].len() < 2);
+            }
+        }
+    }
+}
@@ -0,0 +1,565 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use ::x64::*;
+use ::x64::encode::Fixup;
+
+// Decodes what the Assembler emits back into Instrs over MachRegs.
+//
+// A branch target or a RIP-relative address is given as Label::Local(offset),
+// where offset is from the start of bytes.
+pub fn decode(bytes: &[u8]) -> Result<Vec<(usize, Instr)>, String> {
+    let mut decoder = Decoder { bytes, pos: 0, rip_disp: None };
+    let mut instrs = vec![];
+    while decoder.pos < bytes.len() {
+        let start = decoder.pos;
+        let instr = decoder.decode_one()
+            .map_err(|e| format!("{} at {:#x}", e, start))?;
+        instrs.push((start, instr));
+    }
+    Ok(instrs)
+}
+
+// One line per instruction: offset, bytes and the AT&T syntax. Offsets are
+// shown as the labels bound there, or as the labels of the relocs.
+pub fn disassemble(bytes: &[u8], labels: &HashMap<Label, usize>,
+                   relocs: &[Fixup]) -> Result<String, String> {
+    let mut names = HashMap::new();
+    for (label, &offset) in labels {
+        let name = names.entry(offset).or_insert_with(|| label.clone());
+        // Deterministic when several labels are bound at the same offset.
+        if label < name {
+            *name = label.clone();
+        }
+    }
+
+    let instrs = decode(bytes)?;
+    let mut out = String::new();
+    for (ix, &(start, ref instr)) in instrs.iter().enumerate() {
+        let end = instrs.get(ix + 1).map_or(bytes.len(), |&(next, _)| next);
+        if let Some(label) = names.get(&start) {
+            writeln!(out, "{}:", label).unwrap();
+        }
+        let reloc = relocs.iter().find(|f| start <= f.offset && f.offset < end);
+        let mut instr = instr.clone();
+        for op in &mut instr.ops {
+            name_offset(op, &names, reloc, end);
+        }
+        let hex = bytes[start..end].iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<Vec<_>>()
+            .join(" ");
+        writeln!(out, "{:6x}:  {:<30} {}", start, hex, instr).unwrap();
+    }
+    Ok(out)
+}
+
+fn name_offset(op: &mut Operand, names: &HashMap<usize, Label>,
+               reloc: Option<&Fixup>, end: usize) {
+    match *op {
+        Operand::Label(ref mut l) => {
+            *l = match reloc {
+                Some(f) => f.label.clone(),
+                None => offset_label(l, names),
+            };
+        }
+        Operand::Mem(ref mut m) if m.rip_label().is_some() => {
+            *m = match reloc {
+                Some(f) => {
+                    Mem::new_rip(f.label.clone(), (f.addend + (end - f.offset) as i64) as i32)
+                }
+                None => Mem::new_rip(offset_label(m.rip_label().unwrap(), names), 0),
+            };
+        }
+        _ => (),
+    }
+}
+
+fn offset_label(l: &Label, names: &HashMap<usize, Label>) -> Label {
+    match *l {
+        Label::Local(offset) => names.get(&(offset as usize)).cloned()
+            .unwrap_or_else(|| Label::Named(format!("{:#x}", offset))),
+        Label::Named(_) => l.clone(),
+    }
+}
+
+struct Decoder<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    // Only known to be relative to the end of the instruction.
+    rip_disp: Option<i32>,
+}
+
+// The REX bits that extend register numbers.
+#[derive(Copy, Clone)]
+struct Rex {
+    w: bool,
+    r: u8,
+    x: u8,
+    b: u8,
+}
+
+impl<'a> Decoder<'a> {
+    fn byte(&mut self) -> Result<u8, String> {
+        let b = *self.bytes.get(self.pos)
+            .ok_or_else(|| "Truncated instruction".to_owned())?;
+        self.pos += 1;
+        Ok(b)
+    }
+
+    fn imm8(&mut self) -> Result<Imm, String> {
+        Ok(self.byte()? as i8 as Imm)
+    }
+
+    fn imm32(&mut self) -> Result<i32, String> {
+        let mut v = 0u32;
+        for ix in 0..4 {
+            v |= (self.byte()? as u32) << (ix * 8);
+        }
+        Ok(v as i32)
+    }
+
+    fn imm64(&mut self) -> Result<Imm, String> {
+        let lo = self.imm32()? as u32 as u64;
+        let hi = self.imm32()? as u32 as u64;
+        Ok((lo | (hi << 32)) as Imm)
+    }
+
+    // Relative to the end of the instruction, which follows the rel32.
+    fn rel32(&mut self) -> Result<Operand, String> {
+        let rel = self.imm32()? as i64;
+        Ok(Label::Local((self.pos as i64 + rel) as u32).into_op())
+    }
+
+    fn decode_one(&mut self) -> Result<Instr, String> {
+        let mut op = self.byte()?;
+        // The mandatory prefix of an SSE instruction.
+        let prefix = if op == 0xf2 || op == 0x66 {
+            let prefix = op;
+            op = self.byte()?;
+            Some(prefix)
+        } else {
+            None
+        };
+        let mut rex = Rex { w: false, r: 0, x: 0, b: 0 };
+        if op & 0xf0 == 0x40 {
+            rex = Rex { w: op & 8 != 0, r: (op >> 2) & 1, x: (op >> 1) & 1, b: op & 1 };
+            op = self.byte()?;
+        }
+        if let Some(prefix) = prefix {
+            let instr = self.decode_sse(prefix, op, rex)?;
+            return Ok(self.resolve_rip(instr));
+        }
+        // Everything but push, pop and the branches works on 64 bits.
+        let needs_w = !matches!(op, 0x50..=0x5f | 0xe8 | 0xe9 | 0xc3 | 0xff | 0x0f);
+        if needs_w && !rex.w {
+            return Err(format!("Unsupported operand size of {:#x}", op));
+        }
+
+        let instr = match op {
+            0x01 | 0x29 | 0x31 | 0x39 | 0x89 | 0x87 => {
+                let (reg, rm) = self.modrm(rex)?;
+                Instr::new2(rm_reg_opcode(op), rm, mach_op(reg))
+            }
+            0x03 | 0x2b | 0x33 | 0x3b | 0x8b => {
+                let (reg, rm) = self.modrm(rex)?;
+                Instr::new2(rm_reg_opcode(op - 2), mach_op(reg), rm)
+            }
+            0x8d => match self.modrm(rex)? {
+                (reg, Operand::Mem(m)) => Instr::lea(mach_op(reg), m),
+                _ => return Err("Unsupported lea from a register".to_owned()),
+            },
+            0x81 | 0x83 => {
+                let (ext, rm) = self.modrm_ext(rex)?;
+                let i = if op == 0x83 { self.imm8()? } else { self.imm32()? as Imm };
+                let opcode = match ext {
+                    0 => OpCode::Add,
+                    5 => OpCode::Sub,
+                    6 => OpCode::Xor,
+                    7 => OpCode::Cmp,
+                    _ => return Err(format!("Unsupported {:#x} /{}", op, ext)),
+                };
+                Instr::new2(opcode, rm, Operand::Imm(i))
+            }
+            0xc7 => {
+                let (ext, rm) = self.modrm_ext(rex)?;
+                if ext != 0 {
+                    return Err(format!("Unsupported 0xc7 /{}", ext));
+                }
+                Instr::mov(rm, Operand::Imm(self.imm32()? as Imm))
+            }
+            0xb8..=0xbf => Instr::movabs(mach_op((op - 0xb8) | (rex.b << 3)), self.imm64()?),
+            0x69 | 0x6b => {
+                let (reg, rm) = self.modrm(rex)?;
+                let i = if op == 0x6b { self.imm8()? } else { self.imm32()? as Imm };
+                if rm != mach_op(reg) {
+                    return Err("Unsupported three-operand imul".to_owned());
+                }
+                Instr::imul(rm, Operand::Imm(i))
+            }
+            0x0f => match self.byte()? {
+                0xaf if rex.w => {
+                    let (reg, rm) = self.modrm(rex)?;
+                    Instr::imul(mach_op(reg), rm)
+                }
+                op2 @ 0x80..=0x8f => {
+                    let cond = decode_cond(op2 & 0xf)
+                        .ok_or_else(|| format!("Unsupported jcc {:#x}", op2))?;
+                    Instr::new1(OpCode::Jcc(cond), self.rel32()?)
+                }
+                op2 @ 0x90..=0x9f => {
+                    let cond = decode_cond(op2 & 0xf)
+                        .ok_or_else(|| format!("Unsupported setcc {:#x}", op2))?;
+                    match self.modrm_ext(rex)? {
+                        (0, rm) if rm.is_reg() => Instr::setcc(cond, rm),
+                        _ => return Err(format!("Unsupported 0x0f {:#x}", op2)),
+                    }
+                }
+                op2 => return Err(format!("Unsupported 0x0f {:#x}", op2)),
+            },
+            0x99 => Instr::cqo(),
+            0xf7 => match self.modrm_ext(rex)? {
+                (7, rm) => Instr::idiv(rm),
+                (ext, _) => return Err(format!("Unsupported 0xf7 /{}", ext)),
+            },
+            0xc1 | 0xd3 => {
+                let (ext, rm) = self.modrm_ext(rex)?;
+                let count = if op == 0xc1 {
+                    Operand::Imm(self.imm8()?)
+                } else {
+                    RCX.into_reg().into_op()
+                };
+                match ext {
+                    4 => Instr::shl(rm, count),
+                    7 => Instr::sar(rm, count),
+                    _ => return Err(format!("Unsupported {:#x} /{}", op, ext)),
+                }
+            }
+            // The number of arguments isn't encoded, so a call reads all
+            // of the ARG_REGS.
+            0xe8 => Instr::call(self.rel32()?, ARG_REGS.len()),
+            0xe9 => Instr::new1(OpCode::Jmp, self.rel32()?),
+            0xff => match self.modrm_ext(rex)? {
+                (2, rm) => Instr::call(rm, ARG_REGS.len()),
+                (ext, _) => return Err(format!("Unsupported 0xff /{}", ext)),
+            },
+            0xc3 => Instr::ret(RAX.into_reg().into_op()),
+            0x50..=0x57 => Instr::push(mach_op((op - 0x50) | (rex.b << 3))),
+            0x58..=0x5f => Instr::pop(mach_op((op - 0x58) | (rex.b << 3))),
+            _ => return Err(format!("Unsupported opcode {:#x}", op)),
+        };
+        Ok(self.resolve_rip(instr))
+    }
+
+    fn decode_sse(&mut self, prefix: u8, op: u8, rex: Rex) -> Result<Instr, String> {
+        if op != 0x0f {
+            return Err(format!("Unsupported {:#x} {:#x}", prefix, op));
+        }
+        let op2 = self.byte()?;
+        if rex.w != (op2 == 0x2a) {
+            return Err(format!("Unsupported operand size of {:#x} 0x0f {:#x}", prefix, op2));
+        }
+        let (reg, rm) = self.modrm(rex)?;
+        let reg = RegClass::Xmm.reg(reg as usize).into_reg().into_op();
+        let xmm_rm = match rm {
+            Operand::Reg(Reg::Mach(m)) => RegClass::Xmm.reg(m.num()).into_reg().into_op(),
+            _ => rm.clone(),
+        };
+        Ok(match (prefix, op2) {
+            (0xf2, 0x10) => Instr::movsd(reg, xmm_rm),
+            (0xf2, 0x11) if rm.is_mem() => Instr::movsd(rm, reg),
+            (0xf2, 0x58) => Instr::addsd(reg, xmm_rm),
+            (0xf2, 0x59) => Instr::mulsd(reg, xmm_rm),
+            (0xf2, 0x2a) => Instr::cvtsi2sd(reg, rm),
+            (0x66, 0x2e) => Instr::ucomisd(reg, xmm_rm),
+            _ => return Err(format!("Unsupported {:#x} 0x0f {:#x}", prefix, op2)),
+        })
+    }
+
+    fn resolve_rip(&mut self, mut instr: Instr) -> Instr {
+        if let Some(disp) = self.rip_disp.take() {
+            let target = Label::Local((self.pos as i64 + disp as i64) as u32);
+            for op in &mut instr.ops {
+                if let Operand::Mem(ref mut m) = *op {
+                    if m.rip_label().is_some() {
+                        *m = Mem::new_rip(target.clone(), 0);
+                    }
+                }
+            }
+        }
+        instr
+    }
+
+    // (opcode extension, r/m)
+    fn modrm_ext(&mut self, rex: Rex) -> Result<(u8, Operand), String> {
+        let (reg, rm) = self.modrm(rex)?;
+        Ok((reg & 7, rm))
+    }
+
+    // (reg, r/m)
+    fn modrm(&mut self, rex: Rex) -> Result<(u8, Operand), String> {
+        let modrm = self.byte()?;
+        let md = modrm >> 6;
+        let reg = ((modrm >> 3) & 7) | (rex.r << 3);
+        let rm = modrm & 7;
+        if md == 0b11 {
+            return Ok((reg, mach_op(rm | (rex.b << 3))));
+        }
+        if md == 0b00 && rm == 0b101 {
+            self.rip_disp = Some(self.imm32()?);
+            return Ok((reg, Mem::new_rip(Label::Local(0), 0).into_op()));
+        }
+
+        let (base, index, scale) = if rm == 0b100 {
+            let sib = self.byte()?;
+            let index = ((sib >> 3) & 7) | (rex.x << 3);
+            // No index without REX.X means no index at all.
+            let index = if index == 0b100 { None } else { Some(mach_reg(index)) };
+            let base = sib & 7;
+            let scale = if index.is_some() { decode_scale(sib >> 6) } else { Scale::S1 };
+            if md == 0b00 && base == 0b101 {
+                let disp = self.imm32()?;
+                return Ok((reg, Mem { base: None, index, scale, disp }.into_op()));
+            }
+            (base | (rex.b << 3), index, scale)
+        } else {
+            (rm | (rex.b << 3), None, Scale::S1)
+        };
+        let disp = match md {
+            0b00 => 0,
+            0b01 => self.imm8()? as i32,
+            _ => self.imm32()?,
+        };
+        let m = Mem { base: Some(MemBase::Reg(mach_reg(base))), index, scale, disp };
+        Ok((reg, m.into_op()))
+    }
+}
+
+// The opcode of the `op r/m, r` form.
+fn rm_reg_opcode(op: u8) -> OpCode {
+    match op {
+        0x01 => OpCode::Add,
+        0x29 => OpCode::Sub,
+        0x31 => OpCode::Xor,
+        0x39 => OpCode::Cmp,
+        0x89 => OpCode::Mov,
+        0x87 => OpCode::Xchg,
+        _ => unreachable!(),
+    }
+}
+
+fn decode_cond(code: u8) -> Option<Cond> {
+    match code {
+        0x4 => Some(Cond::E),
+        0x5 => Some(Cond::Ne),
+        0xc => Some(Cond::L),
+        0xd => Some(Cond::Ge),
+        0xe => Some(Cond::Le),
+        0xf => Some(Cond::G),
+        0x2 => Some(Cond::B),
+        0x3 => Some(Cond::Ae),
+        0x6 => Some(Cond::Be),
+        0x7 => Some(Cond::A),
+        _ => None,
+    }
+}
+
+fn decode_scale(bits: u8) -> Scale {
+    match bits {
+        0 => Scale::S1,
+        1 => Scale::S2,
+        2 => Scale::S4,
+        _ => Scale::S8,
+    }
+}
+
+fn mach_reg(num: u8) -> Reg {
+    MachReg::new(num as usize).into_reg()
+}
+
+fn mach_op(num: u8) -> Operand {
+    mach_reg(num).into_op()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ::x64::encode::Assembler;
+    use ::test_utils::XorShift;
+
+    fn r(m: MachReg) -> Operand {
+        m.into_reg().into_op()
+    }
+
+    fn random_reg(rng: &mut XorShift) -> Operand {
+        mach_op(rng.below(16) as u8)
+    }
+
+    fn random_mem(rng: &mut XorShift) -> Operand {
+        let disp = match rng.below(3) {
+            0 => 0,
+            1 => rng.below(256) as i32 - 128,
+            _ => rng.next_u64() as i32,
+        };
+        let base = mach_reg(rng.below(16) as u8);
+        if rng.chance(2) {
+            return Mem::new(base, disp).into_op();
+        }
+        // %rsp can't be an index.
+        let index = *rng.pick(&[0, 1, 2, 3, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15]);
+        let scale = *rng.pick(&[Scale::S1, Scale::S2, Scale::S4, Scale::S8]);
+        let base = if rng.chance(4) { None } else { Some(base) };
+        Mem::new_indexed(base, mach_reg(index), scale, disp).into_op()
+    }
+
+    fn random_rm(rng: &mut XorShift) -> Operand {
+        if rng.chance(2) { random_reg(rng) } else { random_mem(rng) }
+    }
+
+    fn random_xmm(rng: &mut XorShift) -> Operand {
+        RegClass::Xmm.reg(rng.below(16)).into_reg().into_op()
+    }
+
+    fn random_xmm_or_mem(rng: &mut XorShift) -> Operand {
+        if rng.chance(2) { random_xmm(rng) } else { random_mem(rng) }
+    }
+
+    fn random_imm(rng: &mut XorShift, width: ImmWidth) -> Operand {
+        let i = rng.next_u64() as Imm;
+        Operand::Imm(match (width, rng.below(2)) {
+            (ImmWidth::Imm8, _) | (_, 0) => i as i8 as Imm,
+            (ImmWidth::Imm32, _) => i as i32 as Imm,
+            (ImmWidth::Imm64, _) => i,
+        })
+    }
+
+    // Only the forms that the encoder accepts.
+    fn random_instr(rng: &mut XorShift) -> Instr {
+        let opcode = *rng.pick(&[
+            OpCode::Add, OpCode::Sub, OpCode::Cmp, OpCode::Mov, OpCode::MovAbs, OpCode::IMul,
+            OpCode::Cqo, OpCode::IDiv, OpCode::Shl, OpCode::Sar, OpCode::Call(0), OpCode::Ret,
+            OpCode::Xchg, OpCode::Push, OpCode::Pop, OpCode::Lea, OpCode::Xor,
+            OpCode::Movsd, OpCode::Addsd, OpCode::Mulsd, OpCode::Cvtsi2sd, OpCode::Ucomisd,
+            OpCode::Setcc(Cond::L),
+        ]);
+        match opcode {
+            OpCode::Add | OpCode::Sub | OpCode::Xor | OpCode::Cmp | OpCode::Mov => {
+                let (dst, src) = match rng.below(3) {
+                    0 => (random_rm(rng), random_reg(rng)),
+                    1 => (random_reg(rng), random_mem(rng)),
+                    _ => (random_rm(rng), random_imm(rng, ImmWidth::Imm32)),
+                };
+                Instr::new2(opcode, dst, src)
+            }
+            OpCode::MovAbs => match random_imm(rng, ImmWidth::Imm64) {
+                Operand::Imm(i) => Instr::movabs(random_reg(rng), i),
+                _ => unreachable!(),
+            },
+            OpCode::Lea => match random_mem(rng) {
+                Operand::Mem(m) => Instr::lea(random_reg(rng), m),
+                _ => unreachable!(),
+            },
+            OpCode::IMul => {
+                let src = if rng.chance(2) {
+                    random_imm(rng, ImmWidth::Imm32)
+                } else {
+                    random_rm(rng)
+                };
+                Instr::imul(random_reg(rng), src)
+            }
+            OpCode::Cqo => Instr::cqo(),
+            OpCode::IDiv => Instr::idiv(random_rm(rng)),
+            OpCode::Shl | OpCode::Sar => {
+                let count = if rng.chance(2) { r(RCX) } else { random_imm(rng, ImmWidth::Imm8) };
+                Instr::new2(opcode, random_rm(rng), count)
+            }
+            OpCode::Call(_) => Instr::call(random_rm(rng), ARG_REGS.len()),
+            OpCode::Ret => Instr::ret(r(RAX)),
+            // The encoder puts a memory operand first.
+            OpCode::Xchg => Instr::xchg(random_rm(rng), random_reg(rng)),
+            OpCode::Push => Instr::push(random_reg(rng)),
+            OpCode::Pop => Instr::pop(random_reg(rng)),
+            OpCode::Movsd if rng.chance(3) => Instr::movsd(random_mem(rng), random_xmm(rng)),
+            OpCode::Movsd | OpCode::Addsd | OpCode::Mulsd | OpCode::Ucomisd => {
+                Instr::new2(opcode, random_xmm(rng), random_xmm_or_mem(rng))
+            }
+            OpCode::Cvtsi2sd => Instr::cvtsi2sd(random_xmm(rng), random_rm(rng)),
+            OpCode::Setcc(_) => {
+                let cond = *rng.pick(&[Cond::L, Cond::Le, Cond::G, Cond::Ge, Cond::E, Cond::Ne,
+                                       Cond::B, Cond::Be, Cond::A, Cond::Ae]);
+                Instr::setcc(cond, random_reg(rng))
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn random_instrs_survive_encode_decode() {
+        let mut rng = XorShift::new(34);
+        for _ in 0..200 {
+            let instrs = (0..50).map(|_| random_instr(&mut rng)).collect::<Vec<_>>();
+            let mut asm = Assembler::new();
+            for instr in &instrs {
+                asm.encode(instr);
+            }
+            let code = asm.finish();
+            let decoded = decode(&code.bytes).unwrap().into_iter()
+                .map(|(_, instr)| instr)
+                .collect::<Vec<_>>();
+            for (expected, actual) in instrs.iter().zip(&decoded) {
+                assert_eq!(expected, actual, "{} decoded as {}", expected, actual);
+            }
+            assert_eq!(instrs.len(), decoded.len());
+        }
+    }
+
+    #[test]
+    fn can_decode_branches_and_rip_relative() {
+        let l0 = Label::Local(0);
+        let l1 = Label::Local(1);
+        let f = Function::new(vec![
+            Block::new(l0.clone(), vec![
+                Instr::cmp(Mem::new_rip(l1.clone(), 8).into_op(), Operand::Imm(1)),
+                Instr::jcc(Cond::Ge, l1.clone()),
+                Instr::jmp(l0.clone()),
+            ]),
+            Block::new(l1.clone(), vec![
+                Instr::call(Label::Named("ext".to_owned()).into_op(), 0),
+                Instr::ret(r(RAX)),
+            ]),
+        ]);
+        let mut asm = Assembler::new();
+        asm.encode_function(&f);
+        let code = asm.finish();
+
+        let local = |offset| Label::Local(offset);
+        assert_eq!(decode(&code.bytes).unwrap(), vec![
+            (0, Instr::cmp(Mem::new_rip(local(8 + 19), 0).into_op(), Operand::Imm(1))),
+            (8, Instr::jcc(Cond::Ge, local(19))),
+            (14, Instr::jmp(local(0))),
+            // The rel32 is left to the reloc.
+            (19, Instr::call(local(24).into_op(), ARG_REGS.len())),
+            (24, Instr::ret(r(RAX))),
+        ]);
+
+        let listing = disassemble(&code.bytes, &code.labels, &code.relocs).unwrap();
+        assert_eq!(listing, [
+            ".L0:",
+            "     0:  48 83 3d 13 00 00 00 01        cmpq $1, 0x1b(%rip)",
+            "     8:  0f 8d 05 00 00 00              jge .L1",
+            "     e:  e9 ed ff ff ff                 jmp .L0",
+            ".L1:",
+            "    13:  e8 00 00 00 00                 call ext",
+            "    18:  c3                             ret",
+            "",
+        ].join("\n"));
+    }
+
+    #[test]
+    fn can_reject_unsupported_bytes() {
+        // 32-bit add, nop, truncated mov
+        assert!(decode(&[0x01, 0xc8]).is_err());
+        assert_eq!(decode(&[0x48, 0x90]), Err("Unsupported opcode 0x90 at 0x0".to_owned()));
+        assert_eq!(decode(&[0x48, 0x89]), Err("Truncated instruction at 0x0".to_owned()));
+    }
+}
@@ -0,0 +1,602 @@
+use std::collections::HashMap;
+
+use ::x64::*;
+
+// Runs Blocks and Functions, both before and after register allocation:
+// VirtualRegs are just more registers, and the ParallelMoves of an
+// instruction are done right before (start) and after (end) it. An XMM holds
+// the bits of an f64.
+//
+// Reading a register or a stack byte that was never written is an error,
+// and so is reading a caller-saved register after a call. That's how a
+// wrong allocation shows up.
+pub struct Emulator {
+    regs: [Option<i64>; 32],
+    vregs: HashMap<VirtualReg, i64>,
+    flags: Option<Flags>,
+    // Covers [STACK_BASE, STACK_BASE + STACK_SIZE).
+    stack: Vec<Option<u8>>,
+    externs: HashMap<String, Extern>,
+    steps: usize,
+    pub max_steps: usize,
+    // Whether call checks that the callee-saved registers are preserved.
+    pub check_callee_saved: bool,
+}
+
+// What an instruction sets them to, as far as the Conds need.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+struct Flags {
+    zf: bool,
+    sf: bool,
+    of: bool,
+    cf: bool,
+}
+
+type ExternFn = dyn Fn(&[i64]) -> i64;
+
+struct Extern {
+    arity: usize,
+    f: Box<ExternFn>,
+}
+
+enum Next {
+    Fallthrough,
+    Jump(Label),
+    Return(i64),
+}
+
+// The stack grows down from the top, so the bottom is free for test data.
+pub const STACK_BASE: u64 = 0x7fff_0000_0000;
+const STACK_SIZE: usize = 64 * 1024;
+// Pushed by call, and expected to be popped by ret.
+const RETURN_ADDRESS: i64 = 0x5eed_0000_0000;
+
+impl Default for Emulator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Emulator {
+    pub fn new() -> Self {
+        let mut emu = Emulator {
+            regs: [None; 32],
+            vregs: HashMap::new(),
+            flags: None,
+            stack: vec![None; STACK_SIZE],
+            externs: HashMap::new(),
+            steps: 0,
+            max_steps: 1_000_000,
+            check_callee_saved: true,
+        };
+        emu.regs[RSP.ix()] = Some((STACK_BASE + STACK_SIZE as u64) as i64);
+        emu
+    }
+
+    // Can be called by `call name`, as a System V function.
+    pub fn define_extern<F: Fn(&[i64]) -> i64 + 'static>(&mut self, name: &str,
+                                                         arity: usize, f: F) {
+        self.externs.insert(name.to_owned(), Extern { arity, f: Box::new(f) });
+    }
+
+    pub fn reg(&self, r: Reg) -> Result<i64, String> {
+        let v = match r {
+            Reg::Mach(m) => self.regs[m.ix()],
+            Reg::Virtual(v) => self.vregs.get(&v).cloned(),
+        };
+        v.ok_or_else(|| format!("{} is undefined", r))
+    }
+
+    pub fn set_reg(&mut self, r: Reg, v: i64) {
+        match r {
+            Reg::Mach(m) => self.regs[m.ix()] = Some(v),
+            Reg::Virtual(vr) => {
+                self.vregs.insert(vr, v);
+            }
+        }
+    }
+
+    pub fn load(&self, addr: i64) -> Result<i64, String> {
+        let at = self.stack_offset(addr)?;
+        let mut v = 0u64;
+        for ix in 0..8 {
+            let b = self.stack[at + ix]
+                .ok_or_else(|| format!("{:#x} is undefined", addr + ix as i64))?;
+            v |= (b as u64) << (ix * 8);
+        }
+        Ok(v as i64)
+    }
+
+    pub fn store(&mut self, addr: i64, v: i64) -> Result<(), String> {
+        let at = self.stack_offset(addr)?;
+        for ix in 0..8 {
+            self.stack[at + ix] = Some((v >> (ix * 8)) as u8);
+        }
+        Ok(())
+    }
+
+    fn stack_offset(&self, addr: i64) -> Result<usize, String> {
+        let offset = (addr as u64).wrapping_sub(STACK_BASE);
+        if offset > (STACK_SIZE - 8) as u64 {
+            return Err(format!("{:#x} is out of the stack", addr));
+        }
+        Ok(offset as usize)
+    }
+
+    fn push(&mut self, v: i64) -> Result<(), String> {
+        let rsp = self.reg(Reg::rsp())? - 8;
+        self.set_reg(Reg::rsp(), rsp);
+        self.store(rsp, v)
+    }
+
+    fn pop(&mut self) -> Result<i64, String> {
+        let rsp = self.reg(Reg::rsp())?;
+        let v = self.load(rsp)?;
+        self.set_reg(Reg::rsp(), rsp + 8);
+        Ok(v)
+    }
+
+    // Calls f with the System V convention and returns %rax. The callee-saved
+    // registers start out with arbitrary values, and the others undefined.
+    pub fn call(&mut self, f: &Function, args: &[i64]) -> Result<i64, String> {
+        for (r, &arg) in ARG_REGS.iter().zip(args) {
+            self.set_reg(r.into_reg(), arg);
+        }
+        for &arg in args.iter().skip(ARG_REGS.len()).rev() {
+            self.push(arg)?;
+        }
+        self.push(RETURN_ADDRESS)?;
+        let saved = CALLEE_SAVED.iter()
+            .map(|&r| (r, self.regs[r.ix()].unwrap_or(r.ix() as i64 * 0x0101_0101)))
+            .collect::<Vec<_>>();
+        for &(r, v) in &saved {
+            self.set_reg(r.into_reg(), v);
+        }
+        let rsp = self.reg(Reg::rsp())?;
+
+        let res = self.run(f)?;
+
+        if self.reg(Reg::rsp())? != rsp + 8 {
+            return Err("%rsp is not restored".to_owned());
+        }
+        if self.check_callee_saved {
+            for (r, v) in saved {
+                if self.regs[r.ix()] != Some(v) {
+                    return Err(format!("{} is not preserved", r));
+                }
+            }
+        }
+        self.set_reg(Reg::rsp(), rsp + 8 + 8 * args.len().saturating_sub(ARG_REGS.len()) as i64);
+        Ok(res)
+    }
+
+    // Runs f from its entry until a ret, which must find the return address
+    // on the stack.
+    pub fn run(&mut self, f: &Function) -> Result<i64, String> {
+        let ixs = f.block_ixs();
+        let mut b = 0;
+        let mut ix = 0;
+        loop {
+            while ix == f.blocks[b].instrs.len() {
+                b += 1;
+                ix = 0;
+                if b == f.blocks.len() {
+                    return Err(format!("Fell off the end of {}", f.blocks[b - 1].label));
+                }
+            }
+            let instr = &f.blocks[b].instrs[ix];
+            self.steps += 1;
+            if self.steps > self.max_steps {
+                return Err("Too many steps".to_owned());
+            }
+            self.run_moves(instr.parallel_moves.start())?;
+            let next = self.step(instr)
+                .map_err(|e| format!("{} at {}", e, instr))?;
+            match next {
+                Next::Fallthrough => {
+                    self.run_moves(instr.parallel_moves.end())?;
+                    ix += 1;
+                }
+                Next::Jump(l) => {
+                    b = *ixs.get(&l).ok_or_else(|| format!("No block {}", l))?;
+                    ix = 0;
+                }
+                Next::Return(v) => return Ok(v),
+            }
+        }
+    }
+
+    // All the srcs are read before any dst is written.
+    fn run_moves(&mut self, moves: &[ParallelMove]) -> Result<(), String> {
+        let vs = moves.iter().map(|m| self.read(m.src())).collect::<Result<Vec<_>, _>>()?;
+        for (m, v) in moves.iter().zip(vs) {
+            self.write(m.dst(), v)?;
+        }
+        Ok(())
+    }
+
+    fn address(&self, m: &Mem) -> Result<i64, String> {
+        if m.rip_label().is_some() {
+            return Err(format!("Can't address {}", m));
+        }
+        let base = match m.base_reg() {
+            Some(r) => self.reg(r)?,
+            None => 0,
+        };
+        let index = match m.index {
+            Some(r) => self.reg(r)?.wrapping_mul(m.scale.factor() as i64),
+            None => 0,
+        };
+        Ok(base.wrapping_add(index).wrapping_add(m.disp as i64))
+    }
+
+    fn read(&self, op: &Operand) -> Result<i64, String> {
+        match *op {
+            Operand::Reg(r) => self.reg(r),
+            Operand::Mem(ref m) => self.load(self.address(m)?),
+            Operand::Imm(i) => Ok(i),
+            Operand::Label(ref l) => Err(format!("Can't read {}", l)),
+        }
+    }
+
+    fn read_f64(&self, op: &Operand) -> Result<f64, String> {
+        Ok(f64::from_bits(self.read(op)? as u64))
+    }
+
+    fn write(&mut self, op: &Operand, v: i64) -> Result<(), String> {
+        match *op {
+            Operand::Reg(r) => {
+                self.set_reg(r, v);
+                Ok(())
+            }
+            Operand::Mem(ref m) => {
+                let addr = self.address(m)?;
+                self.store(addr, v)
+            }
+            _ => Err(format!("Can't write {}", op)),
+        }
+    }
+
+    fn step(&mut self, instr: &Instr) -> Result<Next, String> {
+        let ops = &instr.ops;
+        match instr.opcode {
+            OpCode::Add | OpCode::Sub | OpCode::Cmp => {
+                let (a, b) = (self.read(&ops[0])?, self.read(&ops[1])?);
+                let ((res, of), cf) = if instr.opcode == OpCode::Add {
+                    (a.overflowing_add(b), (a as u64).overflowing_add(b as u64).1)
+                } else {
+                    (a.overflowing_sub(b), (a as u64) < (b as u64))
+                };
+                self.flags = Some(Flags { zf: res == 0, sf: res < 0, of, cf });
+                if instr.opcode != OpCode::Cmp {
+                    self.write(&ops[0], res)?;
+                }
+            }
+            // `xor r, r` doesn't depend on r.
+            OpCode::Xor if ops[0].is_reg() && ops[0] == ops[1] => {
+                self.write(&ops[0], 0)?;
+                self.flags = Some(Flags { zf: true, sf: false, of: false, cf: false });
+            }
+            OpCode::Xor => {
+                let res = self.read(&ops[0])? ^ self.read(&ops[1])?;
+                self.write(&ops[0], res)?;
+                self.flags = Some(Flags { zf: res == 0, sf: res < 0, of: false, cf: false });
+            }
+            OpCode::IMul => {
+                let res = self.read(&ops[0])?.wrapping_mul(self.read(&ops[1])?);
+                self.write(&ops[0], res)?;
+                self.flags = None;
+            }
+            OpCode::Mov | OpCode::MovAbs | OpCode::Movsd => {
+                let v = self.read(&ops[1])?;
+                self.write(&ops[0], v)?;
+            }
+            OpCode::Lea => {
+                let addr = match ops[1] {
+                    Operand::Mem(ref m) => self.address(m)?,
+                    ref op => return Err(format!("Can't lea {}", op)),
+                };
+                self.write(&ops[0], addr)?;
+            }
+            OpCode::Cqo => {
+                let rax = self.reg(RAX.into_reg())?;
+                self.set_reg(RDX.into_reg(), if rax < 0 { -1 } else { 0 });
+            }
+            OpCode::IDiv => {
+                let divisor = self.read(&ops[0])? as i128;
+                let dividend = ((self.reg(RDX.into_reg())? as i128) << 64) |
+                    (self.reg(RAX.into_reg())? as u64 as i128);
+                if divisor == 0 {
+                    return Err("Division by zero".to_owned());
+                }
+                let q = dividend / divisor;
+                if q != q as i64 as i128 {
+                    return Err("Quotient overflow".to_owned());
+                }
+                self.set_reg(RAX.into_reg(), q as i64);
+                self.set_reg(RDX.into_reg(), (dividend % divisor) as i64);
+                self.flags = None;
+            }
+            OpCode::Shl | OpCode::Sar => {
+                let v = self.read(&ops[0])?;
+                let count = (self.read(&ops[1])? & 63) as u32;
+                let res = if instr.opcode == OpCode::Shl { v << count } else { v >> count };
+                self.write(&ops[0], res)?;
+                self.flags = None;
+            }
+            OpCode::Xchg => {
+                let (a, b) = (self.read(&ops[0])?, self.read(&ops[1])?);
+                self.write(&ops[0], b)?;
+                self.write(&ops[1], a)?;
+            }
+            OpCode::Push => {
+                let v = self.read(&ops[0])?;
+                self.push(v)?;
+            }
+            OpCode::Pop => {
+                let v = self.pop()?;
+                self.write(&ops[0], v)?;
+            }
+            OpCode::Call(num_args) => self.call_extern(&ops[0], num_args as usize)?,
+            OpCode::Ret => {
+                let v = self.read(&ops[0])?;
+                let addr = self.pop()?;
+                if addr != RETURN_ADDRESS {
+                    return Err(format!("Returning to {:#x}", addr));
+                }
+                return Ok(Next::Return(v));
+            }
+            OpCode::Jmp => return Ok(Next::Jump(instr.jump_target().unwrap().clone())),
+            OpCode::Jcc(cond) => {
+                let flags = self.flags.ok_or_else(|| "Flags are undefined".to_owned())?;
+                if flags.test(cond) {
+                    return Ok(Next::Jump(instr.jump_target().unwrap().clone()));
+                }
+            }
+            OpCode::Setcc(cond) => {
+                let flags = self.flags.ok_or_else(|| "Flags are undefined".to_owned())?;
+                let v = self.read(&ops[0])? & !0xff;
+                self.write(&ops[0], v | flags.test(cond) as i64)?;
+            }
+            OpCode::Addsd | OpCode::Mulsd => {
+                let (a, b) = (self.read_f64(&ops[0])?, self.read_f64(&ops[1])?);
+                let res = if instr.opcode == OpCode::Addsd { a + b } else { a * b };
+                self.write(&ops[0], res.to_bits() as i64)?;
+            }
+            OpCode::Cvtsi2sd => {
+                let v = self.read(&ops[1])? as f64;
+                self.write(&ops[0], v.to_bits() as i64)?;
+            }
+            OpCode::Ucomisd => {
+                let (a, b) = (self.read_f64(&ops[0])?, self.read_f64(&ops[1])?);
+                let unordered = a.is_nan() || b.is_nan();
+                self.flags = Some(Flags {
+                    zf: unordered || a == b,
+                    sf: false,
+                    of: false,
+                    cf: unordered || a < b,
+                });
+            }
+        }
+        Ok(Next::Fallthrough)
+    }
+
+    fn call_extern(&mut self, target: &Operand, num_args: usize) -> Result<(), String> {
+        let name = match *target {
+            Operand::Label(Label::Named(ref name)) => name,
+            _ => return Err(format!("Can't call {}", target)),
+        };
+        let rsp = self.reg(Reg::rsp())?;
+        if rsp % 16 != 0 {
+            return Err(format!("Misaligned %rsp {:#x} at call", rsp));
+        }
+        let ext = self.externs.get(name).ok_or_else(|| format!("No extern {}", name))?;
+        if ext.arity.min(ARG_REGS.len()) != num_args {
+            return Err(format!("Calling {} with {} register args instead of {}",
+                               name, num_args, ext.arity.min(ARG_REGS.len())));
+        }
+        let mut args = vec![];
+        for ix in 0..ext.arity {
+            args.push(match ARG_REGS.get(ix) {
+                Some(r) => self.reg(r.into_reg())?,
+                None => self.load(rsp + 8 * (ix - ARG_REGS.len()) as i64)?,
+            });
+        }
+        let res = (ext.f)(&args);
+        for r in CALLER_SAVED {
+            self.regs[r.ix()] = None;
+        }
+        self.set_reg(RAX.into_reg(), res);
+        self.flags = None;
+        Ok(())
+    }
+}
+
+impl Flags {
+    fn test(self, cond: Cond) -> bool {
+        match cond {
+            Cond::E => self.zf,
+            Cond::Ne => !self.zf,
+            Cond::L => self.sf != self.of,
+            Cond::Ge => self.sf == self.of,
+            Cond::Le => self.zf || self.sf != self.of,
+            Cond::G => !self.zf && self.sf == self.of,
+            Cond::B => self.cf,
+            Cond::Be => self.cf || self.zf,
+            Cond::A => !self.cf && !self.zf,
+            Cond::Ae => !self.cf,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ::frame;
+
+    fn r(m: MachReg) -> Operand {
+        m.into_reg().into_op()
+    }
+
+    fn v(ix: u32) -> Operand {
+        Operand::new_virt_reg(ix)
+    }
+
+    fn label(ix: u32) -> Label {
+        Label::Local(ix)
+    }
+
+    fn leaf(instrs: Vec<Instr>) -> Function {
+        Function::new(vec![Block::new(label(0), instrs)])
+    }
+
+    #[test]
+    fn can_run_arithmetic_over_virtual_regs() {
+        let f = leaf(vec![
+            Instr::mov(v(0), r(RDI)),
+            Instr::mov(v(1), Operand::Imm(-7)),
+            Instr::imul(v(0), v(1)),
+            Instr::shl(v(0), Operand::Imm(2)),
+            Instr::mov(r(RAX), v(0)),
+            Instr::cqo(),
+            Instr::mov(v(2), Operand::Imm(3)),
+            Instr::idiv(v(2)),
+            Instr::sub(r(RAX), r(RDX)),
+            Instr::ret(r(RAX)),
+        ]);
+        // (5 * -7) << 2 = -140 = 3 * -46 - 2
+        assert_eq!(Emulator::new().call(&f, &[5]), Ok(-46 + 2));
+    }
+
+    #[test]
+    fn can_run_loops() {
+        // for (i = n, acc = 1; i > 1; i--) acc *= i
+        let f = Function::new(vec![
+            Block::new(label(0), vec![
+                Instr::mov(v(0), Operand::Imm(1)),
+                Instr::jmp(label(1)),
+            ]),
+            Block::new(label(1), vec![
+                Instr::cmp(r(RDI), Operand::Imm(1)),
+                Instr::jcc(Cond::Le, label(2)),
+                Instr::imul(v(0), r(RDI)),
+                Instr::sub(r(RDI), Operand::Imm(1)),
+                Instr::jmp(label(1)),
+            ]),
+            Block::new(label(2), vec![Instr::ret(v(0))]),
+        ]);
+        assert_eq!(Emulator::new().call(&f, &[10]), Ok(3628800));
+    }
+
+    #[test]
+    fn can_run_sse_with_unordered_compares() {
+        let xv = |ix| Reg::new_virt_in(RegClass::Xmm, ix).into_op();
+        let arg = |disp| Mem::new(RDI.into_reg(), disp).into_op();
+        // *p0 cond *(p0 + 8), after the lhs is squared and added to p1.
+        let program = |cond| Function::new(vec![
+            Block::new(label(0), vec![
+                Instr::movsd(xv(0), arg(0)),
+                Instr::movsd(xv(1), arg(8)),
+                Instr::mulsd(xv(0), xv(0)),
+                Instr::cvtsi2sd(xv(2), r(RSI)),
+                Instr::addsd(xv(0), xv(2)),
+                Instr::ucomisd(xv(0), xv(1)),
+                Instr::jcc(cond, label(1)),
+                Instr::mov(r(RAX), Operand::Imm(0)),
+                Instr::ret(r(RAX)),
+            ]),
+            Block::new(label(1), vec![
+                Instr::mov(r(RAX), Operand::Imm(1)),
+                Instr::ret(r(RAX)),
+            ]),
+        ]);
+        let p0 = STACK_BASE as i64;
+        let run = |cond, lhs: f64, rhs: f64, p1| {
+            let mut emu = Emulator::new();
+            emu.store(p0, lhs.to_bits() as i64).unwrap();
+            emu.store(p0 + 8, rhs.to_bits() as i64).unwrap();
+            emu.call(&program(cond), &[p0, p1]).unwrap()
+        };
+        // 1.5 * 1.5 + 1 = 3.25
+        assert_eq!(run(Cond::A, 1.5, 3.0, 1), 1);
+        assert_eq!(run(Cond::A, 1.5, 3.25, 1), 0);
+        assert_eq!(run(Cond::Ae, 1.5, 3.25, 1), 1);
+        assert_eq!(run(Cond::B, 1.5, 3.5, 1), 1);
+        assert_eq!(run(Cond::Be, 1.5, 3.25, 1), 1);
+        assert_eq!(run(Cond::Be, -1.5, 0.0, -3), 1);
+        assert_eq!(run(Cond::E, 2.0, 4.0, 0), 1);
+        // Unordered is both below and equal, but never above.
+        for &cond in &[Cond::A, Cond::Ae] {
+            assert_eq!(run(cond, f64::NAN, 0.0, 0), 0);
+            assert_eq!(run(cond, 0.0, f64::NAN, 0), 0);
+        }
+        assert_eq!(run(Cond::B, f64::NAN, 0.0, 0), 1);
+        assert_eq!(run(Cond::E, f64::NAN, 0.0, 0), 1);
+    }
+
+    #[test]
+    fn can_do_parallel_moves_simultaneously() {
+        let mut swap = Instr::mov(v(2), v(0));
+        swap.parallel_moves.add_to_start(ParallelMove::new(v(0), v(1)));
+        swap.parallel_moves.add_to_start(ParallelMove::new(v(1), v(0)));
+        let mut ret = Instr::ret(v(3));
+        swap.parallel_moves.add_to_end(ParallelMove::new(v(3), v(1)));
+        ret.parallel_moves.add_to_start(ParallelMove::new(v(3), v(2)));
+        let f = leaf(vec![
+            Instr::mov(v(0), Operand::Imm(10)),
+            Instr::mov(v(1), Operand::Imm(20)),
+            swap,
+            Instr::sub(v(3), v(2)),
+            ret,
+        ]);
+        // v2 = 20, v3 = 10 - 20, then v3 = v2 again.
+        assert_eq!(Emulator::new().call(&f, &[]), Ok(20));
+    }
+
+    #[test]
+    fn can_call_externs_with_stack_args() {
+        let args = (1..9).map(Operand::Imm).collect::<Vec<_>>();
+        let mut instrs = frame::lower_call(Label::Named("sum".to_owned()).into_op(),
+                                           &args, Some(Reg::new_virt(0)));
+        instrs.push(Instr::add(v(0), r(RBX)));
+        instrs.push(Instr::ret(v(0)));
+        let mut f = leaf(instrs);
+        // %rbx is written by nobody, so it still holds the caller's value.
+        f.blocks[0].instrs.insert(0, Instr::mov(r(RBX), Operand::Imm(100)));
+        frame::insert_frame(&mut f);
+
+        let mut emu = Emulator::new();
+        emu.define_extern("sum", 8, |args| args.iter().sum());
+        assert_eq!(emu.call(&f, &[]), Ok(136));
+    }
+
+    #[test]
+    fn can_catch_broken_programs() {
+        let run = |instrs: Vec<Instr>| Emulator::new().call(&leaf(instrs), &[1]);
+        assert_eq!(run(vec![Instr::ret(v(0))]),
+                   Err("%v0 is undefined at ret".to_owned()));
+        // Caller-saved registers are dead after a call.
+        let mut emu = Emulator::new();
+        emu.define_extern("f", 0, |_| 0);
+        let mut f = leaf(vec![
+            Instr::call(Label::Named("f".to_owned()).into_op(), 0),
+            Instr::mov(r(RAX), r(RDI)),
+            Instr::ret(r(RAX)),
+        ]);
+        frame::insert_frame(&mut f);
+        assert_eq!(emu.call(&f, &[1]), Err("%rdi is undefined at movq %rdi, %rax".to_owned()));
+        // A call that leaves out an argument register.
+        emu.define_extern("f", 1, |args| args[0]);
+        assert_eq!(emu.call(&f, &[1]),
+                   Err("Calling f with 0 register args instead of 1 at call f".to_owned()));
+        // Clobbering a callee-saved register.
+        assert_eq!(run(vec![Instr::mov(r(R12), r(RDI)), Instr::mov(r(RAX), r(RDI)),
+                            Instr::ret(r(RAX))]),
+                   Err("%r12 is not preserved".to_owned()));
+        // Unbalanced stack.
+        assert_eq!(run(vec![Instr::push(r(RDI)), Instr::mov(r(RAX), r(RDI)),
+                            Instr::ret(r(RAX))]),
+                   Err("Returning to 0x1 at ret".to_owned()));
+        assert_eq!(run(vec![Instr::jmp(label(0))]), Err("Too many steps".to_owned()));
+    }
+}
@@ -0,0 +1,617 @@
+use std::collections::HashMap;
+
+use ::x64::*;
+
+// A 32-bit pc-relative field that refers to a label.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Fixup {
+    // Where the field is in the code.
+    pub offset: usize,
+    pub label: Label,
+    // Same as in ELF's RELA: the field becomes label + addend - offset.
+    pub addend: i64,
+    pub kind: FixupKind,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum FixupKind {
+    // The target of a call or a jump.
+    Branch,
+    // A RIP-relative memory operand.
+    RipRel,
+}
+
+pub struct Assembler {
+    buf: Vec<u8>,
+    labels: HashMap<Label, usize>,
+    fixups: Vec<Fixup>,
+}
+
+#[derive(Debug)]
+pub struct Code {
+    pub bytes: Vec<u8>,
+    pub labels: HashMap<Label, usize>,
+    // Fixups whose labels are not bound in this code, e.g. external symbols.
+    pub relocs: Vec<Fixup>,
+}
+
+impl Default for Assembler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+const REX: u8 = 0x40;
+const REX_W: u8 = 0x48;
+
+impl Assembler {
+    pub fn new() -> Self {
+        Assembler {
+            buf: vec![],
+            labels: HashMap::new(),
+            fixups: vec![],
+        }
+    }
+
+    pub fn offset(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub fn bind(&mut self, label: Label) {
+        let offset = self.offset();
+        let old = self.labels.insert(label, offset);
+        debug_assert!(old.is_none(), "Label bound twice");
+    }
+
+    pub fn finish(mut self) -> Code {
+        let mut relocs = vec![];
+        for fixup in self.fixups.drain(..) {
+            if let Some(&target) = self.labels.get(&fixup.label) {
+                let value = target as i64 + fixup.addend - fixup.offset as i64;
+                debug_assert!(fits_i32(value));
+                write_i32(&mut self.buf[fixup.offset..], value as i32);
+            } else {
+                relocs.push(fixup);
+            }
+        }
+        Code {
+            bytes: self.buf,
+            labels: self.labels,
+            relocs,
+        }
+    }
+
+    pub fn encode_block(&mut self, b: &Block) {
+        self.bind(b.label.clone());
+        for instr in b.instrs() {
+            self.encode(instr);
+        }
+    }
+
+    // In the order of f.blocks, so a jmp to the next block is left out.
+    pub fn encode_function(&mut self, f: &Function) {
+        for (ix, b) in f.blocks.iter().enumerate() {
+            let next = f.blocks.get(ix + 1).map(|next| &next.label);
+            let instrs = match b.instrs.split_last() {
+                Some((last, init)) if last.opcode == OpCode::Jmp &&
+                    next.is_some() && last.jump_target() == next => init,
+                _ => &b.instrs[..],
+            };
+            self.bind(b.label.clone());
+            for instr in instrs {
+                self.encode(instr);
+            }
+        }
+    }
+
+    // Only MachRegs are encodable.
+    pub fn encode(&mut self, instr: &Instr) {
+        debug_assert!(instr.parallel_moves.is_empty(),
+                      "Unresolved parallel moves in {:?}", instr);
+        let ops = &instr.ops;
+        match instr.opcode {
+            OpCode::Add => self.emit_alu(0x01, 0x03, 0, &ops[0], &ops[1]),
+            OpCode::Sub => self.emit_alu(0x29, 0x2b, 5, &ops[0], &ops[1]),
+            OpCode::Xor => self.emit_alu(0x31, 0x33, 6, &ops[0], &ops[1]),
+            OpCode::Cmp => self.emit_alu(0x39, 0x3b, 7, &ops[0], &ops[1]),
+            OpCode::Mov => self.emit_mov(&ops[0], &ops[1]),
+            OpCode::MovAbs => {
+                // REX.W B8+rd io
+                let dst = reg_num(&ops[0]);
+                let i = match ops[1] {
+                    Operand::Imm(i) => i,
+                    ref src => panic!("movabs from {:?}", src),
+                };
+                self.buf.push(REX_W | rex_bit(dst, 0));
+                self.buf.push(0xb8 + (dst & 7));
+                for ix in 0..8 {
+                    self.buf.push((i >> (ix * 8)) as u8);
+                }
+            }
+            OpCode::Lea => {
+                debug_assert!(ops[1].is_mem(), "lea from {:?}", ops[1]);
+                self.emit_modrm(&[0x8d], reg_num(&ops[0]), &ops[1], None)
+            }
+            OpCode::IMul => {
+                let dst = reg_num(&ops[0]);
+                match ops[1] {
+                    Operand::Imm(i) => {
+                        let i = imm32(i);
+                        if fits_i8(i) {
+                            self.emit_modrm(&[0x6b], dst, &ops[0], Some((i, 1)));
+                        } else {
+                            self.emit_modrm(&[0x69], dst, &ops[0], Some((i, 4)));
+                        }
+                    }
+                    ref src => self.emit_modrm(&[0x0f, 0xaf], dst, src, None),
+                }
+            }
+            OpCode::Cqo => self.buf.extend(&[REX_W, 0x99]),
+            OpCode::IDiv => self.emit_modrm(&[0xf7], 7, &ops[0], None),
+            OpCode::Shl => self.emit_shift(4, &ops[0], &ops[1]),
+            OpCode::Sar => self.emit_shift(7, &ops[0], &ops[1]),
+            OpCode::Call(_) => match ops[0] {
+                Operand::Label(ref l) => self.emit_rel32(&[0xe8], l),
+                // Always 64-bit, no REX.W needed.
+                ref target => self.emit_modrm_w(false, &[0xff], 2, target, None),
+            },
+            OpCode::Ret => self.buf.push(0xc3),
+            OpCode::Xchg => match (&ops[0], &ops[1]) {
+                (rm, &Operand::Reg(_)) => self.emit_modrm(&[0x87], reg_num(&ops[1]), rm, None),
+                (&Operand::Reg(_), rm) => self.emit_modrm(&[0x87], reg_num(&ops[0]), rm, None),
+                _ => panic!("Can't encode xchg {:?}", ops),
+            },
+            // 50+rd and 58+rd, always 64-bit.
+            OpCode::Push => self.emit_opcode_reg(0x50, reg_num(&ops[0])),
+            OpCode::Pop => self.emit_opcode_reg(0x58, reg_num(&ops[0])),
+            OpCode::Jmp => self.emit_rel32(&[0xe9], instr.jump_target().unwrap()),
+            OpCode::Jcc(cond) => {
+                self.emit_rel32(&[0x0f, 0x80 | cond_code(cond)], instr.jump_target().unwrap())
+            }
+            // 0F 90+cc /0, only into a register. %spl to %dil need a REX, or
+            // they would be %ah to %bh.
+            OpCode::Setcc(cond) => {
+                let rm = reg_num(&ops[0]);
+                if rm >= 4 {
+                    self.buf.push(REX | rex_bit(rm, 0));
+                }
+                self.buf.extend(&[0x0f, 0x90 | cond_code(cond), modrm(0b11, 0, rm)]);
+            }
+            OpCode::Movsd => match ops[0] {
+                Operand::Mem(_) => self.emit_sse(0xf2, false, 0x11, &ops[1], &ops[0]),
+                _ => self.emit_sse(0xf2, false, 0x10, &ops[0], &ops[1]),
+            },
+            OpCode::Addsd => self.emit_sse(0xf2, false, 0x58, &ops[0], &ops[1]),
+            OpCode::Mulsd => self.emit_sse(0xf2, false, 0x59, &ops[0], &ops[1]),
+            OpCode::Cvtsi2sd => self.emit_sse(0xf2, true, 0x2a, &ops[0], &ops[1]),
+            OpCode::Ucomisd => self.emit_sse(0x66, false, 0x2e, &ops[0], &ops[1]),
+        }
+    }
+
+    // prefix [REX] 0F opcode ModRM: the prefix goes before the REX.
+    fn emit_sse(&mut self, prefix: u8, w: bool, opcode: u8, reg: &Operand, rm: &Operand) {
+        self.buf.push(prefix);
+        self.emit_modrm_w(w, &[0x0f, opcode], reg_num(reg), rm, None)
+    }
+
+    fn emit_opcode_reg(&mut self, opcode: u8, r: u8) {
+        self.emit_rex(REX | rex_bit(r, 0));
+        self.buf.push(opcode + (r & 7));
+    }
+
+    fn emit_rel32(&mut self, opcode: &[u8], target: &Label) {
+        self.buf.extend(opcode);
+        let offset = self.buf.len();
+        self.fixups.push(Fixup {
+            offset,
+            label: target.clone(),
+            addend: -4,
+            kind: FixupKind::Branch,
+        });
+        self.buf.extend(&[0; 4]);
+    }
+
+    // op r/m, r | op r, r/m | op r/m, imm
+    fn emit_alu(&mut self, mr: u8, rm: u8, ext: u8, dst: &Operand, src: &Operand) {
+        match (dst, src) {
+            (_, &Operand::Imm(i)) => {
+                let i = imm32(i);
+                if fits_i8(i) {
+                    self.emit_modrm(&[0x83], ext, dst, Some((i, 1)));
+                } else {
+                    self.emit_modrm(&[0x81], ext, dst, Some((i, 4)));
+                }
+            }
+            (_, &Operand::Reg(_)) => self.emit_modrm(&[mr], reg_num(src), dst, None),
+            (&Operand::Reg(_), &Operand::Mem(_)) => {
+                self.emit_modrm(&[rm], reg_num(dst), src, None)
+            }
+            _ => panic!("Can't encode {:?}, {:?}", dst, src),
+        }
+    }
+
+    fn emit_mov(&mut self, dst: &Operand, src: &Operand) {
+        match (dst, src) {
+            (_, &Operand::Imm(i)) => self.emit_modrm(&[0xc7], 0, dst, Some((imm32(i), 4))),
+            (_, &Operand::Reg(_)) => self.emit_modrm(&[0x89], reg_num(src), dst, None),
+            (&Operand::Reg(_), &Operand::Mem(_)) => {
+                self.emit_modrm(&[0x8b], reg_num(dst), src, None)
+            }
+            _ => panic!("Can't encode mov {:?}, {:?}", dst, src),
+        }
+    }
+
+    fn emit_shift(&mut self, ext: u8, dst: &Operand, count: &Operand) {
+        match *count {
+            Operand::Imm(i) => self.emit_modrm(&[0xc1], ext, dst, Some((imm32(i), 1))),
+            Operand::Reg(Reg::Mach(RCX)) => self.emit_modrm(&[0xd3], ext, dst, None),
+            _ => panic!("Shift count must be %cl or an imm: {:?}", count),
+        }
+    }
+
+    // REX.W opcode ModRM [SIB] [disp] [imm]
+    // `reg` is either a register number or an opcode extension.
+    fn emit_modrm(&mut self, opcode: &[u8], reg: u8, rm: &Operand, imm: Option<(i32, usize)>) {
+        self.emit_modrm_w(true, opcode, reg, rm, imm)
+    }
+
+    fn emit_modrm_w(&mut self, w: bool, opcode: &[u8], reg: u8, rm: &Operand,
+                    imm: Option<(i32, usize)>) {
+        let imm_len = imm.map_or(0, |(_, len)| len);
+        let rex = if w { REX_W } else { REX };
+        match *rm {
+            Operand::Reg(_) => {
+                let rm = reg_num(rm);
+                self.emit_rex(rex | rex_bit(reg, 2) | rex_bit(rm, 0));
+                self.buf.extend(opcode);
+                self.buf.push(modrm(0b11, reg, rm));
+            }
+            Operand::Mem(ref m) => self.emit_mem(rex, opcode, reg, m, imm_len),
+            Operand::Imm(_) | Operand::Label(_) => panic!("{:?} is not a r/m operand", rm),
+        }
+        match imm {
+            Some((i, 1)) => self.buf.push(i as i8 as u8),
+            Some((i, 4)) => {
+                let at = self.buf.len();
+                self.buf.extend(&[0; 4]);
+                write_i32(&mut self.buf[at..], i);
+            }
+            Some((_, len)) => panic!("Unsupported imm size {}", len),
+            None => (),
+        }
+    }
+
+    // A REX without any bit set is redundant.
+    fn emit_rex(&mut self, rex: u8) {
+        if rex != REX {
+            self.buf.push(rex);
+        }
+    }
+
+    fn emit_mem(&mut self, rex: u8, opcode: &[u8], reg: u8, m: &Mem, imm_len: usize) {
+        if let Some(label) = m.rip_label() {
+            debug_assert!(m.index.is_none(), "RIP-relative with an index: {:?}", m);
+            self.emit_rex(rex | rex_bit(reg, 2));
+            self.buf.extend(opcode);
+            self.buf.push(modrm(0b00, reg, 0b101));
+            let offset = self.buf.len();
+            self.fixups.push(Fixup {
+                offset,
+                label: label.clone(),
+                // Relative to the end of the instruction.
+                addend: m.disp as i64 - 4 - imm_len as i64,
+                kind: FixupKind::RipRel,
+            });
+            self.buf.extend(&[0; 4]);
+            return;
+        }
+
+        let base = m.base_reg().map(mach_num);
+        let index = m.index.map(mach_num);
+        debug_assert!(index != Some(RSP.0 as u8), "%rsp can't be an index");
+
+        self.emit_rex(rex | rex_bit(reg, 2) |
+                      rex_bit(index.unwrap_or(0), 1) |
+                      rex_bit(base.unwrap_or(0), 0));
+        self.buf.extend(opcode);
+
+        let base = match base {
+            Some(base) => base,
+            None => {
+                // [index * scale + disp32]: SIB with no base.
+                self.buf.push(modrm(0b00, reg, 0b100));
+                self.buf.push(sib(m.scale, index.unwrap_or(0b100), 0b101));
+                self.emit_disp32(m.disp);
+                return;
+            }
+        };
+
+        // [rbp] and [r13] can only be encoded with a displacement.
+        let md = if m.disp == 0 && base & 7 != 0b101 {
+            0b00
+        } else if fits_i8(m.disp) {
+            0b01
+        } else {
+            0b10
+        };
+
+        // [rsp] and [r12] always need a SIB.
+        if index.is_some() || base & 7 == 0b100 {
+            self.buf.push(modrm(md, reg, 0b100));
+            self.buf.push(sib(m.scale, index.unwrap_or(0b100), base));
+        } else {
+            self.buf.push(modrm(md, reg, base));
+        }
+
+        match md {
+            0b01 => self.buf.push(m.disp as i8 as u8),
+            0b10 => self.emit_disp32(m.disp),
+            _ => (),
+        }
+    }
+
+    fn emit_disp32(&mut self, disp: i32) {
+        let at = self.buf.len();
+        self.buf.extend(&[0; 4]);
+        write_i32(&mut self.buf[at..], disp);
+    }
+}
+
+fn mach_num(r: Reg) -> u8 {
+    match r {
+        Reg::Mach(m) => m.num() as u8,
+        Reg::Virtual(_) => panic!("Can't encode {:?}", r),
+    }
+}
+
+fn reg_num(op: &Operand) -> u8 {
+    match *op {
+        Operand::Reg(r) => mach_num(r),
+        _ => panic!("{:?} is not a reg", op),
+    }
+}
+
+fn cond_code(cond: Cond) -> u8 {
+    match cond {
+        Cond::E => 0x4,
+        Cond::Ne => 0x5,
+        Cond::L => 0xc,
+        Cond::Ge => 0xd,
+        Cond::Le => 0xe,
+        Cond::G => 0xf,
+        Cond::B => 0x2,
+        Cond::Ae => 0x3,
+        Cond::Be => 0x6,
+        Cond::A => 0x7,
+    }
+}
+
+fn imm32(i: Imm) -> i32 {
+    debug_assert!(ImmWidth::Imm32.fits(i), "Imm not legalized: {}", i);
+    i as i32
+}
+
+fn rex_bit(num: u8, shift: u8) -> u8 {
+    ((num >> 3) & 1) << shift
+}
+
+fn modrm(md: u8, reg: u8, rm: u8) -> u8 {
+    (md << 6) | ((reg & 7) << 3) | (rm & 7)
+}
+
+fn sib(scale: Scale, index: u8, base: u8) -> u8 {
+    (scale.log2() << 6) | ((index & 7) << 3) | (base & 7)
+}
+
+fn fits_i8(i: i32) -> bool {
+    i as i8 as i32 == i
+}
+
+fn fits_i32(i: i64) -> bool {
+    i as i32 as i64 == i
+}
+
+fn write_i32(buf: &mut [u8], i: i32) {
+    for (ix, b) in buf[..4].iter_mut().enumerate() {
+        *b = (i >> (ix * 8)) as u8;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn r(m: MachReg) -> Operand {
+        m.into_reg().into_op()
+    }
+
+    fn encode(instrs: Vec<Instr>) -> Code {
+        let mut asm = Assembler::new();
+        for instr in &instrs {
+            asm.encode(instr);
+        }
+        asm.finish()
+    }
+
+    fn assert_encodes_to(instr: Instr, bytes: &[u8]) {
+        let text = format!("{}", instr);
+        let code = encode(vec![instr]);
+        assert_eq!(code.bytes, bytes, "{}", text);
+    }
+
+    #[test]
+    fn can_encode_reg_and_imm_forms() {
+        assert_encodes_to(Instr::mov(r(RAX), r(RCX)), &[0x48, 0x89, 0xc8]);
+        assert_encodes_to(Instr::add(r(R8), r(R15)), &[0x4d, 0x01, 0xf8]);
+        assert_encodes_to(Instr::sub(r(RDX), Operand::Imm(8)), &[0x48, 0x83, 0xea, 0x08]);
+        assert_encodes_to(Instr::xor(r(RAX), r(RAX)), &[0x48, 0x31, 0xc0]);
+        assert_encodes_to(Instr::cmp(r(RBX), Operand::Imm(1000)),
+                          &[0x48, 0x81, 0xfb, 0xe8, 0x03, 0x00, 0x00]);
+        assert_encodes_to(Instr::imul(r(RAX), r(R9)), &[0x49, 0x0f, 0xaf, 0xc1]);
+        assert_encodes_to(Instr::shl(r(RSI), r(RCX)), &[0x48, 0xd3, 0xe6]);
+        assert_encodes_to(Instr::sar(r(R12), Operand::Imm(3)), &[0x49, 0xc1, 0xfc, 0x03]);
+        assert_encodes_to(Instr::cmp(r(RCX), Operand::Imm(-1)), &[0x48, 0x83, 0xf9, 0xff]);
+        assert_encodes_to(Instr::mov(r(RDI), Operand::Imm(-2)),
+                          &[0x48, 0xc7, 0xc7, 0xfe, 0xff, 0xff, 0xff]);
+        assert_encodes_to(Instr::movabs(r(R9), 0x123456789),
+                          &[0x49, 0xb9, 0x89, 0x67, 0x45, 0x23, 0x01, 0x00, 0x00, 0x00]);
+        assert_encodes_to(Instr::cqo(), &[0x48, 0x99]);
+        assert_encodes_to(Instr::xchg(r(RBX), r(R8)), &[0x4c, 0x87, 0xc3]);
+        assert_encodes_to(Instr::idiv(r(R10)), &[0x49, 0xf7, 0xfa]);
+        assert_encodes_to(Instr::call(r(R11), 0), &[0x41, 0xff, 0xd3]);
+        assert_encodes_to(Instr::call(r(RAX), 0), &[0xff, 0xd0]);
+        assert_encodes_to(Instr::ret(r(RAX)), &[0xc3]);
+        assert_encodes_to(Instr::push(r(RBP)), &[0x55]);
+        assert_encodes_to(Instr::push(r(R12)), &[0x41, 0x54]);
+        assert_encodes_to(Instr::pop(r(RBX)), &[0x5b]);
+        assert_encodes_to(Instr::pop(r(R15)), &[0x41, 0x5f]);
+        assert_encodes_to(Instr::setcc(Cond::E, r(RAX)), &[0x0f, 0x94, 0xc0]);
+        assert_encodes_to(Instr::setcc(Cond::L, r(RSI)), &[0x40, 0x0f, 0x9c, 0xc6]);
+        assert_encodes_to(Instr::setcc(Cond::A, r(R9)), &[0x41, 0x0f, 0x97, 0xc1]);
+    }
+
+    #[test]
+    fn can_encode_sse() {
+        assert_encodes_to(Instr::movsd(r(XMM1), r(XMM2)), &[0xf2, 0x0f, 0x10, 0xca]);
+        assert_encodes_to(Instr::addsd(r(XMM0), r(XMM9)), &[0xf2, 0x41, 0x0f, 0x58, 0xc1]);
+        assert_encodes_to(Instr::mulsd(r(XMM12), r(XMM3)), &[0xf2, 0x44, 0x0f, 0x59, 0xe3]);
+        assert_encodes_to(Instr::cvtsi2sd(r(XMM0), r(RDI)), &[0xf2, 0x48, 0x0f, 0x2a, 0xc7]);
+        assert_encodes_to(Instr::ucomisd(r(XMM1), r(XMM0)), &[0x66, 0x0f, 0x2e, 0xc8]);
+        // movsd 8(%rsp), %xmm3 and back
+        let slot = Mem::new(Reg::rsp(), 8).into_op();
+        assert_encodes_to(Instr::movsd(r(XMM3), slot.clone()),
+                          &[0xf2, 0x0f, 0x10, 0x5c, 0x24, 0x08]);
+        assert_encodes_to(Instr::movsd(slot, r(XMM3)), &[0xf2, 0x0f, 0x11, 0x5c, 0x24, 0x08]);
+        assert_encodes_to(Instr::jcc(Cond::A, Label::Local(0)),
+                          &[0x0f, 0x87, 0x00, 0x00, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn can_encode_addressing_modes() {
+        // mov -16(%rbp), %rax
+        assert_encodes_to(Instr::mov(r(RAX), Mem::new(RBP.into_reg(), -16).into_op()),
+                          &[0x48, 0x8b, 0x45, 0xf0]);
+        // mov %rcx, (%rsp)
+        assert_encodes_to(Instr::mov(Mem::new(RSP.into_reg(), 0).into_op(), r(RCX)),
+                          &[0x48, 0x89, 0x0c, 0x24]);
+        // add 0(%r13), %rdx
+        assert_encodes_to(Instr::add(r(RDX), Mem::new(R13.into_reg(), 0).into_op()),
+                          &[0x49, 0x03, 0x55, 0x00]);
+        // mov (%rdi, %r12, 8), %rsi
+        let m = Mem::new_indexed(Some(RDI.into_reg()), R12.into_reg(), Scale::S8, 0);
+        assert_encodes_to(Instr::mov(r(RSI), m.into_op()), &[0x4a, 0x8b, 0x34, 0xe7]);
+        // movq $7, 1024(%rax, %rcx, 4)
+        let m = Mem::new_indexed(Some(RAX.into_reg()), RCX.into_reg(), Scale::S4, 1024);
+        assert_encodes_to(Instr::mov(m.into_op(), Operand::Imm(7)),
+                          &[0x48, 0xc7, 0x84, 0x88, 0x00, 0x04, 0x00, 0x00,
+                            0x07, 0x00, 0x00, 0x00]);
+        // mov -8(, %rbx, 2), %rax
+        let m = Mem::new_indexed(None, RBX.into_reg(), Scale::S2, -8);
+        assert_encodes_to(Instr::mov(r(RAX), m.into_op()),
+                          &[0x48, 0x8b, 0x04, 0x5d, 0xf8, 0xff, 0xff, 0xff]);
+        // leaq 16(%rdi, %rsi, 8), %rax
+        let m = Mem::new_indexed(Some(RDI.into_reg()), RSI.into_reg(), Scale::S8, 16);
+        assert_encodes_to(Instr::lea(r(RAX), m), &[0x48, 0x8d, 0x44, 0xf7, 0x10]);
+    }
+
+    #[test]
+    fn can_encode_rip_relative() {
+        let pool = Label::Local(0);
+        let ext = Label::Named("ext".to_owned());
+        let mut asm = Assembler::new();
+        // cmpq $1, pool+8(%rip)
+        asm.encode(&Instr::cmp(Mem::new_rip(pool.clone(), 8).into_op(), Operand::Imm(1)));
+        // mov ext(%rip), %rax
+        asm.encode(&Instr::mov(r(RAX), Mem::new_rip(ext.clone(), 0).into_op()));
+        asm.encode(&Instr::ret(r(RAX)));
+        asm.bind(pool);
+        let code = asm.finish();
+
+        // pool is at 16, the cmp ends at 8.
+        assert_eq!(&code.bytes[..8], &[0x48, 0x83, 0x3d, 0x10, 0x00, 0x00, 0x00, 0x01]);
+        assert_eq!(&code.bytes[8..11], &[0x48, 0x8b, 0x05]);
+        assert_eq!(code.relocs, vec![Fixup {
+            offset: 11,
+            label: ext,
+            addend: -4,
+            kind: FixupKind::RipRel,
+        }]);
+    }
+
+    #[test]
+    fn can_encode_jumps() {
+        let l0 = Label::Local(0);
+        let l1 = Label::Local(1);
+        let f = Function::new(vec![
+            Block::new(l0.clone(), vec![
+                Instr::cmp(r(RDI), Operand::Imm(0)),
+                Instr::jcc(Cond::Le, l1.clone()),
+                Instr::jmp(l0.clone()),
+            ]),
+            Block::new(l1.clone(), vec![
+                Instr::call(Label::Named("ext".to_owned()).into_op(), 0),
+                Instr::ret(r(RAX)),
+            ]),
+        ]);
+        let mut asm = Assembler::new();
+        asm.encode_function(&f);
+        let code = asm.finish();
+
+        assert_eq!(code.bytes, vec![
+            0x48, 0x83, 0xff, 0x00,
+            // jle .L1
+            0x0f, 0x8e, 0x05, 0x00, 0x00, 0x00,
+            // jmp .L0
+            0xe9, 0xf1, 0xff, 0xff, 0xff,
+            // call ext
+            0xe8, 0x00, 0x00, 0x00, 0x00,
+            0xc3,
+        ]);
+        assert_eq!(code.labels[&l1], 15);
+        assert_eq!(code.relocs, vec![Fixup {
+            offset: 16,
+            label: Label::Named("ext".to_owned()),
+            addend: -4,
+            kind: FixupKind::Branch,
+        }]);
+    }
+
+    #[test]
+    fn can_leave_out_jumps_to_the_next_block() {
+        let l = Label::Local;
+        let f = Function::new(vec![
+            Block::new(l(0), vec![
+                Instr::cmp(r(RDI), Operand::Imm(0)),
+                Instr::jcc(Cond::Le, l(2)),
+                Instr::jmp(l(1)),
+            ]),
+            Block::new(l(1), vec![Instr::jmp(l(2))]),
+            Block::new(l(2), vec![Instr::ret(r(RAX))]),
+        ]);
+        let mut asm = Assembler::new();
+        asm.encode_function(&f);
+        let code = asm.finish();
+
+        assert_eq!(code.bytes, vec![
+            0x48, 0x83, 0xff, 0x00,
+            // jle .L2
+            0x0f, 0x8e, 0x00, 0x00, 0x00, 0x00,
+            0xc3,
+        ]);
+        assert_eq!(code.labels[&l(1)], 10);
+        assert_eq!(code.labels[&l(2)], 10);
+    }
+
+    #[test]
+    fn can_print_addressing_modes() {
+        let m = Mem::new_indexed(Some(Reg::new_virt(0)), RCX.into_reg(), Scale::S8, -16);
+        assert_eq!(format!("{}", Instr::mov(r(RAX), m.into_op())),
+                   "movq -16(%v0, %rcx, 8), %rax");
+        let m = Mem::new_indexed(None, RBX.into_reg(), Scale::S2, 0);
+        assert_eq!(format!("{}", Instr::add(m.into_op(), Operand::Imm(1))),
+                   "addq $1, (, %rbx, 2)");
+        let m = Mem::new_rip(Label::Named("pool".to_owned()), 8);
+        assert_eq!(format!("{}", Instr::call(m.into_op(), 0)), "call *pool+8(%rip)");
+        assert_eq!(format!("{}", Instr::shl(r(RDX), r(RCX))), "shlq %cl, %rdx");
+    }
+}